@@ -1,15 +1,19 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
-use image::{codecs::gif::GifDecoder, AnimationDecoder};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, ImageDecoder};
 use serde::{Deserialize, Serialize};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::mpsc::{self, Sender, Receiver};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::process::{Child, ChildStderr, Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::thread;
-use tauri::State;
+use std::time::{Duration, Instant, SystemTime};
+use tauri::{ipc::{Channel, InvokeBody, Request, Response}, AppHandle, Emitter, Manager, State};
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
@@ -31,6 +35,33 @@ pub enum GifError {
     #[error("gifsicle命令执行失败: {0}")]
     GifsicleExecFailed(String),
     
+    #[error("gifsicle崩溃: {0}")]
+    GifsicleCrashed(String),
+    
+    #[error("gifsicle输出的文件无效: {0}")]
+    GifsicleInvalidOutput(String),
+    
+    #[error("gifsicle超过 {0} 秒没有结束，已终止")]
+    GifsicleTimeout(u64),
+    
+    #[error("调色板文件无效: {0}")]
+    InvalidPalette(String),
+    
+    #[error("参数无效: {0}")]
+    InvalidOption(String),
+    
+    #[error("下载失败: {0}")]
+    Download(String),
+    
+    #[error("只节省了 {saved_percent:.1}%，低于要求的 {required_percent:.1}%，未写出输出文件")]
+    InsufficientSavings { original_kb: f64, best_kb: f64, saved_percent: f64, required_percent: f64 },
+    
+    #[error("任务已取消")]
+    Cancelled,
+    
+    #[error("任务长时间没有进展，已终止: {0}")]
+    JobStalled(String),
+    
     #[error("输入文件不存在: {0}")]
     InputFileNotFound(String),
     
@@ -50,6 +81,64 @@ pub struct CompressProgress {
     status: String, 
     progress: f64,
     details: Option<String>,
+    // optimize_gif所处的阶段，不是压缩过程中的消息（如批量压缩的开始和完成）为空
+    phase: Option<ProgressPhase>,
+    // 估计的剩余时间（秒），见SharedState::eta_seconds，无法估计时为空
+    eta_seconds: Option<f64>,
+    // 已完成/计划的抽帧策略数和压缩参数尝试次数，同一任务中只增不减
+    #[serde(flatten)]
+    counters: SearchCounters,
+    // 上一次进度消息之后新测得的候选结果
+    new_attempts: Vec<SearchAttempt>,
+    // 批量压缩中一个文件完成的消息带有该文件的结果，其他消息为空
+    item_result: Option<Box<CompressResult>>,
+}
+
+// 压缩的阶段，每个阶段占用固定的一段进度
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    // 读取和检查原文件
+    Analyzing,
+    // gifsicle -O3基础优化
+    BaseOptimize,
+    // 各个抽帧策略
+    Strategies,
+    // 把最佳结果复制到输出文件
+    Finalizing,
+}
+
+impl ProgressPhase {
+    // 阶段内的完成比例对应的总进度
+    fn progress(self, fraction: f64) -> f64 {
+        let (start, end) = match self {
+            ProgressPhase::Analyzing => (0.0, 0.1),
+            ProgressPhase::BaseOptimize => (0.1, 0.25),
+            ProgressPhase::Strategies => (0.25, 0.95),
+            ProgressPhase::Finalizing => (0.95, 1.0),
+        };
+        start + (end - start) * fraction.clamp(0.0, 1.0)
+    }
+}
+
+// 搜索进度计数，计划数会随着搜索追加新的尝试而增加
+#[derive(Clone, Copy, Default, Serialize, PartialEq, Debug)]
+pub struct SearchCounters {
+    strategies_done: usize,
+    strategies_total: usize,
+    attempts_done: usize,
+    attempts_total: usize,
+}
+
+// 通过Channel推送给前端的压缩过程消息，按发送顺序到达
+#[derive(Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum CompressStreamMessage {
+    Progress(CompressProgress),
+    // 当前最小的中间结果，预览文件在收到下一个中间结果或最终结果后删除
+    IntermediateResult { preview_path: String, size_kb: f64 },
+    // 最终结果，之后不再发送任何消息
+    Final(Box<CompressResult>),
 }
 
 // 压缩结果
@@ -58,16 +147,619 @@ pub struct CompressResult {
     success: bool,
     original_size: f64,
     compressed_size: f64,
+    // 与original_size、compressed_size相同，以字节为单位，便于精确比较
+    original_bytes: u64,
+    compressed_bytes: u64,
     output_path: String,
     message: String,
+    // 原文件是否已被移到回收站
+    original_trashed: bool,
+    // 是否因时间或gifsicle调用次数预算用完而返回了当前最佳结果
+    time_limited: bool,
+    // 输入和输出文件内容的SHA-256（十六进制）
+    input_sha256: Option<String>,
+    output_sha256: Option<String>,
+    // 压缩过程统计，失败时为空
+    report: Option<CompressionReport>,
+    // 解析符号链接后实际处理的输入文件
+    resolved_input_path: Option<String>,
+    // 基础优化时缩小后的画布尺寸（宽, 高），未缩小时为空
+    downscaled_to: Option<(u32, u32)>,
+    // 画布是否因相对目标大小过大而被自动缩小
+    auto_downscaled: bool,
+    // 不影响压缩结果的提示信息
+    warnings: Vec<String>,
+    // 原文件的ICC颜色配置是否在输出中丢失
+    icc_profile_dropped: bool,
+    // 最终结果是否来自静帧动图策略
+    cinemagraph: bool,
+    // 各项优化手段的贡献，只在设置了analyze_levers时计算
+    lever_breakdown: Option<LeverBreakdown>,
+    // 输出动画总时长相对原文件的变化百分比，无法计算时为空
+    duration_deviation_percent: Option<f64>,
+    // 设置了degradation_order时，搜索用到的最后一项手段及其力度
+    degradation_step: Option<DegradationStep>,
+    // output_format为auto时GIF与WebP的比较结果
+    format_comparison: Option<FormatComparison>,
+    // output_format为bundle时打包的文件列表
+    bundle: Option<BundleInfo>,
+    // output_format为webp时使用的有损质量（gif2webp -q），无损时为空
+    webp_quality: Option<u8>,
+    // 设置了smooth_delays时单帧延迟的最大调整量（厘秒）
+    max_delay_adjustment_cs: Option<u16>,
+    // 是否因节省不足min_savings_percent而没有写出输出文件
+    skipped: bool,
+    // 是否被cancel_compress取消
+    cancelled: bool,
+    // 设置了even_dimensions或dimension_preset时输出的画布尺寸，以及是否为偶数尺寸补了边
+    final_dimensions: Option<(u32, u32)>,
+    padded_to_even: bool,
+    // 按评分选择时最终结果的评分和SSIM
+    winning_score: Option<WinningScore>,
+    // 生成输出文件所用的编码器，便于排查编码器升级带来的变化
+    encoder: Option<EncoderIdentity>,
+    // 设置了strip_redundant_global_table且全局颜色表确实多余时删掉的字节数
+    global_table_saved_bytes: Option<u64>,
+    // 设置了bit_depth时输出GIF中最大的颜色表大小
+    palette_size: Option<usize>,
+    // 设置了quality_level时由它得到的各项参数，可以直接作为单独的参数使用
+    quality_caps: Option<QualityCaps>,
+    // 设置了dimension_preset时画面的缩放和补边情况
+    letterbox: Option<LetterboxInfo>,
+    // 设置了lossy_schedule时每一段的lossy级别和大小
+    lossy_schedule: Option<Vec<ScheduledSegment>>,
+    // 最终结果去掉了透明度时合成到的底色（#rrggbb），保留了透明度时为空
+    matte: Option<String>,
+    // 最终结果所用的抽帧间隔、帧延迟（厘秒）和lossy级别，可以据此用gifsicle手动复现；
+    // 只做了基础优化时skip为1、delay为0、lossy为空
+    skip: usize,
+    delay: u16,
+    lossy: Option<u32>,
+    // 最终结果的画面缩放比例（gifsicle --scale），未缩放时为空
+    scale: Option<f32>,
+}
+
+impl CompressResult {
+    /// 输出文件被再次改写后重新读取大小，读取失败时保留原来的值
+    fn refresh_compressed_size<P: AsRef<Path>>(&mut self, path: P) {
+        if let Ok(bytes) = get_file_size_bytes(path) {
+            self.compressed_bytes = bytes;
+            self.compressed_size = bytes_to_kb(bytes);
+        }
+    }
+}
+
+// 编码器的来源和版本
+#[derive(Clone, Serialize)]
+pub struct EncoderIdentity {
+    // gifsicle、gif2webp，或未找到gifsicle时的builtin（内置帧差分）
+    backend: String,
+    path: Option<String>,
+    version: Option<String>,
+    // system：在PATH或常见安装路径中找到；builtin：程序内置
+    source: String,
+}
+
+impl EncoderIdentity {
+    // 汇总时使用的名称，如"gifsicle 1.94"
+    fn label(&self) -> String {
+        format!("{} {}", self.backend, self.version.as_deref().unwrap_or("unknown"))
+    }
+}
+
+// 压缩过程统计
+#[derive(Clone, Serialize)]
+pub struct CompressionReport {
+    // 整个压缩过程的耗时（毫秒）
+    total_ms: u64,
+    // 解码GIF帧的累计耗时，多线程时为各线程之和
+    decode_ms: u64,
+    // 执行gifsicle的累计耗时，多线程时为各线程之和
+    encode_ms: u64,
+    // 结束时的搜索进度计数，与最后一次进度消息一致
+    #[serde(flatten)]
+    counters: SearchCounters,
+    // 单个gifsicle子进程的最大峰值内存（KB），未设置measure_memory或平台不支持时为空
+    gifsicle_peak_rss_kb: Option<u64>,
+    // 同时运行的gifsicle进程数的最大值
+    peak_concurrent_gifsicle: usize,
+    // 运行gifsicle的总次数
+    gifsicle_runs: usize,
+    // 设置了时间或调用次数预算时的使用情况
+    budget: Option<BudgetUsage>,
+    // 单个抽帧策略的时间限制（毫秒），不限制时为空
+    strategy_timeout_ms: Option<u64>,
+    // 因超过gifsicle_timeout_secs被终止的gifsicle调用次数
+    gifsicle_timeouts: usize,
+    // 各抽帧策略的耗时，按完成顺序排列
+    strategy_timings: Vec<StrategyTiming>,
+    // 搜索中测得的所有候选结果，按测得的顺序排列，可以据此重建大小图表
+    attempts: Vec<SearchAttempt>,
+}
+
+// 搜索中测得的一个候选结果：抽帧后的大小，或抽帧后再加上一个lossy级别的大小
+#[derive(Clone, Serialize)]
+pub struct SearchAttempt {
+    // 所属策略的简短描述，见Strategy::label
+    strategy: String,
+    strategy_skip: usize,
+    // 只抽帧时为空
+    lossy: Option<u32>,
+    // 自动减色尝试的颜色数，其他尝试为空
+    colors: Option<u16>,
+    size_kb: f64,
+    // 是否成为当时该策略的最佳结果
+    kept: bool,
+    // 这次减色已用到min_colors下限，更少的颜色数被下限挡住
+    floored: bool,
+}
+
+// 单个抽帧策略的耗时
+#[derive(Clone, Serialize)]
+pub struct StrategyTiming {
+    // 策略的简短描述，如"frames=1/3"
+    strategy: String,
+    elapsed_ms: u64,
+    // 策略结果的大小（KB），没有结果时为空
+    size_kb: Option<f64>,
+    // 是否因超过单个策略的时间限制被提前结束
+    cut_short: bool,
+}
+
+// 一次压缩的搜索预算
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+pub struct SearchBudget {
+    max_gifsicle_runs: Option<usize>,
+    time_budget_ms: Option<u64>,
+    // 预算是否由安全模式给出
+    safe_mode: bool,
+}
+
+// 搜索预算的使用情况
+#[derive(Clone, Serialize)]
+pub struct BudgetUsage {
+    #[serde(flatten)]
+    budget: SearchBudget,
+    gifsicle_runs: usize,
+    elapsed_ms: u64,
+    // 搜索是否因预算用完而提前结束
+    truncated: bool,
+}
+
+// 输入超过该大小（MB）时自动进入安全模式
+const SAFE_MODE_INPUT_MB: f64 = 50.0;
+// 安全模式下未指定预算时的gifsicle调用次数和时间上限
+const SAFE_MODE_MAX_GIFSICLE_RUNS: usize = 24;
+const SAFE_MODE_TIME_BUDGET_MS: u64 = 10 * 60 * 1000;
+
+/// 按options和输入大小确定搜索预算，用户指定的预算优先于安全模式的默认值
+fn search_budget(options: &CompressOptions, input_bytes: u64) -> SearchBudget {
+    let safe_mode = options.safe_mode.unwrap_or(input_bytes as f64 > SAFE_MODE_INPUT_MB * 1024.0 * 1024.0);
+    SearchBudget {
+        max_gifsicle_runs: options.max_gifsicle_runs.or(safe_mode.then_some(SAFE_MODE_MAX_GIFSICLE_RUNS)),
+        time_budget_ms: options.time_budget_ms.or(safe_mode.then_some(SAFE_MODE_TIME_BUDGET_MS)),
+        safe_mode,
+    }
+}
+
+/// 从items中均匀取出count项，保留第一项和最后一项，顺序不变
+fn spread_subset<T>(items: Vec<T>, count: usize) -> Vec<T> {
+    let len = items.len();
+    if count >= len {
+        return items;
+    }
+    if count <= 1 {
+        return items.into_iter().take(1).collect();
+    }
+    let picks: Vec<usize> = (0..count).map(|i| i * (len - 1) / (count - 1)).collect();
+    items.into_iter().enumerate().filter(|(index, _)| picks.contains(index)).map(|(_, item)| item).collect()
 }
 
 // 压缩参数
 #[derive(Clone, Deserialize)]
 pub struct CompressOptions {
+    // 目标大小（KB），设置了target_like时被参考文件的大小代替
+    #[serde(default)]
     target_size: f64,
-    min_frame_percent: u32,
+    // 至少保留的帧数占原帧数的百分比，不传时为0；设置了quality_level时可以不传
+    #[serde(default)]
+    min_frame_percent: Option<u32>,
     threads: usize,
+    // 设置后按原始帧延迟计算每帧延迟，并按此方式取整为厘秒
+    #[serde(default)]
+    delay_rounding: Option<DelayRounding>,
+    // 压缩成功并验证输出后把原文件移到回收站
+    #[serde(default)]
+    trash_original: bool,
+    // 时间预算（毫秒），用完后停止搜索并返回当前最佳结果
+    #[serde(default)]
+    time_budget_ms: Option<u64>,
+    // gifsicle调用次数预算，用完后同样停止搜索并返回当前最佳结果
+    #[serde(default)]
+    max_gifsicle_runs: Option<usize>,
+    // 安全模式：未指定的预算使用保守的默认值；为空时输入超过SAFE_MODE_INPUT_MB自动开启
+    #[serde(default)]
+    safe_mode: Option<bool>,
+    // 以原GIF中的第几帧（从0开始）作为输出的第一帧，之前的帧依次移到末尾
+    #[serde(default)]
+    start_at_frame: Option<usize>,
+    // 输出画布的最大宽度/高度，按比例缩小，不会放大
+    #[serde(default)]
+    max_width: Option<u32>,
+    #[serde(default)]
+    max_height: Option<u32>,
+    // 画布相对目标大小过大时自动缩小，不传时关闭，只给出警告；设置了max_width/max_height时以它们为准
+    #[serde(default)]
+    auto_downscale: Option<bool>,
+    // 设置后额外尝试按画面变化抽帧：与上一保留帧的平均像素差（0-255）低于该值的帧被丢弃
+    #[serde(default)]
+    motion_threshold: Option<f64>,
+    // 按画面变化抽帧时，对数据量大的帧放宽阈值，更倾向丢弃它们
+    #[serde(default)]
+    prefer_dropping_heavy_frames: bool,
+    // 压缩完成后分析各项优化手段各自节省了多少，需要多次重新压缩，耗时较长
+    #[serde(default)]
+    analyze_levers: bool,
+    // 图片文件，其中的颜色（最多256种）作为输出的固定调色板
+    #[serde(default)]
+    palette_file: Option<String>,
+    // 动画总时长变化超过该百分比时给出警告
+    #[serde(default = "default_duration_tolerance")]
+    duration_tolerance_percent: f64,
+    // 时长变化超过允许范围时视为压缩失败
+    #[serde(default)]
+    preserve_duration: bool,
+    #[serde(default)]
+    extension_conflict: ExtensionConflict,
+    // 输出中视为透明的颜色(R, G, B)
+    #[serde(default)]
+    color_key: Option<[u8; 3]>,
+    // 与color_key的RGB距离（0-441）不超过该值的像素也变为透明
+    #[serde(default)]
+    color_key_tolerance: f64,
+    // 距离在容差之外这个范围内的边缘像素去掉混入的color_key颜色，避免留下色边
+    #[serde(default)]
+    color_key_feather: f64,
+    // 参考GIF文件，以它的大小作为目标大小
+    #[serde(default)]
+    target_like: Option<String>,
+    // 输出最多使用的颜色数（2-256），由用户指定时不受min_colors限制
+    #[serde(default)]
+    max_colors: Option<u16>,
+    // 自动减色时最少保留的颜色数，避免渐变出现明显色带；为空时为DEFAULT_MIN_COLORS
+    #[serde(default)]
+    min_colors: Option<u16>,
+    // lossy仍未达到目标时自动减少颜色数，不传时不减色
+    #[serde(default)]
+    auto_reduce_colors: Option<bool>,
+    // 需要缩小画布时基础优化与缩小的先后顺序
+    #[serde(default)]
+    resize_order: ResizeOrder,
+    // 搜索依次加大力度的手段（colors、lossy、frames、resize），前一项用到上限后才使用下一项
+    #[serde(default)]
+    degradation_order: Option<Vec<String>>,
+    #[serde(default)]
+    output_format: OutputFormat,
+    // output_format为auto时同时保留GIF和WebP两个文件
+    #[serde(default)]
+    keep_both_formats: bool,
+    // 统计每个gifsicle子进程的峰值内存，目前只支持Unix
+    #[serde(default)]
+    measure_memory: bool,
+    // 抽帧时把每个保留帧与其后被丢弃的1-2帧按延迟加权混合，快速运动在低帧率下更平滑
+    #[serde(default)]
+    blend_dropped_frames: bool,
+    // 把输出的帧延迟对齐到delay_grid_cs的整数倍，取整误差累积到后续帧，总时长基本不变
+    #[serde(default)]
+    smooth_delays: bool,
+    #[serde(default = "default_delay_grid")]
+    delay_grid_cs: u16,
+    // 最佳结果比原文件节省的比例低于该百分比时不写出输出文件，原地压缩时原文件保持不变
+    #[serde(default)]
+    min_savings_percent: Option<f64>,
+    // 输出画布的宽高为奇数时在右侧/底部补一列/一行背景，便于之后转换为H.264视频
+    #[serde(default)]
+    even_dimensions: bool,
+    // 达到目标大小的结果中如何选出最终结果
+    #[serde(default)]
+    selection: WinnerSelection,
+    // 按评分选择时大小和画质（SSIM）的权重
+    #[serde(default = "default_score_weight")]
+    size_weight: f64,
+    #[serde(default = "default_score_weight")]
+    quality_weight: f64,
+    // 写入输出GIF的注释，如许可或署名信息
+    #[serde(default)]
+    comment: Option<String>,
+    // 每帧都有局部颜色表时删除用不到的全局颜色表
+    #[serde(default)]
+    strip_redundant_global_table: bool,
+    // 以--verbose运行gifsicle，并把它的输出逐行放进进度的details
+    #[serde(default)]
+    verbose_gifsicle: bool,
+    // 把所有(策略, lossy级别)放进同一个工作队列，由threads个工作线程处理
+    #[serde(default)]
+    unified_queue: bool,
+    // 强制使用的位深（1-8），输出统一使用2^bit_depth种颜色，不再自动减色
+    #[serde(default)]
+    bit_depth: Option<u8>,
+    // 搜索时使用的最大lossy级别，为0时不使用lossy
+    #[serde(default)]
+    max_lossy: Option<u32>,
+    // 缩小画面的策略最多缩小到的比例（%），为100时不尝试缩小画面；只在auto_downscale开启时使用
+    #[serde(default = "default_min_scale_percent")]
+    min_scale_percent: u32,
+    // 依次尝试的lossy级别，必须从小到大排列且不超过MAX_LOSSY_LEVEL，为空时使用LOSSY_LEVELS
+    #[serde(default)]
+    lossy_levels: Option<Vec<u32>>,
+    // 输出的循环次数，为空时保留原文件的循环次数
+    #[serde(default)]
+    loop_count: Option<LoopSetting>,
+    // 画质等级（1-10），1最小、10最好看，由它决定max_lossy、min_frame_percent、min_colors和auto_downscale，并开启自动减色
+    #[serde(default)]
+    quality_level: Option<u8>,
+    // 平台尺寸预设（如sticker、480p）：等比缩放到预设尺寸内，再居中补边到恰好该尺寸，不拉伸
+    #[serde(default)]
+    dimension_preset: Option<String>,
+    // 补边使用的背景色(R, G, B)，为空时补透明像素
+    #[serde(default)]
+    letterbox_background: Option<[u8; 3]>,
+    // 必须去掉透明度时（不透明补边、混合丢弃的帧）合成到的底色，#rrggbb或#rgb，为空时为白色
+    #[serde(default)]
+    matte: Option<String>,
+    // 单个抽帧策略的时间限制（毫秒），超过后终止该策略并使用它目前的最佳结果；为空时按基础优化耗时计算，为0时不限制
+    #[serde(default)]
+    strategy_timeout_ms: Option<u64>,
+    // 单次gifsicle调用的时间限制（秒），超过后终止它并把这次尝试当作失败；为空时为DEFAULT_GIFSICLE_TIMEOUT，为0时不限制；
+    // 基础优化的限制是它的BASE_PASS_TIMEOUT_FACTOR倍，超过后整个压缩以GifsicleTimeout失败
+    #[serde(default)]
+    gifsicle_timeout_secs: Option<u64>,
+    // 输入不超过IN_MEMORY_MAX_KB时通过管道在内存中处理，不创建临时文件；用到不支持的选项或管道失败时仍使用临时文件
+    #[serde(default)]
+    in_memory: bool,
+    // 按帧范围指定的lossy级别，各段必须按顺序覆盖全部帧且不重叠；设置后不再搜索抽帧策略和lossy级别
+    #[serde(default)]
+    lossy_schedule: Option<Vec<LossySegment>>,
+    // 只做基础优化，不与目标大小比较也不搜索抽帧策略；输出WebP时由run_compress设置，前端不能设置
+    #[serde(skip)]
+    base_pass_only: bool,
+}
+
+// lossy_schedule中的一段：第start_frame到end_frame帧（含两端，从0开始）使用同一个lossy级别
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Debug)]
+pub struct LossySegment {
+    start_frame: usize,
+    end_frame: usize,
+    lossy: u32,
+}
+
+// 按lossy_schedule分段压缩时每一段的结果
+#[derive(Clone, Serialize)]
+pub struct ScheduledSegment {
+    #[serde(flatten)]
+    segment: LossySegment,
+    // 这一段单独压缩后的大小（KB），合并后各段共用文件头，总大小略小于各段之和
+    size_kb: f64,
+}
+
+fn default_min_scale_percent() -> u32 {
+    60
+}
+
+fn default_duration_tolerance() -> f64 {
+    5.0
+}
+
+fn default_delay_grid() -> u16 {
+    2
+}
+
+fn default_score_weight() -> f64 {
+    0.5
+}
+
+// 与前端不传任何可选参数时一致
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            target_size: 0.0,
+            min_frame_percent: None,
+            threads: 0,
+            delay_rounding: None,
+            trash_original: false,
+            time_budget_ms: None,
+            strategy_timeout_ms: None,
+            gifsicle_timeout_secs: None,
+            lossy_schedule: None,
+            base_pass_only: false,
+            in_memory: false,
+            max_gifsicle_runs: None,
+            safe_mode: None,
+            start_at_frame: None,
+            max_width: None,
+            max_height: None,
+            auto_downscale: None,
+            motion_threshold: None,
+            prefer_dropping_heavy_frames: false,
+            analyze_levers: false,
+            palette_file: None,
+            duration_tolerance_percent: default_duration_tolerance(),
+            preserve_duration: false,
+            extension_conflict: ExtensionConflict::Error,
+            color_key: None,
+            color_key_tolerance: 0.0,
+            color_key_feather: 0.0,
+            target_like: None,
+            max_colors: None,
+            min_colors: None,
+            auto_reduce_colors: None,
+            resize_order: ResizeOrder::ResizeFirst,
+            degradation_order: None,
+            output_format: OutputFormat::Gif,
+            keep_both_formats: false,
+            measure_memory: false,
+            blend_dropped_frames: false,
+            smooth_delays: false,
+            delay_grid_cs: default_delay_grid(),
+            min_savings_percent: None,
+            even_dimensions: false,
+            selection: WinnerSelection::FirstFound,
+            size_weight: default_score_weight(),
+            quality_weight: default_score_weight(),
+            comment: None,
+            strip_redundant_global_table: false,
+            verbose_gifsicle: false,
+            unified_queue: false,
+            bit_depth: None,
+            max_lossy: None,
+            quality_level: None,
+            dimension_preset: None,
+            letterbox_background: None,
+            matte: None,
+            lossy_levels: None,
+            min_scale_percent: default_min_scale_percent(),
+            loop_count: None,
+        }
+    }
+}
+
+// 输出GIF的循环次数
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopSetting {
+    // 保留原文件的循环次数，原文件没有循环扩展时只播放一次
+    #[default]
+    Preserve,
+    // 无限循环
+    Forever,
+    // 写入指定的循环次数，0与forever相同
+    Count(u16),
+}
+
+// 帧延迟换算为整数厘秒时的取整方式
+#[derive(Clone, Copy, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayRounding {
+    // 四舍五入
+    Nearest,
+    // 向下取整
+    Floor,
+    // 向上取整
+    Ceil,
+    // 四舍五入并把误差累积到后续帧，使总时长误差保持在半个厘秒以内
+    Accumulate,
+}
+
+// 输出文件名带有其他格式的扩展名（如.png）时的处理方式
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtensionConflict {
+    // 返回错误，不进行压缩
+    #[default]
+    Error,
+    // 把扩展名改为.gif
+    Rewrite,
+}
+
+// 缩小画布与基础优化的先后顺序
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeOrder {
+    // 先缩小再优化，在同一次gifsicle调用中完成，速度较快
+    #[default]
+    ResizeFirst,
+    // 先在原尺寸上优化，再缩小并重新优化
+    BaseFirst,
+}
+
+// 最终结果的选择方式
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WinnerSelection {
+    // 使用第一个达到目标大小的结果，其他策略随即停止
+    #[default]
+    FirstFound,
+    // 等所有策略完成，在达到目标大小的结果中选评分最高的：
+    // size_weight * (1 - 大小/目标) + quality_weight * SSIM
+    Score,
+}
+
+// 输出格式
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Gif,
+    // 压缩后再生成动态WebP，保留较小的一个
+    Auto,
+    // 把GIF、动态WebP和第一帧的PNG海报打包成output_path指定的zip文件
+    Bundle,
+    // 压缩后转换为动态WebP，按WebP的大小判断是否达到目标，输出文件使用.webp扩展名
+    Webp,
+}
+
+// 监视输入文件时使用的任务模板
+#[derive(Clone, Deserialize)]
+pub struct WatchJob {
+    output_path: String,
+    options: CompressOptions,
+    // true: 文件变化后自动重新压缩; false: 只发送input-changed事件由前端提示
+    #[serde(default)]
+    auto_recompress: bool,
+}
+
+// 被监视文件发生变化的事件
+#[derive(Clone, Serialize)]
+pub struct InputChanged {
+    path: String,
+    removed: bool,
+}
+
+// 被监视文件自动重新压缩完成的事件
+#[derive(Clone, Serialize)]
+pub struct InputRecompressed {
+    path: String,
+    result: CompressResult,
+}
+
+// 批量压缩中的一个文件
+#[derive(Clone, Deserialize)]
+pub struct BatchItem {
+    input_path: String,
+    output_path: String,
+}
+
+// 批量压缩开始处理一个文件的事件，前端据此显示"正在处理 3/50"
+#[derive(Clone, Serialize)]
+pub struct BatchItemStarted {
+    index: usize,
+    total: usize,
+    input_path: String,
+}
+
+// 批量压缩中一个文件完成的事件，index为该文件在请求中的位置
+#[derive(Clone, Serialize)]
+pub struct BatchItemComplete {
+    index: usize,
+    total: usize,
+    input_path: String,
+    result: CompressResult,
+}
+
+// 批量压缩过程中逐个文件发出的通知
+enum BatchEvent {
+    Started(BatchItemStarted),
+    Complete(Box<BatchItemComplete>),
+}
+
+// 批量压缩全部完成后的汇总，results与请求中的文件一一对应
+#[derive(Clone, Serialize)]
+pub struct BatchResult {
+    results: Vec<CompressResult>,
+    succeeded: usize,
+    failed: usize,
+    // 按编码器及版本统计的文件数，键如"gifsicle 1.94"
+    by_encoder: HashMap<String, usize>,
 }
 
 // 从anyhow::Error到GifError的实现
@@ -98,25 +790,262 @@ fn get_os_type() -> String {
     return "unknown".to_string();
 }
 
+// 检查是否可以输出WebP（需要gif2webp）
+#[tauri::command]
+fn check_webp_support() -> bool {
+    find_gif2webp().is_some()
+}
+
+// 从命令行参数中筛选出要打开的GIF文件
+// 第一个参数是程序自身路径；相对路径按发起启动的进程的工作目录解析
+fn collect_opened_files<I: IntoIterator<Item = String>>(args: I, cwd: &Path) -> Vec<String> {
+    args.into_iter()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .map(|arg| {
+            let path = PathBuf::from(arg);
+            if path.is_absolute() { path } else { cwd.join(path) }
+        })
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .map(|ext| ext.eq_ignore_ascii_case("gif"))
+                    .unwrap_or(false)
+        })
+        .map(|path| path.to_string_lossy().to_string())
+        .collect()
+}
+
+// 处理后续启动转发过来的参数：聚焦已有窗口并通知前端
+fn handle_second_instance(app: &AppHandle, args: Vec<String>, cwd: String) {
+    println!("检测到重复启动，转发参数到已运行的实例: {:?}", args);
+    
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+    
+    let files = collect_opened_files(args, Path::new(&cwd));
+    if !files.is_empty() {
+        let _ = app.emit("files-opened", files);
+    }
+}
+
 // 主程序运行函数
 pub fn run() {
     let app_state = AppState {
         last_result: std::sync::Mutex::new(None),
+        opened_files: std::sync::Mutex::new(Vec::new()),
+        watchers: std::sync::Mutex::new(HashMap::new()),
+        candidates: CandidateStore::new(DEFAULT_CANDIDATE_TTL, DEFAULT_CANDIDATE_MAX_BYTES),
+        quality_curves: std::sync::Mutex::new(HashMap::new()),
+        quality_curve_job: std::sync::Mutex::new(None),
+        lever_analysis_job: std::sync::Mutex::new(None),
+        jobs: Arc::new(JobRegistry::new()),
     };
     
     tauri::Builder::default()
+        // 单实例插件必须最先注册，后续启动会把参数交给已运行的实例后立即退出
+        .plugin(tauri_plugin_single_instance::init(handle_second_instance))
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
         .manage(app_state)
+        .setup(|app| {
+            // 定期删除过期的候选结果
+            let handle = app.handle().clone();
+            thread::spawn(move || loop {
+                thread::sleep(CANDIDATE_EXPIRY_INTERVAL);
+                handle.state::<AppState>().candidates.expire();
+            });
+            
+            // 看门狗：终止长时间没有进展的任务
+            let handle = app.handle().clone();
+            thread::spawn(move || loop {
+                thread::sleep(JOB_WATCHDOG_INTERVAL);
+                handle.state::<AppState>().jobs.abort_stalled();
+            });
+            
+            // 首次启动时前端尚未监听事件，先保存起来由前端主动获取
+            let cwd = std::env::current_dir().unwrap_or_default();
+            let args = std::env::args_os().map(|arg| arg.to_string_lossy().to_string());
+            let files = collect_opened_files(args, &cwd);
+            if !files.is_empty() {
+                let state = app.state::<AppState>();
+                state.opened_files.lock().unwrap().extend(files);
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             greet,
             check_gifsicle_installed,
             compress_gif,
             get_gif_info,
             get_os_type,
+            check_webp_support,
+            take_opened_files,
+            watch_input,
+            unwatch_input,
+            get_concurrency_limits,
+            trash_file,
+            check_gifsicle_features,
+            generate_candidates,
+            commit_candidate,
+            make_perfect_loop,
+            get_candidate_path,
+            release_candidate,
+            get_candidate_store_usage,
+            set_candidate_ttl,
+            compress_to_clipboard,
+            get_quality_curve,
+            cancel_quality_curve,
+            get_frame_sizes,
+            estimate_compression,
+            dry_run_strategies,
+            compress_gif_stream,
+            compress_batch,
+            compress_gif_batch,
+            strip_trailing_frames,
+            get_active_jobs,
+            cancel_compress,
+            cancel_compression,
+            start_compress_job,
+            get_job_status,
+            list_jobs,
+            clear_job,
+            set_job_stall_timeout,
+            check_frame_delays,
+            cancel_lever_analysis,
+            get_motion_heatmap,
+            get_gif_comments,
+            set_gif_comment,
+            remove_gif_comments,
+            compress_gif_url,
+            get_color_stats,
+            recommend_output_format,
+            analyze_global_color_table,
+            analyze_palette,
+            strip_global_color_table,
+            reduce_bit_depth,
+            get_delay_stats,
+            compress_gif_to_bytes,
+            compress_gif_from_bytes,
+            get_min_size_for_quality,
         ])
-        .run(tauri::generate_context!())
-        .expect("错误: 无法启动应用");
+        .build(tauri::generate_context!())
+        .expect("错误: 无法启动应用")
+        .run(|app, event| match event {
+            // 主窗口关闭后不再有人等待结果，立即停止所有压缩，清理在后台线程进行
+            tauri::RunEvent::WindowEvent { label, event: tauri::WindowEvent::Destroyed, .. } if label == "main" => {
+                IN_FLIGHT.shutdown();
+            }
+            // 退出时停止所有压缩，不再等待，直接结束剩下的gifsicle并删除临时文件和未提交的候选结果
+            tauri::RunEvent::Exit => {
+                IN_FLIGHT.shutdown();
+                IN_FLIGHT.finish_shutdown();
+                app.state::<AppState>().candidates.clear();
+            }
+            _ => {}
+        });
+}
+
+// 退出时等待正在运行的gifsicle被终止的最长时间，超过后直接结束它们
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(3);
+
+/// 正在进行的工作留下的gifsicle进程和临时文件，应用退出时据此清理
+///
+/// 临时文件在各个处理步骤中创建，拿不到AppState，所以记录是进程级的
+struct InFlightWork {
+    // 应用正在退出，所有压缩都按已取消处理
+    shutting_down: AtomicBool,
+    // 正在运行的gifsicle进程号
+    gifsicle_pids: std::sync::Mutex<Vec<u32>>,
+    // 尚未删除的临时文件和临时目录
+    temp_paths: std::sync::Mutex<Vec<PathBuf>>,
+}
+
+static IN_FLIGHT: InFlightWork = InFlightWork {
+    shutting_down: AtomicBool::new(false),
+    gifsicle_pids: std::sync::Mutex::new(Vec::new()),
+    temp_paths: std::sync::Mutex::new(Vec::new()),
+};
+
+impl InFlightWork {
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+    
+    fn track_child(&self, pid: u32) {
+        self.gifsicle_pids.lock().unwrap().push(pid);
+    }
+    
+    fn untrack_child(&self, pid: u32) {
+        self.gifsicle_pids.lock().unwrap().retain(|&running| running != pid);
+    }
+    
+    fn track_temp(&self, path: &Path) {
+        self.temp_paths.lock().unwrap().push(path.to_path_buf());
+    }
+    
+    fn untrack_temp(&self, path: &Path) {
+        self.temp_paths.lock().unwrap().retain(|tracked| tracked != path);
+    }
+    
+    /// 设置退出标志，在后台线程等待正在运行的gifsicle被终止，然后删除所有临时文件和临时目录
+    ///
+    /// 设置标志后所有SharedState都报告已取消：不再启动新的gifsicle，正在运行的在下一次轮询时被终止；
+    /// 等待最多SHUTDOWN_WAIT，不在调用线程（事件循环）上进行
+    fn shutdown(&'static self) {
+        if self.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        println!("应用正在退出，停止所有压缩");
+        thread::spawn(move || {
+            let started = Instant::now();
+            while !self.gifsicle_pids.lock().unwrap().is_empty() && started.elapsed() < SHUTDOWN_WAIT {
+                thread::sleep(CHILD_POLL_INTERVAL);
+            }
+            self.finish_shutdown();
+        });
+    }
+    
+    /// 不再等待，直接结束仍在运行的gifsicle并删除所有临时文件和临时目录；进程即将结束时调用
+    fn finish_shutdown(&self) {
+        #[cfg(unix)]
+        for &pid in self.gifsicle_pids.lock().unwrap().iter() {
+            // 轮询线程没能及时终止的进程，在退出前直接结束
+            // SAFETY: kill只接收整数参数，没有内存安全方面的前提。pid是我们启动的gifsicle子进程，
+            // 持有Child的线程回收（wait）它之后才调用untrack_child，所以列表中的pid要么仍在运行，要么是
+            // 尚未回收的僵尸进程，都不会被系统复用；只有回收后、取消记录前的极短时间内pid可能已被释放，
+            // 而Linux和macOS都按递增顺序分配pid，不会立即复用刚释放的号码，退出时接受这一风险
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+        
+        let paths = std::mem::take(&mut *self.temp_paths.lock().unwrap());
+        for path in paths {
+            let _ = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        }
+    }
+}
+
+/// 记录在IN_FLIGHT中的临时目录，丢弃时取消记录
+struct TrackedTempDir(PathBuf);
+
+impl TrackedTempDir {
+    fn new(path: &Path) -> Self {
+        IN_FLIGHT.track_temp(path);
+        Self(path.to_path_buf())
+    }
+}
+
+impl Drop for TrackedTempDir {
+    fn drop(&mut self) {
+        IN_FLIGHT.untrack_temp(&self.0);
+    }
 }
 
 /// 表示临时文件 - 优化版本
@@ -130,6 +1059,7 @@ impl TempFile {
         let path = temp_file.path().to_path_buf();
         let _temp_path = temp_file.into_temp_path();
         // 这里_temp_path会被丢弃，但文件不会被删除
+        IN_FLIGHT.track_temp(&path);
         Self { path }
     }
     
@@ -137,12 +1067,11 @@ impl TempFile {
         self.path.to_string_lossy().to_string()
     }
     
-    // 当不再需要文件时手动删除
+    // 当不再需要文件时手动删除，删除失败时同样取消记录
     fn cleanup(&self) -> std::io::Result<()> {
-        if self.path.exists() {
-            std::fs::remove_file(&self.path)?;
-        }
-        Ok(())
+        let removed = if self.path.exists() { std::fs::remove_file(&self.path) } else { Ok(()) };
+        IN_FLIGHT.untrack_temp(&self.path);
+        removed
     }
 }
 
@@ -163,859 +1092,12204 @@ impl Clone for TempFile {
     }
 }
 
-/// 获取文件大小（KB）
-fn get_file_size_kb<P: AsRef<Path>>(path: P) -> Result<f64, GifError> {
-    let metadata = fs::metadata(path)?;
-    Ok(metadata.len() as f64 / 1024.0)
-}
-
-/// 获取GIF的帧数
-fn get_frame_count<P: AsRef<Path>>(path: P) -> Result<usize, GifError> {
-    let file = File::open(path)?;
-    let decoder = GifDecoder::new(BufReader::new(file))?;
-    let frames = decoder.into_frames();
-    let count = frames.count();
-    Ok(count)
-}
+// 候选结果默认保留时间
+const DEFAULT_CANDIDATE_TTL: Duration = Duration::from_secs(10 * 60);
+// 候选结果占用空间上限，超出时删除最早的
+const DEFAULT_CANDIDATE_MAX_BYTES: u64 = 512 * 1024 * 1024;
+// 后台检查过期候选结果的间隔
+const CANDIDATE_EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
 
-/// 压缩策略结构
-struct Strategy {
-    skip: usize,
-    delay: u16,
+/// 候选结果的附加信息
+#[derive(Clone)]
+struct CandidateMeta {
+    // 所属分组，通常是输入文件路径，可按组一起释放
+    group: String,
+    // 用途说明，显示在占用情况中
+    label: String,
 }
 
-/// 策略处理结果
-struct StrategyResult {
-    size: f64,
-    file: Option<TempFile>,
-    success: bool,
+struct StoredCandidate {
+    file: TempFile,
+    meta: CandidateMeta,
+    bytes: u64,
+    created: Instant,
 }
 
-/// 共享状态结构体，用于线程间通信
-struct SharedState {
-    // 是否找到满足目标大小的结果
-    found_target: AtomicBool,
-    // 当前已找到的最佳大小，初始值设为最大值
-    best_size: std::sync::atomic::AtomicU64,
+/// 候选结果存储
+///
+/// 所有需要把临时文件路径交给前端的功能都通过它保存文件，
+/// 文件只在过期、超出空间上限或被显式释放时删除，前端拿到的路径不会被提前删掉
+struct CandidateStore {
+    entries: std::sync::Mutex<HashMap<String, StoredCandidate>>,
+    next_id: AtomicU64,
+    ttl_ms: AtomicU64,
+    max_bytes: u64,
 }
 
-impl SharedState {
-    fn new() -> Self {
+impl CandidateStore {
+    fn new(ttl: Duration, max_bytes: u64) -> Self {
         Self {
-            found_target: AtomicBool::new(false),
-            best_size: std::sync::atomic::AtomicU64::new(u64::MAX),
+            entries: std::sync::Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            ttl_ms: AtomicU64::new(ttl.as_millis() as u64),
+            max_bytes,
         }
     }
     
-    // 更新最佳大小（如果提供的大小更小）
-    fn update_best_size(&self, size: f64) -> bool {
-        let size_bits = size.to_bits();
-        let mut current = self.best_size.load(Ordering::Relaxed);
+    fn ttl(&self) -> Duration {
+        Duration::from_millis(self.ttl_ms.load(Ordering::SeqCst))
+    }
+    
+    fn set_ttl(&self, ttl: Duration) {
+        self.ttl_ms.store(ttl.as_millis() as u64, Ordering::SeqCst);
+        self.expire();
+    }
+    
+    /// 保存临时文件并返回编号，超出空间上限时删除最早的其他文件
+    fn register(&self, file: TempFile, meta: CandidateMeta) -> String {
+        let id = format!("c{}", self.next_id.fetch_add(1, Ordering::SeqCst));
+        let bytes = fs::metadata(&file.path).map(|m| m.len()).unwrap_or(0);
         
-        loop {
-            // 如果新大小不比当前更好，不更新
-            if size_bits >= current {
-                return false;
-            }
-            
-            // 尝试原子更新，成功则返回true
-            match self.best_size.compare_exchange(
-                current,
-                size_bits,
-                Ordering::SeqCst,
-                Ordering::Relaxed
-            ) {
-                Ok(_) => return true,
-                Err(actual) => current = actual,
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(id.clone(), StoredCandidate { file, meta, bytes, created: Instant::now() });
+        
+        let mut total: u64 = entries.values().map(|entry| entry.bytes).sum();
+        while total > self.max_bytes {
+            let oldest = entries
+                .iter()
+                .filter(|(key, _)| **key != id)
+                .min_by_key(|(_, entry)| entry.created)
+                .map(|(key, _)| key.clone());
+            match oldest.and_then(|key| entries.remove(&key)) {
+                Some(evicted) => {
+                    println!("候选结果占用超出上限，删除: {}", evicted.meta.label);
+                    total -= evicted.bytes;
+                }
+                None => break,
             }
         }
+        id
     }
     
-    // 获取当前最佳大小
-    fn get_best_size(&self) -> f64 {
-        let bits = self.best_size.load(Ordering::Relaxed);
-        f64::from_bits(bits)
+    /// 获取文件路径和附加信息
+    fn get(&self, id: &str) -> Option<(PathBuf, CandidateMeta)> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(id).map(|entry| (entry.file.path.clone(), entry.meta.clone()))
     }
     
-    // 设置已找到目标
-    fn set_found_target(&self) {
-        self.found_target.store(true, Ordering::Relaxed);
+    /// 取出文件，之后由调用方负责它的生命周期
+    fn take(&self, id: &str) -> Option<(TempFile, CandidateMeta)> {
+        self.entries.lock().unwrap().remove(id).map(|entry| (entry.file, entry.meta))
     }
     
-    // 检查是否已找到目标
-    fn is_target_found(&self) -> bool {
-        self.found_target.load(Ordering::Relaxed)
+    /// 释放文件，返回是否存在
+    fn release(&self, id: &str) -> bool {
+        self.entries.lock().unwrap().remove(id).is_some()
     }
-}
-
-/// 提取GIF帧并保存为新的GIF
-fn extract_frames<P: AsRef<Path>, Q: AsRef<Path>>(
-    input_path: P,
-    output_path: Q,
-    skip: usize,
-    delay: u16,
-) -> Result<(), GifError> {
-    // 打开输入文件
-    let file = File::open(&input_path)?;
-    let decoder = GifDecoder::new(BufReader::new(file))?;
     
-    // 提取所有帧
-    let frames = decoder.into_frames().collect_frames()?;
-    let total_frames = frames.len();
+    /// 释放整组文件，返回释放的数量
+    fn release_group(&self, group: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| entry.meta.group != group);
+        before - entries.len()
+    }
     
-    // 根据skip参数选择帧
-    let mut selected_frames = Vec::new();
-    for i in (0..total_frames).step_by(skip) {
-        selected_frames.push(frames[i].clone());
+    // 删除过期的文件，TempFile被丢弃时会删除对应文件
+    fn expire(&self) {
+        let ttl = self.ttl();
+        self.entries.lock().unwrap().retain(|_, entry| entry.created.elapsed() < ttl);
     }
     
-    if selected_frames.is_empty() {
-        // 至少保留一帧
-        if !frames.is_empty() {
-            selected_frames.push(frames[0].clone());
-        } else {
-            return Err(GifError::NoFrames);
-        }
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
     }
     
-    // 由于GIF格式复杂，我们使用临时目录和gifsicle来完成帧提取和合并
-    let temp_dir = tempfile::Builder::new()
-        .prefix("gif_frames_")
-        .tempdir()
+    fn usage(&self) -> CandidateStoreUsage {
+        let entries = self.entries.lock().unwrap();
+        let mut items: Vec<CandidateUsageItem> = entries
+            .iter()
+            .map(|(id, entry)| CandidateUsageItem {
+                id: id.clone(),
+                group: entry.meta.group.clone(),
+                label: entry.meta.label.clone(),
+                bytes: entry.bytes,
+                age_secs: entry.created.elapsed().as_secs(),
+            })
+            .collect();
+        items.sort_by_key(|item| std::cmp::Reverse(item.age_secs));
+        
+        CandidateStoreUsage {
+            total_bytes: items.iter().map(|item| item.bytes).sum(),
+            max_bytes: self.max_bytes,
+            ttl_secs: self.ttl().as_secs(),
+            items,
+        }
+    }
+}
+
+// 候选结果存储的占用情况
+#[derive(Clone, Serialize)]
+pub struct CandidateStoreUsage {
+    total_bytes: u64,
+    max_bytes: u64,
+    ttl_secs: u64,
+    items: Vec<CandidateUsageItem>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct CandidateUsageItem {
+    id: String,
+    group: String,
+    label: String,
+    bytes: u64,
+    age_secs: u64,
+}
+
+/// 输出文件守卫
+///
+/// 先写入同目录下的`<输出文件名>.processing`，全部成功后才重命名到最终路径；
+/// 出错或守卫被丢弃时删除未完成的文件，保证输出路径上不会留下截断的GIF
+struct OutputGuard {
+    partial: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl OutputGuard {
+    fn new<P: AsRef<Path>>(target: P) -> Self {
+        let target = target.as_ref().to_path_buf();
+        let mut partial_name = target.file_name().unwrap_or_default().to_os_string();
+        partial_name.push(".processing");
+        Self {
+            partial: target.with_file_name(partial_name),
+            target,
+            committed: false,
+        }
+    }
+    
+    fn path(&self) -> &Path {
+        &self.partial
+    }
+    
+    // 把写好的文件移动到最终路径，这是写输出的最后一步
+    fn commit(mut self) -> std::io::Result<()> {
+        fs::rename(&self.partial, &self.target)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for OutputGuard {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+// 把摘要转换为十六进制字符串
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 流式计算文件的SHA-256，不会把整个文件读入内存
+fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String, GifError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// 把结果文件写到输出路径，失败时不留下不完整的输出
+///
+/// 复制的同时计算写入内容的SHA-256并返回，不需要再读一遍输出文件
+fn write_output<P: AsRef<Path>, Q: AsRef<Path>>(source: P, output_path: Q) -> Result<String, GifError> {
+    let guard = OutputGuard::new(output_path);
+    
+    let mut reader = File::open(source)?;
+    let mut writer = BufWriter::new(File::create(guard.path())?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+    }
+    // 确保数据落盘后再重命名，避免重命名后才发现写入失败
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    
+    guard.commit()?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// 把内存中的结果写到输出路径，与write_output一样先写到旁边的文件再重命名
+fn write_output_bytes<Q: AsRef<Path>>(data: &[u8], output_path: Q) -> Result<String, GifError> {
+    let guard = OutputGuard::new(output_path);
+    let mut file = File::create(guard.path())?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    
+    guard.commit()?;
+    Ok(to_hex(&Sha256::digest(data)))
+}
+
+/// 获取文件大小（KB）
+fn get_file_size_kb<P: AsRef<Path>>(path: P) -> Result<f64, GifError> {
+    Ok(bytes_to_kb(get_file_size_bytes(path)?))
+}
+
+/// 获取文件大小（字节）
+fn get_file_size_bytes<P: AsRef<Path>>(path: P) -> Result<u64, GifError> {
+    Ok(fs::metadata(path)?.len())
+}
+
+fn bytes_to_kb(bytes: u64) -> f64 {
+    bytes as f64 / 1024.0
+}
+
+/// KB换算为字节，不足1字节的部分舍去，所以大小不超过返回值的文件也不超过kb
+fn kb_to_bytes(kb: f64) -> u64 {
+    (kb * 1024.0).floor() as u64
+}
+
+/// 获取GIF的画布宽高
+fn get_canvas_size<P: AsRef<Path>>(path: P) -> Result<(u32, u32), GifError> {
+    let file = File::open(path)?;
+    let decoder = GifDecoder::new(BufReader::new(file))?;
+    Ok(decoder.dimensions())
+}
+
+// 估算压缩后大小时假设的每帧每像素字节数（经验值）
+const MIN_BYTES_PER_PIXEL_FRAME: f64 = 0.05;
+// 自动缩小画布时最长边的下限
+const MIN_AUTO_EDGE: u32 = 240;
+
+/// 按每像素字节数粗略估算，画布相对目标大小过大时返回建议的最长边
+///
+/// frames为抽帧后最少保留的帧数，即最乐观的情况
+fn auto_max_edge(width: u32, height: u32, frames: usize, target_size_kb: f64) -> Option<u32> {
+    let needed = width as f64 * height as f64 * frames as f64 * MIN_BYTES_PER_PIXEL_FRAME;
+    let budget = target_size_kb * 1024.0;
+    if needed <= budget {
+        return None;
+    }
+    
+    let longest = width.max(height);
+    let edge = ((longest as f64) * (budget / needed).sqrt()).floor() as u32;
+    let edge = edge.max(MIN_AUTO_EDGE);
+    if edge >= longest {
+        None
+    } else {
+        Some(edge)
+    }
+}
+
+/// GIF中一帧图像数据块的信息
+struct FrameBlock {
+    // 图像描述符、局部颜色表和LZW数据的总字节数
+    bytes: u64,
+    // 图形控制扩展中的延迟（厘秒）
+    delay_cs: u16,
+    // 图像描述符在文件中的位置
+    offset: u64,
+    // 延迟字段在文件中的位置，没有图形控制扩展的帧为空
+    delay_offset: Option<u64>,
+    // 局部颜色表的颜色数，没有局部颜色表的帧为空
+    local_palette: Option<usize>,
+}
+
+// 跳过一串数据子块，返回消耗的字节数（包括结束符）
+fn skip_sub_blocks<R: Read + std::io::Seek>(reader: &mut BufReader<R>) -> Result<u64, GifError> {
+    let mut consumed = 0;
+    loop {
+        let mut len = [0u8; 1];
+        reader.read_exact(&mut len)?;
+        consumed += 1;
+        if len[0] == 0 {
+            return Ok(consumed);
+        }
+        reader.seek_relative(len[0] as i64)?;
+        consumed += len[0] as u64;
+    }
+}
+
+// 读取一串数据子块的内容，返回内容和消耗的字节数（包括结束符）
+fn read_sub_blocks<R: Read>(reader: &mut BufReader<R>) -> Result<(Vec<u8>, u64), GifError> {
+    let mut data = Vec::new();
+    let mut consumed = 0;
+    loop {
+        let mut len = [0u8; 1];
+        reader.read_exact(&mut len)?;
+        consumed += 1;
+        if len[0] == 0 {
+            return Ok((data, consumed));
+        }
+        let start = data.len();
+        data.resize(start + len[0] as usize, 0);
+        reader.read_exact(&mut data[start..])?;
+        consumed += len[0] as u64;
+    }
+}
+
+/// GIF中一个注释扩展块
+struct CommentBlock {
+    // 扩展引导符0x21在文件中的位置
+    offset: u64,
+    // 整个扩展块的字节数
+    bytes: u64,
+    text: String,
+}
+
+/// GIF块结构扫描结果
+struct GifScan {
+    frames: Vec<FrameBlock>,
+    // ICCRGBG1应用扩展中的ICC颜色配置，没有时为空
+    icc_profile: Option<Vec<u8>>,
+    comments: Vec<CommentBlock>,
+    // 全局颜色表的颜色数，没有全局颜色表时为空
+    global_palette: Option<usize>,
+    // NETSCAPE2.0应用扩展中的循环次数，0为无限循环；没有该扩展时为空（只播放一次）
+    loop_count: Option<u16>,
+}
+
+// 存放ICC颜色配置的应用扩展标识
+const ICC_APP_IDENTIFIER: &[u8] = b"ICCRGBG1012";
+// 存放循环次数的应用扩展标识，ANIMEXTS1.0是少数旧软件使用的等价写法
+const LOOP_APP_IDENTIFIERS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+/// 扫描一个扩展块或图像块，遇到结束符或未知块时返回错误
+fn scan_gif_block<R: Read + std::io::Seek>(
+    reader: &mut BufReader<R>,
+    introducer: u8,
+    pending_gce: &mut Option<(u16, u64)>,
+    scan: &mut GifScan,
+) -> Result<(), GifError> {
+    match introducer {
+        // 扩展块
+        0x21 => {
+            let mut label = [0u8; 1];
+            reader.read_exact(&mut label)?;
+            if label[0] == 0xF9 {
+                // 图形控制扩展：块大小、标志、延迟（2字节）、透明色、结束符
+                let delay_offset = reader.stream_position()? + 2;
+                let mut gce = [0u8; 6];
+                reader.read_exact(&mut gce)?;
+                *pending_gce = Some((u16::from_le_bytes([gce[2], gce[3]]), delay_offset));
+            } else if label[0] == 0xFE {
+                // 注释扩展：内容全部在数据子块中
+                let offset = reader.stream_position()? - 2;
+                let (text, consumed) = read_sub_blocks(reader)?;
+                scan.comments.push(CommentBlock {
+                    offset,
+                    bytes: 2 + consumed,
+                    text: String::from_utf8_lossy(&text).to_string(),
+                });
+            } else if label[0] == 0xFF {
+                // 应用扩展：第一个子块是应用标识
+                let mut len = [0u8; 1];
+                reader.read_exact(&mut len)?;
+                let mut identifier = vec![0u8; len[0] as usize];
+                reader.read_exact(&mut identifier)?;
+                if identifier == ICC_APP_IDENTIFIER {
+                    // 数据子块中是完整的ICC配置文件
+                    let (profile, _) = read_sub_blocks(reader)?;
+                    scan.icc_profile = Some(profile);
+                } else if LOOP_APP_IDENTIFIERS.contains(&identifier.as_slice()) {
+                    // 循环子块：子块编号1，之后是2字节的循环次数
+                    let (data, _) = read_sub_blocks(reader)?;
+                    if data.len() >= 3 && data[0] == 1 {
+                        scan.loop_count = Some(u16::from_le_bytes([data[1], data[2]]));
+                    }
+                } else if len[0] != 0 {
+                    skip_sub_blocks(reader)?;
+                }
+            } else {
+                skip_sub_blocks(reader)?;
+            }
+        }
+        // 图像描述符
+        0x2C => {
+            let offset = reader.stream_position()? - 1;
+            let mut descriptor = [0u8; 9];
+            reader.read_exact(&mut descriptor)?;
+            let mut bytes = 10;
+            let local_palette = (descriptor[8] & 0x80 != 0).then(|| 1usize << ((descriptor[8] & 0x07) + 1));
+            if descriptor[8] & 0x80 != 0 {
+                let local_table = 3 * (1u64 << ((descriptor[8] & 0x07) + 1));
+                reader.seek_relative(local_table as i64)?;
+                bytes += local_table;
+            }
+            // LZW最小码长
+            reader.seek_relative(1)?;
+            bytes += 1 + skip_sub_blocks(reader)?;
+            let gce = pending_gce.take();
+            scan.frames.push(FrameBlock {
+                bytes,
+                delay_cs: gce.map_or(0, |(delay, _)| delay),
+                offset,
+                delay_offset: gce.map(|(_, delay_offset)| delay_offset),
+                local_palette,
+            });
+        }
+        _ => return Err(GifError::Other("GIF块结构结束".to_string())),
+    }
+    Ok(())
+}
+
+/// 只扫描GIF的块结构，得到每帧压缩数据的大小，不解码像素
+///
+/// 文件被截断时返回已经扫描到的帧
+fn scan_gif_blocks<P: AsRef<Path>>(path: P) -> Result<GifScan, GifError> {
+    scan_gif_reader(BufReader::new(File::open(path)?))
+}
+
+/// 与scan_gif_blocks相同，从任意可定位的数据源读取，用于内存中的GIF
+fn scan_gif_reader<R: Read + Seek>(mut reader: BufReader<R>) -> Result<GifScan, GifError> {
+    
+    // 文件头和逻辑屏幕描述符
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header)?;
+    if &header[0..3] != b"GIF" {
+        return Err(GifError::Other("不是有效的GIF文件".to_string()));
+    }
+    if header[10] & 0x80 != 0 {
+        let global_table = 3 * (1u64 << ((header[10] & 0x07) + 1));
+        reader.seek_relative(global_table as i64)?;
+    }
+    
+    let global_palette = (header[10] & 0x80 != 0).then(|| 1usize << ((header[10] & 0x07) + 1));
+    let mut scan = GifScan { frames: Vec::new(), icc_profile: None, comments: Vec::new(), global_palette, loop_count: None };
+    let mut pending_gce = None;
+    loop {
+        let mut introducer = [0u8; 1];
+        if reader.read_exact(&mut introducer).is_err() {
+            break;
+        }
+        let scanned = scan_gif_block(&mut reader, introducer[0], &mut pending_gce, &mut scan);
+        if scanned.is_err() {
+            // 0x3B结束符、未知块或截断的文件
+            break;
+        }
+    }
+    
+    if scan.frames.is_empty() {
+        return Err(GifError::NoFrames);
+    }
+    Ok(scan)
+}
+
+fn get_frame_count<P: AsRef<Path>>(path: P) -> Result<usize, GifError> {
+    let file = File::open(path)?;
+    let decoder = GifDecoder::new(BufReader::new(file))?;
+    let frames = decoder.into_frames();
+    let count = frames.count();
+    Ok(count)
+}
+
+/// 压缩策略结构
+#[derive(Clone)]
+struct Strategy {
+    skip: usize,
+    delay: u16,
+    // 设置后按画面变化抽帧，忽略skip
+    motion_threshold: Option<f64>,
+    // 静帧动图策略：每帧只保存相对上一帧变化的区域
+    cinemagraph: bool,
+    // 画面缩放比例，如0.8为缩小到80%，为空时不缩放
+    scale: Option<f32>,
+}
+
+impl Strategy {
+    // 用于耗时统计的简短描述
+    fn label(&self) -> String {
+        if self.cinemagraph {
+            "cinemagraph".to_string()
+        } else if let Some(threshold) = self.motion_threshold {
+            format!("frames=motion({:.1})", threshold)
+        } else if let Some(scale) = self.scale {
+            format!("frames=1/{} scale={:.0}%", self.skip, scale * 100.0)
+        } else {
+            format!("frames=1/{}", self.skip)
+        }
+    }
+}
+
+/// 至少保留的帧数：不少于3帧和min_frame_percent，但不超过总帧数
+fn min_frame_floor(frame_count: usize, options: &CompressOptions) -> usize {
+    std::cmp::max(3, (frame_count as f64 * options.min_frame_percent.unwrap_or(0) as f64 / 100.0) as usize).min(frame_count.max(1))
+}
+
+// 规划时被排除的抽帧策略
+#[derive(Clone, Serialize)]
+pub struct RejectedStrategy {
+    strategy: String,
+    kept_frames: usize,
+    reason: String,
+}
+
+/// plan_strategies的结果
+struct StrategyPlan {
+    // 按尝试顺序排列，每隔skip帧取1帧的策略都满足最少帧数，且保留的帧互不相同
+    strategies: Vec<Strategy>,
+    rejected: Vec<RejectedStrategy>,
+    min_frames: usize,
+    // 常规范围内采用的最大抽帧间隔，没有可用的抽帧策略时为1
+    max_skip: usize,
+}
+
+// 常规抽帧范围的最大间隔
+const MAX_REGULAR_SKIP: usize = 10;
+// 帧数超过该值时在常规范围之外再尝试更激进的间隔
+const AGGRESSIVE_SKIP_MIN_FRAMES: usize = 30;
+const AGGRESSIVE_SKIP_STEPS: [usize; 2] = [5, 10];
+
+/// 只根据帧数和选项生成要尝试的抽帧策略
+///
+/// 每隔skip帧取1帧保留ceil(frame_count / skip)帧，少于最少帧数或与前面的策略保留相同帧的候选被排除并记下原因。
+/// 按画面变化抽帧保留的帧数取决于画面，只能在抽帧时确定；静帧动图需要先分析画面，由调用方放在最前面
+fn plan_strategies(frame_count: usize, options: &CompressOptions) -> StrategyPlan {
+    let min_frames = min_frame_floor(frame_count, options);
+    let mut strategies = Vec::new();
+    let mut rejected = Vec::new();
+    
+    // 按画面变化抽帧优先尝试，它保留有明显变化的帧
+    if let Some(threshold) = options.motion_threshold {
+        strategies.push(Strategy {
+            skip: 1,
+            delay: 0,
+            motion_threshold: Some(threshold),
+            cinemagraph: false,
+            scale: None,
+        });
+    }
+    
+    // 从2抽1开始，最多抽到保留最少帧数；帧数很多时再尝试更激进的间隔
+    let regular_max = ((frame_count as f64 / min_frames as f64).ceil() as usize).clamp(2, MAX_REGULAR_SKIP);
+    let mut candidates: Vec<usize> = (2..=regular_max).collect();
+    if frame_count > AGGRESSIVE_SKIP_MIN_FRAMES {
+        candidates.extend(AGGRESSIVE_SKIP_STEPS.iter().map(|step| regular_max + step));
+    }
+    
+    let mut max_skip = 1;
+    for skip in candidates {
+        let strategy = Strategy {
+            skip,
+            delay: ((100.0 * skip as f64) / frame_count as f64) as u16 + 10,
+            motion_threshold: None,
+            cinemagraph: false,
+            scale: None,
+        };
+        let kept_frames = frame_count.div_ceil(skip);
+        if kept_frames < min_frames {
+            rejected.push(RejectedStrategy {
+                strategy: strategy.label(),
+                kept_frames,
+                reason: format!("只保留 {} 帧，少于最少帧数 {}", kept_frames, min_frames),
+            });
+            continue;
+        }
+        // 间隔不小于帧数时都只保留第一帧
+        if let Some(same) = strategies.iter().find(|planned| {
+            planned.motion_threshold.is_none() && planned.skip.min(frame_count) == skip.min(frame_count)
+        }) {
+            rejected.push(RejectedStrategy {
+                strategy: strategy.label(),
+                kept_frames,
+                reason: format!("与 {} 保留的帧相同", same.label()),
+            });
+            continue;
+        }
+        if skip <= regular_max {
+            max_skip = skip;
+        }
+        strategies.push(strategy);
+    }
+    
+    StrategyPlan { strategies, rejected, min_frames, max_skip }
+}
+
+// 大画布额外尝试的画面缩放比例（%），以及与之配合的抽帧间隔
+const SCALE_STRATEGY_PERCENT: [u32; 2] = [80, 60];
+const SCALE_STRATEGY_SKIPS: [usize; 2] = [1, 2];
+// 画布最长边达到该值时才尝试缩小画面的策略
+const SCALE_STRATEGY_MIN_EDGE: u32 = 480;
+
+/// 大画布额外尝试的缩小画面策略：少量抽帧配合按比例缩小，比例不低于min_scale_percent
+///
+/// 关闭了auto_downscale、使用尺寸预设或画布最长边小于SCALE_STRATEGY_MIN_EDGE时不生成
+fn scaled_strategies(frame_count: usize, canvas: (u32, u32), options: &CompressOptions) -> Vec<Strategy> {
+    if options.auto_downscale != Some(true) || options.dimension_preset.is_some() || canvas.0.max(canvas.1) < SCALE_STRATEGY_MIN_EDGE {
+        return Vec::new();
+    }
+    let min_frames = min_frame_floor(frame_count, options);
+    let mut strategies = Vec::new();
+    for percent in SCALE_STRATEGY_PERCENT.into_iter().filter(|&percent| percent >= options.min_scale_percent) {
+        for skip in SCALE_STRATEGY_SKIPS.into_iter().filter(|&skip| frame_count.div_ceil(skip) >= min_frames) {
+            strategies.push(Strategy {
+                skip,
+                // 不抽帧时保留原有延迟
+                delay: if skip == 1 { 0 } else { ((100.0 * skip as f64) / frame_count as f64) as u16 + 10 },
+                motion_threshold: None,
+                cinemagraph: false,
+                scale: Some(percent as f32 / 100.0),
+            });
+        }
+    }
+    strategies
+}
+
+/// 策略处理结果
+struct StrategyResult {
+    size: f64,
+    file: Option<TempFile>,
+    success: bool,
+    // 结果使用的lossy级别，未使用lossy时为空
+    lossy: Option<u32>,
+    // 自动减色后的颜色数，未减色时为空
+    colors: Option<u16>,
+}
+
+/// 共享状态结构体，用于线程间通信
+struct SharedState {
+    // 是否找到满足目标大小的结果
+    found_target: AtomicBool,
+    // 当前已找到的最佳大小，初始值设为最大值
+    best_size: std::sync::atomic::AtomicU64,
+    // 时间预算的截止时间，开始搜索时才设置，基础优化不会被中断
+    deadline: std::sync::OnceLock<Instant>,
+    // 开始搜索的时间，用于估计剩余时间
+    search_started: std::sync::OnceLock<Instant>,
+    // 解码和执行gifsicle的累计耗时（微秒）
+    decode_us: AtomicU64,
+    encode_us: AtomicU64,
+    // 是否已被用户或任务看门狗取消，可以与任务登记表共用
+    cancelled: Arc<AtomicBool>,
+    // 自动减色是否因min_colors下限而停止
+    color_floor_hit: AtomicBool,
+    // 把透明度合成到matte上的策略（Strategy::label），保留全部帧的预处理记为FULL_PASS_LABEL
+    flattened: std::sync::Mutex<Vec<String>>,
+    // 是否统计gifsicle子进程的峰值内存，以及目前观察到的最大值（KB），为0表示没有数据
+    measure_memory: AtomicBool,
+    peak_child_rss_kb: AtomicU64,
+    // 按评分选择时所有策略都要完成，找到目标不通知其他线程退出
+    collect_all: AtomicBool,
+    // 正在运行的gifsicle进程数及其最大值，以及运行过的总次数
+    running_children: AtomicUsize,
+    peak_running_children: AtomicUsize,
+    gifsicle_runs: AtomicUsize,
+    // 搜索预算，未设置时不限制调用次数；以及是否曾因预算用完跳过或终止了尝试
+    budget: std::sync::OnceLock<SearchBudget>,
+    budget_truncated: AtomicBool,
+    // 单个抽帧策略的时间限制，以及已完成策略的耗时
+    strategy_timeout: std::sync::OnceLock<Duration>,
+    strategy_timings: std::sync::Mutex<Vec<StrategyTiming>>,
+    // 单次gifsicle调用的时间限制，未设置时为DEFAULT_GIFSICLE_TIMEOUT；以及因超时被终止的次数
+    gifsicle_timeout: std::sync::OnceLock<Option<Duration>>,
+    gifsicle_timeouts: AtomicUsize,
+    // 测得的所有候选结果，以及其中已随进度消息发出的个数
+    attempts: std::sync::Mutex<(Vec<SearchAttempt>, usize)>,
+    // 是否以--verbose运行gifsicle，以及尚未转发到进度中的输出行
+    verbose: AtomicBool,
+    verbose_lines: std::sync::Mutex<Vec<String>>,
+    // 搜索进度计数，见SearchCounters
+    strategies_done: AtomicUsize,
+    strategies_total: AtomicUsize,
+    attempts_done: AtomicUsize,
+    attempts_total: AtomicUsize,
+}
+
+impl SharedState {
+    fn new() -> Self {
+        Self {
+            found_target: AtomicBool::new(false),
+            best_size: std::sync::atomic::AtomicU64::new(u64::MAX),
+            deadline: std::sync::OnceLock::new(),
+            search_started: std::sync::OnceLock::new(),
+            decode_us: AtomicU64::new(0),
+            encode_us: AtomicU64::new(0),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            color_floor_hit: AtomicBool::new(false),
+            flattened: std::sync::Mutex::new(Vec::new()),
+            measure_memory: AtomicBool::new(false),
+            peak_child_rss_kb: AtomicU64::new(0),
+            collect_all: AtomicBool::new(false),
+            running_children: AtomicUsize::new(0),
+            peak_running_children: AtomicUsize::new(0),
+            gifsicle_runs: AtomicUsize::new(0),
+            budget: std::sync::OnceLock::new(),
+            budget_truncated: AtomicBool::new(false),
+            strategy_timeout: std::sync::OnceLock::new(),
+            strategy_timings: std::sync::Mutex::new(Vec::new()),
+            gifsicle_timeout: std::sync::OnceLock::new(),
+            gifsicle_timeouts: AtomicUsize::new(0),
+            attempts: std::sync::Mutex::new((Vec::new(), 0)),
+            verbose: AtomicBool::new(false),
+            verbose_lines: std::sync::Mutex::new(Vec::new()),
+            strategies_done: AtomicUsize::new(0),
+            strategies_total: AtomicUsize::new(0),
+            attempts_done: AtomicUsize::new(0),
+            attempts_total: AtomicUsize::new(0),
+        }
+    }
+    
+    // 使用外部的取消标志，标志被设置时与调用cancel相同
+    fn with_cancel_flag(cancelled: Arc<AtomicBool>) -> Self {
+        Self { cancelled, ..Self::new() }
+    }
+    
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || IN_FLIGHT.is_shutting_down()
+    }
+    
+    // 取消后正在运行的gifsicle会被终止
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    
+    fn set_color_floor_hit(&self) {
+        self.color_floor_hit.store(true, Ordering::Relaxed);
+    }
+    
+    fn record_flattened(&self, label: String) {
+        let mut flattened = self.flattened.lock().unwrap();
+        if !flattened.contains(&label) {
+            flattened.push(label);
+        }
+    }
+    
+    fn is_flattened(&self, label: &str) -> bool {
+        self.flattened.lock().unwrap().iter().any(|flattened| flattened == label)
+    }
+    
+    // 开始搜索时启用时间预算，并记录开始时间
+    fn arm_deadline(&self, deadline: Option<Instant>) {
+        let _ = self.search_started.set(Instant::now());
+        if let Some(deadline) = deadline {
+            let _ = self.deadline.set(deadline);
+        }
+    }
+    
+    // 累计解码耗时
+    fn add_decode_time(&self, elapsed: Duration) {
+        self.decode_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+    
+    // 累计gifsicle耗时
+    fn add_encode_time(&self, elapsed: Duration) {
+        self.encode_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+    
+    fn enable_memory_measurement(&self) {
+        self.measure_memory.store(true, Ordering::Relaxed);
+    }
+    
+    fn measures_memory(&self) -> bool {
+        self.measure_memory.load(Ordering::Relaxed)
+    }
+    
+    // 记录一个gifsicle子进程的峰值内存
+    fn record_child_rss(&self, rss_kb: u64) {
+        self.peak_child_rss_kb.fetch_max(rss_kb, Ordering::Relaxed);
+    }
+    
+    fn child_started(&self, pid: u32) {
+        IN_FLIGHT.track_child(pid);
+        self.gifsicle_runs.fetch_add(1, Ordering::SeqCst);
+        let running = self.running_children.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_running_children.fetch_max(running, Ordering::SeqCst);
+    }
+    
+    fn child_exited(&self, pid: u32) {
+        IN_FLIGHT.untrack_child(pid);
+        self.running_children.fetch_sub(1, Ordering::SeqCst);
+    }
+    
+    fn set_budget(&self, budget: SearchBudget) {
+        let _ = self.budget.set(budget);
+    }
+    
+    fn gifsicle_runs(&self) -> usize {
+        self.gifsicle_runs.load(Ordering::SeqCst)
+    }
+    
+    // 检查gifsicle调用次数预算是否已用完，正在运行的进程仍会完成，实际次数最多超出线程数
+    fn is_runs_exhausted(&self) -> bool {
+        self.budget
+            .get()
+            .and_then(|budget| budget.max_gifsicle_runs)
+            .is_some_and(|max_runs| self.gifsicle_runs() >= max_runs)
+    }
+    
+    // 时间或调用次数预算是否已用完
+    fn is_budget_exhausted(&self) -> bool {
+        self.is_time_up() || self.is_runs_exhausted()
+    }
+    
+    // 因预算用完而跳过尝试时调用，预算已用完时记录搜索被截断并返回true
+    fn skip_for_budget(&self) -> bool {
+        let exhausted = self.is_budget_exhausted();
+        if exhausted {
+            self.budget_truncated.store(true, Ordering::SeqCst);
+        }
+        exhausted
+    }
+    
+    // 是否有尝试因预算用完被跳过或终止
+    fn is_budget_truncated(&self) -> bool {
+        self.budget_truncated.load(Ordering::SeqCst)
+    }
+    
+    fn set_strategy_timeout(&self, timeout: Option<Duration>) {
+        if let Some(timeout) = timeout {
+            let _ = self.strategy_timeout.set(timeout);
+        }
+    }
+    
+    fn strategy_timeout(&self) -> Option<Duration> {
+        self.strategy_timeout.get().copied()
+    }
+    
+    // 按gifsicle_timeout_secs设置单次gifsicle调用的时间限制，0为不限制
+    fn set_gifsicle_timeout(&self, timeout_secs: Option<u64>) {
+        if let Some(secs) = timeout_secs {
+            let _ = self.gifsicle_timeout.set((secs > 0).then(|| Duration::from_secs(secs)));
+        }
+    }
+    
+    fn gifsicle_timeout(&self) -> Option<Duration> {
+        let timeout = self.gifsicle_timeout.get().copied().unwrap_or(Some(DEFAULT_GIFSICLE_TIMEOUT));
+        if BASE_PASS_GIFSICLE.with(|base| base.get()) {
+            return timeout.map(|timeout| timeout * BASE_PASS_TIMEOUT_FACTOR);
+        }
+        timeout
+    }
+    
+    fn record_gifsicle_timeout(&self) {
+        self.gifsicle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    // 记录一个策略的耗时，started为该策略开始处理的时间
+    fn record_strategy_timing(&self, strategy: &Strategy, started: Instant, result: &StrategyResult) {
+        let elapsed = started.elapsed();
+        let timing = StrategyTiming {
+            strategy: strategy.label(),
+            elapsed_ms: elapsed.as_millis() as u64,
+            size_kb: result.success.then_some(result.size),
+            cut_short: self.strategy_timeout().is_some_and(|timeout| elapsed >= timeout),
+        };
+        self.strategy_timings.lock().unwrap().push(timing);
+    }
+    
+    fn record_attempt(&self, strategy: &Strategy, lossy: Option<u32>, size_kb: f64, kept: bool) {
+        self.push_attempt(strategy, lossy, None, size_kb, kept, false);
+    }
+    
+    // 记录一次自动减色尝试，lossy为减色前最佳结果使用的级别
+    fn record_color_attempt(&self, strategy: &Strategy, lossy: Option<u32>, colors: u16, size_kb: f64, kept: bool, floored: bool) {
+        self.push_attempt(strategy, lossy, Some(colors), size_kb, kept, floored);
+    }
+    
+    fn push_attempt(&self, strategy: &Strategy, lossy: Option<u32>, colors: Option<u16>, size_kb: f64, kept: bool, floored: bool) {
+        self.attempts.lock().unwrap().0.push(SearchAttempt {
+            strategy: strategy.label(),
+            strategy_skip: strategy.skip,
+            lossy,
+            colors,
+            size_kb,
+            kept,
+            floored,
+        });
+    }
+    
+    // 取出还没有随进度消息发出的候选结果
+    fn take_new_attempts(&self) -> Vec<SearchAttempt> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let (recorded, sent) = &mut *attempts;
+        let new = recorded[*sent..].to_vec();
+        *sent = recorded.len();
+        new
+    }
+    
+    fn cut_short_strategies(&self) -> usize {
+        self.strategy_timings.lock().unwrap().iter().filter(|timing| timing.cut_short).count()
+    }
+    
+    fn enable_verbose(&self) {
+        self.verbose.store(true, Ordering::Relaxed);
+    }
+    
+    fn is_verbose(&self) -> bool {
+        self.verbose.load(Ordering::Relaxed)
+    }
+    
+    // 暂存一行gifsicle输出，过长的行被截断，积压过多时丢弃最早的行
+    fn push_verbose_line(&self, line: &str) {
+        if line.is_empty() {
+            return;
+        }
+        let line = match line.char_indices().nth(VERBOSE_LINE_MAX_CHARS) {
+            Some((end, _)) => format!("{}…", &line[..end]),
+            None => line.to_string(),
+        };
+        let mut lines = self.verbose_lines.lock().unwrap();
+        if lines.len() >= VERBOSE_MAX_PENDING_LINES {
+            lines.remove(0);
+        }
+        lines.push(line);
+    }
+    
+    fn has_verbose_lines(&self) -> bool {
+        !self.verbose_lines.lock().unwrap().is_empty()
+    }
+    
+    // 取出所有暂存的输出行，每行一条
+    fn take_verbose_details(&self) -> Option<String> {
+        let lines = std::mem::take(&mut *self.verbose_lines.lock().unwrap());
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+    
+    // 计划更多的抽帧策略或压缩参数尝试
+    fn plan_strategies(&self, count: usize) {
+        self.strategies_total.fetch_add(count, Ordering::Relaxed);
+    }
+    
+    fn plan_attempts(&self, count: usize) {
+        self.attempts_total.fetch_add(count, Ordering::Relaxed);
+    }
+    
+    fn finish_strategy(&self) {
+        self.strategies_done.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    fn finish_attempt(&self) {
+        self.attempts_done.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// 估计搜索还需要的时间（秒）
+    ///
+    /// 按目前的完成比例外推：有压缩参数尝试计数时按尝试数，否则按策略数；
+    /// 还没有任何完成的尝试或策略时没有依据，返回空。找到目标或已取消时为0，有时间预算时不超过剩余预算
+    fn eta_seconds(&self) -> Option<f64> {
+        if self.is_cancelled() || self.is_target_found() {
+            return Some(0.0);
+        }
+        let started = self.search_started.get()?;
+        let counters = self.counters();
+        let done_fraction = if counters.attempts_done > 0 && counters.attempts_total > 0 {
+            counters.attempts_done as f64 / counters.attempts_total as f64
+        } else if counters.strategies_done > 0 && counters.strategies_total > 0 {
+            counters.strategies_done as f64 / counters.strategies_total as f64
+        } else {
+            return None;
+        };
+        let done_fraction = done_fraction.min(1.0);
+        let eta = started.elapsed().as_secs_f64() * (1.0 - done_fraction) / done_fraction;
+        match self.deadline.get() {
+            Some(deadline) => Some(eta.min(deadline.saturating_duration_since(Instant::now()).as_secs_f64())),
+            None => Some(eta),
+        }
+    }
+    
+    fn counters(&self) -> SearchCounters {
+        SearchCounters {
+            strategies_done: self.strategies_done.load(Ordering::Relaxed),
+            strategies_total: self.strategies_total.load(Ordering::Relaxed),
+            attempts_done: self.attempts_done.load(Ordering::Relaxed),
+            attempts_total: self.attempts_total.load(Ordering::Relaxed),
+        }
+    }
+    
+    // 生成从started开始到现在的统计报告
+    fn report(&self, started: Instant) -> CompressionReport {
+        let peak_rss_kb = self.peak_child_rss_kb.load(Ordering::Relaxed);
+        CompressionReport {
+            total_ms: started.elapsed().as_millis() as u64,
+            decode_ms: self.decode_us.load(Ordering::Relaxed) / 1000,
+            encode_ms: self.encode_us.load(Ordering::Relaxed) / 1000,
+            gifsicle_peak_rss_kb: (self.measures_memory() && peak_rss_kb > 0).then_some(peak_rss_kb),
+            peak_concurrent_gifsicle: self.peak_running_children.load(Ordering::Relaxed),
+            gifsicle_runs: self.gifsicle_runs(),
+            budget: self.budget
+                .get()
+                .filter(|budget| budget.max_gifsicle_runs.is_some() || budget.time_budget_ms.is_some())
+                .map(|&budget| BudgetUsage {
+                    budget,
+                    gifsicle_runs: self.gifsicle_runs(),
+                    elapsed_ms: started.elapsed().as_millis() as u64,
+                    truncated: self.is_budget_truncated(),
+                }),
+            strategy_timeout_ms: self.strategy_timeout().map(|timeout| timeout.as_millis() as u64),
+            gifsicle_timeouts: self.gifsicle_timeouts.load(Ordering::Relaxed),
+            strategy_timings: self.strategy_timings.lock().unwrap().clone(),
+            attempts: self.attempts.lock().unwrap().0.clone(),
+            counters: self.counters(),
+        }
+    }
+    
+    // 更新最佳大小（如果提供的大小更小）
+    fn update_best_size(&self, size: f64) -> bool {
+        let size_bits = size.to_bits();
+        let mut current = self.best_size.load(Ordering::Relaxed);
+        
+        loop {
+            // 如果新大小不比当前更好，不更新
+            if size_bits >= current {
+                return false;
+            }
+            
+            // 尝试原子更新，成功则返回true
+            match self.best_size.compare_exchange(
+                current,
+                size_bits,
+                Ordering::SeqCst,
+                Ordering::Relaxed
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+    
+    // 获取当前最佳大小
+    fn get_best_size(&self) -> f64 {
+        let bits = self.best_size.load(Ordering::Relaxed);
+        f64::from_bits(bits)
+    }
+    
+    // 设置已找到目标
+    fn set_found_target(&self) {
+        if !self.collect_all.load(Ordering::Relaxed) {
+            self.found_target.store(true, Ordering::Relaxed);
+        }
+    }
+    
+    fn collect_all_results(&self) {
+        self.collect_all.store(true, Ordering::Relaxed);
+    }
+    
+    // 检查是否已找到目标
+    fn is_target_found(&self) -> bool {
+        self.found_target.load(Ordering::Relaxed)
+    }
+    
+    // 检查时间预算是否已用完
+    fn is_time_up(&self) -> bool {
+        self.deadline.get().is_some_and(|deadline| Instant::now() >= *deadline)
+    }
+    
+    // 检查是否应停止继续搜索，返回停止原因；调用方会据此跳过尝试，因此预算用完时记录搜索被截断
+    fn stop_reason(&self) -> Option<&'static str> {
+        if IN_FLIGHT.is_shutting_down() {
+            Some("应用正在退出")
+        } else if self.cancelled.load(Ordering::Relaxed) {
+            Some("已取消")
+        } else if self.is_target_found() {
+            Some("已有其他线程找到满足条件的结果")
+        } else if self.is_time_up() {
+            self.budget_truncated.store(true, Ordering::SeqCst);
+            Some("时间预算已用完")
+        } else if self.is_runs_exhausted() {
+            self.budget_truncated.store(true, Ordering::SeqCst);
+            Some("gifsicle调用次数预算已用完")
+        } else if strategy_time_up() {
+            Some("当前策略超过了单个策略的时间限制")
+        } else {
+            None
+        }
+    }
+}
+
+thread_local! {
+    // 当前线程正在处理的策略的截止时间，由StrategyTimer设置
+    static STRATEGY_DEADLINE: std::cell::Cell<Option<Instant>> = const { std::cell::Cell::new(None) };
+    // 当前线程上的gifsicle是否是基础优化，使用更长的时间限制，由BasePassGifsicle设置
+    static BASE_PASS_GIFSICLE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// 在当前线程上把单次gifsicle调用的时间限制放宽到BASE_PASS_TIMEOUT_FACTOR倍，离开作用域时恢复
+///
+/// 用于基础优化：它是所有策略的输入，被终止时整个压缩失败，而很大的输入单次-O3就可能超过默认限制；
+/// 畸形GIF上卡住的同样是它，所以仍有时间限制
+struct BasePassGifsicle;
+
+impl BasePassGifsicle {
+    fn start() -> Self {
+        BASE_PASS_GIFSICLE.with(|base| base.set(true));
+        BasePassGifsicle
+    }
+}
+
+impl Drop for BasePassGifsicle {
+    fn drop(&mut self) {
+        BASE_PASS_GIFSICLE.with(|base| base.set(false));
+    }
+}
+
+/// 在当前线程上启用单个策略的时间限制，离开作用域时取消
+///
+/// 限制期间该线程上的stop_reason会报告超时，正在运行的gifsicle被终止，策略带着目前的最佳结果结束
+struct StrategyTimer;
+
+impl StrategyTimer {
+    fn start(started: Instant, timeout: Option<Duration>) -> Self {
+        STRATEGY_DEADLINE.with(|deadline| deadline.set(timeout.map(|timeout| started + timeout)));
+        StrategyTimer
+    }
+}
+
+impl Drop for StrategyTimer {
+    fn drop(&mut self) {
+        STRATEGY_DEADLINE.with(|deadline| deadline.set(None));
+    }
+}
+
+// 当前线程上的策略是否已超过时间限制
+fn strategy_time_up() -> bool {
+    STRATEGY_DEADLINE.with(|deadline| deadline.get()).is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+// 未指定strategy_timeout_ms时，单个策略的时间限制为基础优化耗时的倍数，且不少于最小值
+const STRATEGY_TIMEOUT_BASE_MULTIPLE: u32 = 30;
+const MIN_STRATEGY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 单个策略的时间限制，base_elapsed为基础优化的耗时
+fn strategy_timeout(options: &CompressOptions, base_elapsed: Duration) -> Option<Duration> {
+    match options.strategy_timeout_ms {
+        Some(0) => None,
+        Some(ms) => Some(Duration::from_millis(ms)),
+        None => Some((base_elapsed * STRATEGY_TIMEOUT_BASE_MULTIPLE).max(MIN_STRATEGY_TIMEOUT)),
+    }
+}
+
+// 轮询gifsicle子进程状态的间隔
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// 单次gifsicle调用的默认时间限制，遇到畸形GIF时gifsicle可能一直不结束
+const DEFAULT_GIFSICLE_TIMEOUT: Duration = Duration::from_secs(120);
+// 基础优化处理完整的原文件，时间限制是单次调用限制的倍数
+const BASE_PASS_TIMEOUT_FACTOR: u32 = 5;
+
+/// 判断gifsicle是否是崩溃退出（而不是正常报错或被我们终止），返回崩溃原因
+#[cfg(unix)]
+fn crash_reason(status: &std::process::ExitStatus) -> Option<String> {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .signal()
+        .filter(|signal| matches!(*signal, libc::SIGSEGV | libc::SIGBUS | libc::SIGABRT | libc::SIGILL | libc::SIGFPE))
+        .map(|signal| format!("收到信号 {}", signal))
+}
+
+#[cfg(windows)]
+fn crash_reason(status: &std::process::ExitStatus) -> Option<String> {
+    // 未处理的异常以NTSTATUS错误码（0xC0000000以上）作为退出码，如0xC0000005访问冲突
+    status
+        .code()
+        .map(|code| code as u32)
+        .filter(|code| code & 0xC000_0000 == 0xC000_0000)
+        .map(|code| format!("异常代码 0x{:08X}", code))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn crash_reason(_status: &std::process::ExitStatus) -> Option<String> {
+    None
+}
+
+/// 运行gifsicle，崩溃时加上--careful重试一次
+///
+/// 重试后仍然崩溃时返回GifsicleCrashed，调用方可以只跳过当前策略
+///
+/// gifsicle正常退出但写出的文件不可信（空文件、缺少文件头或结束符、没有帧）时重试一次，
+/// 仍然无效则返回GifsicleInvalidOutput
+fn run_gifsicle<S: AsRef<std::ffi::OsStr>>(
+    gifsicle_path: &str,
+    args: &[S],
+    shared_state: &SharedState,
+) -> Result<Output, GifError> {
+    let output = run_gifsicle_uncrashed(gifsicle_path, args, shared_state)?;
+    let output_file = args
+        .iter()
+        .position(|arg| arg.as_ref() == "-o" || arg.as_ref() == "--output")
+        .and_then(|i| args.get(i + 1))
+        .map(|path| PathBuf::from(path.as_ref()));
+    let output_file = match output_file {
+        Some(path) if output.status.success() => path,
+        _ => return Ok(output),
+    };
+    
+    let reason = match check_gif_output(&output_file) {
+        Ok(()) => return Ok(output),
+        Err(reason) => reason,
+    };
+    println!("gifsicle输出的文件无效（{}），重试一次", reason);
+    let output = run_gifsicle_uncrashed(gifsicle_path, args, shared_state)?;
+    if output.status.success() {
+        check_gif_output(&output_file).map_err(GifError::GifsicleInvalidOutput)?;
+    }
+    Ok(output)
+}
+
+/// 检查输出文件是否是结构完整的GIF：非空、有文件头和结束符、至少一帧
+fn check_gif_output<P: AsRef<Path>>(path: P) -> Result<(), String> {
+    let mut file = File::open(&path).map_err(|e| format!("无法打开: {}", e))?;
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    if len == 0 {
+        return Err("文件为空".to_string());
+    }
+    
+    let mut header = [0u8; 6];
+    if file.read_exact(&mut header).is_err() || (&header != b"GIF87a" && &header != b"GIF89a") {
+        return Err(format!("缺少GIF文件头（{} 字节）", len));
+    }
+    let mut trailer = [0u8; 1];
+    file.seek(std::io::SeekFrom::End(-1)).map_err(|e| e.to_string())?;
+    if file.read_exact(&mut trailer).is_err() || trailer[0] != 0x3B {
+        return Err(format!("缺少GIF结束符（{} 字节）", len));
+    }
+    match scan_gif_blocks(&path) {
+        Ok(scan) if !scan.frames.is_empty() => Ok(()),
+        _ => Err(format!("没有可用的帧（{} 字节）", len)),
+    }
+}
+
+/// 与check_gif_output相同的检查，用于内存中的GIF数据
+fn check_gif_bytes(data: &[u8]) -> Result<(), String> {
+    let len = data.len();
+    if len == 0 {
+        return Err("文件为空".to_string());
+    }
+    if len < 6 || (&data[..6] != b"GIF87a" && &data[..6] != b"GIF89a") {
+        return Err(format!("缺少GIF文件头（{} 字节）", len));
+    }
+    if data[len - 1] != 0x3B {
+        return Err(format!("缺少GIF结束符（{} 字节）", len));
+    }
+    match scan_gif_reader(BufReader::new(std::io::Cursor::new(data))) {
+        Ok(scan) if !scan.frames.is_empty() => Ok(()),
+        _ => Err(format!("没有可用的帧（{} 字节）", len)),
+    }
+}
+
+/// 运行gifsicle，崩溃时加上--careful重试一次
+fn run_gifsicle_uncrashed<S: AsRef<std::ffi::OsStr>>(
+    gifsicle_path: &str,
+    args: &[S],
+    shared_state: &SharedState,
+) -> Result<Output, GifError> {
+    let output = run_gifsicle_once(gifsicle_path, args, shared_state)?;
+    let reason = match crash_reason(&output.status) {
+        Some(reason) => reason,
+        None => return Ok(output),
+    };
+    
+    if args.iter().any(|arg| arg.as_ref() == "--careful") {
+        return Err(GifError::GifsicleCrashed(reason));
+    }
+    println!("gifsicle崩溃（{}），使用--careful重试", reason);
+    
+    let mut careful_args = vec![std::ffi::OsString::from("--careful")];
+    careful_args.extend(args.iter().map(|arg| arg.as_ref().to_os_string()));
+    let output = run_gifsicle_once(gifsicle_path, &careful_args, shared_state)?;
+    match crash_reason(&output.status) {
+        Some(reason) => Err(GifError::GifsicleCrashed(reason)),
+        None => Ok(output),
+    }
+}
+
+// 转发gifsicle输出时每行保留的字符数
+const VERBOSE_LINE_MAX_CHARS: usize = 160;
+// 两次进度报告之间最多积压的输出行，更早的行被丢弃
+const VERBOSE_MAX_PENDING_LINES: usize = 20;
+
+/// 读取gifsicle的全部stderr；给出shared_state时每读到一行就暂存起来，随下一次进度报告发出
+fn read_gifsicle_stderr(stderr: Option<ChildStderr>, verbose: Option<&SharedState>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match (stderr, verbose) {
+        (Some(mut stderr), None) => {
+            let _ = stderr.read_to_end(&mut buf);
+        }
+        (Some(stderr), Some(shared_state)) => {
+            let mut reader = BufReader::new(stderr);
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+                shared_state.push_verbose_line(String::from_utf8_lossy(&line).trim_end());
+                buf.extend_from_slice(&line);
+                line.clear();
+            }
+        }
+        (None, _) => {}
+    }
+    buf
+}
+
+/// 运行gifsicle并等待其结束
+///
+/// 搜索需要停止时（找到目标或时间预算用完）会终止子进程并回收，避免继续占用CPU
+fn run_gifsicle_once<S: AsRef<std::ffi::OsStr>>(
+    gifsicle_path: &str,
+    args: &[S],
+    shared_state: &SharedState,
+) -> Result<Output, GifError> {
+    // 取消后不再启动新的gifsicle
+    if shared_state.is_cancelled() {
+        return Err(GifError::Cancelled);
+    }
+    let started = Instant::now();
+    let mut command = Command::new(gifsicle_path);
+    if shared_state.is_verbose() {
+        command.arg("--verbose");
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    shared_state.child_started(child.id());
+    
+    // 在后台读取stderr，避免管道写满导致子进程阻塞；子进程结束或被终止后管道关闭，读取线程随之结束
+    let stderr = child.stderr.take();
+    thread::scope(|scope| {
+        let stderr_reader = scope.spawn(|| read_gifsicle_stderr(stderr, shared_state.is_verbose().then_some(shared_state)));
+        let status = wait_gifsicle(&mut child, shared_state, started);
+        shared_state.child_exited(child.id());
+        let status = status?;
+        Ok(Output {
+            status,
+            stdout: Vec::new(),
+            stderr: stderr_reader.join().unwrap_or_default(),
+        })
+    })
+}
+
+/// 运行gifsicle，输入写到stdin，结果从stdout读出，不使用任何文件
+///
+/// args中用"-"表示从stdin读取，并用"-o -"输出到stdout；输出不是完整的GIF时返回GifsicleInvalidOutput
+fn run_gifsicle_piped(gifsicle_path: &str, args: &[&str], input: &[u8], shared_state: &SharedState) -> Result<Vec<u8>, GifError> {
+    // 取消后不再启动新的gifsicle
+    if shared_state.is_cancelled() {
+        return Err(GifError::Cancelled);
+    }
+    let started = Instant::now();
+    let mut command = Command::new(gifsicle_path);
+    if shared_state.is_verbose() {
+        command.arg("--verbose");
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    shared_state.child_started(child.id());
+    
+    // 写stdin、读stdout和stderr都在后台进行，任何一个管道写满都不会互相等待；子进程被终止后管道关闭，这些线程随之结束
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            if let Some(mut stdin) = stdin {
+                let _ = stdin.write_all(input);
+            }
+        });
+        let stdout_reader = scope.spawn(move || {
+            let mut data = Vec::new();
+            if let Some(mut stdout) = stdout {
+                let _ = stdout.read_to_end(&mut data);
+            }
+            data
+        });
+        let stderr_reader = scope.spawn(|| read_gifsicle_stderr(stderr, shared_state.is_verbose().then_some(shared_state)));
+        let status = wait_gifsicle(&mut child, shared_state, started);
+        shared_state.child_exited(child.id());
+        let status = status?;
+        let data = stdout_reader.join().unwrap_or_default();
+        let stderr = stderr_reader.join().unwrap_or_default();
+        if !status.success() {
+            return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&stderr).to_string()));
+        }
+        check_gif_bytes(&data).map_err(GifError::GifsicleInvalidOutput)?;
+        Ok(data)
+    })
+}
+
+/// 等待gifsicle结束，需要停止搜索时终止它
+fn wait_gifsicle(child: &mut Child, shared_state: &SharedState, started: Instant) -> Result<ExitStatus, GifError> {
+    let status = loop {
+        let exited = if shared_state.measures_memory() {
+            try_wait_with_rss(child)?
+        } else {
+            child.try_wait()?.map(|status| (status, None))
+        };
+        if let Some((status, rss_kb)) = exited {
+            if let Some(rss_kb) = rss_kb {
+                shared_state.record_child_rss(rss_kb);
+            }
+            break status;
+        }
+        if let Some(reason) = shared_state.stop_reason() {
+            let _ = child.kill();
+            let _ = child.wait();
+            shared_state.add_encode_time(started.elapsed());
+            return Err(GifError::Other(format!("gifsicle已终止: {}", reason)));
+        }
+        if let Some(timeout) = shared_state.gifsicle_timeout().filter(|timeout| started.elapsed() >= *timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            shared_state.add_encode_time(started.elapsed());
+            shared_state.record_gifsicle_timeout();
+            println!("gifsicle超过 {} 秒没有结束，已终止", timeout.as_secs());
+            return Err(GifError::GifsicleTimeout(timeout.as_secs()));
+        }
+        thread::sleep(CHILD_POLL_INTERVAL);
+    };
+    shared_state.add_encode_time(started.elapsed());
+    Ok(status)
+}
+
+/// 检查子进程是否已结束，结束时同时返回它的峰值内存（KB）
+///
+/// 用wait4回收子进程，取得该子进程自己的资源使用情况
+#[cfg(unix)]
+fn try_wait_with_rss(child: &mut Child) -> std::io::Result<Option<(ExitStatus, Option<u64>)>> {
+    use std::os::unix::process::ExitStatusExt;
+    
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, libc::WNOHANG, &mut usage) };
+    match pid {
+        0 => Ok(None),
+        -1 => {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                Ok(None)
+            } else {
+                Err(error)
+            }
+        }
+        _ => {
+            // macOS上ru_maxrss以字节为单位，Linux上以KB为单位
+            let max_rss = usage.ru_maxrss.max(0) as u64;
+            let rss_kb = if cfg!(target_os = "macos") { max_rss / 1024 } else { max_rss };
+            Ok(Some((ExitStatus::from_raw(status), Some(rss_kb))))
+        }
+    }
+}
+
+// 其他平台无法取得子进程的峰值内存
+#[cfg(not(unix))]
+fn try_wait_with_rss(child: &mut Child) -> std::io::Result<Option<(ExitStatus, Option<u64>)>> {
+    Ok(child.try_wait()?.map(|status| (status, None)))
+}
+
+/// 将小数厘秒的帧延迟按指定方式取整
+fn round_delays(delays_cs: &[f64], mode: DelayRounding) -> Vec<u16> {
+    let mut carry = 0.0;
+    delays_cs
+        .iter()
+        .map(|&delay| {
+            let rounded = match mode {
+                DelayRounding::Nearest => delay.round(),
+                DelayRounding::Floor => delay.floor(),
+                DelayRounding::Ceil => delay.ceil(),
+                DelayRounding::Accumulate => {
+                    let wanted = delay + carry;
+                    let rounded = wanted.round().max(0.0);
+                    carry = wanted - rounded;
+                    rounded
+                }
+            };
+            rounded.clamp(0.0, u16::MAX as f64) as u16
+        })
+        .collect()
+}
+
+/// 计算两帧RGB通道的平均绝对差（0-255），尺寸不同时视为完全不同
+fn frame_difference(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+    if a.dimensions() != b.dimensions() || a.is_empty() {
+        return 255.0;
+    }
+    let total: u64 = a
+        .pixels()
+        .zip(b.pixels())
+        .map(|(pa, pb)| (0..3).map(|c| pa[c].abs_diff(pb[c]) as u64).sum::<u64>())
+        .sum();
+    total as f64 / (a.width() as f64 * a.height() as f64 * 3.0)
+}
+
+// 数据量大的帧放宽阈值的最大倍数
+const HEAVY_FRAME_MAX_BIAS: f64 = 3.0;
+
+/// 按帧数据量相对中位数的比例放宽阈值，不小于1倍
+fn heavy_frame_bias(frame_bytes: &[u64]) -> Vec<f64> {
+    let mut sorted = frame_bytes.to_vec();
+    sorted.sort_unstable();
+    let median = sorted.get(sorted.len() / 2).copied().unwrap_or(0).max(1) as f64;
+    frame_bytes
+        .iter()
+        .map(|&bytes| (bytes as f64 / median).clamp(1.0, HEAVY_FRAME_MAX_BIAS))
+        .collect()
+}
+
+// 内置的平台尺寸预设：名称、宽、高
+const DIMENSION_PRESETS: [(&str, u32, u32); 4] = [
+    // 常见的贴纸尺寸
+    ("sticker", 512, 512),
+    // 正方形缩略图
+    ("thumbnail", 200, 200),
+    // 16:9的网页嵌入
+    ("480p", 854, 480),
+    ("360p", 640, 360),
+];
+
+/// 按名称查找options中的尺寸预设，返回输出画布的宽高
+fn dimension_preset(options: &CompressOptions) -> Result<Option<(u32, u32)>, GifError> {
+    let name = match options.dimension_preset.as_deref() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    match DIMENSION_PRESETS.iter().find(|(preset, _, _)| *preset == name) {
+        Some(&(_, width, height)) => Ok(Some((width, height))),
+        None => {
+            let names: Vec<&str> = DIMENSION_PRESETS.iter().map(|(preset, _, _)| *preset).collect();
+            Err(GifError::InvalidOption(format!("未知的尺寸预设 {}，可用的预设: {}", name, names.join("、"))))
+        }
+    }
+}
+
+// 等比缩放后居中补边到固定尺寸的结果
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+pub struct LetterboxInfo {
+    width: u32,
+    height: u32,
+    // 缩放后画面本身的尺寸
+    scaled_width: u32,
+    scaled_height: u32,
+    // 左右、上下合计补的像素，两侧各一半，奇数时多出的一像素补在右侧/底部
+    pad_x: u32,
+    pad_y: u32,
+}
+
+/// 计算把source等比缩放到target内并居中补边的位置，画面可能被放大，但不会被拉伸
+fn letterbox_geometry(source: (u32, u32), target: (u32, u32)) -> LetterboxInfo {
+    let (source_width, source_height) = (source.0.max(1) as f64, source.1.max(1) as f64);
+    let (width, height) = target;
+    let scale = (width as f64 / source_width).min(height as f64 / source_height);
+    let scaled_width = ((source_width * scale).round() as u32).clamp(1, width);
+    let scaled_height = ((source_height * scale).round() as u32).clamp(1, height);
+    LetterboxInfo {
+        width,
+        height,
+        scaled_width,
+        scaled_height,
+        pad_x: width - scaled_width,
+        pad_y: height - scaled_height,
+    }
+}
+
+// 去掉透明度时默认合成到的底色（白色）
+const DEFAULT_MATTE: [u8; 3] = [255, 255, 255];
+// 调整起始帧、透明色、尺寸预设等保留全部帧的预处理，以及内置帧差分，记录合成情况时使用的标签
+const FULL_PASS_LABEL: &str = "frames=1/1";
+
+/// 解析matte颜色，支持#rrggbb和#rgb，#可以省略；未设置时为白色
+fn parse_matte(matte: Option<&str>) -> Result<[u8; 3], GifError> {
+    let text = match matte {
+        Some(text) => text.trim(),
+        None => return Ok(DEFAULT_MATTE),
+    };
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    let digits: Option<Vec<u8>> = hex.chars().map(|c| c.to_digit(16).map(|digit| digit as u8)).collect();
+    match digits.as_deref() {
+        Some(&[r, g, b]) => Ok([r * 17, g * 17, b * 17]),
+        Some(&[r1, r2, g1, g2, b1, b2]) => Ok([r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2]),
+        _ => Err(GifError::InvalidOption(format!("matte颜色 {} 无效，应为#rrggbb或#rgb", text))),
+    }
+}
+
+/// 最终结果去掉了透明度时返回所用的matte（#rrggbb），保留了透明度时为空
+///
+/// 预处理合成过时所有结果都受影响，否则只看最终结果所用的策略
+fn reported_matte(options: &CompressOptions, shared_state: &SharedState, winner: Option<&WinningConfig>) -> Option<String> {
+    let flattened = shared_state.is_flattened(FULL_PASS_LABEL)
+        || winner
+            .and_then(|winner| winner.strategy.as_ref())
+            .is_some_and(|strategy| shared_state.is_flattened(&strategy.label()));
+    if !flattened {
+        return None;
+    }
+    let [r, g, b] = parse_matte(options.matte.as_deref()).unwrap_or(DEFAULT_MATTE);
+    Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+/// 把不完全不透明的像素按不透明度合成到matte上，变为完全不透明；keep_clear为true时完全透明的像素保持透明
+///
+/// 所有需要去掉透明度的地方都通过它合成，返回是否有像素被合成
+fn composite_onto_matte(image: &mut image::RgbaImage, matte: [u8; 3], keep_clear: bool) -> bool {
+    let mut composited = false;
+    for pixel in image.pixels_mut() {
+        let alpha = pixel[3];
+        if alpha == 255 || (alpha == 0 && keep_clear) {
+            continue;
+        }
+        let coverage = alpha as f64 / 255.0;
+        for c in 0..3 {
+            pixel[c] = (pixel[c] as f64 * coverage + matte[c] as f64 * (1.0 - coverage)).round() as u8;
+        }
+        pixel[3] = 255;
+        composited = true;
+    }
+    composited
+}
+
+/// 按letterbox_geometry的结果缩放一帧并补边，background为空时补透明像素
+///
+/// 补不透明的边时画面本身也不再保留透明，先合成到matte上；第二个返回值表示是否有像素被合成
+fn letterbox_frame(
+    image: &image::RgbaImage,
+    info: &LetterboxInfo,
+    background: Option<[u8; 3]>,
+    matte: [u8; 3],
+) -> (image::RgbaImage, bool) {
+    let fill = background.map_or(image::Rgba([0, 0, 0, 0]), |[r, g, b]| image::Rgba([r, g, b, 255]));
+    let mut canvas = image::RgbaImage::from_pixel(info.width, info.height, fill);
+    let mut flattened_image = None;
+    if background.is_some() {
+        let mut opaque = image.clone();
+        if composite_onto_matte(&mut opaque, matte, false) {
+            flattened_image = Some(opaque);
+        }
+    }
+    let flattened = flattened_image.is_some();
+    let image = flattened_image.as_ref().unwrap_or(image);
+    let (left, top) = ((info.pad_x / 2) as i64, (info.pad_y / 2) as i64);
+    if image.dimensions() == (info.scaled_width, info.scaled_height) {
+        image::imageops::overlay(&mut canvas, image, left, top);
+    } else {
+        let scaled = image::imageops::resize(image, info.scaled_width, info.scaled_height, image::imageops::FilterType::Lanczos3);
+        image::imageops::overlay(&mut canvas, &scaled, left, top);
+    }
+    (canvas, flattened)
+}
+
+/// 把接近color_key的像素设为透明
+///
+/// 距离不超过tolerance的像素变为透明；设置feather时，距离在其后feather范围内的像素
+/// 视为前景与key颜色的混合，按距离估算混合比例后还原前景颜色，GIF只有全透明和不透明，
+/// 这些像素保持不透明
+fn apply_color_key(image: &mut image::RgbaImage, key: [u8; 3], tolerance: f64, feather: f64) {
+    for pixel in image.pixels_mut() {
+        if pixel[3] < GIF_ALPHA_THRESHOLD {
+            continue;
+        }
+        let distance = (0..3)
+            .map(|c| (pixel[c] as f64 - key[c] as f64).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        if distance <= tolerance {
+            *pixel = image::Rgba([0, 0, 0, 0]);
+        } else if feather > 0.0 && distance < tolerance + feather {
+            let coverage = (distance - tolerance) / feather;
+            for c in 0..3 {
+                let foreground = (pixel[c] as f64 - (1.0 - coverage) * key[c] as f64) / coverage;
+                pixel[c] = foreground.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// 是否有像素从不透明变为透明，这种变化不能叠加在上一帧画面上完成
+fn introduces_transparency(prev: &image::RgbaImage, cur: &image::RgbaImage) -> bool {
+    prev.pixels().zip(cur.pixels()).any(|(a, b)| a[3] != 0 && b[3] == 0)
+}
+
+// 像素在某个通道上变化超过这个值才算变化，避免压缩噪点干扰静帧动图判断
+const CINEMAGRAPH_CHANGE_TOLERANCE: u8 = 8;
+// 判断静帧动图时每隔几个像素采样一次
+const CINEMAGRAPH_SAMPLE_STEP: u32 = 4;
+// 整个动画中从未变化的像素达到这个比例时视为静帧动图
+const CINEMAGRAPH_STATIC_RATIO: f64 = 0.9;
+
+/// 采样计算整个动画中从未变化的像素比例
+fn static_pixel_ratio(frames: &[image::Frame]) -> f64 {
+    let first = match frames.first() {
+        Some(frame) => frame.buffer(),
+        None => return 0.0,
+    };
+    let (width, height) = first.dimensions();
+    let samples: Vec<(u32, u32)> = (0..height)
+        .step_by(CINEMAGRAPH_SAMPLE_STEP as usize)
+        .flat_map(|y| (0..width).step_by(CINEMAGRAPH_SAMPLE_STEP as usize).map(move |x| (x, y)))
+        .collect();
+    if samples.is_empty() {
+        return 0.0;
+    }
+    
+    let mut changed = vec![false; samples.len()];
+    for pair in frames.windows(2) {
+        let (prev, cur) = (pair[0].buffer(), pair[1].buffer());
+        if prev.dimensions() != cur.dimensions() {
+            return 0.0;
+        }
+        for (flag, &(x, y)) in changed.iter_mut().zip(&samples) {
+            if !*flag {
+                let (a, b) = (prev.get_pixel(x, y), cur.get_pixel(x, y));
+                *flag = (0..4).any(|c| a[c].abs_diff(b[c]) > CINEMAGRAPH_CHANGE_TOLERANCE);
+            }
+        }
+    }
+    changed.iter().filter(|flag| !**flag).count() as f64 / samples.len() as f64
+}
+
+/// 解码GIF并判断是否为静帧动图，返回静止像素比例
+fn detect_cinemagraph<P: AsRef<Path>>(path: P, shared_state: &SharedState) -> Result<Option<f64>, GifError> {
+    let started = Instant::now();
+    let file = File::open(path)?;
+    // 解码全部帧耗时较长，逐帧检查是否已取消
+    let mut frames = Vec::new();
+    for frame in GifDecoder::new(BufReader::new(file))?.into_frames() {
+        if shared_state.is_cancelled() {
+            return Err(GifError::Cancelled);
+        }
+        frames.push(frame?);
+    }
+    let ratio = static_pixel_ratio(&frames);
+    // 只保存变化区域时无法让像素变回透明
+    let clears_pixels = frames.windows(2).any(|pair| introduces_transparency(pair[0].buffer(), pair[1].buffer()));
+    shared_state.add_decode_time(started.elapsed());
+    Ok((frames.len() >= 3 && ratio >= CINEMAGRAPH_STATIC_RATIO && !clears_pixels).then_some(ratio))
+}
+
+/// 计算cur相对prev发生变化的最小矩形(x, y, 宽, 高)，完全相同时返回None
+fn changed_bounds(prev: &image::RgbaImage, cur: &image::RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    if prev.dimensions() != cur.dimensions() {
+        return Some((0, 0, cur.width(), cur.height()));
+    }
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in cur.enumerate_pixels() {
+        if prev.get_pixel(x, y) != pixel {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    (min_x != u32::MAX).then(|| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+// 透明度低于这个值的像素在GIF中按透明处理
+const GIF_ALPHA_THRESHOLD: u8 = 128;
+// 校验帧差分结果时每隔几个像素采样一次
+const DIFFERENCING_VERIFY_STEP: u32 = 3;
+
+/// 帧差分后的一帧：只包含变化区域，未变化的像素为透明
+struct DifferencedFrame {
+    left: u32,
+    top: u32,
+    region: image::RgbaImage,
+    delay_cs: u16,
+    // 显示后清除为透明，下一帧需要出现新的透明像素时使用
+    clear_after: bool,
+}
+
+/// 把透明像素统一为(0, 0, 0, 0)，之后可以直接比较像素是否相同
+fn normalize_transparency(image: &mut image::RgbaImage) {
+    for pixel in image.pixels_mut() {
+        if pixel[3] < GIF_ALPHA_THRESHOLD {
+            *pixel = image::Rgba([0, 0, 0, 0]);
+        } else {
+            pixel[3] = 255;
+        }
+    }
+}
+
+/// 对合成后的完整画面逐帧做差分
+///
+/// 每帧与上一帧显示后的画面比较，只保留变化区域，区域内未变化的像素设为透明；
+/// 原本不透明的像素在下一帧变为透明时，透明像素无法覆盖已有画面，
+/// 这时上一帧改为完整画面并在显示后清除
+fn difference_frames(canvases: &[image::RgbaImage], delays_cs: &[u16]) -> Vec<DifferencedFrame> {
+    let (width, height) = match canvases.first() {
+        Some(first) => first.dimensions(),
+        None => return Vec::new(),
+    };
+    let needs_clear: Vec<bool> = (0..canvases.len())
+        .map(|i| {
+            i > 0 && introduces_transparency(&canvases[i - 1], &canvases[i])
+        })
+        .collect();
+    
+    let blank = image::RgbaImage::new(width, height);
+    let mut displayed = blank.clone();
+    let mut frames = Vec::with_capacity(canvases.len());
+    for (i, canvas) in canvases.iter().enumerate() {
+        let clear_after = needs_clear.get(i + 1).copied().unwrap_or(false);
+        let (left, top, region_width, region_height) = if clear_after {
+            (0, 0, width, height)
+        } else {
+            // 完全相同的帧保留一个透明像素，只用来承载延迟
+            changed_bounds(&displayed, canvas).unwrap_or((0, 0, 1, 1))
+        };
+        let region = image::RgbaImage::from_fn(region_width, region_height, |x, y| {
+            let (x, y) = (left + x, top + y);
+            let pixel = *canvas.get_pixel(x, y);
+            if pixel == *displayed.get_pixel(x, y) {
+                image::Rgba([0, 0, 0, 0])
+            } else {
+                pixel
+            }
+        });
+        frames.push(DifferencedFrame {
+            left,
+            top,
+            region,
+            delay_cs: delays_cs.get(i).copied().unwrap_or(0),
+            clear_after,
+        });
+        displayed = if clear_after { blank.clone() } else { canvas.clone() };
+    }
+    frames
+}
+
+/// 原文件的循环次数，读取失败时按无限循环处理
+fn source_loop_count(scan: Result<GifScan, GifError>) -> Option<u16> {
+    scan.map_or(Some(0), |scan| scan.loop_count)
+}
+
+/// 按loop_count选项得到输出的循环次数：为空时不写循环扩展（只播放一次），0为无限循环
+fn output_loop_count(options: &CompressOptions, source_loop: Option<u16>) -> Option<u16> {
+    match options.loop_count.unwrap_or_default() {
+        LoopSetting::Preserve => source_loop,
+        LoopSetting::Forever => Some(0),
+        LoopSetting::Count(count) => Some(count),
+    }
+}
+
+/// 输出循环次数对应的gifsicle参数
+fn loopcount_arg(loop_count: Option<u16>) -> String {
+    match loop_count {
+        None => "--no-loopcount".to_string(),
+        Some(0) => "--loopcount=forever".to_string(),
+        Some(count) => format!("--loopcount={}", count),
+    }
+}
+
+/// 把差分后的帧编码为GIF
+fn write_differenced_gif<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    frames: Vec<DifferencedFrame>,
+    loop_count: Option<u16>,
+) -> Result<(), GifError> {
+    let to_u16 = |value: u32| u16::try_from(value).map_err(|_| GifError::Other(format!("画布尺寸 {} 超出GIF范围", value)));
+    let encode_error = |e: gif::EncodingError| GifError::Other(format!("GIF编码失败: {}", e));
+    
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = gif::Encoder::new(writer, to_u16(width)?, to_u16(height)?, &[]).map_err(encode_error)?;
+    if let Some(count) = loop_count {
+        let repeat = if count == 0 { gif::Repeat::Infinite } else { gif::Repeat::Finite(count) };
+        encoder.set_repeat(repeat).map_err(encode_error)?;
+    }
+    for frame in frames {
+        let (region_width, region_height) = frame.region.dimensions();
+        let mut pixels = frame.region.into_raw();
+        let mut gif_frame = gif::Frame::from_rgba_speed(to_u16(region_width)?, to_u16(region_height)?, &mut pixels, 10);
+        gif_frame.left = to_u16(frame.left)?;
+        gif_frame.top = to_u16(frame.top)?;
+        gif_frame.delay = frame.delay_cs;
+        gif_frame.dispose = if frame.clear_after {
+            gif::DisposalMethod::Background
+        } else {
+            gif::DisposalMethod::Keep
+        };
+        encoder.write_frame(&gif_frame).map_err(encode_error)?;
+    }
+    Ok(())
+}
+
+/// 解码输出文件，采样比较每帧合成后的画面是否与原图一致
+fn verify_differenced<P: AsRef<Path>>(path: P, expected: &[image::RgbaImage]) -> Result<bool, GifError> {
+    let frames = GifDecoder::new(BufReader::new(File::open(path)?))?.into_frames().collect_frames()?;
+    if frames.len() != expected.len() {
+        return Ok(false);
+    }
+    for (frame, expected) in frames.into_iter().zip(expected) {
+        let mut actual = frame.into_buffer();
+        if actual.dimensions() != expected.dimensions() {
+            return Ok(false);
+        }
+        normalize_transparency(&mut actual);
+        let (width, height) = expected.dimensions();
+        for y in (0..height).step_by(DIFFERENCING_VERIFY_STEP as usize) {
+            for x in (0..width).step_by(DIFFERENCING_VERIFY_STEP as usize) {
+                if actual.get_pixel(x, y) != expected.get_pixel(x, y) {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// 不依赖gifsicle的帧差分优化
+///
+/// 解码后重新编码每帧的变化区域，保留原始延迟和透明度，并按options调整起始帧和透明色；
+/// 输出会重新解码与原图比较，画面不一致（例如合成后单帧超过256色）时返回错误
+fn differencing_pass<P: AsRef<Path>, Q: AsRef<Path>>(
+    input_path: P,
+    output_path: Q,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+) -> Result<(), GifError> {
+    let start_at_frame = options.start_at_frame.unwrap_or(0);
+    let decode_started = Instant::now();
+    let file = File::open(&input_path)?;
+    let frames = GifDecoder::new(BufReader::new(file))?.into_frames().collect_frames()?;
+    if frames.is_empty() {
+        return Err(GifError::NoFrames);
+    }
+    
+    let letterbox = dimension_preset(options)?.map(|size| letterbox_geometry(frames[0].buffer().dimensions(), size));
+    let matte = parse_matte(options.matte.as_deref())?;
+    let mut delays_cs = Vec::with_capacity(frames.len());
+    let mut canvases = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        delays_cs.push(((numer as f64 / denom.max(1) as f64) / 10.0).round().min(u16::MAX as f64) as u16);
+        let mut canvas = frame.into_buffer();
+        if let Some(key) = options.color_key {
+            apply_color_key(&mut canvas, key, options.color_key_tolerance, options.color_key_feather);
+        }
+        if let Some(info) = &letterbox {
+            let (letterboxed, flattened) = letterbox_frame(&canvas, info, options.letterbox_background, matte);
+            if flattened {
+                shared_state.record_flattened(FULL_PASS_LABEL.to_string());
+            }
+            canvas = letterboxed;
+        }
+        normalize_transparency(&mut canvas);
+        canvases.push(canvas);
+    }
+    if start_at_frame > 0 && start_at_frame < canvases.len() {
+        canvases.rotate_left(start_at_frame);
+        delays_cs.rotate_left(start_at_frame);
+    }
+    shared_state.add_decode_time(decode_started.elapsed());
+    
+    let (width, height) = canvases[0].dimensions();
+    let started = Instant::now();
+    let differenced = difference_frames(&canvases, &delays_cs);
+    let loop_count = output_loop_count(options, source_loop_count(scan_gif_blocks(&input_path)));
+    write_differenced_gif(&output_path, width, height, differenced, loop_count)?;
+    println!("帧差分完成，耗时: {:?}", started.elapsed());
+    
+    if !verify_differenced(&output_path, &canvases)? {
+        return Err(GifError::Other("帧差分结果与原图画面不一致".to_string()));
+    }
+    Ok(())
+}
+
+/// 选出要保留的帧，返回每个保留帧所代替的原始帧范围
+///
+/// 设置了motion_threshold时，与上一保留帧差异低于阈值的帧被并入上一保留帧；
+/// 给出frame_bytes时，数据量大的帧使用更高的阈值
+fn select_frames(
+    frames: &[image::Frame],
+    skip: usize,
+    motion_threshold: Option<f64>,
+    frame_bytes: Option<&[u64]>,
+) -> Vec<std::ops::Range<usize>> {
+    let total_frames = frames.len();
+    match motion_threshold {
+        Some(threshold) => {
+            let bias = frame_bytes
+                .filter(|bytes| bytes.len() == total_frames)
+                .map(heavy_frame_bias)
+                .unwrap_or_else(|| vec![1.0; total_frames]);
+            let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+            for i in 0..total_frames {
+                match ranges.last_mut() {
+                    Some(last) if frame_difference(frames[last.start].buffer(), frames[i].buffer()) < threshold * bias[i] => {
+                        last.end = i + 1;
+                    }
+                    _ => ranges.push(i..i + 1),
+                }
+            }
+            ranges
+        }
+        None => (0..total_frames)
+            .step_by(skip)
+            .map(|i| i..std::cmp::min(i + skip, total_frames))
+            .collect(),
+    }
+}
+
+// 混合时每个保留帧最多合入其后的几个被丢弃帧
+const BLEND_MAX_DROPPED_FRAMES: usize = 2;
+
+/// 把若干连续的完整画面按播放延迟加权平均成一帧，位置和延迟沿用第一帧
+///
+/// 颜色按不透明度加权，透明像素不会把颜色拉向黑色
+fn blend_frames(frames: &[image::Frame]) -> image::Frame {
+    let first = &frames[0];
+    let weights: Vec<f64> = frames
+        .iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let delay_cs = (numer as f64 / denom.max(1) as f64 / 10.0).round() as u16;
+            playback_delay_cs(delay_cs) as f64
+        })
+        .collect();
+    let total_weight: f64 = weights.iter().sum();
+    
+    let mut blended = first.buffer().clone();
+    for (x, y, pixel) in blended.enumerate_pixels_mut() {
+        let mut alpha = 0.0f64;
+        let mut color = [0.0f64; 3];
+        for (frame, weight) in frames.iter().zip(&weights) {
+            let source = frame.buffer().get_pixel(x, y);
+            let a = source[3] as f64 * weight;
+            alpha += a;
+            for c in 0..3 {
+                color[c] += source[c] as f64 * a;
+            }
+        }
+        *pixel = if alpha > 0.0 {
+            image::Rgba([
+                (color[0] / alpha).round() as u8,
+                (color[1] / alpha).round() as u8,
+                (color[2] / alpha).round() as u8,
+                (alpha / total_weight).round() as u8,
+            ])
+        } else {
+            image::Rgba([0, 0, 0, 0])
+        };
+    }
+    image::Frame::from_parts(blended, first.left(), first.top(), first.delay())
+}
+
+/// 提取GIF帧并保存为新的GIF
+fn extract_frames<P: AsRef<Path>, Q: AsRef<Path>>(
+    input_path: P,
+    output_path: Q,
+    strategy: &Strategy,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+) -> Result<(), GifError> {
+    // 打开输入文件
+    let decode_started = Instant::now();
+    let file = File::open(&input_path)?;
+    let decoder = GifDecoder::new(BufReader::new(file))?;
+    
+    // 提取所有帧
+    let mut frames = decoder.into_frames().collect_frames()?;
+    let total_frames = frames.len();
+    shared_state.add_decode_time(decode_started.elapsed());
+    
+    // 调整起始帧：把指定帧之前的帧移到末尾，每帧的延迟随帧一起移动
+    if let Some(start) = options.start_at_frame {
+        if start > 0 && start < total_frames {
+            frames.rotate_left(start);
+        }
+    }
+    
+    // 在保存各帧之前把color_key颜色变为透明
+    if let Some(key) = options.color_key {
+        for frame in frames.iter_mut() {
+            apply_color_key(frame.buffer_mut(), key, options.color_key_tolerance, options.color_key_feather);
+        }
+    }
+    
+    // 按尺寸预设缩放并补边，透明色已经处理过，补的边不会被当作透明色
+    let matte = parse_matte(options.matte.as_deref())?;
+    let mut flattened = false;
+    if let Some(size) = dimension_preset(options)? {
+        if let Some(first) = frames.first() {
+            let info = letterbox_geometry(first.buffer().dimensions(), size);
+            for frame in frames.iter_mut() {
+                let (canvas, composited) = letterbox_frame(frame.buffer(), &info, options.letterbox_background, matte);
+                flattened |= composited;
+                *frame = image::Frame::from_parts(canvas, 0, 0, frame.delay());
+            }
+        }
+    }
+    
+    // 需要时扫描每帧的数据量，顺序与调整起始帧后的帧一致
+    let frame_bytes = if strategy.motion_threshold.is_some() && options.prefer_dropping_heavy_frames {
+        scan_gif_blocks(&input_path).ok().map(|scan| {
+            let mut bytes: Vec<u64> = scan.frames.iter().map(|block| block.bytes).collect();
+            if let Some(start) = options.start_at_frame {
+                if start > 0 && start < bytes.len() {
+                    bytes.rotate_left(start);
+                }
+            }
+            bytes
+        })
+    } else {
+        None
+    };
+    
+    // 根据策略选择帧，同时累计每个保留帧所代替的原始帧的延迟（厘秒）
+    let compare_started = Instant::now();
+    let ranges = select_frames(&frames, strategy.skip, strategy.motion_threshold, frame_bytes.as_deref());
+    if strategy.motion_threshold.is_some() {
+        // 逐帧比较也算作解码耗时
+        shared_state.add_decode_time(compare_started.elapsed());
+    }
+    let mut selected_frames = Vec::new();
+    let mut source_delays_cs = Vec::new();
+    // 静帧动图只保存变化区域，混合会让不变的区域也发生变化
+    let blend = options.blend_dropped_frames && !strategy.cinemagraph;
+    for range in ranges {
+        let blended = range.len() > 1 && blend;
+        if blended {
+            let end = range.end.min(range.start + 1 + BLEND_MAX_DROPPED_FRAMES);
+            let mut frame = blend_frames(&frames[range.start..end]);
+            // 透明度不同的帧混合后出现GIF无法保存的半透明像素，合成到matte上
+            flattened |= composite_onto_matte(frame.buffer_mut(), matte, true);
+            selected_frames.push(frame);
+        } else {
+            selected_frames.push(frames[range.start].clone());
+        }
+        let covered_ms: f64 = frames[range]
+            .iter()
+            .map(|frame| {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                numer as f64 / denom.max(1) as f64
+            })
+            .sum();
+        source_delays_cs.push(covered_ms / 10.0);
+    }
+    if flattened {
+        shared_state.record_flattened(strategy.label());
+    }
+    
+    if selected_frames.is_empty() {
+        // 至少保留一帧
+        if !frames.is_empty() {
+            selected_frames.push(frames[0].clone());
+            source_delays_cs.push(0.0);
+        } else {
+            return Err(GifError::NoFrames);
+        }
+    }
+    
+    // 由于GIF格式复杂，我们使用临时目录和gifsicle来完成帧提取和合并
+    let temp_dir = tempfile::Builder::new()
+        .prefix("gif_frames_")
+        .tempdir()
         .map_err(|e| GifError::TempDirFailed(e.to_string()))?;
+    let _tracked_dir = TrackedTempDir::new(temp_dir.path());
+    
+    // 保存所有选择的帧到临时目录，并收集路径字符串
+    // 静帧动图策略下第一帧之后只保存相对上一帧变化的区域及其位置；
+    // 叠加的区域无法让像素变回透明，有像素变透明时仍保存完整画面
+    let diff_frames = strategy.cinemagraph
+        && !selected_frames.windows(2).any(|pair| introduces_transparency(pair[0].buffer(), pair[1].buffer()));
+    if strategy.cinemagraph && !diff_frames {
+        println!("有像素在后续帧中变为透明，静帧动图策略改为保存完整画面");
+    }
+    let mut frame_paths = Vec::new();
+    let mut frame_positions = Vec::new();
+    for (i, frame) in selected_frames.iter().enumerate() {
+        let frame_path = temp_dir.path().join(format!("frame_{}.gif", i));
+        let frame_file = File::create(&frame_path)?;
+        let mut frame_writer = BufWriter::new(frame_file);
+        
+        if diff_frames && i > 0 {
+            // 完全相同的帧保留一个像素，只用来承载延迟
+            let (x, y, width, height) = changed_bounds(selected_frames[i - 1].buffer(), frame.buffer())
+                .unwrap_or((0, 0, 1, 1));
+            let region = image::imageops::crop_imm(frame.buffer(), x, y, width, height).to_image();
+            region.write_to(&mut frame_writer, image::ImageOutputFormat::Gif)?;
+            frame_positions.push((x, y));
+        } else {
+            // 使用image库保存单帧GIF
+            frame.buffer().write_to(&mut frame_writer, image::ImageOutputFormat::Gif)?;
+            frame_positions.push((0, 0));
+        }
+        
+        // 保存路径字符串
+        frame_paths.push(frame_path.to_string_lossy().to_string());
+    }
+    
+    // 使用gifsicle合并帧
+    let output_path_str = output_path.as_ref().to_string_lossy().to_string();
+    let delay_str = strategy.delay.to_string();
+    
+    // 保留原始节奏时使用逐帧延迟，否则所有帧使用策略给出的统一延迟
+    // 按画面变化抽帧时每个保留帧代替的帧数不同，必须使用逐帧延迟；静帧动图策略保留原始节奏
+    let rounding = if strategy.motion_threshold.is_some() || strategy.cinemagraph {
+        Some(options.delay_rounding.unwrap_or(DelayRounding::Nearest))
+    } else {
+        options.delay_rounding
+    };
+    let frame_delays = rounding.map(|mode| round_delays(&source_delays_cs, mode));
+    
+    // 检查gifsicle是否存在
+    let gifsicle_path = match find_gifsicle() {
+        Some(path) => path,
+        None => return Err(GifError::GifsicleNotFound),
+    };
+    
+    // 构建优化的参数列表
+    let mut gifsicle_args = Vec::with_capacity(frame_paths.len() + 8);
+    
+    // 添加优化选项
+    gifsicle_args.push("--no-warnings".to_string());        // 减少不必要的输出
+    gifsicle_args.push("--no-conserve-memory".to_string()); // 使用更多内存提高速度
+    gifsicle_args.push("--no-app-extensions".to_string());  // 移除应用扩展数据
+    gifsicle_args.push("--no-comments".to_string());        // 移除注释
+    gifsicle_args.push("--no-names".to_string());           // 移除名称元数据
+    gifsicle_args.push("-o".to_string());
+    gifsicle_args.push(output_path_str);
+    if frame_delays.is_none() {
+        gifsicle_args.push("--delay".to_string());
+        gifsicle_args.push(delay_str);
+    }
+    // 帧文件不带循环扩展，按原文件或loop_count选项写入
+    gifsicle_args.push(loopcount_arg(output_loop_count(options, source_loop_count(scan_gif_blocks(&input_path)))));
+    if diff_frames {
+        // 变化区域叠加在上一帧之上，不清除之前的画面
+        gifsicle_args.push("--disposal=none".to_string());
+    } else if selected_frames.iter().any(|frame| frame.buffer().pixels().any(|pixel| pixel[3] == 0)) {
+        // 每帧都是完整画面，有透明像素时必须先清除上一帧，否则透明处会露出上一帧
+        gifsicle_args.push("--disposal=background".to_string());
+    }
+    
+    // 添加所有帧路径 (已经是String类型)，逐帧延迟需要写在对应帧之前
+    for (i, path) in frame_paths.iter().enumerate() {
+        if let Some(delays) = &frame_delays {
+            gifsicle_args.push("--delay".to_string());
+            gifsicle_args.push(delays[i].to_string());
+        }
+        if diff_frames {
+            let (x, y) = frame_positions[i];
+            gifsicle_args.push("--position".to_string());
+            gifsicle_args.push(format!("{},{}", x, y));
+        }
+        gifsicle_args.push(path.clone());
+    }
+    
+    // 执行gifsicle命令
+    let _output = run_gifsicle(&gifsicle_path, &gifsicle_args, shared_state)?;
+    
+    // 检查命令是否成功
+    if !_output.status.success() {
+        let stderr = String::from_utf8_lossy(&_output.stderr).to_string();
+        return Err(GifError::GifsicleExecFailed(stderr));
+    }
+    
+    Ok(())
+}
+
+/// 按策略抽帧并做一次基础优化，返回优化后的文件和大小；失败或需要提前退出时返回None
+fn optimize_strategy_frames(
+    input_path: &str,
+    strategy: &Strategy,
+    options: &CompressOptions,
+    gifsicle_path: &str,
+    shared_state: &SharedState,
+    log: &dyn Fn(&str),
+) -> Option<(TempFile, f64)> {
+    // 使用image库提取帧
+    let temp_frames = match NamedTempFile::new() {
+        Ok(file) => TempFile::new(file),
+        Err(_) => {
+            log("  创建临时文件失败");
+            return None;
+        }
+    };
+    
+    // 检查是否有线程已经找到结果
+    if let Some(reason) = shared_state.stop_reason() {
+        log(&format!("{}，提前退出", reason));
+        return None;
+    }
+    
+    let temp_frames_path = temp_frames.path_str();
+    
+    if let Err(e) = extract_frames(input_path, &temp_frames_path, strategy, options, shared_state) {
+        log(&format!("  帧提取失败: {}", e));
+        return None;
+    }
+    
+    // 检查是否有线程已经找到结果
+    if let Some(reason) = shared_state.stop_reason() {
+        log(&format!("{}，提前退出", reason));
+        return None;
+    }
+    
+    // 检查提取是否成功
+    match get_file_size_kb(&temp_frames_path) {
+        Ok(size) if size < 1.0 => {
+            log("  帧提取生成的文件过小");
+            return None;
+        },
+        Ok(_) => {}, // 文件大小正常，继续处理
+        Err(_) => {
+            log("  无法读取提取的帧大小");
+            return None;
+        }
+    };
+    
+    // 优化提取后的帧
+    let temp_frames_opt = match NamedTempFile::new() {
+        Ok(file) => TempFile::new(file),
+        Err(_) => {
+            log("  创建优化临时文件失败");
+            return None;
+        }
+    };
+    
+    // 检查是否有线程已经找到结果
+    if let Some(reason) = shared_state.stop_reason() {
+        log(&format!("{}，提前退出", reason));
+        return None;
+    }
+    
+    let temp_frames_opt_path = temp_frames_opt.path_str();
+    
+    let scale_arg = strategy.scale.map(|scale| scale.to_string());
+    let mut args = vec!["-O3"];
+    if let Some(scale) = &scale_arg {
+        args.extend(["--scale", scale]);
+    }
+    args.extend([temp_frames_path.as_str(), "-o", &temp_frames_opt_path]);
+    
+    let _output = match run_gifsicle(gifsicle_path, &args, shared_state) {
+        Ok(output) => output,
+        Err(GifError::GifsicleCrashed(reason)) => {
+            log(&format!("  gifsicle崩溃（{}），--careful重试后仍然崩溃，跳过此策略", reason));
+            return None;
+        }
+        Err(GifError::GifsicleTimeout(secs)) => {
+            log(&format!("  gifsicle帧优化超过 {} 秒没有结束，跳过此策略", secs));
+            return None;
+        }
+        Err(_) => {
+            log("  执行gifsicle帧优化失败");
+            return None;
+        }
+    };
+    
+    if !_output.status.success() {
+        log("  帧优化失败");
+        return None;
+    }
+    
+    // 清理第一个临时文件，不再需要它
+    let _ = temp_frames.cleanup();
+    
+    let frames_size = match get_file_size_kb(&temp_frames_opt_path) {
+        Ok(size) => size,
+        Err(_) => {
+            log("  无法读取优化后帧大小");
+            return None;
+        }
+    };
+    Some((temp_frames_opt, frames_size))
+}
+
+/// 逐步减少颜色数，更小的结果替换best_file，返回最终结果使用的颜色数；达到目标或min_colors下限时停止
+///
+/// 每次尝试都记录为source的候选结果，source为best_file所属的策略和使用的lossy级别
+fn reduce_colors(
+    gifsicle_path: &str,
+    source: (&Strategy, Option<u32>),
+    best_file: &mut Option<TempFile>,
+    best_size: &mut f64,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+    log: &dyn Fn(&str),
+) -> Option<u16> {
+    let target_size_kb = options.target_size;
+    let mut best_colors = None;
+    let (levels, floored) = color_reduction_levels(options);
+    if floored && levels.is_empty() {
+        shared_state.set_color_floor_hit();
+    }
+    // lossy未达到目标时才追加减色尝试
+    shared_state.plan_attempts(levels.len());
+    for (i, &colors) in levels.iter().enumerate() {
+        if let Some(reason) = shared_state.stop_reason() {
+            log(&format!("{}，提前退出", reason));
+            break;
+        }
+        
+        let current_best_path = match best_file.as_ref() {
+            Some(file) => file.path_str(),
+            None => break,
+        };
+        let temp_file = match NamedTempFile::new() {
+            Ok(file) => TempFile::new(file),
+            Err(_) => {
+                log(&format!("  创建colors={}临时文件失败", colors));
+                break;
+            }
+        };
+        let temp_path = temp_file.path_str();
+        let colors_arg = colors.to_string();
+        let args = vec![
+            "-O3",
+            "--no-warnings",
+            "--colors",
+            &colors_arg,
+            &current_best_path,
+            "-o",
+            &temp_path
+        ];
+        
+        let output = run_gifsicle(gifsicle_path, &args, shared_state);
+        shared_state.finish_attempt();
+        let size = match output {
+            Ok(output) if output.status.success() => match get_file_size_kb(&temp_path) {
+                Ok(size) => size,
+                Err(_) => {
+                    log(&format!("  无法读取colors={}压缩后大小", colors));
+                    break;
+                }
+            },
+            _ => {
+                log(&format!("  colors={}压缩失败", colors));
+                break;
+            }
+        };
+        log(&format!("  减少到 {} 种颜色后大小: {:.2} KB", colors, size));
+        
+        // 下限允许的最少颜色数仍未达到目标，更少的颜色数被下限挡住
+        let stopped_by_floor = floored && i + 1 == levels.len() && size > target_size_kb;
+        let kept = size < *best_size;
+        shared_state.record_color_attempt(source.0, source.1, colors, size, kept, stopped_by_floor);
+        if kept {
+            if let Some(old_file) = best_file.take() {
+                let _ = old_file.cleanup();
+            }
+            *best_size = size;
+            *best_file = Some(temp_file);
+            best_colors = Some(colors);
+        }
+        if size <= target_size_kb {
+            log(&format!("  colors={} 已达到目标大小!", colors));
+            shared_state.set_found_target();
+            break;
+        }
+        if stopped_by_floor {
+            log(&format!("  已达到颜色数下限 {}，不再继续减色", colors));
+            shared_state.set_color_floor_hit();
+        }
+    }
+    best_colors
+}
+
+/// 处理单个策略
+fn process_strategy(
+    input_path: &str,
+    strategy: Strategy,
+    options: &CompressOptions,
+    thread_id: usize,
+    shared_state: &SharedState,
+) -> StrategyResult {
+    let target_size_kb = options.target_size;
+    
+    // 创建跟踪输出的记录器
+    let output_prefix = format!("线程 {}: ", thread_id);
+    let log = |msg: &str| {
+        let message = format!("{}{}", output_prefix, msg);
+        // 使用Mutex来确保输出不会被打断
+        println!("{}", message);
+    };
+    
+    // 如果已经找到目标，立即返回
+    if let Some(reason) = shared_state.stop_reason() {
+        log(&format!("{}，提前退出", reason));
+        return StrategyResult {
+            size: f64::MAX,
+            file: None,
+            success: false,
+            lossy: None,
+            colors: None,
+        };
+    }
+    
+    // 获取gifsicle路径
+    let gifsicle_path = match find_gifsicle() {
+        Some(path) => path,
+        None => {
+            log("未找到gifsicle程序");
+            return StrategyResult {
+                size: f64::MAX,
+                file: None,
+                success: false,
+                lossy: None,
+                colors: None,
+            };
+        }
+    };
+    
+    let skip = strategy.skip;
+    let delay = strategy.delay;
+    
+    if let Some(threshold) = strategy.motion_threshold {
+        log(&format!("策略: 按画面变化抽帧 (差异阈值: {:.1})", threshold));
+    } else {
+        // 预计剩余帧数
+        let expected_frames = match get_frame_count(input_path) {
+            Ok(count) => (count as f64 / skip as f64).ceil() as usize,
+            Err(_) => 0,
+        };
+        
+        log(&format!("策略: 保留约 {} 帧 (每 {} 帧取1帧), 帧延迟: {}ms", 
+                    expected_frames, skip, delay));
+    }
+    
+    let (temp_frames_opt, frames_size) = match optimize_strategy_frames(input_path, &strategy, options, &gifsicle_path, shared_state, &log) {
+        Some(frames) => frames,
+        None => {
+            return StrategyResult {
+                size: f64::MAX,
+                file: None,
+                success: false,
+                lossy: None,
+                colors: None,
+            };
+        }
+    };
+    
+    log(&format!("  抽帧后大小: {:.2} KB", frames_size));
+    shared_state.record_attempt(&strategy, None, frames_size, true);
+    
+    if frames_size <= target_size_kb {
+        log("  已达到目标大小!");
+        // 设置标志通知其他线程已找到满足条件的结果
+        shared_state.set_found_target();
+        return StrategyResult {
+            size: frames_size,
+            file: Some(temp_frames_opt),
+            success: true,
+            lossy: None,
+            colors: None,
+        };
+    }
+    
+    // 跟踪当前策略下的最佳结果
+    let mut best_size = frames_size;
+    let mut best_file = Some(temp_frames_opt);
+    let mut best_lossy = None;
+    
+    // 批量尝试不同的lossy值
+    // 创建临时文件和对应的lossy级别
+    let lossy_levels = lossy_levels(options);
+    
+    // 每次处理两个lossy级别，平衡进程创建开销和并行效率
+    let chunk_size = 2;
+    
+    // gifsicle重试后仍然崩溃时不再尝试更多lossy级别
+    let mut crashed = false;
+    
+    for chunk in lossy_levels.chunks(chunk_size) {
+        // 先检查是否有线程已经找到结果或时间预算已用完
+        if let Some(reason) = shared_state.stop_reason() {
+            log(&format!("{}，提前退出", reason));
+            return StrategyResult {
+                size: best_size,
+                file: best_file,
+                success: true,
+                lossy: best_lossy,
+                colors: None,
+            };
+        }
+        
+        let mut temp_files = Vec::with_capacity(chunk.len());
+        let mut results = Vec::with_capacity(chunk.len());
+        
+        // 创建这一批次的临时文件
+        for &level in chunk {
+            match NamedTempFile::new() {
+                Ok(file) => {
+                    temp_files.push((level, TempFile::new(file)));
+                },
+                Err(_) => {
+                    log(&format!("  创建lossy={}临时文件失败", level));
+                }
+            }
+        }
+        
+        let current_best_path = match &best_file {
+            Some(file) => file.path_str(),
+            None => break,
+        };
+        
+        // 处理这一批次的lossy级别
+        for (level, temp_file) in &temp_files {
+            let temp_path = temp_file.path_str();
+            
+            // 创建lossy参数
+            let lossy_arg = format!("--lossy={}", level);
+            
+            // 优化的gifsicle命令参数
+            let args = vec![
+                "-O3", 
+                "--no-warnings",
+                "--no-conserve-memory", 
+                "--no-comments", 
+                "--no-names",
+                &lossy_arg,
+                &current_best_path, 
+                "-o", 
+                &temp_path
+            ];
+            
+            let output = run_gifsicle(&gifsicle_path, &args, shared_state);
+            shared_state.finish_attempt();
+            let _output = match output {
+                Ok(output) if output.status.success() => {
+                    match get_file_size_kb(&temp_path) {
+                        Ok(size) => {
+                            log(&format!("  抽帧 + lossy={} 后大小: {:.2} KB", level, size));
+                            results.push((*level, size));
+                        },
+                        Err(_) => {
+                            log(&format!("  无法读取lossy={}压缩后大小", level));
+                        }
+                    }
+                },
+                Err(GifError::GifsicleCrashed(reason)) => {
+                    log(&format!("  lossy={} gifsicle崩溃（{}），跳过此策略剩余的lossy级别", level, reason));
+                    crashed = true;
+                    break;
+                },
+                Err(GifError::GifsicleTimeout(secs)) => {
+                    log(&format!("  lossy={} gifsicle超过 {} 秒没有结束，继续尝试其他lossy级别", level, secs));
+                },
+                _ => {
+                    log(&format!("  lossy={}压缩失败", level));
+                }
+            };
+        }
+        
+        // 处理这一批次的结果
+        let mut kept_levels = Vec::new();
+        for (_result_idx, (level, size)) in results.iter().enumerate() {
+            if *size <= target_size_kb {
+                log(&format!("  lossy={} 已达到目标大小!", level));
+                
+                // 找到对应的临时文件，从批次中取出，避免批次结束时被删除
+                if let Some(pos) = temp_files.iter().position(|(l, _)| *l == *level) {
+                    // 如果当前结果比之前的好，替换并清理旧文件
+                    if best_size > *size {
+                        if let Some(old_file) = best_file.take() {
+                            let _ = old_file.cleanup(); // 清理旧文件
+                        }
+                        best_size = *size;
+                        best_file = Some(temp_files.swap_remove(pos).1);
+                        best_lossy = Some(*level);
+                        kept_levels.push(*level);
+                    }
+                }
+                
+                // 设置标志通知其他线程已找到满足条件的结果
+                shared_state.set_found_target();
+                break;
+            } else if *size < best_size {
+                // 找到对应的临时文件，从批次中取出，避免批次结束时被删除
+                if let Some(pos) = temp_files.iter().position(|(l, _)| *l == *level) {
+                    // 替换旧文件并清理
+                    if let Some(old_file) = best_file.take() {
+                        let _ = old_file.cleanup(); // 清理旧文件
+                    }
+                    best_size = *size;
+                    best_file = Some(temp_files.swap_remove(pos).1);
+                    best_lossy = Some(*level);
+                    kept_levels.push(*level);
+                }
+            }
+        }
+        
+        for (level, size) in &results {
+            shared_state.record_attempt(&strategy, Some(*level), *size, kept_levels.contains(level));
+        }
+        
+        // 如果已找到目标、时间预算已用完或gifsicle崩溃，不再处理更多批次
+        // 按评分选择时其他策略不会停止，本策略达到目标后同样不再尝试更高的lossy
+        if shared_state.stop_reason().is_some() || crashed || best_size <= target_size_kb {
+            break;
+        }
+        
+        // 清理这批次中未被选中的临时文件
+        for (_level, temp_file) in &temp_files {
+            if let Some(best) = &best_file {
+                if best.path != temp_file.path {
+                    let _ = temp_file.cleanup();
+                }
+            } else {
+                let _ = temp_file.cleanup();
+            }
+        }
+    }
+    
+    // lossy仍未达到目标时逐步减少颜色数
+    let best_colors = if !crashed
+        && shared_state.stop_reason().is_none()
+        && best_size > target_size_kb
+        && auto_color_reduction(options)
+    {
+        reduce_colors(&gifsicle_path, (&strategy, best_lossy), &mut best_file, &mut best_size, options, shared_state, &log)
+    } else {
+        None
+    };
+    
+    StrategyResult {
+        size: best_size,
+        file: best_file,
+        success: true,
+        lossy: best_lossy,
+        colors: best_colors,
+    }
+}
+
+/// 对source应用一个lossy级别，返回输出文件和大小
+fn lossy_attempt(
+    gifsicle_path: &str,
+    source: &str,
+    level: u32,
+    shared_state: &SharedState,
+) -> Result<(TempFile, f64), GifError> {
+    let temp_file = TempFile::new(NamedTempFile::new()?);
+    let temp_path = temp_file.path_str();
+    let lossy_arg = format!("--lossy={}", level);
+    let args = vec![
+        "-O3",
+        "--no-warnings",
+        "--no-conserve-memory",
+        "--no-comments",
+        "--no-names",
+        &lossy_arg,
+        source,
+        "-o",
+        &temp_path
+    ];
+    
+    let output = run_gifsicle(gifsicle_path, &args, shared_state);
+    shared_state.finish_attempt();
+    let output = output?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    let size = get_file_size_kb(&temp_path)?;
+    Ok((temp_file, size))
+}
+
+/// 检查lossy_schedule是否按顺序覆盖全部帧、没有重叠且lossy级别不超过MAX_LOSSY_LEVEL，返回按起始帧排序的分段
+fn validate_lossy_schedule(schedule: &[LossySegment], frame_count: usize) -> Result<Vec<LossySegment>, GifError> {
+    if schedule.is_empty() {
+        return Err(GifError::InvalidOption("lossy_schedule不能为空".to_string()));
+    }
+    let mut segments = schedule.to_vec();
+    segments.sort_by_key(|segment| segment.start_frame);
+    // 下一段应该开始的帧
+    let mut next = 0;
+    for segment in &segments {
+        if segment.start_frame > segment.end_frame {
+            return Err(GifError::InvalidOption(format!(
+                "lossy_schedule中的分段 {}-{} 起始帧大于结束帧", segment.start_frame, segment.end_frame
+            )));
+        }
+        if segment.start_frame < next {
+            return Err(GifError::InvalidOption(format!(
+                "lossy_schedule中的分段 {}-{} 与前一段重叠", segment.start_frame, segment.end_frame
+            )));
+        }
+        if segment.start_frame > next {
+            return Err(GifError::InvalidOption(format!(
+                "第 {} 到 {} 帧不在lossy_schedule中", next, segment.start_frame - 1
+            )));
+        }
+        if segment.lossy > MAX_LOSSY_LEVEL {
+            return Err(GifError::InvalidOption(format!(
+                "lossy_schedule中的分段 {}-{} 的lossy级别 {} 超出范围，不能超过{}",
+                segment.start_frame, segment.end_frame, segment.lossy, MAX_LOSSY_LEVEL
+            )));
+        }
+        next = segment.end_frame + 1;
+    }
+    if next > frame_count {
+        return Err(GifError::InvalidOption(format!("lossy_schedule超出范围，GIF共有 {} 帧", frame_count)));
+    }
+    if next < frame_count {
+        return Err(GifError::InvalidOption(format!("第 {} 到 {} 帧不在lossy_schedule中", next, frame_count - 1)));
+    }
+    Ok(segments)
+}
+
+/// 按分段把source拆开，每段用自己的lossy级别压缩，再用gifsicle无损合并
+fn apply_lossy_schedule(
+    gifsicle_path: &str,
+    source: &str,
+    segments: &[LossySegment],
+    shared_state: &SharedState,
+) -> Result<(TempFile, Vec<ScheduledSegment>), GifError> {
+    let mut parts = Vec::new();
+    let mut results = Vec::new();
+    for segment in segments {
+        if shared_state.is_cancelled() {
+            return Err(GifError::Cancelled);
+        }
+        let part = TempFile::new(NamedTempFile::new()?);
+        let part_path = part.path_str();
+        let lossy_arg = format!("--lossy={}", segment.lossy);
+        let frames_arg = format!("#{}-{}", segment.start_frame, segment.end_frame);
+        // 先还原成完整的帧再取出这一段，每段的第一帧不依赖前一段的画面
+        let args = vec![
+            "-O3",
+            "--no-warnings",
+            "--no-conserve-memory",
+            "--no-comments",
+            "--no-names",
+            &lossy_arg,
+            "--unoptimize",
+            source,
+            &frames_arg,
+            "-o",
+            &part_path
+        ];
+        let output = run_gifsicle(gifsicle_path, &args, shared_state)?;
+        if !output.status.success() {
+            return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+        }
+        let size_kb = get_file_size_kb(&part_path)?;
+        println!("第 {} 到 {} 帧使用lossy {}: {:.2} KB", segment.start_frame, segment.end_frame, segment.lossy, size_kb);
+        results.push(ScheduledSegment { segment: *segment, size_kb });
+        parts.push(part);
+    }
+    
+    // 合并时只做无损优化，各段的lossy效果保持不变
+    let merged = TempFile::new(NamedTempFile::new()?);
+    let merged_path = merged.path_str();
+    let part_paths: Vec<String> = parts.iter().map(TempFile::path_str).collect();
+    let mut args = vec!["-O3", "--no-warnings", "--no-conserve-memory"];
+    args.extend(part_paths.iter().map(String::as_str));
+    args.extend(["-o", &merged_path]);
+    let output = run_gifsicle(gifsicle_path, &args, shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    Ok((merged, results))
+}
+
+// 设置in_memory时，不超过该大小（KB）的输入在内存中处理
+const IN_MEMORY_MAX_KB: f64 = 1024.0;
+
+/// 检查能否在内存中处理，不能时返回原因
+///
+/// 内存中只能通过gifsicle的帧选择抽帧，所有帧使用策略的统一延迟，需要逐帧处理画面或逐帧延迟的选项都不支持
+fn in_memory_unsupported(options: &CompressOptions, scan: &GifScan, canvas: (u32, u32)) -> Option<&'static str> {
+    let frame_count = scan.frames.len();
+    let delays: Vec<u16> = scan.frames.iter().map(|block| block.delay_cs).collect();
+    if options.start_at_frame.unwrap_or(0) > 0 || options.color_key.is_some() || options.dimension_preset.is_some() {
+        Some("需要逐帧处理画面")
+    } else if options.max_width.is_some() || options.max_height.is_some() || options.palette_file.is_some() {
+        Some("需要缩小画布或映射调色板")
+    } else if options.degradation_order.is_some() || options.lossy_schedule.is_some() || options.unified_queue {
+        Some("使用了其他搜索方式")
+    } else if options.motion_threshold.is_some() || options.blend_dropped_frames || options.selection == WinnerSelection::Score {
+        Some("抽帧方式需要解码画面")
+    } else if options.delay_rounding.is_some() || delay_stats(&delays).variable_timing {
+        Some("需要逐帧延迟")
+    } else if options.auto_downscale == Some(true)
+        && auto_max_edge(canvas.0, canvas.1, min_frame_floor(frame_count, options), options.target_size).is_some()
+    {
+        Some("画布需要自动缩小")
+    } else {
+        None
+    }
+}
+
+/// 检查输入是否可以在内存中处理，可以时返回输入文件的内容，否则返回原因
+fn in_memory_candidate<P: AsRef<Path>>(input_path: P, options: &CompressOptions) -> Result<Vec<u8>, String> {
+    let size_kb = get_file_size_kb(&input_path).map_err(|e| e.to_string())?;
+    if size_kb > IN_MEMORY_MAX_KB {
+        return Err(format!("输入 {:.2} KB 超过 {} KB", size_kb, IN_MEMORY_MAX_KB));
+    }
+    let scan = scan_gif_blocks(&input_path).map_err(|e| e.to_string())?;
+    let canvas = get_canvas_size(&input_path).map_err(|e| e.to_string())?;
+    if let Some(reason) = in_memory_unsupported(options, &scan, canvas) {
+        return Err(reason.to_string());
+    }
+    fs::read(&input_path).map_err(|e| e.to_string())
+}
+
+/// optimize_in_memory的结果
+struct InMemoryOutcome {
+    data: Vec<u8>,
+    size_kb: f64,
+    // 为空表示基础优化的结果
+    winner: Option<WinningConfig>,
+    // 时间或gifsicle调用次数预算在达到目标前用完
+    time_limited: bool,
+}
+
+/// 在内存中完成基础优化和按顺序的抽帧、lossy、减色搜索，与临时文件流程的逐策略搜索一致，只是依次运行
+///
+/// 抽帧用gifsicle的帧选择（"#0" "#2" ...）从基础优化的结果中取帧，先还原成完整的帧再重新优化
+fn optimize_in_memory(
+    gifsicle_path: &str,
+    input: &[u8],
+    options: &CompressOptions,
+    shared_state: &SharedState,
+    events: EventSink<'_>,
+    deadline: Option<Instant>,
+) -> Result<InMemoryOutcome, GifError> {
+    let target_size_kb = options.target_size;
+    let size_kb = |data: &[u8]| data.len() as f64 / 1024.0;
+    
+    let mut base_args = vec![
+        "-O3",
+        "--no-warnings",
+        "--no-conserve-memory",
+        "--no-comments",
+        "--no-names",
+        "--careful",
+    ];
+    // 基础优化时写入输出的循环次数，之后的步骤都沿用它
+    let loop_arg = loopcount_arg(output_loop_count(options, source_loop_count(scan_gif_reader(BufReader::new(std::io::Cursor::new(input))))));
+    base_args.push(&loop_arg);
+    let colors_arg = options.max_colors.map(|colors| colors.to_string());
+    if let Some(colors) = &colors_arg {
+        base_args.extend(["--colors", colors]);
+    }
+    base_args.extend(["-", "-o", "-"]);
+    let base_pass = BasePassGifsicle::start();
+    let base = run_gifsicle_piped(gifsicle_path, &base_args, input, shared_state);
+    drop(base_pass);
+    let base = base?;
+    let base_kb = size_kb(&base);
+    println!("基础优化后大小: {:.2} KB", base_kb);
+    report_progress(events, shared_state, ProgressPhase::BaseOptimize, "基础优化完成", 1.0, Some(format!("{:.2} KB", base_kb)));
+    
+    let mut best = InMemoryOutcome { size_kb: base_kb, data: base.clone(), winner: None, time_limited: false };
+    if base_kb <= target_size_kb || options.base_pass_only {
+        return Ok(best);
+    }
+    
+    let frame_count = scan_gif_reader(BufReader::new(std::io::Cursor::new(&base)))?.frames.len();
+    let strategies = plan_strategies(frame_count, options).strategies;
+    shared_state.plan_strategies(strategies.len());
+    shared_state.plan_attempts(strategies.len() * lossy_levels(options).len());
+    shared_state.arm_deadline(deadline);
+    
+    for (index, strategy) in strategies.iter().enumerate() {
+        if shared_state.is_cancelled() {
+            return Err(GifError::Cancelled);
+        }
+        if shared_state.skip_for_budget() {
+            best.time_limited = true;
+            break;
+        }
+        let (data, size_kb, lossy, colors) = match in_memory_strategy(gifsicle_path, &base, frame_count, strategy, index, options, shared_state) {
+            Ok(result) => result,
+            // 预算用完时正在运行的gifsicle被终止，使用目前的最佳结果
+            Err(_) if !shared_state.is_cancelled() && shared_state.skip_for_budget() => {
+                best.time_limited = true;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        
+        shared_state.finish_strategy();
+        report_progress(
+            events,
+            shared_state,
+            ProgressPhase::Strategies,
+            "压缩策略完成",
+            (index + 1) as f64 / strategies.len() as f64,
+            Some(format!("{}/{}", index + 1, strategies.len())),
+        );
+        if size_kb < best.size_kb {
+            best = InMemoryOutcome {
+                data,
+                size_kb,
+                winner: Some(WinningConfig {
+                    strategy: Some(strategy.clone()),
+                    lossy,
+                    colors,
+                    degradation: None,
+                    score: None,
+                    lossy_schedule: None,
+                }),
+                time_limited: false,
+            };
+        }
+        // 按计划顺序依次尝试，第一个达到目标的策略就是结果，与first_found一致
+        if best.size_kb <= target_size_kb {
+            break;
+        }
+    }
+    Ok(best)
+}
+
+// 内存中一个策略的结果：(数据, 大小, lossy, 颜色数)
+type InMemoryResult = (Vec<u8>, f64, Option<u32>, Option<u16>);
+
+/// 在内存中处理一个抽帧策略：抽帧后依次尝试lossy级别，仍未达到目标时减色
+///
+/// 返回该策略的最佳结果及其大小、lossy级别和颜色数
+fn in_memory_strategy(
+    gifsicle_path: &str,
+    base: &[u8],
+    frame_count: usize,
+    strategy: &Strategy,
+    index: usize,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+) -> Result<InMemoryResult, GifError> {
+    let target_size_kb = options.target_size;
+    let size_kb = |data: &[u8]| data.len() as f64 / 1024.0;
+    
+    let delay_arg = strategy.delay.to_string();
+    let selections: Vec<String> = (0..frame_count).step_by(strategy.skip).map(|frame| format!("#{}", frame)).collect();
+    let mut frame_args = vec![
+        "-O3",
+        "--no-warnings",
+        "--no-conserve-memory",
+        "--no-comments",
+        "--no-names",
+        "--delay",
+        &delay_arg,
+        "--unoptimize",
+        "-",
+    ];
+    frame_args.extend(selections.iter().map(String::as_str));
+    frame_args.extend(["-o", "-"]);
+    let frames = run_gifsicle_piped(gifsicle_path, &frame_args, base, shared_state)?;
+    let frames_kb = size_kb(&frames);
+    println!("策略 {}: 每 {} 帧取1帧后大小: {:.2} KB", index + 1, strategy.skip, frames_kb);
+    
+    // 本策略目前的最佳结果
+    let mut current = (frames.clone(), frames_kb, None, None);
+    if frames_kb > target_size_kb {
+        for level in lossy_levels(options) {
+            let lossy_arg = format!("--lossy={}", level);
+            let args = ["-O3", "--no-warnings", "--no-conserve-memory", &lossy_arg, "-", "-o", "-"];
+            let output = run_gifsicle_piped(gifsicle_path, &args, &frames, shared_state);
+            shared_state.finish_attempt();
+            let output = match output {
+                // 超时只放弃这个lossy级别
+                Err(GifError::GifsicleTimeout(secs)) => {
+                    println!("策略 {}:   lossy={} 超过 {} 秒没有结束，跳过", index + 1, level, secs);
+                    continue;
+                }
+                output => output?,
+            };
+            let output_kb = size_kb(&output);
+            println!("策略 {}:   抽帧 + lossy={} 后大小: {:.2} KB", index + 1, level, output_kb);
+            if output_kb < current.1 {
+                current = (output, output_kb, Some(level), None);
+            }
+            if current.1 <= target_size_kb {
+                break;
+            }
+        }
+    }
+    if current.1 > target_size_kb && auto_color_reduction(options) {
+        let (levels, floored) = color_reduction_levels(options);
+        if floored && levels.is_empty() {
+            shared_state.set_color_floor_hit();
+        }
+        for (i, &colors) in levels.iter().enumerate() {
+            let colors_arg = colors.to_string();
+            let args = ["-O3", "--no-warnings", "--colors", &colors_arg, "-", "-o", "-"];
+            let output = run_gifsicle_piped(gifsicle_path, &args, &current.0, shared_state)?;
+            let output_kb = size_kb(&output);
+            println!("策略 {}:   减少到 {} 种颜色后大小: {:.2} KB", index + 1, colors, output_kb);
+            let stopped_by_floor = floored && i + 1 == levels.len() && output_kb > target_size_kb;
+            let kept = output_kb < current.1;
+            shared_state.record_color_attempt(strategy, current.2, colors, output_kb, kept, stopped_by_floor);
+            if kept {
+                current = (output, output_kb, current.2, Some(colors));
+            }
+            if current.1 <= target_size_kb {
+                break;
+            }
+            if stopped_by_floor {
+                shared_state.set_color_floor_hit();
+            }
+        }
+    }
+    Ok(current)
+}
+
+/// 统一队列中的一项任务，参数为策略序号
+#[derive(Clone, Copy)]
+enum SearchJob {
+    // 抽帧并做基础优化
+    Frames(usize),
+    // 对抽帧结果应用一个lossy级别
+    Lossy(usize, u32),
+    // lossy未达到目标时对最佳结果减色
+    Colors(usize),
+}
+
+/// 统一队列中单个策略的搜索进度
+struct StrategySlot {
+    strategy: Strategy,
+    // 抽帧优化后的文件，各lossy级别都以它为输入
+    frames: Option<TempFile>,
+    best_size: f64,
+    // lossy得到的最佳结果，为空时最佳结果就是抽帧文件
+    best_file: Option<TempFile>,
+    best_lossy: Option<u32>,
+    // 尚未完成的lossy任务数
+    pending_lossy: usize,
+    // gifsicle重试后仍然崩溃时跳过剩余的lossy级别
+    crashed: bool,
+    // 第一项任务开始的时间，单个策略的时间限制从这里算起
+    started: Option<Instant>,
+}
+
+impl StrategySlot {
+    // 取出最佳结果文件，抽帧文件不是最佳结果时删除它
+    fn take_best(&mut self) -> Option<TempFile> {
+        match self.best_file.take() {
+            Some(file) => {
+                if let Some(frames) = self.frames.take() {
+                    let _ = frames.cleanup();
+                }
+                Some(file)
+            }
+            None => self.frames.take(),
+        }
+    }
+}
+
+// 统一队列中待处理的任务和正在处理的任务数，两者都为空时工作线程退出
+type SearchQueue = (VecDeque<SearchJob>, usize);
+
+/// 工作线程正在处理的一项任务，丢弃时（包括任务panic时）减少正在处理的任务数并唤醒其他线程，
+/// 否则其他线程会一直等待这项任务的后续任务
+struct RunningSearchJob<'a> {
+    queue: &'a std::sync::Mutex<SearchQueue>,
+    ready: &'a Condvar,
+}
+
+impl Drop for RunningSearchJob<'_> {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).1 -= 1;
+        self.ready.notify_all();
+    }
+}
+
+/// 把所有策略的抽帧、lossy和减色尝试放进同一个任务队列，由thread_count个工作线程共同消费
+/// 各lossy级别都直接作用于抽帧结果，互不依赖，因此同一策略的多个级别可以同时运行
+fn run_unified_queue(
+    input_path: &str,
+    strategies: Vec<Strategy>,
+    options: &CompressOptions,
+    thread_count: usize,
+    shared_state: &SharedState,
+    tx: &Sender<StrategyMessage>,
+) {
+    let gifsicle_path = match find_gifsicle() {
+        Some(path) => path,
+        None => {
+            println!("未找到gifsicle程序");
+            for strategy in strategies {
+                let _ = tx.send((strategy, StrategyResult {
+                    size: f64::MAX,
+                    file: None,
+                    success: false,
+                    lossy: None,
+                    colors: None,
+                }));
+            }
+            return;
+        }
+    };
+    
+    let slots: Vec<std::sync::Mutex<StrategySlot>> = strategies
+        .into_iter()
+        .map(|strategy| std::sync::Mutex::new(StrategySlot {
+            strategy,
+            frames: None,
+            best_size: f64::MAX,
+            best_file: None,
+            best_lossy: None,
+            pending_lossy: 0,
+            crashed: false,
+            started: None,
+        }))
+        .collect();
+    let queue: std::sync::Mutex<SearchQueue> = std::sync::Mutex::new(((0..slots.len()).map(SearchJob::Frames).collect(), 0));
+    let ready = Condvar::new();
+    
+    thread::scope(|scope| {
+        for _ in 0..thread_count {
+            scope.spawn(|| loop {
+                let job = {
+                    let mut guard = queue.lock().unwrap_or_else(|e| e.into_inner());
+                    loop {
+                        if let Some(job) = guard.0.pop_front() {
+                            guard.1 += 1;
+                            break job;
+                        }
+                        if guard.1 == 0 {
+                            return;
+                        }
+                        guard = ready.wait(guard).unwrap_or_else(|e| e.into_inner());
+                    }
+                };
+                let running = RunningSearchJob { queue: &queue, ready: &ready };
+                
+                let follow_ups = run_search_job(job, input_path, &slots, options, &gifsicle_path, shared_state, tx);
+                
+                // 后续任务放在队首，先完成已开始的策略，减少同时保留的抽帧文件；放入后才减少正在处理的任务数
+                {
+                    let mut guard = queue.lock().unwrap_or_else(|e| e.into_inner());
+                    for job in follow_ups.into_iter().rev() {
+                        guard.0.push_front(job);
+                    }
+                }
+                drop(running);
+            });
+        }
+    });
+}
+
+/// 执行统一队列中的一项任务，返回由它产生的后续任务
+fn run_search_job(
+    job: SearchJob,
+    input_path: &str,
+    slots: &[std::sync::Mutex<StrategySlot>],
+    options: &CompressOptions,
+    gifsicle_path: &str,
+    shared_state: &SharedState,
+    tx: &Sender<StrategyMessage>,
+) -> Vec<SearchJob> {
+    let target_size_kb = options.target_size;
+    let index = match job {
+        SearchJob::Frames(index) | SearchJob::Lossy(index, _) | SearchJob::Colors(index) => index,
+    };
+    let output_prefix = format!("策略 {}: ", index + 1);
+    let log = |msg: &str| {
+        println!("{}{}", output_prefix, msg);
+    };
+    
+    // 同一策略的后续任务沿用抽帧任务开始时算出的截止时间
+    let started = *slots[index].lock().unwrap().started.get_or_insert_with(Instant::now);
+    let _timer = StrategyTimer::start(started, shared_state.strategy_timeout());
+    
+    match job {
+        SearchJob::Frames(_) => {
+            let strategy = slots[index].lock().unwrap().strategy.clone();
+            if let Some(reason) = shared_state.stop_reason() {
+                log(&format!("{}，提前退出", reason));
+                finish_slot(slots, index, None, target_size_kb, shared_state, tx);
+                return Vec::new();
+            }
+            if let Some(threshold) = strategy.motion_threshold {
+                log(&format!("按画面变化抽帧 (差异阈值: {:.1})", threshold));
+            } else {
+                log(&format!("每 {} 帧取1帧, 帧延迟: {}ms", strategy.skip, strategy.delay));
+            }
+            
+            let (frames, frames_size) = match optimize_strategy_frames(input_path, &strategy, options, gifsicle_path, shared_state, &log) {
+                Some(frames) => frames,
+                None => {
+                    finish_slot(slots, index, None, target_size_kb, shared_state, tx);
+                    return Vec::new();
+                }
+            };
+            log(&format!("  抽帧后大小: {:.2} KB", frames_size));
+            shared_state.record_attempt(&strategy, None, frames_size, true);
+            
+            let levels = lossy_levels(options);
+            {
+                let mut slot = slots[index].lock().unwrap();
+                slot.frames = Some(frames);
+                slot.best_size = frames_size;
+                slot.pending_lossy = levels.len();
+            }
+            if frames_size <= target_size_kb {
+                log("  已达到目标大小!");
+                shared_state.set_found_target();
+                finish_slot(slots, index, None, target_size_kb, shared_state, tx);
+                return Vec::new();
+            }
+            if levels.is_empty() {
+                return reduce_or_finish(slots, index, options, shared_state, tx);
+            }
+            levels.into_iter().map(|level| SearchJob::Lossy(index, level)).collect()
+        }
+        SearchJob::Lossy(_, level) => {
+            let source = {
+                let slot = slots[index].lock().unwrap();
+                // 已找到目标、本策略已达到目标或已崩溃时跳过，不再运行gifsicle
+                if shared_state.stop_reason().is_some() || slot.crashed || slot.best_size <= target_size_kb {
+                    None
+                } else {
+                    slot.frames.as_ref().map(|file| file.path_str())
+                }
+            };
+            
+            match source {
+                Some(source) => match lossy_attempt(gifsicle_path, &source, level, shared_state) {
+                    Ok((file, size)) => {
+                        log(&format!("  抽帧 + lossy={} 后大小: {:.2} KB", level, size));
+                        let mut slot = slots[index].lock().unwrap();
+                        let kept = size < slot.best_size;
+                        if kept {
+                            if let Some(old_file) = slot.best_file.replace(file) {
+                                let _ = old_file.cleanup();
+                            }
+                            slot.best_size = size;
+                            slot.best_lossy = Some(level);
+                        } else {
+                            let _ = file.cleanup();
+                        }
+                        shared_state.record_attempt(&slot.strategy, Some(level), size, kept);
+                        if size <= target_size_kb {
+                            log(&format!("  lossy={} 已达到目标大小!", level));
+                            shared_state.set_found_target();
+                        }
+                    }
+                    Err(GifError::GifsicleCrashed(reason)) => {
+                        log(&format!("  lossy={} gifsicle崩溃（{}），跳过此策略剩余的lossy级别", level, reason));
+                        slots[index].lock().unwrap().crashed = true;
+                    }
+                    Err(GifError::GifsicleTimeout(secs)) => {
+                        log(&format!("  lossy={} gifsicle超过 {} 秒没有结束，继续尝试其他lossy级别", level, secs));
+                    }
+                    Err(_) => log(&format!("  lossy={}压缩失败", level)),
+                },
+                None => shared_state.finish_attempt(),
+            }
+            
+            // 最后一个lossy任务决定是继续减色还是结束这个策略
+            let mut slot = slots[index].lock().unwrap();
+            slot.pending_lossy -= 1;
+            if slot.pending_lossy > 0 {
+                return Vec::new();
+            }
+            drop(slot);
+            reduce_or_finish(slots, index, options, shared_state, tx)
+        }
+        SearchJob::Colors(_) => {
+            // 所有lossy任务都已完成，只有这个任务会访问该策略
+            let (strategy, lossy, mut best_file, mut best_size) = {
+                let mut slot = slots[index].lock().unwrap();
+                (slot.strategy.clone(), slot.best_lossy, slot.take_best(), slot.best_size)
+            };
+            let colors = reduce_colors(gifsicle_path, (&strategy, lossy), &mut best_file, &mut best_size, options, shared_state, &log);
+            {
+                let mut slot = slots[index].lock().unwrap();
+                slot.best_file = best_file;
+                slot.best_size = best_size;
+            }
+            finish_slot(slots, index, colors, target_size_kb, shared_state, tx);
+            Vec::new()
+        }
+    }
+}
+
+/// lossy任务都完成后，仍未达到目标时加入减色任务，否则结束这个策略
+fn reduce_or_finish(
+    slots: &[std::sync::Mutex<StrategySlot>],
+    index: usize,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+    tx: &Sender<StrategyMessage>,
+) -> Vec<SearchJob> {
+    let target_size_kb = options.target_size;
+    let reduce = {
+        let slot = slots[index].lock().unwrap();
+        !slot.crashed
+            && shared_state.stop_reason().is_none()
+            && slot.best_size > target_size_kb
+            && auto_color_reduction(options)
+    };
+    if reduce {
+        vec![SearchJob::Colors(index)]
+    } else {
+        finish_slot(slots, index, None, target_size_kb, shared_state, tx);
+        Vec::new()
+    }
+}
+
+/// 结束一个策略，像逐策略处理时一样更新共享的最佳大小，并把结果发送给主线程
+fn finish_slot(
+    slots: &[std::sync::Mutex<StrategySlot>],
+    index: usize,
+    colors: Option<u16>,
+    target_size_kb: f64,
+    shared_state: &SharedState,
+    tx: &Sender<StrategyMessage>,
+) {
+    let mut slot = slots[index].lock().unwrap();
+    let file = slot.take_best();
+    let result = StrategyResult {
+        size: if file.is_some() { slot.best_size } else { f64::MAX },
+        success: file.is_some(),
+        file,
+        lossy: slot.best_lossy,
+        colors,
+    };
+    
+    if result.success && result.size < shared_state.get_best_size() {
+        let is_better = shared_state.update_best_size(result.size);
+        if is_better && result.size <= target_size_kb {
+            shared_state.set_found_target();
+        }
+    }
+    
+    shared_state.record_strategy_timing(&slot.strategy, slot.started.unwrap_or_else(Instant::now), &result);
+    let _ = tx.send((slot.strategy.clone(), result));
+}
+
+// 依次尝试的lossy级别
+const LOSSY_LEVELS: [u32; 8] = [30, 60, 90, 120, 150, 180, 210, 240];
+// lossy_levels中允许的最大级别
+const MAX_LOSSY_LEVEL: u32 = 300;
+
+/// 搜索时实际使用的lossy级别：设置了lossy_levels时使用它，否则使用LOSSY_LEVELS，都不超过max_lossy
+fn lossy_levels(options: &CompressOptions) -> Vec<u32> {
+    let levels = options.lossy_levels.as_deref().unwrap_or(&LOSSY_LEVELS);
+    levels.iter().copied().filter(|&level| options.max_lossy.is_none_or(|max| level <= max)).collect()
+}
+
+/// 检查自定义的lossy级别：不能为空，必须严格从小到大排列，且都不超过MAX_LOSSY_LEVEL
+fn check_lossy_levels(options: &CompressOptions) -> Result<(), GifError> {
+    let levels = match &options.lossy_levels {
+        Some(levels) => levels,
+        None => return Ok(()),
+    };
+    if levels.is_empty() {
+        return Err(GifError::Other("lossy_levels不能为空".to_string()));
+    }
+    if let Some(level) = levels.iter().find(|&&level| level > MAX_LOSSY_LEVEL) {
+        return Err(GifError::Other(format!("lossy级别 {} 超出范围，不能超过{}", level, MAX_LOSSY_LEVEL)));
+    }
+    if levels.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(GifError::Other(format!("lossy_levels必须从小到大排列且不重复: {:?}", levels)));
+    }
+    Ok(())
+}
+
+// 自动减色依次尝试的颜色数
+const COLOR_REDUCTION_LEVELS: [u16; 6] = [128, 64, 32, 16, 8, 4];
+// 没有设置min_colors时自动减色最少保留的颜色数，更少时渐变会出现明显色带
+const DEFAULT_MIN_COLORS: u16 = 32;
+
+/// 是否在lossy之后自动减色：需要开启auto_reduce_colors，用户指定了颜色数或调色板时不减色
+fn auto_color_reduction(options: &CompressOptions) -> bool {
+    options.auto_reduce_colors == Some(true) && options.max_colors.is_none() && options.palette_file.is_none()
+}
+
+/// 自动减色时最少保留的颜色数
+fn color_floor(options: &CompressOptions) -> u16 {
+    options.min_colors.unwrap_or(DEFAULT_MIN_COLORS).clamp(2, 256)
+}
+
+/// 自动减色依次尝试的颜色数，不低于min_colors；第二项表示是否有更少的颜色数被下限挡住
+fn color_reduction_levels(options: &CompressOptions) -> (Vec<u16>, bool) {
+    let floor = color_floor(options);
+    let levels: Vec<u16> = COLOR_REDUCTION_LEVELS.iter().copied().filter(|&colors| colors >= floor).collect();
+    let floored = levels.len() < COLOR_REDUCTION_LEVELS.len();
+    (levels, floored)
+}
+
+// 按降级顺序搜索时依次尝试的画布缩放比例（%）
+const RESIZE_LADDER_PERCENT: [u32; 4] = [90, 75, 60, 50];
+
+/// 未达到目标大小且自动减色被min_colors下限挡住时给出的提示
+fn color_floor_warning(options: &CompressOptions, shared_state: &SharedState) -> Option<String> {
+    if !shared_state.color_floor_hit.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(format!("自动减色已达到下限 {} 种颜色，没有继续减少，可以降低min_colors", color_floor(options)))
+}
+
+/// 按降级顺序搜索的结果
+struct LadderResult {
+    file: TempFile,
+    size: f64,
+    winner: WinningConfig,
+    downscaled_to: Option<(u32, u32)>,
+}
+
+/// 按degradation_order依次加大各项手段的力度
+///
+/// 前一项手段用到它的上限后保持在该力度，才开始使用下一项；达到目标大小后立即停止。
+/// 返回所有尝试中最小的结果，全部失败时为空
+fn run_degradation_ladder(
+    gifsicle_path: &str,
+    input_path: &str,
+    order: &[DegradationKnob],
+    frame_levels: &[Strategy],
+    options: &CompressOptions,
+    shared_state: &SharedState,
+    events: EventSink,
+) -> Result<Option<LadderResult>, GifError> {
+    let target_size_kb = options.target_size;
+    let lossy_levels = lossy_levels(options);
+    
+    // 用户指定了颜色数或调色板时不自动减色
+    let (color_levels, colors_floored) = if options.max_colors.is_some() || options.palette_file.is_some() {
+        (Vec::new(), false)
+    } else {
+        color_reduction_levels(options)
+    };
+    let (width, height) = get_canvas_size(input_path)?;
+    let scale_levels: Vec<(u32, u32)> = RESIZE_LADDER_PERCENT
+        .iter()
+        .map(|percent| ((width * percent / 100).max(1), (height * percent / 100).max(1)))
+        .collect();
+    
+    let level_count = |knob: DegradationKnob| match knob {
+        DegradationKnob::Colors => color_levels.len(),
+        DegradationKnob::Lossy => lossy_levels.len(),
+        DegradationKnob::Frames => frame_levels.len(),
+        DegradationKnob::Resize => scale_levels.len(),
+    };
+    let total_steps: usize = order.iter().map(|&knob| level_count(knob)).sum();
+    shared_state.plan_attempts(total_steps);
+    
+    let mut config = LeverConfig { strategy: None, lossy: None, colors: None, scale: None };
+    let mut best: Option<(TempFile, f64, LeverConfig, DegradationStep)> = None;
+    let mut step_index = 0;
+    
+    'ladder: for (depth, &knob) in order.iter().enumerate() {
+        if knob == DegradationKnob::Colors && colors_floored && color_levels.is_empty() {
+            shared_state.set_color_floor_hit();
+        }
+        for level in 0..level_count(knob) {
+            if let Some(reason) = shared_state.stop_reason() {
+                println!("{}，停止按降级顺序搜索", reason);
+                break 'ladder;
+            }
+            
+            let (attempt, description) = match knob {
+                DegradationKnob::Colors => (
+                    LeverConfig { colors: Some(color_levels[level]), ..config },
+                    format!("colors={}", color_levels[level]),
+                ),
+                DegradationKnob::Lossy => (
+                    LeverConfig { lossy: Some(lossy_levels[level]), ..config },
+                    format!("lossy={}", lossy_levels[level]),
+                ),
+                DegradationKnob::Frames => {
+                    let strategy = &frame_levels[level];
+                    let description = match strategy.motion_threshold {
+                        Some(threshold) => format!("frames=motion({:.1})", threshold),
+                        None => format!("frames=1/{}", strategy.skip),
+                    };
+                    (LeverConfig { strategy: Some(strategy), ..config }, description)
+                }
+                DegradationKnob::Resize => {
+                    let (w, h) = scale_levels[level];
+                    (LeverConfig { scale: Some((w, h)), ..config }, format!("resize={}x{}", w, h))
+                }
+            };
+            // 即使这一级没有变小，后面的手段也在这一力度上继续
+            config = attempt;
+            step_index += 1;
+            
+            let file = match render_config(gifsicle_path, Path::new(input_path), attempt, options, shared_state) {
+                Ok(file) => file,
+                Err(GifError::Cancelled) => return Err(GifError::Cancelled),
+                Err(e) => {
+                    println!("降级 {} 失败: {}", description, e);
+                    continue;
+                }
+            };
+            let size = get_file_size_kb(&file.path)?;
+            println!("降级 {}: {:.2} KB", description, size);
+            shared_state.finish_attempt();
+            report_progress(
+                events,
+                shared_state,
+                ProgressPhase::Strategies,
+                "按降级顺序搜索",
+                step_index as f64 / total_steps.max(1) as f64,
+                Some(format!("{} {:.2} KB", description, size)),
+            );
+            
+            let improved = match &best {
+                Some((_, best_size, _, _)) => size < *best_size,
+                None => true,
+            };
+            if improved {
+                report_event(events, OptimizeEvent::Intermediate { path: &file.path, size_kb: size });
+                let step = DegradationStep {
+                    knob: knob.name().to_string(),
+                    depth: depth + 1,
+                    of: order.len(),
+                    level: description,
+                };
+                best = Some((file, size, attempt, step));
+            }
+            if size <= target_size_kb {
+                shared_state.set_found_target();
+                break 'ladder;
+            }
+            // 减色用到下限后仍未达到目标，继续使用后面的手段
+            if knob == DegradationKnob::Colors && colors_floored && level + 1 == color_levels.len() {
+                shared_state.set_color_floor_hit();
+            }
+        }
+    }
+    
+    let Some((file, size, config, step)) = best else {
+        return Ok(None);
+    };
+    let downscaled_to = match config.scale {
+        Some(_) => Some(get_canvas_size(&file.path)?),
+        None => None,
+    };
+    Ok(Some(LadderResult {
+        file,
+        size,
+        winner: WinningConfig {
+            strategy: config.strategy.cloned(),
+            lossy: config.lossy,
+            colors: config.colors,
+            degradation: Some(step),
+            score: None,
+            lossy_schedule: None,
+        },
+        downscaled_to,
+    }))
+}
+
+// 固定调色板最多的颜色数
+const MAX_PALETTE_COLORS: usize = 256;
+
+/// 读取调色板图片中的颜色，写成gifsicle的--use-colormap可以读取的GIF文件
+///
+/// 透明像素被忽略，颜色按第一次出现的顺序排列
+fn prepare_palette(palette_file: &str) -> Result<TempFile, GifError> {
+    if !Path::new(palette_file).exists() {
+        return Err(GifError::InvalidPalette(format!("文件不存在: {}", palette_file)));
+    }
+    let image = image::open(palette_file)
+        .map_err(|e| GifError::InvalidPalette(format!("无法读取图片 {}: {}", palette_file, e)))?
+        .to_rgba8();
+    
+    let mut colors: Vec<[u8; 3]> = Vec::new();
+    for pixel in image.pixels().filter(|pixel| pixel[3] >= GIF_ALPHA_THRESHOLD) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        if !colors.contains(&color) {
+            if colors.len() == MAX_PALETTE_COLORS {
+                return Err(GifError::InvalidPalette(format!("颜色超过 {} 种", MAX_PALETTE_COLORS)));
+            }
+            colors.push(color);
+        }
+    }
+    if colors.is_empty() {
+        return Err(GifError::InvalidPalette("图片中没有不透明的颜色".to_string()));
+    }
+    
+    // 每种颜色占一个像素，全局调色板就是目标调色板
+    let encode_error = |e: gif::EncodingError| GifError::InvalidPalette(format!("无法生成调色板: {}", e));
+    let width = colors.len() as u16;
+    let global_palette: Vec<u8> = colors.iter().flatten().copied().collect();
+    let palette = TempFile::new(tempfile::Builder::new().prefix("gif_palette_").suffix(".gif").tempfile()?);
+    {
+        let writer = BufWriter::new(File::create(&palette.path)?);
+        let mut encoder = gif::Encoder::new(writer, width, 1, &global_palette).map_err(encode_error)?;
+        let frame = gif::Frame {
+            width,
+            height: 1,
+            buffer: std::borrow::Cow::Owned((0..colors.len()).map(|i| i as u8).collect()),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).map_err(encode_error)?;
+    }
+    println!("使用指定调色板，共 {} 种颜色", colors.len());
+    Ok(palette)
+}
+
+/// 结果相对原文件节省的比例不足min_savings_percent时返回错误，调用方不再写出输出文件
+fn check_savings(options: &CompressOptions, original_kb: f64, best_kb: f64) -> Result<(), GifError> {
+    let Some(required_percent) = options.min_savings_percent else {
+        return Ok(());
+    };
+    let saved_percent = if original_kb > 0.0 { (1.0 - best_kb / original_kb) * 100.0 } else { 0.0 };
+    if saved_percent < required_percent {
+        return Err(GifError::InsufficientSavings { original_kb, best_kb, saved_percent, required_percent });
+    }
+    Ok(())
+}
+
+// 计算SSIM时最多比较的帧数，在动画中均匀取样
+const SSIM_SAMPLE_FRAMES: usize = 8;
+// SSIM按块计算的块边长
+const SSIM_BLOCK: u32 = 8;
+
+/// 两张同样尺寸的灰度图的SSIM，按块计算后取平均
+fn ssim_luma(a: &image::GrayImage, b: &image::GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for by in (0..height).step_by(SSIM_BLOCK as usize) {
+        for bx in (0..width).step_by(SSIM_BLOCK as usize) {
+            let pixels: Vec<(f64, f64)> = (by..(by + SSIM_BLOCK).min(height))
+                .flat_map(|y| (bx..(bx + SSIM_BLOCK).min(width)).map(move |x| (x, y)))
+                .map(|(x, y)| (a.get_pixel(x, y)[0] as f64, b.get_pixel(x, y)[0] as f64))
+                .collect();
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / n;
+            let var_a = pixels.iter().map(|p| (p.0 - mean_a).powi(2)).sum::<f64>() / n;
+            let var_b = pixels.iter().map(|p| (p.1 - mean_b).powi(2)).sum::<f64>() / n;
+            let covariance = pixels.iter().map(|p| (p.0 - mean_a) * (p.1 - mean_b)).sum::<f64>() / n;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            blocks += 1;
+        }
+    }
+    if blocks == 0 { 1.0 } else { total / blocks as f64 }
+}
+
+/// 解码GIF的所有完整画面及每帧开始播放的时间（毫秒）
+fn decode_timeline<P: AsRef<Path>>(path: P) -> Result<(Vec<image::RgbaImage>, Vec<u64>, u64), GifError> {
+    let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+    let mut images = Vec::new();
+    let mut starts = Vec::new();
+    let mut elapsed = 0;
+    for frame in decoder.into_frames() {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_cs = (numer as f64 / denom.max(1) as f64 / 10.0).round() as u16;
+        starts.push(elapsed);
+        elapsed += playback_delay_cs(delay_cs) as u64 * 10;
+        images.push(frame.into_buffer());
+    }
+    if images.is_empty() {
+        return Err(GifError::NoFrames);
+    }
+    Ok((images, starts, elapsed))
+}
+
+/// 候选GIF相对原GIF的画质（SSIM，0-1）
+///
+/// 在原动画中均匀取样若干帧，与候选动画在同一时刻显示的帧比较；
+/// 候选画布尺寸不同时先缩放到原尺寸
+fn gif_ssim<P: AsRef<Path>, Q: AsRef<Path>>(reference: P, candidate: Q) -> Result<f64, GifError> {
+    let (reference_frames, reference_starts, _) = decode_timeline(reference)?;
+    let (candidate_frames, candidate_starts, candidate_duration) = decode_timeline(candidate)?;
+    let (width, height) = reference_frames[0].dimensions();
+    
+    let step = (reference_frames.len() / SSIM_SAMPLE_FRAMES).max(1);
+    let mut total = 0.0;
+    let mut samples = 0;
+    for (frame, &start) in reference_frames.iter().zip(&reference_starts).step_by(step) {
+        let time = start % candidate_duration.max(1);
+        let index = candidate_starts.partition_point(|&candidate_start| candidate_start <= time).saturating_sub(1);
+        let shown = &candidate_frames[index];
+        let shown = if shown.dimensions() == (width, height) {
+            image::DynamicImage::ImageRgba8(shown.clone())
+        } else {
+            image::DynamicImage::ImageRgba8(image::imageops::resize(shown, width, height, image::imageops::FilterType::Triangle))
+        };
+        total += ssim_luma(&image::DynamicImage::ImageRgba8(frame.clone()).to_luma8(), &shown.to_luma8());
+        samples += 1;
+    }
+    Ok(total / samples.max(1) as f64)
+}
+
+/// 按评分选择时结果的评分，越大越好
+fn candidate_score(size_kb: f64, ssim: f64, target_kb: f64, (size_weight, quality_weight): (f64, f64)) -> f64 {
+    size_weight * (1.0 - size_kb / target_kb.max(f64::MIN_POSITIVE)) + quality_weight * ssim
+}
+
+/// 在(大小, SSIM)列表中选出达到目标大小且评分最高的一项，返回它的位置和SSIM
+///
+/// 无法计算SSIM的项不参与评分
+fn pick_by_score(candidates: &[(f64, Option<f64>)], target_kb: f64, weights: (f64, f64)) -> Option<(usize, f64)> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (size, _))| *size <= target_kb)
+        .filter_map(|(index, &(size, ssim))| ssim.map(|ssim| (index, ssim, candidate_score(size, ssim, target_kb, weights))))
+        .max_by(|a, b| a.2.total_cmp(&b.2))
+        .map(|(index, ssim, _)| (index, ssim))
+}
+
+/// optimize_gif的结果
+struct OptimizeOutcome {
+    original_size: f64,
+    final_size: f64,
+    original_bytes: u64,
+    final_bytes: u64,
+    // 是否因时间预算用完而提前结束搜索
+    time_limited: bool,
+    input_sha256: String,
+    output_sha256: String,
+    report: CompressionReport,
+    downscaled_to: Option<(u32, u32)>,
+    auto_downscaled: bool,
+    warnings: Vec<String>,
+    // 最终结果是否来自静帧动图策略
+    cinemagraph: bool,
+    // 最终结果使用的抽帧策略和lossy级别，基础优化已达标时为空
+    winner: Option<WinningConfig>,
+    // 最终结果去掉了透明度时合成到的底色，见reported_matte
+    matte: Option<String>,
+    // 搜索中途被取消，输出的是取消时最好的结果
+    cancelled: bool,
+}
+
+// 工作线程发给主线程的消息：所用策略及其结果
+type StrategyMessage = (Strategy, StrategyResult);
+
+/// 最终结果使用的配置
+struct WinningConfig {
+    // 抽帧策略，按降级顺序搜索且没有抽帧时为空
+    strategy: Option<Strategy>,
+    lossy: Option<u32>,
+    colors: Option<u16>,
+    degradation: Option<DegradationStep>,
+    score: Option<WinningScore>,
+    // 按lossy_schedule分段压缩时每一段的结果
+    lossy_schedule: Option<Vec<ScheduledSegment>>,
+}
+
+// 按评分选出的结果的评分
+#[derive(Clone, Copy, Serialize)]
+pub struct WinningScore {
+    score: f64,
+    ssim: f64,
+}
+
+/// 可以按degradation_order排列的降级手段
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DegradationKnob {
+    Colors,
+    Lossy,
+    Frames,
+    Resize,
+}
+
+impl DegradationKnob {
+    const ALL: [DegradationKnob; 4] = [Self::Colors, Self::Lossy, Self::Frames, Self::Resize];
+    
+    fn name(self) -> &'static str {
+        match self {
+            Self::Colors => "colors",
+            Self::Lossy => "lossy",
+            Self::Frames => "frames",
+            Self::Resize => "resize",
+        }
+    }
+}
+
+/// 检查degradation_order，未知或重复的项视为参数错误
+fn parse_degradation_order(order: &[String]) -> Result<Vec<DegradationKnob>, GifError> {
+    let mut knobs = Vec::with_capacity(order.len());
+    for name in order {
+        let knob = DegradationKnob::ALL
+            .into_iter()
+            .find(|knob| knob.name() == name)
+            .ok_or_else(|| GifError::InvalidOption(format!(
+                "degradation_order中的未知项 {}，可用: colors, lossy, frames, resize", name
+            )))?;
+        if knobs.contains(&knob) {
+            return Err(GifError::InvalidOption(format!("degradation_order中的 {} 重复", name)));
+        }
+        knobs.push(knob);
+    }
+    Ok(knobs)
+}
+
+// 按降级顺序搜索时最终结果所在的位置
+#[derive(Clone, Serialize)]
+pub struct DegradationStep {
+    // 用到的最后一项手段
+    knob: String,
+    // 该手段在degradation_order中的位置，从1开始
+    depth: usize,
+    // degradation_order的总项数
+    of: usize,
+    // 该手段使用的力度，如"lossy=120"
+    level: String,
+}
+
+/// optimize_gif在处理过程中报告的事件
+enum OptimizeEvent<'a> {
+    Progress(CompressProgress),
+    // 找到了更小的结果，文件只在回调期间有效
+    Intermediate { path: &'a Path, size_kb: f64 },
+}
+
+// 接收处理事件的回调
+type EventSink<'s> = Option<&'s dyn Fn(OptimizeEvent<'_>)>;
+
+/// 把处理事件交给调用方，没有注册回调时忽略
+fn report_event(events: EventSink<'_>, event: OptimizeEvent<'_>) {
+    if let Some(callback) = events {
+        callback(event);
+    }
+}
+
+/// 报告处理进度，fraction为当前阶段内的完成比例，取值0到1
+fn report_progress(
+    events: EventSink<'_>,
+    shared_state: &SharedState,
+    phase: ProgressPhase,
+    status: &str,
+    fraction: f64,
+    details: Option<String>,
+) {
+    report_event(events, OptimizeEvent::Progress(CompressProgress {
+        status: status.to_string(),
+        progress: phase.progress(fraction),
+        phase: Some(phase),
+        eta_seconds: shared_state.eta_seconds(),
+        new_attempts: shared_state.take_new_attempts(),
+        // 暂存的gifsicle输出接在其他说明之后，否则带说明的进度会把它们一直压住
+        details: match (details, shared_state.take_verbose_details()) {
+            (Some(details), Some(verbose)) => Some(format!("{}\n{}", details, verbose)),
+            (details, verbose) => details.or(verbose),
+        },
+        counters: shared_state.counters(),
+        item_result: None,
+    }));
+}
+
+// 等待策略结果时检查尝试次数是否变化的间隔
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// 时间预算用完后，等待正在运行的策略交回当前最佳结果的最长时间
+const TIME_BUDGET_GRACE: Duration = Duration::from_millis(1500);
+
+/// 优化GIF到目标大小 (并行版本)
+fn optimize_gif<P: AsRef<Path>, Q: AsRef<Path>>(
+    input_path: P,
+    output_path: Q,
+    options: &CompressOptions,
+    threads: usize,
+    events: EventSink<'_>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+) -> Result<OptimizeOutcome, GifError> {
+    let target_size_kb = options.target_size;
+    // 时间预算从开始处理时计算，基础优化也计入其中
+    let started = Instant::now();
+    let budget = search_budget(options, fs::metadata(input_path.as_ref()).map_or(0, |metadata| metadata.len()));
+    if budget.safe_mode {
+        println!("输入较大，使用安全模式: 最多运行 {:?} 次gifsicle，时间预算 {:?} 毫秒", budget.max_gifsicle_runs, budget.time_budget_ms);
+    }
+    let deadline = budget.time_budget_ms.map(|ms| started + Duration::from_millis(ms));
+    
+    // 创建共享状态
+    let shared_state = Arc::new(cancel_flag.map_or_else(SharedState::new, SharedState::with_cancel_flag));
+    shared_state.set_budget(budget);
+    if options.measure_memory {
+        shared_state.enable_memory_measurement();
+    }
+    if options.verbose_gifsicle {
+        shared_state.enable_verbose();
+    }
+    shared_state.set_gifsicle_timeout(options.gifsicle_timeout_secs);
+    
+    // 获取初始文件大小
+    report_progress(events, &shared_state, ProgressPhase::Analyzing, "正在分析原文件", 0.0, None);
+    let original_bytes = get_file_size_bytes(&input_path)?;
+    let original_size = bytes_to_kb(original_bytes);
+    println!("原始大小: {:.2} KB", original_size);
+    let input_sha256 = sha256_file(&input_path)?;
+    // 是否达到目标大小按字节比较，避免KB换算的误差
+    let target_bytes = kb_to_bytes(target_size_kb);
+    
+    // 需要调整起始帧时原文件不能直接使用
+    let start_at_frame = options.start_at_frame.unwrap_or(0);
+    
+    // 指定了最大尺寸时原文件也可能需要缩小
+    let explicit_resize = options.max_width.is_some() || options.max_height.is_some();
+    
+    // 指定了调色板时先转换成gifsicle可用的GIF调色板，原文件也需要重新映射颜色
+    let palette = options.palette_file.as_deref().map(prepare_palette).transpose()?;
+    
+    if let Some(colors) = options.max_colors {
+        if !(2..=256).contains(&colors) {
+            return Err(GifError::Other(format!("颜色数 {} 超出范围，必须在2到256之间", colors)));
+        }
+    }
+    parse_matte(options.matte.as_deref())?;
+    check_lossy_levels(options)?;
+    if !(1..=100).contains(&options.min_scale_percent) {
+        return Err(GifError::InvalidOption(format!("min_scale_percent {} 超出范围，必须在1到100之间", options.min_scale_percent)));
+    }
+    
+    // 指定了降级顺序时改为按顺序逐级搜索
+    let degradation = options.degradation_order.as_deref().map(parse_degradation_order).transpose()?;
+    
+    // 尺寸预设固定了输出画布，不能再缩小
+    let letterbox_size = dimension_preset(options)?;
+    if letterbox_size.is_some() {
+        if explicit_resize {
+            return Err(GifError::InvalidOption("dimension_preset不能与max_width/max_height同时指定".to_string()));
+        }
+        if degradation.as_ref().is_some_and(|order| order.contains(&DegradationKnob::Resize)) {
+            return Err(GifError::InvalidOption("dimension_preset不能与degradation_order中的resize同时使用".to_string()));
+        }
+    }
+    
+    // 如果已经小于目标大小，直接复制
+    if original_bytes <= target_bytes
+        && !options.base_pass_only
+        && start_at_frame == 0
+        && !explicit_resize
+        && palette.is_none()
+        && options.color_key.is_none()
+        && options.max_colors.is_none()
+        && letterbox_size.is_none()
+        && options.lossy_schedule.is_none()
+    {
+        println!("文件已经小于目标大小，无需压缩");
+        check_savings(options, original_size, original_size)?;
+        let output_sha256 = write_output(&input_path, &output_path)?;
+        return Ok(OptimizeOutcome {
+            original_size,
+            final_size: original_size,
+            original_bytes,
+            final_bytes: original_bytes,
+            time_limited: false,
+            cancelled: false,
+            input_sha256,
+            output_sha256,
+            report: shared_state.report(started),
+            downscaled_to: None,
+            auto_downscaled: false,
+            warnings: Vec::new(),
+            cinemagraph: false,
+            matte: None,
+            winner: None,
+        });
+    }
+    
+    // 获取初始帧数
+    let original_frame_count = get_frame_count(&input_path)?;
+    println!("原始帧数: {}", original_frame_count);
+    report_progress(events, &shared_state, ProgressPhase::Analyzing, "分析完成", 1.0, Some(format!("{} 帧", original_frame_count)));
+    
+    // 分段lossy按原文件的帧序号划分，不能与改变帧顺序或逐级搜索的选项同时使用
+    let lossy_schedule = match &options.lossy_schedule {
+        Some(schedule) => {
+            if start_at_frame > 0 {
+                return Err(GifError::InvalidOption("lossy_schedule不能与start_at_frame同时使用".to_string()));
+            }
+            if degradation.is_some() {
+                return Err(GifError::InvalidOption("lossy_schedule不能与degradation_order同时使用".to_string()));
+            }
+            Some(validate_lossy_schedule(schedule, original_frame_count)?)
+        }
+        None => None,
+    };
+    
+    // 检查gifsicle是否存在，不存在时只能使用内置的帧差分优化
+    let gifsicle_path = match find_gifsicle() {
+        Some(path) => path,
+        None => {
+            println!("未找到gifsicle，使用内置的帧差分优化");
+            let differenced = TempFile::new(NamedTempFile::new()?);
+            differencing_pass(&input_path, &differenced.path, options, &shared_state)?;
+            let differenced_bytes = get_file_size_bytes(&differenced.path)?;
+            let differenced_size = bytes_to_kb(differenced_bytes);
+            println!("帧差分后大小: {:.2} KB", differenced_size);
+            
+            let mut warnings = vec!["未找到gifsicle，只进行了内置的帧差分优化，没有抽帧和有损压缩".to_string()];
+            if explicit_resize {
+                warnings.push("未找到gifsicle，无法缩小画布".to_string());
+            }
+            if palette.is_some() {
+                warnings.push("未找到gifsicle，无法使用指定的调色板".to_string());
+            }
+            if options.max_colors.is_some() {
+                warnings.push("未找到gifsicle，无法减少颜色数".to_string());
+            }
+            if lossy_schedule.is_some() {
+                warnings.push("未找到gifsicle，无法按lossy_schedule分段压缩".to_string());
+            }
+            // 调整了起始帧、设置了透明色或尺寸预设时必须使用重新编码的结果
+            let must_reencode = start_at_frame > 0 || options.color_key.is_some() || letterbox_size.is_some();
+            let (final_bytes, output_sha256) = if differenced_bytes < original_bytes || must_reencode {
+                check_savings(options, original_size, differenced_size)?;
+                (differenced_bytes, write_output(&differenced.path, &output_path)?)
+            } else {
+                check_savings(options, original_size, original_size)?;
+                (original_bytes, write_output(&input_path, &output_path)?)
+            };
+            return Ok(OptimizeOutcome {
+                original_size,
+                final_size: bytes_to_kb(final_bytes),
+                original_bytes,
+                final_bytes,
+                time_limited: false,
+                cancelled: false,
+                input_sha256,
+                output_sha256,
+                report: shared_state.report(started),
+                downscaled_to: None,
+                auto_downscaled: false,
+                warnings,
+                cinemagraph: false,
+                matte: reported_matte(options, &shared_state, None),
+                winner: None,
+            });
+        }
+    };
+    
+    // 小文件可以在内存中完成，失败时回到下面使用临时文件的流程
+    if options.in_memory {
+        match in_memory_candidate(&input_path, options) {
+            Ok(input) => match optimize_in_memory(&gifsicle_path, &input, options, &shared_state, events, deadline) {
+                Ok(outcome) => {
+                    check_savings(options, original_size, outcome.size_kb)?;
+                    let output_sha256 = write_output_bytes(&outcome.data, &output_path)?;
+                    println!("在内存中完成! 最终大小: {:.2} KB", outcome.size_kb);
+                    let mut warnings = Vec::new();
+                    if outcome.time_limited {
+                        warnings.push(budget_warning(&shared_state));
+                    }
+                    if outcome.data.len() as u64 > target_bytes {
+                        warnings.extend(color_floor_warning(options, &shared_state));
+                    }
+                    return Ok(OptimizeOutcome {
+                        original_size,
+                        final_size: outcome.size_kb,
+                        original_bytes,
+                        final_bytes: outcome.data.len() as u64,
+                        time_limited: outcome.time_limited,
+                        cancelled: false,
+                        input_sha256,
+                        output_sha256,
+                        report: shared_state.report(started),
+                        downscaled_to: None,
+                        auto_downscaled: false,
+                        warnings,
+                        cinemagraph: false,
+                        matte: None,
+                        winner: outcome.winner,
+                    });
+                }
+                Err(_) if shared_state.is_cancelled() => return Err(GifError::Cancelled),
+                // 同样的输入改用临时文件也会卡住
+                Err(e @ GifError::GifsicleTimeout(_)) => return Err(e),
+                Err(e) => println!("内存中处理失败（{}），改用临时文件", e),
+            },
+            Err(reason) => println!("不在内存中处理: {}", reason),
+        }
+    }
+    
+    // 使用String而不是&str，避免生命周期问题
+    let mut input_path_str = input_path.as_ref().to_string_lossy().to_string();
+    
+    // 调整起始帧、设置透明色或尺寸预设：先生成处理后的完整GIF，之后的基础优化和各个策略都以它为输入
+    let mut strategy_options = options.clone();
+    let _rotated_input = if start_at_frame > 0 || options.color_key.is_some() || letterbox_size.is_some() {
+        if start_at_frame >= original_frame_count {
+            return Err(GifError::Other(format!(
+                "起始帧 {} 超出范围，GIF共有 {} 帧", start_at_frame, original_frame_count
+            )));
+        }
+        if start_at_frame > 0 {
+            println!("以第 {} 帧作为起始帧", start_at_frame);
+        }
+        if let Some([r, g, b]) = options.color_key {
+            println!("把颜色 #{:02x}{:02x}{:02x} 设为透明", r, g, b);
+        }
+        if let Some((width, height)) = letterbox_size {
+            println!("按尺寸预设缩放并补边到 {}x{}", width, height);
+        }
+        
+        let rotated = TempFile::new(NamedTempFile::new()?);
+        // 保留全部帧和原始延迟，只调整顺序、透明色和尺寸
+        let rotate_options = CompressOptions {
+            delay_rounding: Some(options.delay_rounding.unwrap_or(DelayRounding::Nearest)),
+            ..options.clone()
+        };
+        let keep_all = Strategy { skip: 1, delay: 0, motion_threshold: None, cinemagraph: false, scale: None };
+        extract_frames(&input_path_str, rotated.path_str(), &keep_all, &rotate_options, &shared_state)?;
+        
+        input_path_str = rotated.path_str();
+        strategy_options.start_at_frame = None;
+        strategy_options.color_key = None;
+        strategy_options.dimension_preset = None;
+        Some(rotated)
+    } else {
+        None
+    };
+    
+    // 计算最小保留帧数
+    let min_frames = min_frame_floor(original_frame_count, options);
+    
+    // 画布缩小：用户指定的最大尺寸优先，否则在画布过大时自动计算最长边；尺寸预设的画布不再缩小
+    let (width, height) = match letterbox_size {
+        Some(size) => size,
+        None => get_canvas_size(&input_path)?,
+    };
+    let suggested_edge = auto_max_edge(width, height, min_frames, target_size_kb);
+    let mut warnings = Vec::new();
+    let mut auto_downscaled = false;
+    let resize_fit = if explicit_resize {
+        let dimension = |value: Option<u32>| value.map_or("_".to_string(), |v| v.to_string());
+        Some(format!("{}x{}", dimension(options.max_width), dimension(options.max_height)))
+    } else if letterbox_size.is_some() {
+        if suggested_edge.is_some() {
+            warnings.push(format!("尺寸预设的画布 {}x{} 相对目标大小过大，目标大小可能无法达到", width, height));
+        }
+        None
+    } else {
+        let ladder_resizes = degradation.as_ref().map(|order| order.contains(&DegradationKnob::Resize));
+        match suggested_edge {
+            // 降级顺序中有resize时由搜索决定何时缩小
+            Some(_) if ladder_resizes == Some(true) => None,
+            Some(edge) if options.auto_downscale == Some(true) && ladder_resizes.is_none() => {
+                println!("画布 {}x{} 相对目标大小过大，自动缩小到最长边 {} 像素", width, height, edge);
+                auto_downscaled = true;
+                Some(format!("{}x{}", edge, edge))
+            }
+            Some(_) => {
+                warnings.push(format!("画布 {}x{} 相对目标大小过大，目标大小可能无法达到", width, height));
+                None
+            }
+            None => None,
+        }
+    };
+    
+    // 原文件节奏不均匀时统一延迟会抹掉刻意的停顿，未指定取整方式时改用逐帧延迟
+    if strategy_options.delay_rounding.is_none() {
+        if let Ok(scan) = scan_gif_blocks(&input_path) {
+            let delays: Vec<u16> = scan.frames.iter().map(|block| block.delay_cs).collect();
+            if delay_stats(&delays).variable_timing {
+                println!("原文件帧延迟不均匀，抽帧时保留逐帧延迟");
+                strategy_options.delay_rounding = Some(DelayRounding::Accumulate);
+                warnings.push("原文件帧延迟不均匀，已按原始节奏计算每帧延迟".to_string());
+            }
+        }
+    }
+    
+    // 基础优化 - 使用gifsicle的最高优化级别和更多高级选项
+    let temp_file = NamedTempFile::new()?;
+    let temp_file_opt = TempFile::new(temp_file);
+    let temp_file_opt_path = temp_file_opt.path_str();
+    
+    // 构建优化的参数列表
+    let mut args = vec![
+        "-O3",                            // 最高级别优化
+        "--no-warnings",                  // 不显示警告
+        "--no-conserve-memory",           // 使用更多内存以提高速度
+        "--no-comments",                  // 删除注释以减小文件大小
+        "--no-names",                     // 删除图像和对象名称
+        "--careful",                      // 更慎重的优化，避免损坏文件
+    ];
+    let loop_arg = loopcount_arg(output_loop_count(options, source_loop_count(scan_gif_blocks(&input_path))));
+    args.push(&loop_arg);                 // 保留原文件的循环次数或按loop_count选项改写
+    let base_first = options.resize_order == ResizeOrder::BaseFirst && resize_fit.is_some();
+    if let Some(fit) = resize_fit.as_ref().filter(|_| !base_first) {
+        args.extend(["--resize-fit", fit]); // 按比例缩小到指定范围内
+    }
+    let palette_path = palette.as_ref().map(|file| file.path_str());
+    if let Some(path) = &palette_path {
+        args.extend(["--use-colormap", path]); // 把颜色映射到指定调色板
+    }
+    let colors_arg = options.max_colors.map(|colors| colors.to_string());
+    if let Some(colors) = &colors_arg {
+        args.extend(["--colors", colors]); // 用户指定的颜色数
+    }
+    // 先优化后缩小时，原尺寸的优化结果写入中间文件
+    let full_size_opt = if base_first {
+        Some(TempFile::new(NamedTempFile::new()?))
+    } else {
+        None
+    };
+    let base_output_path = full_size_opt.as_ref().map_or(temp_file_opt_path.clone(), |file| file.path_str());
+    args.extend([
+        &input_path_str,                  // 输入文件
+        "-o",                             // 输出选项
+        &base_output_path                 // 输出文件
+    ]);
+    
+    report_progress(events, &shared_state, ProgressPhase::BaseOptimize, "正在进行基础优化", 0.0, None);
+    let base_started = Instant::now();
+    let base_pass = BasePassGifsicle::start();
+    let _output = run_gifsicle(&gifsicle_path, &args, &shared_state)?;
+    
+    if !_output.status.success() {
+        let stderr = String::from_utf8_lossy(&_output.stderr).to_string();
+        return Err(GifError::GifsicleExecFailed(stderr));
+    }
+    
+    if let (Some(full_size), Some(fit)) = (&full_size_opt, &resize_fit) {
+        println!("原尺寸优化后大小: {:.2} KB，开始缩小画布", get_file_size_kb(&full_size.path)?);
+        let resize_args = vec![
+            "-O3",
+            "--no-warnings",
+            "--careful",
+            "--resize-fit",
+            fit,
+            &base_output_path,
+            "-o",
+            &temp_file_opt_path
+        ];
+        let output = run_gifsicle(&gifsicle_path, &resize_args, &shared_state)?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            return Err(GifError::GifsicleExecFailed(stderr));
+        }
+    }
+    drop(base_pass);
+    
+    let base_elapsed = base_started.elapsed();
+    let opt_bytes = get_file_size_bytes(&temp_file_opt_path)?;
+    let opt_size = bytes_to_kb(opt_bytes);
+    println!("基础优化后大小: {:.2} KB", opt_size);
+    report_progress(events, &shared_state, ProgressPhase::BaseOptimize, "基础优化完成", 1.0, Some(format!("{:.2} KB", opt_size)));
+    report_event(events, OptimizeEvent::Intermediate { path: &temp_file_opt.path, size_kb: opt_size });
+    
+    // 缩小后的画布作为各个策略的输入
+    let downscaled_to = if resize_fit.is_some() {
+        let resized = get_canvas_size(&temp_file_opt_path)?;
+        if resized != (width, height) {
+            Some(resized)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    
+    // 指定了分段lossy时直接按分段压缩基础优化的结果，不再搜索抽帧策略和lossy级别
+    if let Some(segments) = &lossy_schedule {
+        let (scheduled, segment_results) = apply_lossy_schedule(&gifsicle_path, &temp_file_opt_path, segments, &shared_state)?;
+        let final_bytes = get_file_size_bytes(&scheduled.path)?;
+        let final_size = bytes_to_kb(final_bytes);
+        println!("分段压缩后大小: {:.2} KB", final_size);
+        check_savings(options, original_size, final_size)?;
+        let output_sha256 = write_output(&scheduled.path, &output_path)?;
+        return Ok(OptimizeOutcome {
+            original_size,
+            final_size,
+            original_bytes,
+            final_bytes,
+            time_limited: false,
+            cancelled: false,
+            input_sha256,
+            output_sha256,
+            report: shared_state.report(started),
+            downscaled_to,
+            auto_downscaled,
+            warnings,
+            cinemagraph: false,
+            matte: reported_matte(options, &shared_state, None),
+            winner: Some(WinningConfig {
+                strategy: None,
+                lossy: None,
+                colors: None,
+                degradation: None,
+                score: None,
+                lossy_schedule: Some(segment_results),
+            }),
+        });
+    }
+    
+    // 如果已经达到目标大小或只需要基础优化，直接复制
+    if opt_bytes <= target_bytes || options.base_pass_only {
+        check_savings(options, original_size, opt_size)?;
+        let output_sha256 = write_output(&temp_file_opt_path, &output_path)?;
+        return Ok(OptimizeOutcome {
+            original_size,
+            final_size: opt_size,
+            original_bytes,
+            final_bytes: opt_bytes,
+            time_limited: false,
+            cancelled: false,
+            input_sha256,
+            output_sha256,
+            report: shared_state.report(started),
+            downscaled_to,
+            auto_downscaled,
+            warnings,
+            cinemagraph: false,
+            matte: reported_matte(options, &shared_state, None),
+            winner: None,
+        });
+    }
+    
+    // 缩小画布、映射调色板或减少颜色数后，各个策略以基础优化的结果为输入
+    // 基础优化的结果可能作为当前最佳结果被替换删除，策略使用单独的副本
+    let _strategy_input = if downscaled_to.is_some() || palette.is_some() || options.max_colors.is_some() {
+        let copy = TempFile::new(NamedTempFile::new()?);
+        fs::copy(&temp_file_opt_path, &copy.path)?;
+        input_path_str = copy.path_str();
+        Some(copy)
+    } else {
+        None
+    };
+    
+    // 构建抽帧策略
+    let mut strategies = Vec::new();
+    
+    // 静帧动图只需保存变化区域，画面无损且通常最小，最先尝试
+    match detect_cinemagraph(&input_path_str, &shared_state) {
+        Ok(Some(ratio)) => {
+            println!("检测到静帧动图，{:.1}% 的画面保持不变", ratio * 100.0);
+            strategies.push(Strategy {
+                skip: 1,
+                delay: 0,
+                motion_threshold: None,
+                cinemagraph: true,
+                scale: None,
+            });
+        }
+        Ok(None) => {}
+        Err(e) => println!("静帧动图检测失败: {}", e),
+    }
+    
+    // 其余策略只由帧数和选项决定，开始处理前已排除保留帧数不足的策略
+    let plan = plan_strategies(original_frame_count, options);
+    for rejected in &plan.rejected {
+        println!("跳过抽帧策略 {}: {}", rejected.strategy, rejected.reason);
+    }
+    strategies.extend(plan.strategies);
+    
+    // 指定了降级顺序时按顺序逐级尝试，不再并行搜索各个策略
+    if let Some(order) = &degradation {
+        let frame_levels: Vec<Strategy> = strategies.into_iter().filter(|strategy| !strategy.cinemagraph).collect();
+        shared_state.arm_deadline(deadline);
+        let ladder = run_degradation_ladder(
+            &gifsicle_path,
+            &input_path_str,
+            order,
+            &frame_levels,
+            &strategy_options,
+            &shared_state,
+            events,
+        )?;
+        
+        if shared_state.is_cancelled() {
+            return Err(GifError::Cancelled);
+        }
+        
+        let found_solution = ladder.as_ref().is_some_and(|result| result.size <= target_size_kb);
+        let time_limited = !found_solution && shared_state.is_budget_truncated();
+        if !found_solution {
+            warnings.extend(color_floor_warning(options, &shared_state));
+        }
+        if time_limited {
+            warnings.push(budget_warning(&shared_state));
+        }
+        // 所有尝试都不比基础优化小时使用基础优化的结果
+        let (best, winner, ladder_downscaled) = match ladder {
+            Some(result) if result.size < opt_size => (result.file, Some(result.winner), result.downscaled_to),
+            _ => (temp_file_opt, None, None),
+        };
+        check_savings(options, original_size, get_file_size_kb(&best.path)?)?;
+        let output_sha256 = write_output(&best.path, &output_path)?;
+        let _ = best.cleanup();
+        let final_bytes = get_file_size_bytes(&output_path)?;
+        let final_size = bytes_to_kb(final_bytes);
+        println!("完成! 最终大小: {:.2} KB", final_size);
+        
+        return Ok(OptimizeOutcome {
+            original_size,
+            final_size,
+            original_bytes,
+            final_bytes,
+            time_limited,
+            cancelled: false,
+            input_sha256,
+            output_sha256,
+            report: shared_state.report(started),
+            downscaled_to: ladder_downscaled.or(downscaled_to),
+            auto_downscaled,
+            warnings,
+            cinemagraph: false,
+            matte: reported_matte(options, &shared_state, winner.as_ref()),
+            winner,
+        });
+    }
+    
+    // 大画布缩小画面通常比抽帧更有效，额外尝试少量抽帧配合缩小画面
+    let scaled = scaled_strategies(original_frame_count, downscaled_to.unwrap_or((width, height)), options);
+    if !scaled.is_empty() {
+        println!("画布较大，额外尝试 {} 个缩小画面的策略", scaled.len());
+        strategies.extend(scaled);
+    }
+    
+    // 调用次数预算不够尝试所有策略时，先在整个抽帧范围内均匀地粗略尝试
+    if let Some(max_runs) = budget.max_gifsicle_runs {
+        let per_strategy = 2 + lossy_levels(&strategy_options).len();
+        let affordable = (max_runs.saturating_sub(shared_state.gifsicle_runs()) / per_strategy).max(1);
+        if affordable < strategies.len() {
+            println!("调用次数预算只够尝试 {} 个策略，从 {} 个策略中均匀选取", affordable, strategies.len());
+            warnings.push(format!("gifsicle调用次数预算有限，只尝试了 {} 个抽帧策略中的 {} 个", strategies.len(), affordable));
+            strategies = spread_subset(strategies, affordable);
+        }
+    }
+    
+    // 限制线程数，不超过策略数量；统一队列中每个策略有多项任务，使用全部线程
+    let strategy_count = strategies.len();
+    let thread_count = if options.unified_queue { threads.max(1) } else { std::cmp::min(threads, strategy_count) };
+    println!("开始使用 {} 个线程并行处理 {} 个压缩策略...", thread_count, strategies.len());
+    
+    // 创建通道以接收处理结果
+    let (tx, rx): (Sender<StrategyMessage>, Receiver<StrategyMessage>) = mpsc::channel();
+    
+    // 创建线程池
+    let input_path_arc = Arc::new(input_path_str);
+    let options_arc = Arc::new(strategy_options);
+    let mut handles = Vec::new();
+    
+    // 开始搜索，启用时间预算和单个策略的时间限制
+    shared_state.arm_deadline(deadline);
+    shared_state.set_strategy_timeout(strategy_timeout(options, base_elapsed));
+    shared_state.plan_strategies(strategy_count);
+    let score_selection = options.selection == WinnerSelection::Score;
+    if score_selection {
+        shared_state.collect_all_results();
+    }
+    shared_state.plan_attempts(strategy_count * lossy_levels(options).len());
+    
+    // 设置初始最佳大小为基础优化后的大小
+    shared_state.update_best_size(opt_size);
+    
+    if options.unified_queue {
+        let tx_clone = tx.clone();
+        let input_path_clone = Arc::clone(&input_path_arc);
+        let shared_state_clone = Arc::clone(&shared_state);
+        let options_clone = Arc::clone(&options_arc);
+        handles.push(thread::spawn(move || {
+            run_unified_queue(&input_path_clone, strategies, &options_clone, thread_count, &shared_state_clone, &tx_clone);
+        }));
+    } else {
+        // 策略队列，工作线程依次取出处理，保证同时运行的策略不超过thread_count
+        let queue = Arc::new(std::sync::Mutex::new(strategies.into_iter().enumerate()));
+        
+        for _ in 0..thread_count {
+            let tx_clone = tx.clone();
+            let input_path_clone = Arc::clone(&input_path_arc);
+            let shared_state_clone = Arc::clone(&shared_state);
+            let queue_clone = Arc::clone(&queue);
+            let options_clone = Arc::clone(&options_arc);
+        
+            // 创建工作线程处理队列中的策略
+            let handle = thread::spawn(move || loop {
+                let next = queue_clone.lock().unwrap().next();
+                let (i, strategy) = match next {
+                    Some(item) => item,
+                    None => break,
+                };
+            
+                let applied = strategy.clone();
+                let started = Instant::now();
+                let timer = StrategyTimer::start(started, shared_state_clone.strategy_timeout());
+                let result = process_strategy(
+                    &input_path_clone,
+                    strategy,
+                    &options_clone,
+                    i + 1,
+                    &shared_state_clone
+                );
+                drop(timer);
+                shared_state_clone.record_strategy_timing(&applied, started, &result);
+            
+                // 如果这是一个好的结果，更新共享状态中的最佳大小
+                if result.success && result.size < shared_state_clone.get_best_size() {
+                    let is_better = shared_state_clone.update_best_size(result.size);
+                
+                    // 如果我们的结果被接受为更好的结果，并且达到了目标大小，设置found_target标志
+                    if is_better && result.size <= target_size_kb {
+                        shared_state_clone.set_found_target();
+                    }
+                }
+            
+                // 发送结果到主线程，附带所用的策略
+                let _ = tx_clone.send((applied, result));
+            });
+        
+            handles.push(handle);
+        }
+    }
+    
+    // 丢弃发送者以允许接收者知道何时所有发送者都已完成
+    drop(tx);
+    
+    // 等待并收集所有策略的结果
+    let mut best_size = opt_size;
+    let mut best_file: Option<TempFile> = Some(temp_file_opt);
+    // 当前最佳结果所用的策略，基础优化结果为空
+    let mut best_winner: Option<WinningConfig> = None;
+    let mut found_solution = false;
+    let mut received = 0;
+    // 按评分选择时达到目标大小的结果，全部完成后再评分
+    let mut qualifying: Vec<StrategyMessage> = Vec::new();
+    
+    // 从通道接收结果，有时间预算时最多等到截止时间后的宽限期
+    // 等待期间定期检查计数，尝试次数变化时推送进度
+    // 取消后不再等待正在运行的策略，只收取已经送达的结果
+    let mut last_counters = shared_state.counters();
+    let mut draining = false;
+    loop {
+        let message = if draining {
+            match rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        } else {
+            let wait = match deadline {
+                Some(deadline) => {
+                    let wait_until = deadline + TIME_BUDGET_GRACE;
+                    let now = Instant::now();
+                    if now >= wait_until {
+                        break;
+                    }
+                    (wait_until - now).min(PROGRESS_POLL_INTERVAL)
+                }
+                None => PROGRESS_POLL_INTERVAL,
+            };
+            match rx.recv_timeout(wait) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                // 所有工作线程都已结束
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        };
+        let (strategy, result) = match message {
+            Some(message) => message,
+            None => {
+                if shared_state.is_cancelled() {
+                    draining = true;
+                    continue;
+                }
+                let counters = shared_state.counters();
+                if counters != last_counters || shared_state.has_verbose_lines() {
+                    last_counters = counters;
+                    report_progress(
+                        events,
+                        &shared_state,
+                        ProgressPhase::Strategies,
+                        "正在尝试压缩参数",
+                        received as f64 / strategy_count as f64,
+                        None,
+                    );
+                }
+                continue;
+            }
+        };
+        
+        received += 1;
+        shared_state.finish_strategy();
+        last_counters = shared_state.counters();
+        // 带上该策略的抽帧方式、lossy级别和结果大小
+        let mut details = format!("{}/{}", received, strategy_count);
+        if result.success {
+            details.push_str(&format!("，{}", strategy.label()));
+            if let Some(lossy) = result.lossy {
+                details.push_str(&format!(" lossy={}", lossy));
+            }
+            details.push_str(&format!("，{:.2} KB", result.size));
+        }
+        report_progress(
+            events,
+            &shared_state,
+            ProgressPhase::Strategies,
+            "压缩策略完成",
+            received as f64 / strategy_count as f64,
+            Some(details),
+        );
+        
+        if !result.success {
+            continue;
+        }
+        
+        // 结构不完整的文件即使很小也不能作为结果
+        if let Some(reason) = result.file.as_ref().and_then(|file| check_gif_output(&file.path).err()) {
+            println!("丢弃无效的结果: {}", reason);
+            continue;
+        }
+        
+        if result.size <= target_size_kb && score_selection {
+            println!("策略达到目标大小: {:.2} KB，等待其他策略完成后评分", result.size);
+            qualifying.push((strategy, result));
+        } else if result.size <= target_size_kb {
+            // 清理之前的最佳文件（如果有的话）
+            if let Some(old_file) = best_file.take() {
+                let _ = old_file.cleanup();
+            }
+            
+            best_size = result.size;
+            best_file = result.file;
+            best_winner = Some(WinningConfig {
+                strategy: Some(strategy),
+                lossy: result.lossy,
+                colors: result.colors,
+                degradation: None,
+                score: None,
+                lossy_schedule: None,
+            });
+            if let Some(best) = &best_file {
+                report_event(events, OptimizeEvent::Intermediate { path: &best.path, size_kb: best_size });
+            }
+            found_solution = true;
+            println!("找到达到目标大小的策略! 大小: {:.2} KB", best_size);
+            // 设置标志，以便其他线程可以提前退出
+            shared_state.set_found_target();
+            break; // 提前退出循环，不再处理其他结果
+        } else if result.size < best_size {
+            // 清理之前的最佳文件（如果有的话）
+            if let Some(old_file) = best_file.take() {
+                let _ = old_file.cleanup();
+            }
+            
+            best_size = result.size;
+            best_file = result.file;
+            best_winner = Some(WinningConfig {
+                strategy: Some(strategy),
+                lossy: result.lossy,
+                colors: result.colors,
+                degradation: None,
+                score: None,
+                lossy_schedule: None,
+            });
+            if let Some(best) = &best_file {
+                report_event(events, OptimizeEvent::Intermediate { path: &best.path, size_kb: best_size });
+            }
+        } else if result.file.is_some() {
+            // 该结果不比当前最佳结果好，清理它
+            if let Some(file) = result.file {
+                let _ = file.cleanup();
+            }
+        }
+    }
+    
+    // 计算每个达到目标的结果相对原文件的SSIM，选出评分最高的
+    if !qualifying.is_empty() {
+        let scored: Vec<(f64, Option<f64>)> = qualifying
+            .iter()
+            .map(|(_, result)| {
+                let ssim = result.file.as_ref().and_then(|file| match gif_ssim(&input_path, &file.path) {
+                    Ok(ssim) => Some(ssim),
+                    Err(e) => {
+                        println!("无法计算 {:.2} KB 结果的SSIM，不参与评分: {}", result.size, e);
+                        None
+                    }
+                });
+                (result.size, ssim)
+            })
+            .collect();
+        let unscored = scored.iter().filter(|(_, ssim)| ssim.is_none()).count();
+        if unscored > 0 {
+            warnings.push(format!("{} 个达到目标的结果无法计算SSIM，未参与评分", unscored));
+        }
+        let weights = (options.size_weight, options.quality_weight);
+        if let Some((index, ssim)) = pick_by_score(&scored, target_size_kb, weights) {
+            let size = scored[index].0;
+            let (strategy, result) = qualifying.swap_remove(index);
+            let score = candidate_score(size, ssim, target_size_kb, weights);
+            println!("评分最高的结果: {:.2} KB，SSIM {:.4}，评分 {:.4}", size, ssim, score);
+            if let Some(old_file) = best_file.take() {
+                let _ = old_file.cleanup();
+            }
+            best_size = result.size;
+            best_file = result.file;
+            best_winner = Some(WinningConfig {
+                strategy: Some(strategy),
+                lossy: result.lossy,
+                colors: result.colors,
+                degradation: None,
+                score: Some(WinningScore { score, ssim }),
+                lossy_schedule: None,
+            });
+            found_solution = true;
+        }
+    }
+    
+    // 我们不再等待所有线程完成
+    // 如果已经找到满足条件的结果，其他线程会自动退出
+    // 如果我们想要优雅地等待，可以设置一个超时
+    let time_limited = !found_solution && shared_state.is_budget_truncated();
+    if IN_FLIGHT.is_shutting_down() {
+        // 应用正在退出，不再写出结果，临时文件随之删除
+        return Err(GifError::Cancelled);
+    } else if shared_state.is_cancelled() {
+        println!("已取消，保存取消时最好的结果");
+    } else if found_solution {
+        println!("已找到满足条件的结果，不再等待其他线程");
+    } else if time_limited {
+        println!("搜索预算已用完，使用当前最佳结果，不再等待其他线程");
+        warnings.push(budget_warning(&shared_state));
+    } else {
+        println!("尚未找到满足目标大小的结果，等待所有线程完成...");
+        // 等待所有线程完成
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+    
+    // 被取消或被看门狗终止时各个策略很快带着当时的最佳结果退出，上面已收完它们的结果，
+    // 下面照常写出其中最好的一个，其余的临时文件随之删除
+    
+    if !found_solution {
+        warnings.extend(color_floor_warning(options, &shared_state));
+    }
+    let cut_short = shared_state.cut_short_strategies();
+    if cut_short > 0 {
+        warnings.push(format!("{} 个抽帧策略超过单个策略的时间限制，已提前结束并使用其当时的最佳结果", cut_short));
+    }
+    
+    // 使用找到的最佳文件
+    if let Some(best) = best_file {
+        check_savings(options, original_size, best_size)?;
+        println!("\n复制最佳结果到输出文件...");
+        report_progress(events, &shared_state, ProgressPhase::Finalizing, "复制最佳结果到输出文件", 0.0, Some(format!("{:.2} KB", best_size)));
+        let output_sha256 = write_output(&best.path, &output_path)?;
+        
+        // 复制完成后清理临时文件
+        let _ = best.cleanup();
+        
+        let final_bytes = get_file_size_bytes(&output_path)?;
+        let final_size = bytes_to_kb(final_bytes);
+        println!("完成! 最终大小: {:.2} KB", final_size);
+        
+        // 缩小了画面的策略胜出时报告实际输出的尺寸
+        let scaled = best_winner
+            .as_ref()
+            .and_then(|winner| winner.strategy.as_ref())
+            .is_some_and(|strategy| strategy.scale.is_some());
+        let downscaled_to = if scaled { Some(get_canvas_size(&output_path)?) } else { downscaled_to };
+        
+        return Ok(OptimizeOutcome {
+            original_size,
+            final_size,
+            original_bytes,
+            final_bytes,
+            time_limited,
+            cancelled: shared_state.is_cancelled(),
+            input_sha256,
+            output_sha256,
+            report: shared_state.report(started),
+            downscaled_to,
+            auto_downscaled,
+            warnings,
+            cinemagraph: best_winner
+                .as_ref()
+                .and_then(|winner| winner.strategy.as_ref())
+                .is_some_and(|strategy| strategy.cinemagraph),
+            matte: reported_matte(options, &shared_state, best_winner.as_ref()),
+            winner: best_winner,
+        });
+    } else {
+        return Err(GifError::NoValidResults);
+    }
+}
+
+// 任务类型
+#[derive(Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    // 前端发起的压缩
+    Compress,
+    // 监视的文件变化后自动重新压缩
+    WatchRecompress,
+    // 计算质量曲线
+    QualityCurve,
+    // 计算画质下限内能达到的最小大小
+    QualityFloor,
+}
+
+// 正在运行的任务
+#[derive(Clone, Serialize)]
+pub struct ActiveJob {
+    id: String,
+    kind: JobKind,
+    input_path: String,
+    output_path: Option<String>,
+    // 当前阶段和进度（0-1），与推送给前端的进度消息一致
+    phase: String,
+    progress: f64,
+    // 当前阶段的补充说明，例如已完成的策略数
+    details: Option<String>,
+    elapsed_ms: u64,
+    cancellable: bool,
+    pausable: bool,
+    // 排队等待的任务在队列中的位置，正在运行的任务为空；目前所有任务都立即开始
+    queue_position: Option<usize>,
+}
+
+// 任务的状态
+#[derive(Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+// get_job_status和list_jobs返回的任务记录
+#[derive(Clone, Serialize)]
+pub struct JobRecord {
+    id: String,
+    status: JobStatus,
+    // 运行中任务的阶段和进度，任务刚结束、结果还没记录时也可能为空
+    job: Option<ActiveJob>,
+    // start_compress_job启动的任务结束后的结果
+    result: Option<CompressResult>,
+}
+
+impl JobRecord {
+    fn finished(id: &str, result: &CompressResult) -> Self {
+        let status = if result.cancelled {
+            JobStatus::Cancelled
+        } else if result.success {
+            JobStatus::Succeeded
+        } else {
+            JobStatus::Failed
+        };
+        Self { id: id.to_string(), status, job: None, result: Some(result.clone()) }
+    }
+}
+
+struct JobEntry {
+    job: ActiveJob,
+    started: Instant,
+    last_progress: Instant,
+    // 共用给任务的SharedState，设置后任务中正在运行的gifsicle会被终止
+    cancel: Arc<AtomicBool>,
+    // 被看门狗终止时的诊断信息
+    stalled: Option<String>,
+}
+
+// 默认的无进展超时，超过后任务被看门狗终止
+const DEFAULT_JOB_STALL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// 看门狗检查任务进展的间隔
+const JOB_WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 正在运行的任务登记表，任务结束时立即移除
+///
+/// start_compress_job在后台启动的任务另外记在detached中，结束后保留结果，直到clear_job清除
+struct JobRegistry {
+    entries: std::sync::Mutex<HashMap<String, JobEntry>>,
+    // 按启动顺序排列，结果为空表示仍在运行
+    detached: std::sync::Mutex<Vec<(String, Option<CompressResult>)>>,
+    next_id: AtomicU64,
+    stall_timeout_ms: AtomicU64,
+}
+
+impl JobRegistry {
+    fn new() -> Self {
+        Self {
+            entries: std::sync::Mutex::new(HashMap::new()),
+            detached: std::sync::Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            stall_timeout_ms: AtomicU64::new(DEFAULT_JOB_STALL_TIMEOUT.as_millis() as u64),
+        }
+    }
+    
+    fn set_stall_timeout(&self, timeout: Duration) {
+        self.stall_timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+    
+    fn next_id(&self) -> String {
+        format!("j{}", self.next_id.fetch_add(1, Ordering::SeqCst))
+    }
+    
+    // 登记新任务，返回的JobGuard被丢弃时任务移除
+    fn start(
+        self: &Arc<Self>,
+        kind: JobKind,
+        input_path: &str,
+        output_path: Option<&str>,
+        cancellable: bool,
+    ) -> JobGuard {
+        self.start_as(self.next_id(), kind, input_path, output_path, cancellable)
+    }
+    
+    // 使用预先分配的id登记任务，见detach
+    fn start_as(
+        self: &Arc<Self>,
+        id: String,
+        kind: JobKind,
+        input_path: &str,
+        output_path: Option<&str>,
+        cancellable: bool,
+    ) -> JobGuard {
+        let job = ActiveJob {
+            id: id.clone(),
+            kind,
+            input_path: input_path.to_string(),
+            output_path: output_path.map(str::to_string),
+            phase: "准备中".to_string(),
+            progress: 0.0,
+            details: None,
+            elapsed_ms: 0,
+            cancellable,
+            pausable: false,
+            queue_position: None,
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        let now = Instant::now();
+        let entry = JobEntry { job, started: now, last_progress: now, cancel: Arc::clone(&cancel), stalled: None };
+        self.entries.lock().unwrap().insert(id.clone(), entry);
+        JobGuard { registry: Arc::clone(self), id, cancel }
+    }
+    
+    fn update(&self, id: &str, phase: &str, progress: f64, details: Option<&str>) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.job.phase = phase.to_string();
+            entry.job.progress = progress;
+            entry.job.details = details.map(str::to_string);
+            entry.last_progress = Instant::now();
+        }
+    }
+    
+    // 终止超过超时仍没有进展的任务，返回本次终止的任务数
+    fn abort_stalled(&self) -> usize {
+        let timeout = Duration::from_millis(self.stall_timeout_ms.load(Ordering::Relaxed));
+        let mut aborted = 0;
+        for entry in self.entries.lock().unwrap().values_mut() {
+            let idle = entry.last_progress.elapsed();
+            if entry.stalled.is_some() || idle < timeout {
+                continue;
+            }
+            let diagnostic = format!(
+                "{}秒没有进展，停在「{}」{}（进度 {:.0}%）",
+                idle.as_secs(),
+                entry.job.phase,
+                entry.job.details.as_ref().map_or(String::new(), |details| format!(" {}", details)),
+                entry.job.progress * 100.0
+            );
+            println!("任务 {} {}，强制终止", entry.job.id, diagnostic);
+            entry.cancel.store(true, Ordering::Relaxed);
+            entry.job.phase = "没有进展，正在终止".to_string();
+            entry.stalled = Some(diagnostic);
+            aborted += 1;
+        }
+        aborted
+    }
+    
+    // 取消可以取消的任务：给出id时只取消该任务，否则取消所有该类型的任务，返回本次取消的任务数
+    fn cancel(&self, kind: JobKind, id: Option<&str>) -> usize {
+        let mut cancelled = 0;
+        for entry in self.entries.lock().unwrap().values_mut() {
+            let matches = match id {
+                Some(id) => entry.job.id == id,
+                None => entry.job.kind == kind,
+            };
+            if !matches || !entry.job.cancellable || entry.cancel.swap(true, Ordering::Relaxed) {
+                continue;
+            }
+            entry.job.phase = "正在取消".to_string();
+            cancelled += 1;
+        }
+        cancelled
+    }
+    
+    // 按开始时间排列
+    fn list(&self) -> Vec<ActiveJob> {
+        let entries = self.entries.lock().unwrap();
+        let mut jobs: Vec<(Instant, ActiveJob)> = entries
+            .values()
+            .map(|entry| {
+                let mut job = entry.job.clone();
+                job.elapsed_ms = entry.started.elapsed().as_millis() as u64;
+                (entry.started, job)
+            })
+            .collect();
+        jobs.sort_by_key(|(started, _)| *started);
+        jobs.into_iter().map(|(_, job)| job).collect()
+    }
+    
+    // 为后台任务分配id，任务本身用start_as登记，结束后调用finish_detached
+    fn detach(&self) -> String {
+        let id = self.next_id();
+        self.detached.lock().unwrap().push((id.clone(), None));
+        id
+    }
+    
+    fn finish_detached(&self, id: &str, result: CompressResult) {
+        if let Some((_, slot)) = self.detached.lock().unwrap().iter_mut().find(|(detached, _)| detached == id) {
+            *slot = Some(result);
+        }
+    }
+    
+    fn status(&self, id: &str) -> Option<JobRecord> {
+        let running = self.list().into_iter().find(|job| job.id == id);
+        if let Some(job) = running {
+            return Some(JobRecord { id: id.to_string(), status: JobStatus::Running, job: Some(job), result: None });
+        }
+        let detached = self.detached.lock().unwrap();
+        let (_, result) = detached.iter().find(|(detached, _)| detached == id)?;
+        Some(match result {
+            Some(result) => JobRecord::finished(id, result),
+            None => JobRecord { id: id.to_string(), status: JobStatus::Running, job: None, result: None },
+        })
+    }
+    
+    // 正在运行的任务在前，之后是已结束、尚未清除的后台任务
+    fn records(&self) -> Vec<JobRecord> {
+        let mut records: Vec<JobRecord> = self
+            .list()
+            .into_iter()
+            .map(|job| JobRecord { id: job.id.clone(), status: JobStatus::Running, job: Some(job), result: None })
+            .collect();
+        for (id, result) in self.detached.lock().unwrap().iter() {
+            match result {
+                Some(result) => records.push(JobRecord::finished(id, result)),
+                None if !records.iter().any(|record| &record.id == id) => {
+                    records.push(JobRecord { id: id.clone(), status: JobStatus::Running, job: None, result: None });
+                }
+                None => {}
+            }
+        }
+        records
+    }
+    
+    // 清除已结束的后台任务的结果，任务不存在或仍在运行时返回false
+    fn clear_detached(&self, id: &str) -> bool {
+        let mut detached = self.detached.lock().unwrap();
+        let before = detached.len();
+        detached.retain(|(detached, result)| detached != id || result.is_none());
+        detached.len() != before
+    }
+}
+
+/// 登记中的任务，丢弃时从登记表移除
+struct JobGuard {
+    registry: Arc<JobRegistry>,
+    id: String,
+    cancel: Arc<AtomicBool>,
+}
+
+impl JobGuard {
+    fn update(&self, phase: &str, progress: f64, details: Option<&str>) {
+        self.registry.update(&self.id, phase, progress, details);
+    }
+    
+    // 交给任务的取消标志，看门狗通过它终止任务
+    fn cancel_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
+    }
+    
+    // 被看门狗终止时返回诊断信息
+    fn stalled(&self) -> Option<String> {
+        self.registry.entries.lock().unwrap().get(&self.id).and_then(|entry| entry.stalled.clone())
+    }
+    
+    // 被看门狗终止时把错误换成JobStalled
+    fn map_stalled<T>(&self, result: Result<T, GifError>) -> Result<T, GifError> {
+        result.map_err(|e| self.stalled().map_or(e, GifError::JobStalled))
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.registry.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+// 应用状态管理
+struct AppState {
+    // 保存处理结果
+    last_result: std::sync::Mutex<Option<CompressResult>>,
+    // 启动时通过命令行参数传入、尚未被前端取走的文件
+    opened_files: std::sync::Mutex<Vec<String>>,
+    // 正在监视的输入文件及其停止标志
+    watchers: std::sync::Mutex<HashMap<String, Arc<AtomicBool>>>,
+    // 交给前端、需要跨命令保留的临时文件
+    candidates: CandidateStore,
+    // 已计算的质量曲线探测结果，键为(文件SHA-256, 抽帧间隔)
+    quality_curves: std::sync::Mutex<HashMap<(String, usize), QualityProbes>>,
+    // 正在计算的质量曲线，用于取消
+    quality_curve_job: std::sync::Mutex<Option<Arc<SharedState>>>,
+    // 正在进行的优化手段贡献分析，用于取消
+    lever_analysis_job: std::sync::Mutex<Option<Arc<SharedState>>>,
+    // 正在运行的任务
+    jobs: Arc<JobRegistry>,
+}
+
+// 查找gifsicle可执行文件的辅助函数
+fn find_gifsicle() -> Option<String> {
+    // 常见的gifsicle安装路径
+    let possible_paths = vec![
+        "gifsicle",                    // PATH中的版本
+        "/opt/homebrew/bin/gifsicle",  // M1/M2 Mac的Homebrew路径
+        "/usr/local/bin/gifsicle",     // Intel Mac的Homebrew路径
+        "/usr/bin/gifsicle",           // Linux常见路径
+        "C:\\Program Files\\gifsicle\\gifsicle.exe" // Windows可能路径
+    ];
+
+    println!("DEBUG: 正在查找gifsicle可执行文件...");
+    
+    for path in possible_paths {
+        println!("DEBUG: 尝试路径: {}", path);
+        match Command::new(path).arg("--version").status() {
+            Ok(status) => {
+                println!("DEBUG: 路径 {} 可用，状态: {}", path, status);
+                return Some(path.to_string());
+            },
+            Err(err) => {
+                println!("DEBUG: 路径 {} 不可用: {}", path, err);
+            }
+        }
+    }
+    
+    println!("DEBUG: 未找到gifsicle可执行文件");
+    None
+}
+
+// 查找gif2webp（libwebp的命令行工具）
+fn find_gif2webp() -> Option<String> {
+    let possible_paths = [
+        "gif2webp",
+        "/opt/homebrew/bin/gif2webp",
+        "/usr/local/bin/gif2webp",
+        "/usr/bin/gif2webp",
+        "C:\\Program Files\\libwebp\\bin\\gif2webp.exe",
+    ];
+    possible_paths
+        .into_iter()
+        .find(|path| Command::new(path).arg("-version").output().is_ok())
+        .map(|path| path.to_string())
+}
+
+// 查找gifsicle并获取版本，未找到时结果来自内置的帧差分优化
+fn probe_gifsicle_identity() -> EncoderIdentity {
+    match find_gifsicle() {
+        Some(path) => EncoderIdentity {
+            backend: "gifsicle".to_string(),
+            version: gifsicle_version(&path).map(|(major, minor)| format!("{}.{}", major, minor)),
+            path: Some(path),
+            source: "system".to_string(),
+        },
+        None => EncoderIdentity {
+            backend: "builtin".to_string(),
+            path: None,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            source: "builtin".to_string(),
+        },
+    }
+}
+
+// 查找gif2webp并获取版本，`gif2webp -version`只输出版本号
+fn probe_gif2webp_identity() -> Option<EncoderIdentity> {
+    let path = find_gif2webp()?;
+    let version = Command::new(&path)
+        .arg("-version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).lines().next().map(|line| line.trim().to_string()))
+        .filter(|version| !version.is_empty());
+    Some(EncoderIdentity {
+        backend: "gif2webp".to_string(),
+        path: Some(path),
+        version,
+        source: "system".to_string(),
+    })
+}
+
+// 依赖较新gifsicle版本的功能：(功能名, 对应的选项, 最低版本)
+const VERSION_GATED_FEATURES: &[(&str, &str, (u32, u32))] = &[
+    ("lossy", "--lossy", (1, 92)),
+    ("resize_fit", "--resize-fit", (1, 71)),
+    ("colors", "--colors", (1, 40)),
+    ("use_colormap", "--use-colormap", (1, 40)),
+    ("logical_screen", "--logical-screen", (1, 40)),
+];
+
+// 已安装gifsicle不支持的功能
+#[derive(Clone, Serialize)]
+pub struct UnsupportedFeature {
+    feature: String,
+    option: String,
+    required_version: String,
+}
+
+// gifsicle功能检查结果
+#[derive(Clone, Serialize)]
+pub struct GifsicleFeatureCheck {
+    gifsicle_path: String,
+    version: Option<String>,
+    unsupported: Vec<UnsupportedFeature>,
+}
+
+/// 从`gifsicle --version`的输出中解析版本号，如"LCDF Gifsicle 1.94"
+fn parse_gifsicle_version(output: &str) -> Option<(u32, u32)> {
+    let first_line = output.lines().next()?;
+    let version = first_line.split_whitespace().find(|word| word.starts_with(|c: char| c.is_ascii_digit()))?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor_digits: String = parts.next()?.chars().take_while(|c| c.is_ascii_digit()).collect();
+    Some((major, minor_digits.parse().ok()?))
+}
+
+// 获取gifsicle的版本号
+fn gifsicle_version(gifsicle_path: &str) -> Option<(u32, u32)> {
+    let output = Command::new(gifsicle_path).arg("--version").output().ok()?;
+    parse_gifsicle_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+// 根据压缩参数列出需要用到的、依赖gifsicle版本的功能
+fn required_features(options: &CompressOptions) -> Vec<&'static str> {
+    let degradation_uses = |knob: &str| {
+        options.degradation_order.as_ref().is_some_and(|order| order.iter().any(|name| name == knob))
+    };
+    let mut features = Vec::new();
+    // 除非max_lossy为0，每个抽帧策略都会尝试--lossy
+    if options.max_lossy != Some(0) || options.lossy_schedule.is_some() {
+        features.push("lossy");
+    }
+    if options.max_width.is_some() || options.max_height.is_some() || options.auto_downscale == Some(true) || degradation_uses("resize") {
+        features.push("resize_fit");
+    }
+    if options.max_colors.is_some() || options.bit_depth.is_some() || auto_color_reduction(options) || degradation_uses("colors") {
+        features.push("colors");
+    }
+    if options.palette_file.is_some() {
+        features.push("use_colormap");
+    }
+    if options.even_dimensions {
+        features.push("logical_screen");
+    }
+    features
+}
+
+/// 检查已安装的gifsicle是否支持压缩参数需要的全部功能
+///
+/// 无法识别版本时不做判断，避免误报
+fn check_features(gifsicle_path: &str, version: Option<(u32, u32)>, options: &CompressOptions) -> GifsicleFeatureCheck {
+    let required = required_features(options);
+    let unsupported = match version {
+        Some(version) => VERSION_GATED_FEATURES
+            .iter()
+            .filter(|(feature, _, min_version)| required.contains(feature) && version < *min_version)
+            .map(|(feature, option, (major, minor))| UnsupportedFeature {
+                feature: feature.to_string(),
+                option: option.to_string(),
+                required_version: format!("{}.{}", major, minor),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    
+    GifsicleFeatureCheck {
+        gifsicle_path: gifsicle_path.to_string(),
+        version: version.map(|(major, minor)| format!("{}.{}", major, minor)),
+        unsupported,
+    }
+}
+
+// 检查已安装的gifsicle是否支持所选压缩参数
+#[tauri::command]
+fn check_gifsicle_features(options: CompressOptions) -> Result<GifsicleFeatureCheck, String> {
+    let gifsicle_path = find_gifsicle().ok_or_else(|| GifError::GifsicleNotFound.to_string())?;
+    let version = gifsicle_version(&gifsicle_path);
+    Ok(check_features(&gifsicle_path, version, &options))
+}
+
+// 检查gifsicle是否已安装
+#[tauri::command]
+fn check_gifsicle_installed() -> bool {
+    println!("DEBUG: 直接使用Command::new检查gifsicle是否已安装");
+    // 先尝试简单的PATH检查
+    let result = Command::new("gifsicle").arg("--version").output();
+    let is_installed = result.is_ok();
+    println!("DEBUG: 简单PATH检查 gifsicle已安装: {}", is_installed);
+    
+    if is_installed {
+        true
+    } else {
+        // 如果PATH检查失败，尝试具体路径
+        println!("DEBUG: PATH检查失败，尝试特定路径");
+        find_gifsicle().is_some()
+    }
+}
+
+// 取走启动时传入的文件列表（之后的启动会通过files-opened事件通知）
+#[tauri::command]
+fn take_opened_files(state: State<'_, AppState>) -> Vec<String> {
+    std::mem::take(&mut *state.opened_files.lock().unwrap())
+}
+
+// 并发上限建议
+#[derive(Clone, Serialize)]
+pub struct ConcurrencyLimits {
+    recommended: usize,
+    cpu_count: usize,
+    open_file_limit: Option<u64>,
+    temp_available_bytes: Option<u64>,
+    // 决定上限的资源: "cpu" / "open_files" / "temp_space"
+    limited_by: String,
+}
+
+// 每个策略同时占用的文件句柄数估计（临时文件和gifsicle子进程管道）
+const FDS_PER_STRATEGY: u64 = 8;
+// 为应用本身（窗口、日志等）保留的文件句柄数
+const RESERVED_FDS: u64 = 64;
+// 每个策略占用的临时空间约为输入文件大小的倍数（逐帧展开后的GIF和各lossy候选）
+const TEMP_SPACE_FACTOR: u64 = 4;
+
+// 获取进程的打开文件数软限制，无限制或无法获取时返回None
+#[cfg(unix)]
+fn open_file_limit() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit只会写入传入的rlimit结构体
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    if ret != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+// Windows的句柄数没有实际意义上的低限制
+#[cfg(not(unix))]
+fn open_file_limit() -> Option<u64> {
+    None
+}
+
+/// 根据CPU数、文件句柄限制和临时空间计算建议的最大并发数
+fn recommend_concurrency(
+    cpu_count: usize,
+    open_file_limit: Option<u64>,
+    temp_available_bytes: Option<u64>,
+    input_size_bytes: Option<u64>,
+) -> ConcurrencyLimits {
+    let mut recommended = cpu_count.max(1);
+    let mut limited_by = "cpu";
+    
+    if let Some(limit) = open_file_limit {
+        let by_fds = (limit.saturating_sub(RESERVED_FDS) / FDS_PER_STRATEGY).max(1) as usize;
+        if by_fds < recommended {
+            recommended = by_fds;
+            limited_by = "open_files";
+        }
+    }
+    
+    if let (Some(available), Some(input_size)) = (temp_available_bytes, input_size_bytes) {
+        let per_strategy = input_size.saturating_mul(TEMP_SPACE_FACTOR).max(1);
+        let by_space = (available / per_strategy).max(1) as usize;
+        if by_space < recommended {
+            recommended = by_space;
+            limited_by = "temp_space";
+        }
+    }
+    
+    ConcurrencyLimits {
+        recommended,
+        cpu_count,
+        open_file_limit,
+        temp_available_bytes,
+        limited_by: limited_by.to_string(),
+    }
+}
+
+// 检测当前系统的资源限制
+fn detect_concurrency_limits(input_path: Option<&str>) -> ConcurrencyLimits {
+    let temp_available_bytes = fs2::available_space(std::env::temp_dir()).ok();
+    let input_size_bytes = input_path.and_then(|path| fs::metadata(path).ok()).map(|m| m.len());
+    recommend_concurrency(num_cpus::get(), open_file_limit(), temp_available_bytes, input_size_bytes)
+}
+
+// 获取不会耗尽文件句柄和临时空间的建议并发数
+#[tauri::command]
+fn get_concurrency_limits(input_path: Option<String>) -> ConcurrencyLimits {
+    detect_concurrency_limits(input_path.as_deref())
+}
+
+// 线程数为0时自动选择，受CPU数和系统资源限制
+fn effective_threads(options: &CompressOptions, input_path: &str) -> usize {
+    if options.threads != 0 {
+        return options.threads;
+    }
+    let limits = detect_concurrency_limits(Some(input_path));
+    if limits.limited_by != "cpu" {
+        println!("自动线程数受{}限制，使用 {} 个线程", limits.limited_by, limits.recommended);
+    }
+    limits.recommended
+}
+
+/// 预算用完时给出的提示，说明用掉了多少预算
+fn budget_warning(shared_state: &SharedState) -> String {
+    let budget = shared_state.budget.get().copied();
+    let mut used = Vec::new();
+    if let Some(max_runs) = budget.and_then(|budget| budget.max_gifsicle_runs) {
+        used.push(format!("gifsicle {}/{} 次", shared_state.gifsicle_runs(), max_runs));
+    }
+    if let Some(ms) = budget.and_then(|budget| budget.time_budget_ms) {
+        used.push(format!("时间预算 {} 毫秒", ms));
+    }
+    let prefix = if budget.is_some_and(|budget| budget.safe_mode) { "安全模式的" } else { "" };
+    format!("{}搜索预算已用完（{}），搜索被提前结束，返回的是目前最好的结果", prefix, used.join("，"))
+}
+
+// 根据optimize_gif的返回值生成前端使用的压缩结果
+fn build_compress_result(
+    result: Result<OptimizeOutcome, GifError>,
+    output_path: &str,
+    target_size: f64,
+) -> CompressResult {
+    match result {
+        Ok(OptimizeOutcome {
+            original_size,
+            final_size,
+            original_bytes,
+            final_bytes,
+            time_limited,
+            cancelled,
+            input_sha256,
+            output_sha256,
+            report,
+            downscaled_to,
+            auto_downscaled,
+            warnings,
+            cinemagraph,
+            winner,
+            matte,
+        }) => {
+            let success = !cancelled && final_bytes <= kb_to_bytes(target_size);
+            let mut msg = String::new();
+            if let (true, Some((width, height))) = (auto_downscaled, downscaled_to) {
+                msg.push_str(&format!("画布过大，已自动缩小到 {}x{}；", width, height));
+            }
+            msg.push_str(&if cancelled {
+                format!("已取消，已保存取消时最好的结果: {:.2} KB", final_size)
+            } else if success {
+                format!("成功压缩GIF到目标大小以下，压缩率: {:.1}%", (1.0 - (final_size / original_size)) * 100.0)
+            } else {
+                format!("无法达到目标大小，但已尽可能压缩，压缩率: {:.1}%", (1.0 - (final_size / original_size)) * 100.0)
+            });
+            if time_limited {
+                msg.push_str("（已用完搜索预算，返回当前最佳结果）");
+            }
+            if cinemagraph {
+                msg.push_str("（静帧动图：只保存了每帧变化的区域）");
+            }
+            if report.gifsicle_timeouts > 0 {
+                msg.push_str(&format!("（有 {} 次gifsicle调用超时被终止）", report.gifsicle_timeouts));
+            }
+            let winning_score = winner.as_ref().and_then(|winner| winner.score);
+            let (skip, delay, scale) = winner
+                .as_ref()
+                .and_then(|winner| winner.strategy.as_ref())
+                .map_or((1, 0, None), |strategy| (strategy.skip, strategy.delay, strategy.scale));
+            let lossy = winner.as_ref().and_then(|winner| winner.lossy);
+            let lossy_schedule = winner.as_ref().and_then(|winner| winner.lossy_schedule.clone());
+            if let Some(segments) = &lossy_schedule {
+                msg.push_str(&format!("（按lossy_schedule分 {} 段压缩）", segments.len()));
+            }
+            let degradation_step = winner.and_then(|winner| winner.degradation);
+            if let Some(step) = &degradation_step {
+                msg.push_str(&format!("（按降级顺序用到第 {} 项: {}）", step.depth, step.level));
+            }
+            
+            CompressResult {
+                success,
+                original_size,
+                compressed_size: final_size,
+                original_bytes,
+                compressed_bytes: final_bytes,
+                output_path: output_path.to_string(),
+                message: msg,
+                original_trashed: false,
+                time_limited,
+                input_sha256: Some(input_sha256),
+                output_sha256: Some(output_sha256),
+                report: Some(report),
+                resolved_input_path: None,
+                downscaled_to,
+                auto_downscaled,
+                warnings,
+                icc_profile_dropped: false,
+                cinemagraph,
+                lever_breakdown: None,
+                duration_deviation_percent: None,
+                degradation_step,
+                format_comparison: None,
+                webp_quality: None,
+                bundle: None,
+                max_delay_adjustment_cs: None,
+                skipped: false,
+                cancelled,
+                final_dimensions: None,
+                padded_to_even: false,
+                winning_score,
+                encoder: None,
+                global_table_saved_bytes: None,
+                palette_size: None,
+                quality_caps: None,
+                letterbox: None,
+                lossy_schedule,
+                matte,
+                skip,
+                delay,
+                lossy,
+                scale,
+            }
+        },
+        Err(GifError::InsufficientSavings { original_kb, best_kb, saved_percent, required_percent }) => {
+            let message = format!(
+                "已跳过：只节省了 {:.1}%，低于要求的 {:.1}%，未写出输出文件",
+                saved_percent, required_percent
+            );
+            println!("{}", message);
+            CompressResult {
+                success: false,
+                original_size: original_kb,
+                compressed_size: best_kb,
+                original_bytes: kb_to_bytes(original_kb),
+                compressed_bytes: kb_to_bytes(best_kb),
+                output_path: String::new(),
+                message,
+                original_trashed: false,
+                time_limited: false,
+                input_sha256: None,
+                output_sha256: None,
+                report: None,
+                resolved_input_path: None,
+                downscaled_to: None,
+                auto_downscaled: false,
+                warnings: Vec::new(),
+                icc_profile_dropped: false,
+                cinemagraph: false,
+                lever_breakdown: None,
+                duration_deviation_percent: None,
+                degradation_step: None,
+                format_comparison: None,
+                webp_quality: None,
+                bundle: None,
+                max_delay_adjustment_cs: None,
+                skipped: true,
+                cancelled: false,
+                final_dimensions: None,
+                padded_to_even: false,
+                winning_score: None,
+                encoder: None,
+                global_table_saved_bytes: None,
+                palette_size: None,
+                quality_caps: None,
+                letterbox: None,
+                lossy_schedule: None,
+                matte: None,
+                skip: 1,
+                delay: 0,
+                lossy: None,
+                scale: None,
+            }
+        }
+        Err(e) => {
+            // 取消不是错误，前端据此显示"已取消"；搜索开始后取消时走上面的分支，保存了当时最好的结果
+            let cancelled = matches!(e, GifError::Cancelled);
+            CompressResult {
+                success: false,
+                original_size: 0.0,
+                compressed_size: 0.0,
+                original_bytes: 0,
+                compressed_bytes: 0,
+                output_path: String::new(),
+                message: if cancelled { "已取消，还没有可用的结果，没有写出输出文件".to_string() } else { format!("压缩失败: {}", e) },
+                original_trashed: false,
+                time_limited: false,
+                input_sha256: None,
+                output_sha256: None,
+                report: None,
+                resolved_input_path: None,
+                downscaled_to: None,
+                auto_downscaled: false,
+                warnings: Vec::new(),
+                icc_profile_dropped: false,
+                cinemagraph: false,
+                lever_breakdown: None,
+                duration_deviation_percent: None,
+                degradation_step: None,
+                format_comparison: None,
+                webp_quality: None,
+                bundle: None,
+                max_delay_adjustment_cs: None,
+                skipped: false,
+                cancelled,
+                final_dimensions: None,
+                padded_to_even: false,
+                winning_score: None,
+                encoder: None,
+                global_table_saved_bytes: None,
+                palette_size: None,
+                quality_caps: None,
+                letterbox: None,
+                lossy_schedule: None,
+                matte: None,
+                skip: 1,
+                delay: 0,
+                lossy: None,
+                scale: None,
+            }
+        }
+    }
+}
+
+/// 解析输入路径中的符号链接，得到实际要处理的文件
+///
+/// 断开的符号链接会在错误信息中同时给出链接和它指向的目标
+fn resolve_input_path<P: AsRef<Path>>(path: P) -> Result<PathBuf, GifError> {
+    let path = path.as_ref();
+    dunce::canonicalize(path).map_err(|_| match fs::read_link(path) {
+        Ok(target) => GifError::InputFileNotFound(format!(
+            "{} -> {}（链接指向的文件不存在）",
+            path.display(),
+            target.display()
+        )),
+        Err(_) => GifError::InputFileNotFound(path.display().to_string()),
+    })
+}
+
+/// 解析输出路径中的符号链接
+///
+/// 输出文件通常还不存在，此时解析它所在的目录再拼上文件名
+fn resolve_output_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    if let Ok(resolved) = dunce::canonicalize(path) {
+        return resolved;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            dunce::canonicalize(parent)
+                .map(|parent| parent.join(name))
+                .unwrap_or_else(|_| path.to_path_buf())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+// 比较两个路径是否指向同一个文件（按解析符号链接后的真实路径比较）
+fn is_same_file<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> bool {
+    let a = resolve_input_path(&a).unwrap_or_else(|_| resolve_output_path(&a));
+    let b = resolve_input_path(&b).unwrap_or_else(|_| resolve_output_path(&b));
+    a == b
+}
+
+// 检查输出文件是否写入完整并能正常解码
+fn verify_gif_output<P: AsRef<Path>>(path: P) -> bool {
+    get_frame_count(path).map(|count| count > 0).unwrap_or(false)
+}
+
+/// 把文件移到系统回收站
+///
+/// 不支持回收站的卷（部分网络共享）会返回错误，调用方只提示警告，绝不退化为直接删除
+fn move_to_trash<P: AsRef<Path>>(path: P) -> Result<(), String> {
+    trash::delete(path.as_ref()).map_err(|e| format!("无法移到回收站，原文件已保留: {}", e))
+}
+
+// 确认输出有效且与输入不是同一个文件后，再把原文件移到回收站
+fn trash_original_after_compress(input_path: &str, output_path: &str) -> Result<(), String> {
+    if is_same_file(input_path, output_path) {
+        return Err("输出与原文件相同，未移到回收站".to_string());
+    }
+    if !verify_gif_output(output_path) {
+        return Err("输出文件校验失败，原文件已保留".to_string());
+    }
+    move_to_trash(input_path)
+}
+
+// 单独把文件移到回收站的结果
+#[derive(Clone, Serialize)]
+pub struct TrashResult {
+    trashed: bool,
+    message: String,
+}
+
+// 把文件移到系统回收站
+#[tauri::command]
+fn trash_file(path: String) -> Result<TrashResult, String> {
+    if !Path::new(&path).exists() {
+        return Err(GifError::InputFileNotFound(path).to_string());
+    }
+    
+    Ok(match move_to_trash(&path) {
+        Ok(()) => TrashResult { trashed: true, message: format!("已移到回收站: {}", path) },
+        Err(warning) => {
+            println!("警告: {}", warning);
+            TrashResult { trashed: false, message: warning }
+        }
+    })
+}
+
+// 通过on_progress发送的一条消息，coalesce为true的中间进度可以被后来的进度替换
+struct QueuedProgress {
+    progress: CompressProgress,
+    coalesce: bool,
+}
+
+// 待发送的消息和是否已关闭，以及有新消息时用来唤醒发送线程的条件变量
+type ProgressQueue = (std::sync::Mutex<(VecDeque<QueuedProgress>, bool)>, Condvar);
+
+/// 把一个任务的进度按顺序推送到调用方传入的Channel或事件
+///
+/// 发送在单独的线程中进行，前端接收得慢时还没发出的中间进度只保留最新一条，不会拖慢压缩；
+/// 开始、结束和候选结果消息不会被丢弃。最后一个引用释放时发完剩余消息
+struct ProgressChannel {
+    queue: Arc<ProgressQueue>,
+    // 最近一次进度的数值和计数，候选结果和结束消息沿用它，保证计数不减少
+    last: std::sync::Mutex<(f64, SearchCounters, Option<ProgressPhase>)>,
+    // 发出的进度映射到的区间（起点, 长度）；批量压缩时是当前文件占用的一段，保证整体进度不回退
+    span: std::sync::Mutex<(f64, f64)>,
+    sender: Option<thread::JoinHandle<()>>,
+}
+
+impl ProgressChannel {
+    fn new(channel: Channel<CompressProgress>) -> Self {
+        Self::with_sink(move |progress| channel.send(progress).map_err(|e| e.to_string()))
+    }
+    
+    // sink在发送线程中依次收到每条消息
+    fn with_sink<F: Fn(CompressProgress) -> Result<(), String> + Send + 'static>(sink: F) -> Self {
+        let queue = Arc::new((std::sync::Mutex::new((VecDeque::<QueuedProgress>::new(), false)), Condvar::new()));
+        let sender_queue = Arc::clone(&queue);
+        let sender = thread::spawn(move || loop {
+            let next = {
+                let (lock, ready) = &*sender_queue;
+                let mut guard = lock.lock().unwrap();
+                loop {
+                    if let Some(next) = guard.0.pop_front() {
+                        break next;
+                    }
+                    if guard.1 {
+                        return;
+                    }
+                    guard = ready.wait(guard).unwrap();
+                }
+            };
+            if let Err(e) = sink(next.progress) {
+                println!("推送压缩进度失败: {}", e);
+            }
+        });
+        Self {
+            queue,
+            last: std::sync::Mutex::new((0.0, SearchCounters::default(), None)),
+            span: std::sync::Mutex::new((0.0, 1.0)),
+            sender: Some(sender),
+        }
+    }
+    
+    // 之后的进度p按start + p * length发出
+    fn set_span(&self, start: f64, length: f64) {
+        *self.span.lock().unwrap() = (start, length);
+    }
+    
+    fn push(&self, progress: CompressProgress, coalesce: bool) {
+        let (start, length) = *self.span.lock().unwrap();
+        let progress = CompressProgress { progress: start + progress.progress * length, ..progress };
+        let (lock, ready) = &*self.queue;
+        let mut guard = lock.lock().unwrap();
+        match guard.0.back_mut() {
+            // 合并进度时保留被替换的消息中的候选结果
+            Some(last) if coalesce && last.coalesce => {
+                let mut progress = progress;
+                let mut attempts = std::mem::take(&mut last.progress.new_attempts);
+                attempts.append(&mut progress.new_attempts);
+                progress.new_attempts = attempts;
+                *last = QueuedProgress { progress, coalesce };
+            }
+            _ => guard.0.push_back(QueuedProgress { progress, coalesce }),
+        }
+        drop(guard);
+        ready.notify_one();
+    }
+    
+    // 发送不可合并的开始或结束消息
+    fn lifecycle(&self, status: &str, progress: f64, details: Option<String>) {
+        self.lifecycle_with_result(status, progress, details, None);
+    }
+    
+    fn lifecycle_with_result(&self, status: &str, progress: f64, details: Option<String>, item_result: Option<CompressResult>) {
+        let counters = self.last.lock().unwrap().1;
+        self.push(CompressProgress {
+            status: status.to_string(),
+            progress,
+            details,
+            phase: None,
+            eta_seconds: None,
+            counters,
+            new_attempts: Vec::new(),
+            item_result: item_result.map(Box::new),
+        }, false);
+    }
+    
+    // 转发optimize_gif报告的事件，候选结果只发送大小，文件在回调结束后可能被删除
+    fn handle(&self, event: &OptimizeEvent<'_>) {
+        match event {
+            OptimizeEvent::Progress(progress) => {
+                *self.last.lock().unwrap() = (progress.progress, progress.counters, progress.phase);
+                self.push(progress.clone(), true);
+            }
+            OptimizeEvent::Intermediate { size_kb, .. } => {
+                let (progress, counters, phase) = *self.last.lock().unwrap();
+                self.push(CompressProgress {
+                    status: "找到更小的结果".to_string(),
+                    progress,
+                    details: Some(format!("{:.2} KB", size_kb)),
+                    phase,
+                    eta_seconds: None,
+                    counters,
+                    new_attempts: Vec::new(),
+                    item_result: None,
+                }, false);
+            }
+        }
+    }
+}
+
+impl Drop for ProgressChannel {
+    fn drop(&mut self) {
+        self.queue.0.lock().unwrap().1 = true;
+        self.queue.1.notify_one();
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+    }
+}
+
+// 压缩GIF文件
+//
+// 这个任务的进度、候选结果和开始/结束消息按顺序作为compress-progress事件发给调用的窗口，
+// 传入on_progress时同时发送到它；搜索中测得的每个候选大小另外作为compress-attempt事件发出
+#[tauri::command]
+async fn compress_gif(
+    window: tauri::Window,
+    state: State<'_, AppState>,
+    input_path: String, 
+    output_path: String,
+    options: CompressOptions,
+    on_progress: Option<Channel<CompressProgress>>,
+) -> Result<CompressResult, String> {
+    let progress = Arc::new(ProgressChannel::with_sink(move |progress| {
+        if let Some(channel) = &on_progress {
+            channel.send(progress.clone()).map_err(|e| e.to_string())?;
+        }
+        for attempt in &progress.new_attempts {
+            window.emit("compress-attempt", attempt.clone()).map_err(|e| e.to_string())?;
+        }
+        window.emit("compress-progress", progress).map_err(|e| e.to_string())
+    }));
+    run_compress(state, input_path, output_path, options, None, Some(progress), None).await
+}
+
+// 下载远程GIF的大小上限
+const MAX_DOWNLOAD_BYTES: u64 = 100 * 1024 * 1024;
+// 下载时最多跟随的重定向次数
+const MAX_DOWNLOAD_REDIRECTS: usize = 5;
+// 连续这么久没有收到数据时放弃下载
+const DOWNLOAD_READ_TIMEOUT: Duration = Duration::from_secs(30);
+// 整个下载过程的最长时间
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+fn is_http_scheme(url: &reqwest::Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+}
+
+/// 下载http(s)地址的GIF，下载进度记录在job中，job被取消时中止下载并删除已下载的部分
+async fn download_gif(url: &str, max_bytes: u64, job: &JobGuard) -> Result<TempFile, GifError> {
+    let cancel = job.cancel_flag();
+    let cancelled = async {
+        while !cancel.load(Ordering::Relaxed) {
+            tokio::time::sleep(CHILD_POLL_INTERVAL).await;
+        }
+    };
+    let result = tokio::select! {
+        result = fetch_gif(url, max_bytes, job) => result,
+        _ = cancelled => Err(GifError::Cancelled),
+    };
+    job.map_stalled(result)
+}
+
+/// 把http(s)地址的GIF边下载边写入临时文件，超过max_bytes时中止
+///
+/// 服务器声明的类型不是GIF、或内容不以GIF文件头开始时返回错误
+async fn fetch_gif(url: &str, max_bytes: u64, job: &JobGuard) -> Result<TempFile, GifError> {
+    let url = reqwest::Url::parse(url).map_err(|e| GifError::Download(format!("地址无效: {}", e)))?;
+    if !is_http_scheme(&url) {
+        return Err(GifError::Download(format!("只支持http和https地址: {}", url)));
+    }
+    
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_DOWNLOAD_REDIRECTS {
+                attempt.error("重定向次数过多")
+            } else if !is_http_scheme(attempt.url()) {
+                attempt.error("重定向到了非http(s)地址")
+            } else {
+                attempt.follow()
+            }
+        }))
+        .connect_timeout(Duration::from_secs(15))
+        .read_timeout(DOWNLOAD_READ_TIMEOUT)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| GifError::Download(e.to_string()))?;
+    let mut response = client.get(url).send().await.map_err(|e| GifError::Download(format!("请求失败: {}", e)))?;
+    if !response.status().is_success() {
+        return Err(GifError::Download(format!("服务器返回 {}", response.status())));
+    }
+    
+    // 有的服务器不声明类型或只声明为二进制流，这种情况靠文件头判断
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+    match content_type.as_deref() {
+        None | Some("image/gif") | Some("application/octet-stream") => {}
+        Some(other) => return Err(GifError::Download(format!("内容不是GIF（{}）", other))),
+    }
+    if let Some(length) = response.content_length().filter(|&length| length > max_bytes) {
+        return Err(GifError::Download(format!("文件过大: {:.1} MB，上限 {:.1} MB", length as f64 / 1048576.0, max_bytes as f64 / 1048576.0)));
+    }
+    
+    let download = TempFile::new(tempfile::Builder::new().prefix("gif_download_").suffix(".gif").tempfile()?);
+    let mut writer = BufWriter::new(File::create(&download.path)?);
+    let mut received = 0u64;
+    while let Some(chunk) = response.chunk().await.map_err(|e| GifError::Download(format!("下载中断: {}", e)))? {
+        received += chunk.len() as u64;
+        if received > max_bytes {
+            return Err(GifError::Download(format!("文件超过 {:.1} MB 上限", max_bytes as f64 / 1048576.0)));
+        }
+        writer.write_all(&chunk)?;
+        job.update("正在下载", 0.0, Some(&format!("{:.2} KB", received as f64 / 1024.0)));
+    }
+    writer.flush()?;
+    drop(writer);
+    
+    let mut header = [0u8; 6];
+    let is_gif = File::open(&download.path)?.read_exact(&mut header).is_ok() && (&header == b"GIF87a" || &header == b"GIF89a");
+    if !is_gif {
+        return Err(GifError::Download("下载的内容不是GIF".to_string()));
+    }
+    println!("已下载 {:.2} KB 到 {}", received as f64 / 1024.0, download.path.display());
+    Ok(download)
+}
+
+// 下载http(s)地址的GIF并压缩，下载的临时文件在返回前删除
+#[tauri::command]
+async fn compress_gif_url(
+    state: State<'_, AppState>,
+    url: String,
+    output_path: String,
+    options: CompressOptions,
+) -> Result<CompressResult, String> {
+    // 下载期间单独登记为压缩任务，cancel_compress和看门狗都能中止下载
+    let job = state.jobs.start(JobKind::Compress, &url, Some(&output_path), true);
+    let download = download_gif(&url, MAX_DOWNLOAD_BYTES, &job).await.map_err(|e| e.to_string())?;
+    drop(job);
+    let mut options = options;
+    // 原文件是下载的临时文件，不需要移到回收站
+    options.trash_original = false;
+    let mut result = run_compress(state, download.path_str(), output_path, options, None, None, None).await?;
+    result.resolved_input_path = Some(url);
+    Ok(result)
+}
+
+/// 依次压缩多个文件
+///
+/// 每个文件开始时发送batch-item-started事件，完成后立即发送batch-item-complete事件，全部完成后才返回汇总，
+/// 前端可以在汇总返回前逐个显示结果；传入on_progress时改为把所有文件的进度和开始、完成消息按顺序发送到它
+///
+/// 单个文件失败时该项的success为false，其余文件继续处理
+#[tauri::command]
+async fn compress_batch(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    items: Vec<BatchItem>,
+    options: CompressOptions,
+    on_progress: Option<Channel<CompressProgress>>,
+) -> Result<BatchResult, String> {
+    let progress = on_progress.map(|channel| Arc::new(ProgressChannel::new(channel)));
+    let notify = |event: BatchEvent| match (&progress, event) {
+        (Some(progress), event) => notify_batch_channel(progress, event),
+        (None, BatchEvent::Started(started)) => {
+            let _ = app.emit("batch-item-started", started);
+        }
+        (None, BatchEvent::Complete(complete)) => {
+            let _ = app.emit("batch-item-complete", complete);
+        }
+    };
+    let compress = |item: &BatchItem| run_compress(state.clone(), item.input_path.clone(), item.output_path.clone(), options.clone(), None, progress.clone(), None);
+    Ok(run_batch(items, options.target_size, compress, notify).await)
+}
+
+/// 按(输入路径, 输出路径)依次压缩多个文件，只返回各文件的结果
+///
+/// 与compress_batch相同，保留给按这种参数调用的前端；单个文件失败时该项的success为false，其余文件继续处理
+#[tauri::command]
+async fn compress_gif_batch(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    items: Vec<(String, String)>,
+    options: CompressOptions,
+    on_progress: Option<Channel<CompressProgress>>,
+) -> Result<Vec<CompressResult>, String> {
+    let batch = compress_batch(app, state, batch_items(items), options, on_progress).await?;
+    Ok(batch.results)
+}
+
+fn batch_items(pairs: Vec<(String, String)>) -> Vec<BatchItem> {
+    pairs
+        .into_iter()
+        .map(|(input_path, output_path)| BatchItem { input_path, output_path })
+        .collect()
+}
+
+/// 把批量压缩的开始和完成消息发到on_progress
+///
+/// 处理第index个文件期间它自己的进度映射到(index + p) / total，整体进度不回退；完成消息带有该文件的结果
+fn notify_batch_channel(progress: &ProgressChannel, event: BatchEvent) {
+    match event {
+        BatchEvent::Started(started) => {
+            let total = started.total as f64;
+            progress.lifecycle(
+                &format!("正在处理 {}/{}", started.index + 1, started.total),
+                started.index as f64 / total,
+                Some(started.input_path),
+            );
+            progress.set_span(started.index as f64 / total, 1.0 / total);
+        }
+        BatchEvent::Complete(complete) => {
+            progress.set_span(0.0, 1.0);
+            let complete = *complete;
+            progress.lifecycle_with_result(
+                &format!("批量压缩 {}/{} 完成", complete.index + 1, complete.total),
+                (complete.index + 1) as f64 / complete.total as f64,
+                Some(format!("{}: {}", complete.input_path, complete.result.message)),
+                Some(complete.result),
+            );
+        }
+    }
+}
+
+// 依次处理批量中的每个文件，每个文件完成后立即通知，全部完成后返回汇总
+async fn run_batch<F, Fut>(items: Vec<BatchItem>, target_size: f64, mut compress: F, notify: impl Fn(BatchEvent)) -> BatchResult
+where
+    F: FnMut(&BatchItem) -> Fut,
+    Fut: std::future::Future<Output = Result<CompressResult, String>>,
+{
+    let total = items.len();
+    let mut results = Vec::with_capacity(total);
+    for (index, item) in items.into_iter().enumerate() {
+        println!("批量压缩 {}: {}", index + 1, item.input_path);
+        notify(BatchEvent::Started(BatchItemStarted {
+            index,
+            total,
+            input_path: item.input_path.clone(),
+        }));
+        let result = match compress(&item).await {
+            Ok(result) => result,
+            Err(e) => build_compress_result(Err(GifError::Other(e)), &item.output_path, target_size),
+        };
+        notify(BatchEvent::Complete(Box::new(BatchItemComplete {
+            index,
+            total,
+            input_path: item.input_path,
+            result: result.clone(),
+        })));
+        results.push(result);
+    }
+    
+    let succeeded = results.iter().filter(|result| result.success).count();
+    let mut by_encoder = HashMap::new();
+    for encoder in results.iter().filter_map(|result| result.encoder.as_ref()) {
+        *by_encoder.entry(encoder.label()).or_insert(0) += 1;
+    }
+    BatchResult {
+        by_encoder,
+        failed: results.len() - succeeded,
+        succeeded,
+        results,
+    }
+}
+
+/// 向前端推送压缩过程，保证最终结果只发送一次且之后不再发送消息
+struct CompressStream {
+    channel: Channel<CompressStreamMessage>,
+    closed: AtomicBool,
+    // 最近一个中间结果的预览文件，被替换或流结束时删除
+    preview: std::sync::Mutex<Option<TempFile>>,
+}
+
+impl CompressStream {
+    fn new(channel: Channel<CompressStreamMessage>) -> Self {
+        Self {
+            channel,
+            closed: AtomicBool::new(false),
+            preview: std::sync::Mutex::new(None),
+        }
+    }
+    
+    fn send(&self, message: CompressStreamMessage) {
+        if !self.closed.load(Ordering::SeqCst) {
+            if let Err(e) = self.channel.send(message) {
+                println!("推送压缩进度失败: {}", e);
+            }
+        }
+    }
+    
+    // 处理optimize_gif报告的事件，中间结果先复制成预览文件，原文件随后可能被删除
+    fn handle(&self, event: OptimizeEvent<'_>) {
+        match event {
+            OptimizeEvent::Progress(progress) => self.send(CompressStreamMessage::Progress(progress)),
+            OptimizeEvent::Intermediate { path, size_kb } => {
+                let preview = tempfile::Builder::new()
+                    .prefix("gif_preview_")
+                    .suffix(".gif")
+                    .tempfile()
+                    .map(TempFile::new)
+                    .and_then(|preview| fs::copy(path, &preview.path).map(|_| preview));
+                match preview {
+                    Ok(preview) => {
+                        let preview_path = preview.path_str();
+                        *self.preview.lock().unwrap() = Some(preview);
+                        self.send(CompressStreamMessage::IntermediateResult { preview_path, size_kb });
+                    }
+                    Err(e) => println!("无法生成中间结果预览: {}", e),
+                }
+            }
+        }
+    }
+    
+    // 关闭并删除预览文件，返回是否是第一次关闭
+    fn close(&self) -> bool {
+        let first = !self.closed.swap(true, Ordering::SeqCst);
+        if first {
+            self.preview.lock().unwrap().take();
+        }
+        first
+    }
+    
+    // 发送最终结果并关闭，重复调用时忽略
+    fn finish(&self, result: CompressResult) {
+        if self.close() {
+            if let Err(e) = self.channel.send(CompressStreamMessage::Final(Box::new(result))) {
+                println!("推送压缩结果失败: {}", e);
+            }
+        }
+    }
+}
+
+// 压缩GIF文件，通过Channel按顺序推送进度、中间结果和最终结果
+#[tauri::command]
+async fn compress_gif_stream(
+    state: State<'_, AppState>,
+    input_path: String,
+    output_path: String,
+    options: CompressOptions,
+    on_event: Channel<CompressStreamMessage>,
+) -> Result<(), String> {
+    let stream = Arc::new(CompressStream::new(on_event));
+    match run_compress(state, input_path, output_path, options, Some(Arc::clone(&stream)), None, None).await {
+        Ok(result) => {
+            stream.finish(result);
+            Ok(())
+        }
+        Err(e) => {
+            // 出错时命令本身返回错误，不再发送最终结果
+            stream.close();
+            Err(e)
+        }
+    }
+}
+
+// compress_gif_to_bytes直接返回的输出大小上限，更大的结果应写到文件
+const MAX_INLINE_OUTPUT_BYTES: u64 = 50 * 1024 * 1024;
+
+// 读入要直接返回的输出，超过max_bytes时提示改用写文件的命令
+fn read_inline_output(path: &Path, max_bytes: u64) -> Result<Vec<u8>, String> {
+    let size = fs::metadata(path).map_err(|e| e.to_string())?.len();
+    if size > max_bytes {
+        return Err(format!(
+            "输出 {:.1} MB 超过直接返回的上限 {:.0} MB，请使用compress_gif写到文件",
+            size as f64 / 1048576.0,
+            max_bytes as f64 / 1048576.0
+        ));
+    }
+    fs::read(path).map_err(|e| e.to_string())
+}
+
+// 压缩GIF但不写到用户指定的文件，输出内容作为二进制响应直接返回
+//
+// 压缩结果通过on_result在命令返回之前发送；输出先写到临时文件，读入内存后删除
+#[tauri::command]
+async fn compress_gif_to_bytes(
+    state: State<'_, AppState>,
+    input_path: String,
+    options: CompressOptions,
+    on_result: Channel<CompressResult>,
+) -> Result<Response, String> {
+    let suffix = if options.output_format == OutputFormat::Bundle { ".zip" } else { ".gif" };
+    // 自动选择格式或输出WebP时WebP写在同名的.webp文件中，放在单独的临时目录里，不会与其他文件重名，返回前整个删除
+    let output_dir = tempfile::Builder::new().prefix("gif_bytes_").tempdir().map_err(|e| e.to_string())?;
+    let _tracked_dir = TrackedTempDir::new(output_dir.path());
+    let output = output_dir.path().join(format!("output{}", suffix));
+    
+    let result = run_compress(state, input_path, output.to_string_lossy().to_string(), options, None, None, None).await?;
+    if result.output_sha256.is_none() {
+        return Err(result.message);
+    }
+    let data = read_inline_output(Path::new(&result.output_path), MAX_INLINE_OUTPUT_BYTES)?;
+    
+    if let Err(e) = on_result.send(result) {
+        println!("发送压缩结果失败: {}", e);
+    }
+    Ok(Response::new(data))
+}
+
+// compress_gif_from_bytes的其他参数，以JSON放在请求头中
+#[derive(Deserialize)]
+struct FromBytesArgs {
+    output_path: String,
+    options: CompressOptions,
+}
+
+// 存放compress_gif_from_bytes参数的请求头，内容需经过encodeURIComponent编码
+const FROM_BYTES_ARGS_HEADER: &str = "x-compress-args";
+
+/// 解码encodeURIComponent编码的文本
+fn percent_decode(encoded: &str) -> Result<String, GifError> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = encoded
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| GifError::InvalidOption(format!("{}编码无效", FROM_BYTES_ARGS_HEADER)))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| GifError::InvalidOption(format!("{}不是UTF-8文本", FROM_BYTES_ARGS_HEADER)))
+}
+
+/// 把前端传来的GIF内容写到临时文件，内容不以GIF文件头开始时返回错误
+fn write_input_bytes(data: &[u8]) -> Result<TempFile, GifError> {
+    if !(data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")) {
+        return Err(GifError::Other("传入的内容不是GIF".to_string()));
+    }
+    let input = TempFile::new(tempfile::Builder::new().prefix("gif_input_").suffix(".gif").tempfile()?);
+    fs::write(&input.path, data)?;
+    Ok(input)
+}
+
+// 压缩前端直接传来的GIF内容，请求体是原始二进制，输出路径和选项放在x-compress-args请求头中
+//
+// 输入先写到临时文件，压缩结束后删除
+#[tauri::command]
+async fn compress_gif_from_bytes(state: State<'_, AppState>, request: Request<'_>) -> Result<CompressResult, String> {
+    let data = match request.body() {
+        InvokeBody::Raw(data) => data,
+        InvokeBody::Json(_) => return Err("请求体必须是二进制的GIF内容".to_string()),
+    };
+    let args = request
+        .headers()
+        .get(FROM_BYTES_ARGS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| format!("缺少{}请求头", FROM_BYTES_ARGS_HEADER))?;
+    let args: FromBytesArgs = serde_json::from_str(&percent_decode(args).map_err(|e| e.to_string())?)
+        .map_err(|e| format!("{}无效: {}", FROM_BYTES_ARGS_HEADER, e))?;
+    
+    let input = write_input_bytes(data).map_err(|e| e.to_string())?;
+    println!("收到 {:.2} KB 的GIF内容", data.len() as f64 / 1024.0);
+    let mut options = args.options;
+    // 原文件只是临时文件，不需要移到回收站
+    options.trash_original = false;
+    let mut result = run_compress(state, input.path_str(), args.output_path, options, None, None, None).await?;
+    result.resolved_input_path = None;
+    Ok(result)
+}
+
+// 会被误认为其他格式的扩展名，出现在输出文件名末尾时视为冲突
+const CONFLICTING_EXTENSIONS: &[&str] = &[
+    "png", "apng", "jpg", "jpeg", "webp", "avif", "bmp", "tif", "tiff", "mp4", "webm", "mov",
+];
+
+/// 设置了target_like时读取参考文件的大小，作为目标大小
+///
+/// 没有target_like时target_size必须为正数，前端不传时默认的0不是有效的目标
+fn apply_target_like(options: &mut CompressOptions) -> Result<(), GifError> {
+    let reference = match &options.target_like {
+        Some(reference) => reference,
+        None if options.target_size.is_finite() && options.target_size > 0.0 => return Ok(()),
+        None => {
+            return Err(GifError::InvalidOption(format!(
+                "目标大小 {} KB 无效，请指定大于0的target_size或target_like",
+                options.target_size
+            )))
+        }
+    };
+    let real_reference = resolve_input_path(reference)
+        .map_err(|_| GifError::Other(format!("参考文件不存在: {}", reference)))?;
+    File::open(&real_reference).map_err(|e| GifError::Other(format!("无法读取参考文件 {}: {}", reference, e)))?;
+    let size = get_file_size_kb(&real_reference)?;
+    if size <= 0.0 {
+        return Err(GifError::Other(format!("参考文件为空: {}", reference)));
+    }
+    println!("以参考文件 {} 的大小 {:.2} KB 作为目标", reference, size);
+    options.target_size = size;
+    Ok(())
+}
+
+// bit_depth允许的取值，GIF颜色表最少2种、最多256种颜色
+const BIT_DEPTH_RANGE: std::ops::RangeInclusive<u8> = 1..=8;
+
+/// 位深对应的颜色数
+fn bit_depth_colors(bits: u8) -> Result<u16, GifError> {
+    if !BIT_DEPTH_RANGE.contains(&bits) {
+        return Err(GifError::InvalidOption(format!("位深 {} 超出范围，必须在1到8之间", bits)));
+    }
+    Ok(1 << bits)
+}
+
+/// 把bit_depth换成对应的max_colors，两者同时指定且不一致时返回错误
+fn apply_bit_depth(options: &mut CompressOptions) -> Result<(), GifError> {
+    let bits = match options.bit_depth {
+        Some(bits) => bits,
+        None => return Ok(()),
+    };
+    let colors = bit_depth_colors(bits)?;
+    if let Some(max_colors) = options.max_colors.filter(|&max_colors| max_colors != colors) {
+        return Err(GifError::InvalidOption(format!("位深 {} 对应 {} 种颜色，与max_colors {} 冲突", bits, colors, max_colors)));
+    }
+    println!("按位深 {} 统一使用 {} 种颜色", bits, colors);
+    options.max_colors = Some(colors);
+    Ok(())
+}
+
+// 画质等级对应的参数
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+pub struct QualityCaps {
+    level: u8,
+    max_lossy: u32,
+    min_frame_percent: u32,
+    min_colors: u16,
+    // 画布相对目标大小过大时是否允许自动缩小
+    allow_resize: bool,
+}
+
+// 画质等级1-10依次对应的(max_lossy, min_frame_percent, min_colors, allow_resize)
+//
+// 等级  max_lossy  保留帧  最少颜色  自动缩小
+//   1     240       10%       4        是
+//   2     210       20%       8        是
+//   3     180       30%      16        是
+//   4     150       40%      16        是
+//   5     120       50%      32        是
+//   6      90       60%      32        否
+//   7      60       70%      64        否
+//   8      30       80%     128        否
+//   9      30       90%     128        否
+//  10       0      100%     256        否
+const QUALITY_LEVEL_TABLE: [(u32, u32, u16, bool); 10] = [
+    (240, 10, 4, true),
+    (210, 20, 8, true),
+    (180, 30, 16, true),
+    (150, 40, 16, true),
+    (120, 50, 32, true),
+    (90, 60, 32, false),
+    (60, 70, 64, false),
+    (30, 80, 128, false),
+    (30, 90, 128, false),
+    (0, 100, 256, false),
+];
+
+/// 画质等级对应的搜索上限
+fn quality_caps(level: u8) -> Result<QualityCaps, GifError> {
+    let (max_lossy, min_frame_percent, min_colors, allow_resize) = *level
+        .checked_sub(1)
+        .and_then(|index| QUALITY_LEVEL_TABLE.get(index as usize))
+        .ok_or_else(|| GifError::InvalidOption(format!("画质等级 {} 超出范围，必须在1到10之间", level)))?;
+    Ok(QualityCaps { level, max_lossy, min_frame_percent, min_colors, allow_resize })
+}
+
+/// 按quality_level改写对应的参数，返回使用的参数
+///
+/// 同时明确指定了与之不同的max_lossy、min_frame_percent、min_colors或auto_downscale时返回错误
+fn apply_quality_level(options: &mut CompressOptions) -> Result<Option<QualityCaps>, GifError> {
+    let caps = match options.quality_level {
+        Some(level) => quality_caps(level)?,
+        None => return Ok(None),
+    };
+    let mut conflicts = Vec::new();
+    if options.max_lossy.is_some_and(|max_lossy| max_lossy != caps.max_lossy) {
+        conflicts.push("max_lossy");
+    }
+    if options.min_frame_percent.is_some_and(|percent| percent != caps.min_frame_percent) {
+        conflicts.push("min_frame_percent");
+    }
+    if options.min_colors.is_some() && options.min_colors != Some(caps.min_colors) {
+        conflicts.push("min_colors");
+    }
+    if options.auto_downscale.is_some_and(|allow| allow != caps.allow_resize) {
+        conflicts.push("auto_downscale");
+    }
+    if !conflicts.is_empty() {
+        return Err(GifError::InvalidOption(format!("quality_level不能与{}同时指定", conflicts.join("、"))));
+    }
+    
+    println!(
+        "画质等级 {}: max_lossy={}, min_frame_percent={}, min_colors={}, 自动缩小{}",
+        caps.level,
+        caps.max_lossy,
+        caps.min_frame_percent,
+        caps.min_colors,
+        if caps.allow_resize { "允许" } else { "不允许" }
+    );
+    options.max_lossy = Some(caps.max_lossy);
+    options.min_frame_percent = Some(caps.min_frame_percent);
+    options.min_colors = Some(caps.min_colors);
+    options.auto_downscale = Some(caps.allow_resize);
+    // 画质等级的颜色数下限需要自动减色才起作用，明确关闭时保持关闭
+    options.auto_reduce_colors.get_or_insert(true);
+    Ok(Some(caps))
+}
+
+/// 规范输出文件的扩展名，expected为输出格式的小写扩展名，返回规范后的路径和需要提示用户的信息
+///
+/// 没有可识别的扩展名时追加expected；大小写不同的写法保留但给出提示；
+/// 其他格式的扩展名（包括.gif）按conflict处理
+fn normalize_output_extension(output_path: &str, expected: &str, conflict: ExtensionConflict) -> Result<(String, Option<String>), GifError> {
+    let path = Path::new(output_path);
+    let extension = path.extension().and_then(|ext| ext.to_str()).filter(|ext| !ext.is_empty());
+    let format_name = expected.to_ascii_uppercase();
+    match extension {
+        Some(ext) if ext == expected => Ok((output_path.to_string(), None)),
+        Some(ext) if ext.eq_ignore_ascii_case(expected) => Ok((
+            output_path.to_string(),
+            Some(format!("输出文件扩展名为.{}，部分程序只识别小写的.{}", ext, expected)),
+        )),
+        Some(ext) if ext.eq_ignore_ascii_case("gif") || CONFLICTING_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()) => match conflict {
+            ExtensionConflict::Error => Err(GifError::Other(format!("输出文件扩展名.{}与{}格式不符", ext, format_name))),
+            ExtensionConflict::Rewrite => {
+                let rewritten = path.with_extension(expected).to_string_lossy().to_string();
+                Ok((rewritten.clone(), Some(format!("输出文件扩展名.{}与{}格式不符，已改为 {}", ext, format_name, rewritten))))
+            }
+        },
+        _ => {
+            // 以点结尾的文件名去掉末尾的点再追加
+            let appended = format!("{}.{}", output_path.strip_suffix('.').unwrap_or(output_path), expected);
+            Ok((appended.clone(), Some(format!("输出文件名没有.{}扩展名，已保存为 {}", expected, appended))))
+        }
+    }
+}
+
+/// 确定与输出同名的.webp文件路径
+///
+/// WebP会覆盖这个位置已有的文件，所以它不能是原文件，也不能是已存在的目录
+fn webp_sibling_path(input_path: &str, output_path: &str) -> Result<PathBuf, GifError> {
+    let webp = Path::new(output_path).with_extension("webp");
+    if is_same_file(input_path, &webp) {
+        return Err(GifError::InvalidOption(format!("WebP输出 {} 会覆盖原文件", webp.display())));
+    }
+    if webp.is_dir() {
+        return Err(GifError::InvalidOption(format!("WebP输出 {} 是已存在的目录", webp.display())));
+    }
+    Ok(webp)
+}
+
+/// compress_gif的实现，给出stream时推送处理过程
+async fn run_compress(
+    state: State<'_, AppState>,
+    input_path: String,
+    output_path: String,
+    options: CompressOptions,
+    stream: Option<Arc<CompressStream>>,
+    progress: Option<Arc<ProgressChannel>>,
+    job_id: Option<String>,
+) -> Result<CompressResult, String> {
+    let mut options = options;
+    apply_target_like(&mut options).map_err(|e| e.to_string())?;
+    let bit_depth = options.bit_depth;
+    apply_bit_depth(&mut options).map_err(|e| e.to_string())?;
+    let quality_caps = apply_quality_level(&mut options).map_err(|e| e.to_string())?;
+    
+    // 先确定最终的输出文件名，之后所有检查都使用它；打包时输出的是zip，不按GIF检查扩展名
+    let (output_path, extension_warning) = match options.output_format {
+        OutputFormat::Bundle => (output_path, None),
+        OutputFormat::Webp => {
+            let (path, warning) = normalize_output_extension(&output_path, "webp", options.extension_conflict).map_err(|e| e.to_string())?;
+            // 与自动选择格式时的WebP一样，不能覆盖原文件或已存在的目录
+            (webp_sibling_path(&input_path, &path).map_err(|e| e.to_string())?.to_string_lossy().to_string(), warning)
+        }
+        _ => normalize_output_extension(&output_path, "gif", options.extension_conflict).map_err(|e| e.to_string())?,
+    };
+    if options.output_format == OutputFormat::Webp && find_gif2webp().is_none() {
+        return Err("未找到gif2webp，无法输出WebP".to_string());
+    }
+    // 自动选择格式时WebP写在GIF旁边，开始压缩前先确认这个位置可以写入
+    let webp_sibling = match options.output_format {
+        OutputFormat::Auto => Some(webp_sibling_path(&input_path, &output_path).map_err(|e| e.to_string())?),
+        _ => None,
+    };
+    
+    // 打包或输出WebP时GIF先写到临时文件，最后再与其他格式一起写入zip或转换为WebP
+    let staged_gif = if matches!(options.output_format, OutputFormat::Bundle | OutputFormat::Webp) {
+        Some(TempFile::new(tempfile::Builder::new().prefix("gif_staged_").suffix(".gif").tempfile().map_err(|e| e.to_string())?))
+    } else {
+        None
+    };
+    let final_path = staged_gif.as_ref().map(|_| output_path.clone());
+    let output_path = staged_gif.as_ref().map_or(output_path, |file| file.path_str());
+    
+    // 在这里先克隆一次，这样闭包中使用的是克隆版本
+    let output_path_for_result = output_path.clone();
+    let input_path_for_trash = input_path.clone();
+    let target_size = options.target_size;
+    // 输出WebP时GIF阶段只做基础优化，WebP由基础优化的结果生成，再按WebP的大小与目标比较；
+    // 基础优化的WebP仍超过目标时才按原来的参数抽帧，见convert_to_webp
+    let webp_search = (options.output_format == OutputFormat::Webp)
+        .then(|| (options.clone(), effective_threads(&options, &input_path)));
+    if webp_search.is_some() {
+        options = base_pass_options(&options);
+    }
+    let trash_original = options.trash_original;
+    let duration_tolerance = options.duration_tolerance_percent;
+    let preserve_duration = options.preserve_duration;
+    let output_format = options.output_format;
+    let keep_both_formats = options.keep_both_formats;
+    let delay_grid = options.smooth_delays.then_some(options.delay_grid_cs.max(1));
+    let even_dimensions = options.even_dimensions;
+    let letterbox_size = dimension_preset(&options).map_err(|e| e.to_string())?;
+    let comment = options.comment.clone();
+    let strip_global_table = options.strip_redundant_global_table;
+    // 每个任务开始时记录一次gifsicle的路径和版本
+    let encoder = probe_gifsicle_identity();
+    println!("使用的编码器: {} ({})", encoder.label(), encoder.path.as_deref().unwrap_or("内置"));
+    
+    // 登记任务，函数返回时移除，保证最终结果发出前任务已结束；后台任务使用预先分配的id
+    let job_id = job_id.unwrap_or_else(|| state.jobs.next_id());
+    let job = Arc::new(state.jobs.start_as(job_id, JobKind::Compress, &input_path, Some(&output_path), true));
+    let job_clone = Arc::clone(&job);
+    if let Some(progress) = &progress {
+        progress.lifecycle("开始压缩", 0.0, Some(input_path.clone()));
+    }
+    let progress_clone = progress.clone();
+    
+    // 贡献分析可以被取消
+    let lever_job = Arc::new(SharedState::new());
+    if options.analyze_levers {
+        if let Some(previous) = state.lever_analysis_job.lock().unwrap().replace(Arc::clone(&lever_job)) {
+            previous.cancel();
+        }
+    }
+    let lever_job_clone = Arc::clone(&lever_job);
+    
+    let (resolved_input, result, lever_breakdown) = tokio::task::spawn_blocking(move || {
+        // 内部统一使用解析符号链接后的真实路径，返回给前端的仍是用户给出的路径
+        let real_input = match resolve_input_path(&input_path) {
+            Ok(path) => path,
+            Err(e) => return (None, Err(e), None),
+        };
+        let real_output = resolve_output_path(&output_path);
+        if real_input != Path::new(&input_path) {
+            println!("实际处理的文件: {}", real_input.display());
+        }
+        
+        let on_event = |event: OptimizeEvent<'_>| {
+            if let OptimizeEvent::Progress(progress) = &event {
+                job_clone.update(&progress.status, progress.progress, progress.details.as_deref());
+            }
+            if let Some(channel) = &progress_clone {
+                channel.handle(&event);
+            }
+            if let Some(stream) = &stream {
+                stream.handle(event);
+            }
+        };
+        let result = optimize_gif(
+            &real_input,
+            &real_output,
+            &options,
+            effective_threads(&options, &input_path),
+            Some(&on_event),
+            Some(job_clone.cancel_flag())
+        );
+        let result = job_clone.map_stalled(result);
+        let lever_breakdown = match &result {
+            Ok(outcome) if options.analyze_levers && !outcome.cancelled => {
+                job_clone.update("分析各项优化手段的贡献", 1.0, None);
+                Some(analyze_levers(&real_input, outcome, &options, &lever_job_clone))
+            }
+            _ => None,
+        };
+        (Some(real_input.to_string_lossy().to_string()), result, lever_breakdown)
+    }).await.unwrap();
+    
+    {
+        let mut job = state.lever_analysis_job.lock().unwrap();
+        if job.as_ref().is_some_and(|current| Arc::ptr_eq(current, &lever_job)) {
+            *job = None;
+        }
+    }
+    
+    let mut compress_result = build_compress_result(result, &output_path_for_result, target_size);
+    // 被看门狗终止时同样保存了当时最好的结果，消息中说明终止的原因
+    if let (true, Some(diagnostic)) = (compress_result.cancelled, job.stalled()) {
+        compress_result.message = format!("{}，已保存当时最好的结果", GifError::JobStalled(diagnostic));
+    }
+    compress_result.warnings.extend(extension_warning);
+    compress_result.encoder = Some(encoder);
+    compress_result.quality_caps = quality_caps;
+    match lever_breakdown {
+        Some(Ok(breakdown)) => compress_result.lever_breakdown = Some(breakdown),
+        Some(Err(e)) => compress_result.warnings.push(format!("优化手段贡献分析未完成: {}", e)),
+        None => {}
+    }
+    
+    // 对齐帧延迟后再检查时长，取整误差超出允许范围时同样会给出警告
+    if let (Some(grid), true) = (delay_grid, compress_result.output_sha256.is_some()) {
+        let real_output = resolve_output_path(&output_path_for_result);
+        match smooth_output_delays(&real_output, grid) {
+            Ok(adjustment) => {
+                println!("帧延迟已对齐到 {} 厘秒的整数倍，单帧最多调整 {} 厘秒", grid, adjustment);
+                compress_result.max_delay_adjustment_cs = Some(adjustment);
+                compress_result.output_sha256 = sha256_file(&real_output).ok();
+            }
+            Err(e) => compress_result.warnings.push(format!("未能对齐帧延迟: {}", e)),
+        }
+    }
+    
+    if let (Some(size), Some(real_input), true) = (letterbox_size, &resolved_input, compress_result.output_sha256.is_some()) {
+        if let Ok(source) = get_canvas_size(real_input) {
+            let info = letterbox_geometry(source, size);
+            println!("画面缩放到 {}x{}，补边 {}x{} 像素", info.scaled_width, info.scaled_height, info.pad_x, info.pad_y);
+            compress_result.letterbox = Some(info);
+            compress_result.final_dimensions = Some(size);
+        }
+    }
+    
+    if even_dimensions && compress_result.output_sha256.is_some() {
+        let real_output = resolve_output_path(&output_path_for_result);
+        // 使用任务的取消标志，补边时同样可以被cancel_compress终止
+        match pad_to_even_dimensions(&real_output, &SharedState::with_cancel_flag(job.cancel_flag())) {
+            Ok((dimensions, padded)) => {
+                compress_result.final_dimensions = Some(dimensions);
+                compress_result.padded_to_even = padded;
+                if padded {
+                    compress_result.refresh_compressed_size(&real_output);
+                    compress_result.output_sha256 = sha256_file(&real_output).ok();
+                }
+            }
+            Err(e) => compress_result.warnings.push(format!("未能把画布调整为偶数尺寸: {}", e)),
+        }
+    }
+    
+    if strip_global_table && compress_result.output_sha256.is_some() {
+        let real_output = resolve_output_path(&output_path_for_result);
+        match strip_global_color_table_file(&real_output, &real_output) {
+            Ok(Some(saved)) => {
+                println!("删除了多余的全局颜色表，节省 {} 字节", saved);
+                compress_result.global_table_saved_bytes = Some(saved);
+                compress_result.refresh_compressed_size(&real_output);
+                compress_result.output_sha256 = sha256_file(&real_output).ok();
+            }
+            Ok(None) => {}
+            Err(e) => compress_result.warnings.push(format!("未能删除全局颜色表: {}", e)),
+        }
+    }
+    
+    // gifsicle会移除所有注释，最后再写入要求的注释
+    if let (Some(comment), true) = (&comment, compress_result.output_sha256.is_some()) {
+        let real_output = resolve_output_path(&output_path_for_result);
+        match write_gif_comment_file(&real_output, &real_output, Some(comment)) {
+            Ok(_) => {
+                compress_result.refresh_compressed_size(&real_output);
+                compress_result.output_sha256 = sha256_file(&real_output).ok();
+            }
+            Err(e) => compress_result.warnings.push(format!("未能写入注释: {}", e)),
+        }
+    }
+    
+    if bit_depth.is_some() && compress_result.output_sha256.is_some() {
+        let real_output = resolve_output_path(&output_path_for_result);
+        compress_result.palette_size = scan_gif_blocks(&real_output).ok().and_then(|scan| max_palette_size(&scan));
+    }
+    
+    // 原文件的ICC颜色配置，生成WebP时写入它的ICCP块
+    let source_icc = resolved_input.as_ref().and_then(|input| scan_gif_blocks(input).ok()).and_then(|scan| scan.icc_profile);
+    // 抽帧和改写延迟可能改变动画时长，与原文件比较
+    let mut duration_failed = false;
+    if let (Some(real_input), true) = (&resolved_input, compress_result.output_sha256.is_some()) {
+        let deviation = duration_deviation_percent(real_input, resolve_output_path(&output_path_for_result));
+        if let Some(deviation) = deviation.filter(|deviation| deviation.abs() > duration_tolerance) {
+            let warning = format!("动画时长变化了 {:.1}%，超过允许的 {:.1}%", deviation, duration_tolerance);
+            if preserve_duration {
+                duration_failed = true;
+                compress_result.success = false;
+                compress_result.message = format!("{}，输出文件已保留供检查", warning);
+            }
+            compress_result.warnings.push(warning);
+        }
+        compress_result.duration_deviation_percent = deviation;
+    }
+    compress_result.resolved_input_path = resolved_input.clone();
+    
+    // 打包成功后才处理原文件
+    if let (OutputFormat::Bundle, Some(archive), true) = (output_format, &final_path, compress_result.output_sha256.is_some()) {
+        match write_bundle(Path::new(&output_path_for_result), Path::new(archive), source_icc.as_deref(), &SharedState::with_cancel_flag(job.cancel_flag())) {
+            Ok(bundle) => {
+                compress_result.warnings.extend(bundle.warnings.iter().cloned());
+                compress_result.output_path = archive.clone();
+                compress_result.output_sha256 = sha256_file(archive).ok();
+                compress_result.bundle = Some(bundle);
+            }
+            Err(e) => {
+                compress_result.success = false;
+                compress_result.output_sha256 = None;
+                compress_result.message = format!("打包失败: {}", e);
+            }
+        }
+    }
+    
+    // GIF的后续处理都完成后再转换为WebP，是否达到目标按WebP的大小判断
+    if let (Some((search_options, threads)), Some(webp_path), Some(real_input), true) =
+        (webp_search, &final_path, &resolved_input, compress_result.output_sha256.is_some())
+    {
+        job.update("正在转换为WebP", 1.0, None);
+        let base_gif = PathBuf::from(&output_path_for_result);
+        let source = PathBuf::from(real_input);
+        let icc_profile = source_icc.clone();
+        let cancel_flag = job.cancel_flag();
+        let conversion = tokio::task::spawn_blocking(move || {
+            convert_to_webp(&base_gif, &source, &search_options, threads, icc_profile.as_deref(), cancel_flag)
+        }).await.unwrap();
+        match conversion.and_then(|conversion| write_output(&conversion.file.path, webp_path).map(|_| conversion)) {
+            Ok(conversion) => {
+                let quality = conversion.quality;
+                compress_result.output_path = webp_path.clone();
+                compress_result.refresh_compressed_size(webp_path);
+                compress_result.output_sha256 = sha256_file(webp_path).ok();
+                compress_result.encoder = probe_gif2webp_identity();
+                compress_result.webp_quality = quality;
+                let reached = compress_result.compressed_bytes <= kb_to_bytes(target_size);
+                compress_result.success = reached && !duration_failed && !compress_result.cancelled;
+                let ratio = (1.0 - compress_result.compressed_size / compress_result.original_size) * 100.0;
+                let encoding = quality.map_or("无损".to_string(), |quality| format!("质量 {}", quality));
+                let source = if conversion.frames_dropped { "按目标大小抽帧后" } else { "" };
+                // GIF阶段没有以目标大小为准，它的消息不再适用
+                compress_result.message = if reached {
+                    format!("已{}输出{}WebP，达到目标大小，压缩率: {:.1}%", source, encoding, ratio)
+                } else {
+                    format!("已{}输出{}WebP，仍未达到目标大小，压缩率: {:.1}%", source, encoding, ratio)
+                };
+            }
+            Err(GifError::Cancelled) => {
+                compress_result.success = false;
+                compress_result.cancelled = true;
+                compress_result.output_sha256 = None;
+                compress_result.message = "转换WebP时已取消".to_string();
+            }
+            Err(e) => {
+                compress_result.success = false;
+                compress_result.output_sha256 = None;
+                compress_result.message = format!("转换WebP失败: {}", e);
+            }
+        }
+    }
+    
+    if trash_original && compress_result.success {
+        match trash_original_after_compress(&input_path_for_trash, &output_path_for_result) {
+            Ok(()) => compress_result.original_trashed = true,
+            Err(warning) => {
+                println!("警告: {}", warning);
+                compress_result.message = format!("{}（{}）", compress_result.message, warning);
+            }
+        }
+    }
+    
+    // 原文件的处理以校验过的GIF为准，之后才可能用WebP替换它
+    if let (Some(webp_output), true) = (&webp_sibling, compress_result.output_sha256.is_some()) {
+        match pick_output_format(
+            &resolve_output_path(&output_path_for_result),
+            webp_output,
+            keep_both_formats,
+            source_icc.as_deref(),
+            &SharedState::with_cancel_flag(job.cancel_flag()),
+        ) {
+            Ok(comparison) => {
+                if comparison.winner == "webp" {
+                    compress_result.output_path = webp_output.to_string_lossy().to_string();
+                    compress_result.refresh_compressed_size(webp_output);
+                    compress_result.output_sha256 = sha256_file(webp_output).ok();
+                    compress_result.encoder = probe_gif2webp_identity();
+                    if !duration_failed && compress_result.compressed_bytes <= kb_to_bytes(target_size) {
+                        compress_result.success = true;
+                    }
+                }
+                compress_result.message = format!(
+                    "{}（GIF {:.2} KB，WebP {:.2} KB，保留{}）",
+                    compress_result.message,
+                    comparison.gif_kb,
+                    comparison.webp_kb,
+                    if comparison.kept_both { "两者".to_string() } else { comparison.winner.to_uppercase() }
+                );
+                compress_result.format_comparison = Some(comparison);
+            }
+            Err(e) => compress_result.warnings.push(format!("未能生成WebP进行比较，只输出了GIF: {}", e)),
+        }
+    }
+    
+    // GIF输出只能沿用ICC应用扩展，抽帧时会被移除，需要告诉用户颜色配置已丢弃；WebP输出带有ICCP块
+    let output_is_webp = output_format == OutputFormat::Webp
+        || compress_result.format_comparison.as_ref().is_some_and(|comparison| comparison.winner == "webp" && !comparison.kept_both);
+    if source_icc.is_some() && !output_is_webp && compress_result.output_sha256.is_some() {
+        // 打包时检查的是包中的GIF，它仍在暂存的临时文件中
+        let kept_profile = scan_gif_blocks(resolve_output_path(&output_path_for_result)).is_ok_and(|scan| scan.icc_profile.is_some());
+        if !kept_profile {
+            compress_result.icc_profile_dropped = true;
+            compress_result.warnings.push("原文件的ICC颜色配置未能保留到输出GIF中".to_string());
+        }
+    }
+    
+    // 更新状态
+    *state.last_result.lock().unwrap() = Some(compress_result.clone());
+    drop(job);
+    
+    if let Some(progress) = &progress {
+        let status = if compress_result.success { "压缩完成" } else { "压缩失败" };
+        progress.lifecycle(status, 1.0, Some(compress_result.message.clone()));
+    }
+    
+    Ok(compress_result)
+}
+
+// zip包中的一个文件
+#[derive(Clone, Serialize)]
+pub struct BundleEntry {
+    name: String,
+    size_bytes: u64,
+}
+
+// 打包结果
+#[derive(Clone, Serialize)]
+pub struct BundleInfo {
+    archive_path: String,
+    entries: Vec<BundleEntry>,
+    // 未能生成的格式等提示，同时会加入压缩结果的warnings
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+/// 按GIF的第一帧生成PNG海报
+fn encode_poster(gif_path: &Path) -> Result<TempFile, GifError> {
+    let decoder = GifDecoder::new(BufReader::new(File::open(gif_path)?))?;
+    let frame = decoder.into_frames().next().ok_or(GifError::NoFrames)??;
+    let poster = TempFile::new(tempfile::Builder::new().prefix("gif_poster_").suffix(".png").tempfile()?);
+    frame.into_buffer().save_with_format(&poster.path, image::ImageFormat::Png)?;
+    Ok(poster)
+}
+
+/// 把压缩后的GIF、由它生成的动态WebP和PNG海报写入zip
+///
+/// 各文件以zip文件名为前缀；没有gif2webp时只打包GIF和海报并给出提示
+fn write_bundle(gif_path: &Path, archive_path: &Path, icc_profile: Option<&[u8]>, shared_state: &SharedState) -> Result<BundleInfo, GifError> {
+    let stem = archive_path
+        .file_stem()
+        .map_or("output".to_string(), |stem| stem.to_string_lossy().to_string());
+    let mut warnings = Vec::new();
+    let webp = match encode_webp(gif_path, None, icc_profile, shared_state) {
+        Ok(webp) => Some(webp),
+        Err(GifError::Cancelled) => return Err(GifError::Cancelled),
+        Err(e) => {
+            warnings.push(format!("未能生成WebP，包中没有WebP文件: {}", e));
+            None
+        }
+    };
+    let poster = encode_poster(gif_path)?;
+    
+    let mut files = vec![(format!("{}.gif", stem), gif_path.to_path_buf())];
+    if let Some(webp) = &webp {
+        files.push((format!("{}.webp", stem), webp.path.clone()));
+    }
+    files.push((format!("{}_poster.png", stem), poster.path.clone()));
+    
+    // 先写到临时文件，完整写出后再移到输出路径
+    let archive = TempFile::new(tempfile::Builder::new().prefix("gif_bundle_").suffix(".zip").tempfile()?);
+    let zip_error = |e: zip::result::ZipError| GifError::Other(format!("写入zip失败: {}", e));
+    let mut writer = zip::ZipWriter::new(BufWriter::new(File::create(&archive.path)?));
+    let mut entries = Vec::with_capacity(files.len());
+    for (name, path) in &files {
+        // GIF、WebP和PNG本身已经压缩过，直接存储
+        let file_options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        writer.start_file(name.as_str(), file_options).map_err(zip_error)?;
+        let size_bytes = std::io::copy(&mut File::open(path)?, &mut writer)?;
+        entries.push(BundleEntry { name: name.clone(), size_bytes });
+    }
+    writer.finish().map_err(zip_error)?.flush()?;
+    write_output(&archive.path, archive_path)?;
+    
+    println!("已打包 {} 个文件到 {}", entries.len(), archive_path.display());
+    Ok(BundleInfo {
+        archive_path: archive_path.to_string_lossy().to_string(),
+        entries,
+        warnings,
+    })
+}
+
+// GIF与动态WebP的比较结果
+#[derive(Clone, Serialize)]
+pub struct FormatComparison {
+    gif_kb: f64,
+    webp_kb: f64,
+    // 较小的格式："gif"或"webp"
+    winner: String,
+    // 是否两个文件都保留了
+    kept_both: bool,
+}
+
+/// 用gif2webp把GIF转换为动态WebP，quality为空时无损，画面与GIF相同
+///
+/// 与gifsicle使用同一套启动、超时和取消处理，进程登记在shared_state中；
+/// 给出icc_profile时写入WebP的ICCP块，计入WebP的大小
+fn encode_webp(gif_path: &Path, quality: Option<u8>, icc_profile: Option<&[u8]>, shared_state: &SharedState) -> Result<TempFile, GifError> {
+    let gif2webp = find_gif2webp().ok_or_else(|| GifError::Other("未找到gif2webp".to_string()))?;
+    let webp = TempFile::new(tempfile::Builder::new().prefix("gif_webp_").suffix(".webp").tempfile()?);
+    let mut args: Vec<std::ffi::OsString> = ["-quiet", "-min_size", "-m", "6"].iter().map(Into::into).collect();
+    if let Some(quality) = quality {
+        args.extend(["-lossy".into(), "-q".into(), quality.to_string().into()]);
+    }
+    args.extend([gif_path.as_os_str().to_os_string(), "-o".into(), webp.path.as_os_str().to_os_string()]);
+    // 被取消终止时返回Cancelled，调用方据此区分取消与转换失败
+    let output = run_gifsicle_once(&gif2webp, &args, shared_state)
+        .map_err(|e| if shared_state.is_cancelled() { GifError::Cancelled } else { e })?;
+    if !output.status.success() {
+        return Err(GifError::Other(format!("gif2webp执行失败: {}", String::from_utf8_lossy(&output.stderr))));
+    }
+    if let Some(profile) = icc_profile {
+        let data = fs::read(&webp.path)?;
+        fs::write(&webp.path, attach_webp_icc(&data, profile)?)?;
+    }
+    Ok(webp)
+}
+
+/// 把ICC颜色配置写入WebP的ICCP块，返回新的文件内容
+///
+/// 只有VP8或VP8L块的简单格式先转换为带VP8X块的扩展格式；已有ICCP块时替换它
+fn attach_webp_icc(webp: &[u8], profile: &[u8]) -> Result<Vec<u8>, GifError> {
+    let invalid = || GifError::Other("不是有效的WebP文件".to_string());
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err(invalid());
+    }
+    
+    // 拆分RIFF中的各个块，块的数据长度为奇数时后面有一个填充字节
+    let mut chunks: Vec<(&[u8], &[u8])> = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= webp.len() {
+        let size = u32::from_le_bytes([webp[pos + 4], webp[pos + 5], webp[pos + 6], webp[pos + 7]]) as usize;
+        let data = webp.get(pos + 8..pos + 8 + size).ok_or_else(invalid)?;
+        chunks.push((&webp[pos..pos + 4], data));
+        pos += 8 + size + size % 2;
+    }
+    chunks.retain(|(fourcc, _)| *fourcc != b"ICCP");
+    
+    let (fourcc, data) = *chunks.first().ok_or_else(invalid)?;
+    let mut vp8x = if fourcc == b"VP8X" && data.len() >= 10 {
+        chunks.remove(0);
+        data[..10].to_vec()
+    } else {
+        // 从图像数据的头部读出画布尺寸和是否有透明度
+        let (width, height, alpha) = match fourcc {
+            b"VP8L" if data.len() >= 5 && data[0] == 0x2F => {
+                let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, (bits >> 28) & 1 == 1)
+            }
+            b"VP8 " if data.len() >= 10 && data[3..6] == [0x9D, 0x01, 0x2A] => (
+                u16::from_le_bytes([data[6], data[7]]) as u32 & 0x3FFF,
+                u16::from_le_bytes([data[8], data[9]]) as u32 & 0x3FFF,
+                false,
+            ),
+            _ => return Err(invalid()),
+        };
+        let mut vp8x = vec![if alpha { 0x10 } else { 0 }, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        vp8x
+    };
+    // VP8X标志中的ICC位
+    vp8x[0] |= 0x20;
+    
+    // ICCP块必须紧跟在VP8X块之后
+    let mut body = b"WEBP".to_vec();
+    for (fourcc, data) in [(&b"VP8X"[..], vp8x.as_slice()), (&b"ICCP"[..], profile)].into_iter().chain(chunks) {
+        body.extend_from_slice(fourcc);
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+    let mut file = b"RIFF".to_vec();
+    file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    file.extend_from_slice(&body);
+    Ok(file)
+}
+
+// 输出WebP时无损结果超过目标后，依次尝试的有损质量（gif2webp -q）
+const WEBP_QUALITY_LEVELS: [u8; 6] = [90, 75, 60, 45, 30, 15];
+
+/// 把GIF转换为WebP，返回WebP文件、它的字节数和使用的有损质量，无损时质量为空
+///
+/// 先尝试无损，再按WEBP_QUALITY_LEVELS从高到低尝试有损，使用第一个不超过目标大小的；都超过时使用最小的一个
+fn webp_for_target(
+    gif_path: &Path,
+    target_size_kb: f64,
+    icc_profile: Option<&[u8]>,
+    shared_state: &SharedState,
+) -> Result<(TempFile, u64, Option<u8>), GifError> {
+    let target_bytes = kb_to_bytes(target_size_kb);
+    let mut best: Option<(TempFile, u64, Option<u8>)> = None;
+    for quality in std::iter::once(None).chain(WEBP_QUALITY_LEVELS.into_iter().map(Some)) {
+        let webp = encode_webp(gif_path, quality, icc_profile, shared_state)?;
+        let bytes = get_file_size_bytes(&webp.path)?;
+        println!("WebP（{}）: {:.2} KB", quality.map_or("无损".to_string(), |quality| format!("质量 {}", quality)), bytes_to_kb(bytes));
+        let better = match &best {
+            Some((_, best_bytes, _)) => bytes < *best_bytes,
+            None => true,
+        };
+        if better {
+            best = Some((webp, bytes, quality));
+        }
+        if bytes <= target_bytes {
+            break;
+        }
+    }
+    best.ok_or(GifError::NoValidResults)
+}
+
+/// 输出WebP时GIF阶段使用的参数：只做基础优化，节省比例按WebP的结果判断
+fn base_pass_options(options: &CompressOptions) -> CompressOptions {
+    CompressOptions { base_pass_only: true, min_savings_percent: None, ..options.clone() }
+}
+
+// 输出WebP时的转换结果
+struct WebpConversion {
+    file: TempFile,
+    bytes: u64,
+    // 使用的有损质量，无损时为空
+    quality: Option<u8>,
+    // 是否改用了按目标大小抽帧后的GIF
+    frames_dropped: bool,
+}
+
+/// 把基础优化的GIF转换为WebP，按WebP的大小与options.target_size比较
+///
+/// 最低的有损质量仍超过目标时，按options对原文件正常搜索抽帧和lossy，把得到的GIF再转换一次，保留较小的WebP；
+/// 抽帧得到的GIF不再经过对齐延迟、写入注释等只针对GIF的后续处理
+fn convert_to_webp(
+    base_gif: &Path,
+    source: &Path,
+    options: &CompressOptions,
+    threads: usize,
+    icc_profile: Option<&[u8]>,
+    cancel_flag: Arc<AtomicBool>,
+) -> Result<WebpConversion, GifError> {
+    let shared_state = SharedState::with_cancel_flag(Arc::clone(&cancel_flag));
+    let (file, bytes, quality) = webp_for_target(base_gif, options.target_size, icc_profile, &shared_state)?;
+    let best = WebpConversion { file, bytes, quality, frames_dropped: false };
+    if best.bytes <= kb_to_bytes(options.target_size) {
+        return Ok(best);
+    }
+    
+    println!("WebP在最低质量下仍超过目标大小，按目标大小抽帧后再转换");
+    let frames_gif = TempFile::new(tempfile::Builder::new().prefix("gif_webp_frames_").suffix(".gif").tempfile()?);
+    match optimize_gif(source, &frames_gif.path, options, threads, None, Some(cancel_flag)) {
+        Ok(outcome) if outcome.cancelled => return Err(GifError::Cancelled),
+        Ok(_) => {}
+        Err(GifError::Cancelled) => return Err(GifError::Cancelled),
+        Err(e) => {
+            println!("抽帧失败（{}），使用基础优化的WebP", e);
+            return Ok(best);
+        }
+    }
+    let (file, bytes, quality) = webp_for_target(&frames_gif.path, options.target_size, icc_profile, &shared_state)?;
+    if bytes < best.bytes {
+        return Ok(WebpConversion { file, bytes, quality, frames_dropped: true });
+    }
+    Ok(best)
+}
+
+/// 用最终的GIF生成无损动态WebP，两者画面相同，保留较小的一个
+///
+/// WebP写到webp_path，GIF较小时除非keep_both否则不写出WebP；WebP较小时删除GIF
+fn pick_output_format(
+    gif_path: &Path,
+    webp_path: &Path,
+    keep_both: bool,
+    icc_profile: Option<&[u8]>,
+    shared_state: &SharedState,
+) -> Result<FormatComparison, GifError> {
+    let webp = encode_webp(gif_path, None, icc_profile, shared_state)?;
+    let gif_kb = get_file_size_kb(gif_path)?;
+    let webp_kb = get_file_size_kb(&webp.path)?;
+    let webp_wins = webp_kb < gif_kb;
+    println!("GIF {:.2} KB，WebP {:.2} KB", gif_kb, webp_kb);
+    if webp_wins || keep_both {
+        write_output(&webp.path, webp_path)?;
+    }
+    if webp_wins && !keep_both {
+        fs::remove_file(gif_path)?;
+    }
+    // 临时WebP在这里被删除
+    Ok(FormatComparison {
+        gif_kb,
+        webp_kb,
+        winner: if webp_wins { "webp" } else { "gif" }.to_string(),
+        kept_both: keep_both,
+    })
+}
+
+// 候选结果的参数取向
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateRegime {
+    // 保留更多帧，使用尽量轻的lossy
+    MoreFrames,
+    // 抽掉更多帧，不使用lossy
+    NoLossy,
+    // 保留全部帧，缩小画布
+    Resized,
+}
+
+// 返回给前端的候选结果
+#[derive(Clone, Serialize)]
+pub struct CandidateInfo {
+    id: String,
+    regime: CandidateRegime,
+    size_kb: f64,
+    frame_count: usize,
+    skip: usize,
+    lossy: Option<u32>,
+    max_edge: Option<u32>,
+    // 第一帧缩略图（PNG data URL）
+    thumbnail: String,
+}
+
+// 候选结果缩略图的最大边长
+const CANDIDATE_THUMBNAIL_SIZE: u32 = 160;
+
+/// 生成GIF第一帧的PNG缩略图
+fn candidate_thumbnail<P: AsRef<Path>>(path: P) -> Result<String, GifError> {
+    let file = File::open(path)?;
+    let decoder = GifDecoder::new(BufReader::new(file))?;
+    let frame = decoder.into_frames().next().ok_or(GifError::NoFrames)??;
+    let thumbnail = image::DynamicImage::ImageRgba8(frame.into_buffer())
+        .thumbnail(CANDIDATE_THUMBNAIL_SIZE, CANDIDATE_THUMBNAIL_SIZE);
+    
+    let mut png = std::io::Cursor::new(Vec::new());
+    thumbnail.write_to(&mut png, image::ImageOutputFormat::Png)?;
+    Ok(format!("data:image/png;base64,{}", BASE64.encode(png.into_inner())))
+}
+
+/// 只抽帧不使用lossy，返回优化后的文件
+fn render_without_lossy(
+    gifsicle_path: &str,
+    input_path: &str,
+    strategy: &Strategy,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+) -> Result<TempFile, GifError> {
+    let frames = TempFile::new(NamedTempFile::new()?);
+    extract_frames(input_path, frames.path_str(), strategy, options, shared_state)?;
+    
+    let optimized = TempFile::new(NamedTempFile::new()?);
+    let output = run_gifsicle(gifsicle_path, &["-O3", &frames.path_str(), "-o", &optimized.path_str()], shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    Ok(optimized)
+}
+
+/// 保留全部帧，把画布缩小到大致能达到目标大小的尺寸
+fn render_resized(
+    gifsicle_path: &str,
+    input_path: &str,
+    max_edge: u32,
+    shared_state: &SharedState,
+) -> Result<TempFile, GifError> {
+    let resized = TempFile::new(NamedTempFile::new()?);
+    let fit = format!("{}x{}", max_edge, max_edge);
+    let args = ["-O3", "--no-warnings", "--resize-fit", &fit, input_path, "-o", &resized.path_str()];
+    let output = run_gifsicle(gifsicle_path, &args, shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    Ok(resized)
+}
+
+/// 按不同参数取向各生成一个候选结果，最多count个
+///
+/// 各取向相互独立并行处理，某个取向失败不影响其他取向；cancel_flag被设置时所有取向都停止
+fn generate_candidate_files(
+    input_path: &Path,
+    options: &CompressOptions,
+    count: usize,
+    cancel_flag: &Arc<AtomicBool>,
+) -> Result<Vec<(CandidateInfo, TempFile)>, GifError> {
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let input_path_str = input_path.to_string_lossy().to_string();
+    let original_size = get_file_size_kb(input_path)?;
+    let frame_count = get_frame_count(input_path)?;
+    let (width, height) = get_canvas_size(input_path)?;
+    
+    // 与optimize_gif相同的抽帧范围
+    let max_skip = plan_strategies(frame_count, options).max_skip;
+    let strategy_for = |skip: usize| Strategy {
+        skip,
+        delay: ((100.0 * skip as f64) / frame_count as f64) as u16 + 10,
+        motion_threshold: None,
+        cinemagraph: false,
+        scale: None,
+    };
+    
+    // 按面积估算缩小比例
+    let longest = width.max(height);
+    let scale = (options.target_size / original_size).sqrt().min(1.0);
+    let resize_edge = ((longest as f64 * scale).floor() as u32).max(MIN_AUTO_EDGE);
+    
+    let mut regimes = vec![CandidateRegime::MoreFrames, CandidateRegime::NoLossy];
+    if resize_edge < longest {
+        regimes.push(CandidateRegime::Resized);
+    }
+    regimes.truncate(count.max(1));
+    
+    let results: Vec<Option<(CandidateInfo, TempFile)>> = thread::scope(|scope| {
+        let handles: Vec<_> = regimes
+            .iter()
+            .enumerate()
+            .map(|(i, &regime)| {
+                let gifsicle_path = &gifsicle_path;
+                let input_path_str = &input_path_str;
+                let strategy_for = &strategy_for;
+                scope.spawn(move || {
+                    // 每个取向独立搜索，互不提前终止，但共用任务的取消标志
+                    let shared_state = SharedState::with_cancel_flag(Arc::clone(cancel_flag));
+                    let (file, skip, lossy, max_edge) = match regime {
+                        CandidateRegime::MoreFrames => {
+                            let strategy = match options.motion_threshold {
+                                Some(threshold) => Strategy { skip: 1, delay: 0, motion_threshold: Some(threshold), cinemagraph: false, scale: None },
+                                None => strategy_for(2),
+                            };
+                            let skip = strategy.skip;
+                            let result = process_strategy(input_path_str, strategy, options, i + 1, &shared_state);
+                            (result.file?, skip, result.lossy, None)
+                        }
+                        CandidateRegime::NoLossy => {
+                            let strategy = strategy_for(max_skip);
+                            match render_without_lossy(gifsicle_path, input_path_str, &strategy, options, &shared_state) {
+                                Ok(file) => (file, max_skip, None, None),
+                                Err(e) => {
+                                    println!("候选结果（不使用lossy）生成失败: {}", e);
+                                    return None;
+                                }
+                            }
+                        }
+                        CandidateRegime::Resized => {
+                            match render_resized(gifsicle_path, input_path_str, resize_edge, &shared_state) {
+                                Ok(file) => (file, 1, None, Some(resize_edge)),
+                                Err(e) => {
+                                    println!("候选结果（缩小画布）生成失败: {}", e);
+                                    return None;
+                                }
+                            }
+                        }
+                    };
+                    
+                    let info = CandidateInfo {
+                        // 保存到CandidateStore时分配
+                        id: String::new(),
+                        regime,
+                        size_kb: get_file_size_kb(&file.path).ok()?,
+                        frame_count: get_frame_count(&file.path).ok()?,
+                        skip,
+                        lossy,
+                        max_edge,
+                        thumbnail: candidate_thumbnail(&file.path).unwrap_or_default(),
+                    };
+                    Some((info, file))
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().ok().flatten()).collect()
+    });
+    
+    let candidates: Vec<_> = results.into_iter().flatten().collect();
+    if candidates.is_empty() {
+        return Err(GifError::NoValidResults);
+    }
+    Ok(candidates)
+}
+
+// 生成几个不同取向的候选结果，由用户挑选后再用commit_candidate写出
+#[tauri::command]
+async fn generate_candidates(
+    state: State<'_, AppState>,
+    input_path: String,
+    options: CompressOptions,
+    count: usize,
+) -> Result<Vec<CandidateInfo>, String> {
+    let real_input = resolve_input_path(&input_path).map_err(|e| e.to_string())?;
+    let key = real_input.to_string_lossy().to_string();
+    
+    // 同一文件重新生成时，之前的候选结果作废
+    state.candidates.release_group(&key);
+    
+    // 登记为压缩任务，cancel_compress可以中止生成
+    let job = state.jobs.start(JobKind::Compress, &input_path, None, true);
+    let cancel_flag = job.cancel_flag();
+    let generated = tokio::task::spawn_blocking(move || generate_candidate_files(&real_input, &options, count, &cancel_flag))
+        .await
+        .map_err(|e| e.to_string())?;
+    let generated = job.map_stalled(generated).map_err(|e| e.to_string())?;
+    drop(job);
+    
+    let infos = generated
+        .into_iter()
+        .map(|(mut info, file)| {
+            let meta = CandidateMeta {
+                group: key.clone(),
+                label: format!("候选结果 {:.1} KB", info.size_kb),
+            };
+            info.id = state.candidates.register(file, meta);
+            info
+        })
+        .collect();
+    
+    Ok(infos)
+}
+
+// 候选结果写出后的信息
+#[derive(Clone, Serialize)]
+pub struct CommittedCandidate {
+    output_path: String,
+    size_kb: f64,
+    output_sha256: String,
+}
+
+// 把选中的候选结果写到输出路径，并删除同一组的其他候选结果
+#[tauri::command]
+fn commit_candidate(
+    state: State<'_, AppState>,
+    candidate_id: String,
+    output_path: String,
+) -> Result<CommittedCandidate, String> {
+    let (file, meta) = state
+        .candidates
+        .take(&candidate_id)
+        .ok_or_else(|| format!("候选结果 {} 不存在或已过期", candidate_id))?;
+    // 同一组的其他候选结果不再需要
+    state.candidates.release_group(&meta.group);
+    
+    let real_output = resolve_output_path(&output_path);
+    let output_sha256 = write_output(&file.path, &real_output).map_err(|e| e.to_string())?;
+    let size_kb = get_file_size_kb(&real_output).map_err(|e| e.to_string())?;
+    
+    Ok(CommittedCandidate {
+        output_path,
+        size_kb,
+        output_sha256,
+    })
+}
+
+// 获取候选结果的临时文件路径，用于预览，文件在释放前一直有效
+#[tauri::command]
+fn get_candidate_path(state: State<'_, AppState>, candidate_id: String) -> Option<String> {
+    state
+        .candidates
+        .get(&candidate_id)
+        .map(|(path, _)| path.to_string_lossy().to_string())
+}
+
+// 释放一个候选结果
+#[tauri::command]
+fn release_candidate(state: State<'_, AppState>, candidate_id: String) -> bool {
+    state.candidates.release(&candidate_id)
+}
+
+// 获取候选结果存储的占用情况
+#[tauri::command]
+fn get_candidate_store_usage(state: State<'_, AppState>) -> CandidateStoreUsage {
+    state.candidates.usage()
+}
+
+// 设置候选结果的保留时间（秒）
+#[tauri::command]
+fn set_candidate_ttl(state: State<'_, AppState>, ttl_secs: u64) {
+    state.candidates.set_ttl(Duration::from_secs(ttl_secs));
+}
+
+// 未指定搜索范围时，在最后这一比例的帧中寻找循环点
+const LOOP_SEARCH_FRACTION: f64 = 0.25;
+
+/// 在末尾的search_window帧中找出与第0帧最相似的帧，返回(帧序号, 差异)
+///
+/// 该帧与第0帧几乎相同，从它开始截掉后循环就能无缝衔接
+fn find_loop_point(frames: &[image::Frame], search_window: usize) -> Option<(usize, f64)> {
+    let total = frames.len();
+    if total < 3 {
+        return None;
+    }
+    // 至少保留两帧
+    let start = total.saturating_sub(search_window).max(2);
+    let first = frames[0].buffer();
+    (start..total)
+        .map(|i| (i, frame_difference(first, frames[i].buffer())))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+// 无缝循环处理结果
+#[derive(Clone, Serialize)]
+pub struct PerfectLoopResult {
+    output_path: String,
+    // 截取后的循环帧数
+    loop_length: usize,
+    total_frames: usize,
+    // 截断处的帧与第0帧的平均像素差（0-255）
+    difference: f64,
+    size_kb: f64,
+}
+
+fn make_perfect_loop_file(
+    input_path: &Path,
+    output_path: &Path,
+    search_window: Option<usize>,
+    shared_state: &SharedState,
+) -> Result<PerfectLoopResult, GifError> {
+    let file = File::open(input_path)?;
+    let decoder = GifDecoder::new(BufReader::new(file))?;
+    let frames = decoder.into_frames().collect_frames()?;
+    let total = frames.len();
+    
+    let window = search_window.unwrap_or_else(|| ((total as f64 * LOOP_SEARCH_FRACTION).ceil() as usize).max(1));
+    let (loop_point, difference) = find_loop_point(&frames, window)
+        .ok_or_else(|| GifError::Other(format!("GIF只有 {} 帧，无法寻找循环点", total)))?;
+    println!("循环点: 第 {} 帧 (差异: {:.2})，保留 {} / {} 帧", loop_point, difference, loop_point, total);
+    
+    // 使用gifsicle的帧选择保留原有的延迟和处置方式
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let trimmed = TempFile::new(NamedTempFile::new()?);
+    let selection = format!("#0-{}", loop_point - 1);
+    let args = [
+        std::ffi::OsStr::new("-O3"),
+        std::ffi::OsStr::new("--no-warnings"),
+        input_path.as_os_str(),
+        std::ffi::OsStr::new(&selection),
+        std::ffi::OsStr::new("-o"),
+        trimmed.path.as_os_str(),
+    ];
+    let output = run_gifsicle(&gifsicle_path, &args, shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    
+    write_output(&trimmed.path, output_path)?;
+    Ok(PerfectLoopResult {
+        output_path: output_path.to_string_lossy().to_string(),
+        loop_length: loop_point,
+        total_frames: total,
+        difference,
+        size_kb: get_file_size_kb(output_path)?,
+    })
+}
+
+// 把GIF截到与第0帧最相似的位置，使循环无缝衔接
+#[tauri::command]
+async fn make_perfect_loop(
+    state: State<'_, AppState>,
+    input_path: String,
+    output_path: String,
+    search_window: Option<usize>,
+) -> Result<PerfectLoopResult, String> {
+    let real_input = resolve_input_path(&input_path).map_err(|e| e.to_string())?;
+    let real_output = resolve_output_path(&output_path);
+    if is_same_file(&real_input, &real_output) {
+        return Err("输出文件不能与输入文件相同".to_string());
+    }
+    
+    // 登记为压缩任务，cancel_compress可以终止其中的gifsicle
+    let job = state.jobs.start(JobKind::Compress, &input_path, Some(&output_path), true);
+    let cancel_flag = job.cancel_flag();
+    let result = tokio::task::spawn_blocking(move || {
+        make_perfect_loop_file(&real_input, &real_output, search_window, &SharedState::with_cancel_flag(cancel_flag))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let mut result = job.map_stalled(result).map_err(|e| e.to_string())?;
+    // 返回给前端的仍是用户给出的路径
+    result.output_path = output_path;
+    Ok(result)
+}
+
+/// 末尾与最后一帧完全相同的帧数（包括最后一帧本身）
+fn trailing_duplicate_run(frames: &[image::Frame]) -> usize {
+    let last = match frames.last() {
+        Some(frame) => frame.buffer(),
+        None => return 0,
+    };
+    frames.iter().rev().take_while(|frame| frame.buffer() == last).count()
+}
+
+// 去掉末尾重复帧的结果
+#[derive(Clone, Serialize)]
+pub struct TrailingFramesResult {
+    output_path: String,
+    // 去掉的帧数，为0时输出与原文件相同
+    removed_frames: usize,
+    total_frames: usize,
+    // 保留下来的最后一帧的显示时长（毫秒）
+    pause_ms: u64,
+    // 循环一次的时长变化（毫秒），按实际播放延迟计算
+    duration_before_ms: u64,
+    duration_after_ms: u64,
+    size_kb: f64,
+}
+
+fn strip_trailing_frames_file(
+    input_path: &Path,
+    output_path: &Path,
+    pause_ms: Option<u64>,
+    shared_state: &SharedState,
+) -> Result<TrailingFramesResult, GifError> {
+    let file = File::open(input_path)?;
+    let decoder = GifDecoder::new(BufReader::new(file))?;
+    let frames = decoder.into_frames().collect_frames()?;
+    let total = frames.len();
+    if total == 0 {
+        return Err(GifError::NoFrames);
+    }
+    
+    let run = trailing_duplicate_run(&frames);
+    let keep = total - run + 1;
+    let last_index = keep - 1;
+    let duration_before_ms = animation_duration_ms(input_path)?;
+    let (numer, denom) = frames[last_index].delay().numer_denom_ms();
+    let original_pause_ms = (numer / denom.max(1)) as u64;
+    println!("末尾有 {} 帧相同，保留 {} / {} 帧", run, keep, total);
+    
+    if run <= 1 && pause_ms.is_none() {
+        write_output(input_path, output_path)?;
+        return Ok(TrailingFramesResult {
+            output_path: output_path.to_string_lossy().to_string(),
+            removed_frames: 0,
+            total_frames: total,
+            pause_ms: original_pause_ms,
+            duration_before_ms,
+            duration_after_ms: duration_before_ms,
+            size_kb: get_file_size_kb(output_path)?,
+        });
+    }
+    
+    // 使用gifsicle的帧选择保留原有的延迟和处置方式，需要停顿时单独设置最后一帧的延迟
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let trimmed = TempFile::new(NamedTempFile::new()?);
+    let mut args: Vec<std::ffi::OsString> = vec!["-O3".into(), "--no-warnings".into()];
+    match pause_ms {
+        Some(pause_ms) => {
+            if last_index > 0 {
+                args.push(input_path.into());
+                args.push(format!("#0-{}", last_index - 1).into());
+            }
+            let delay_cs = (pause_ms / 10).min(u16::MAX as u64).to_string();
+            args.extend(["--delay".into(), delay_cs.into(), input_path.into(), format!("#{}", last_index).into()]);
+        }
+        None => {
+            args.push(input_path.into());
+            args.push(format!("#0-{}", last_index).into());
+        }
+    }
+    args.extend(["-o".into(), trimmed.path.clone().into()]);
+    // run_gifsicle会检查输出文件是否完整
+    let output = run_gifsicle(&gifsicle_path, &args, shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    
+    write_output(&trimmed.path, output_path)?;
+    Ok(TrailingFramesResult {
+        output_path: output_path.to_string_lossy().to_string(),
+        removed_frames: run - 1,
+        total_frames: total,
+        pause_ms: pause_ms.map_or(original_pause_ms, |pause_ms| pause_ms / 10 * 10),
+        duration_before_ms,
+        duration_after_ms: animation_duration_ms(output_path)?,
+        size_kb: get_file_size_kb(output_path)?,
+    })
+}
+
+// 把末尾连续的相同帧合并成一帧，去掉循环前多余的停顿；pause_ms设置合并后那一帧的显示时长
+#[tauri::command]
+async fn strip_trailing_frames(
+    state: State<'_, AppState>,
+    input_path: String,
+    output_path: String,
+    pause_ms: Option<u64>,
+) -> Result<TrailingFramesResult, String> {
+    let real_input = resolve_input_path(&input_path).map_err(|e| e.to_string())?;
+    let real_output = resolve_output_path(&output_path);
+    if is_same_file(&real_input, &real_output) {
+        return Err("输出文件不能与输入文件相同".to_string());
+    }
+    
+    // 登记为压缩任务，cancel_compress可以终止其中的gifsicle
+    let job = state.jobs.start(JobKind::Compress, &input_path, Some(&output_path), true);
+    let cancel_flag = job.cancel_flag();
+    let result = tokio::task::spawn_blocking(move || {
+        strip_trailing_frames_file(&real_input, &real_output, pause_ms, &SharedState::with_cancel_flag(cancel_flag))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let mut result = job.map_stalled(result).map_err(|e| e.to_string())?;
+    // 返回给前端的仍是用户给出的路径
+    result.output_path = output_path;
+    Ok(result)
+}
+
+/// 把过小的延迟调高到min_cs，返回新的延迟和放大倍数
+///
+/// 非零延迟按同一倍数放大，使最小的非零延迟达到min_cs，帧之间的相对快慢不变；
+/// 延迟为0的帧没有相对时长可言，直接设为min_cs
+fn normalize_delays(delays_cs: &[u16], min_cs: u16) -> (Vec<u16>, f64) {
+    if delays_cs.iter().all(|&delay| delay >= min_cs) {
+        return (delays_cs.to_vec(), 1.0);
+    }
+    let scale = delays_cs
+        .iter()
+        .filter(|&&delay| delay > 0)
+        .min()
+        .map_or(1.0, |&smallest| (min_cs as f64 / smallest as f64).max(1.0));
+    let normalized = delays_cs
+        .iter()
+        .map(|&delay| match delay {
+            0 => min_cs,
+            _ => (delay as f64 * scale).round().min(u16::MAX as f64) as u16,
+        })
+        .collect();
+    (normalized, scale)
+}
+
+/// 直接改写文件中的帧延迟，其余数据保持不变；没有图形控制扩展的帧在图像描述符前插入一个
+fn rewrite_frame_delays(data: &[u8], frames: &[FrameBlock], delays_cs: &[u16]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() + frames.len() * 8);
+    let mut copied = 0;
+    for (frame, &delay) in frames.iter().zip(delays_cs) {
+        let delay = delay.to_le_bytes();
+        match frame.delay_offset {
+            Some(offset) => {
+                let offset = offset as usize;
+                output.extend_from_slice(&data[copied..offset]);
+                output.extend_from_slice(&delay);
+                copied = offset + 2;
+            }
+            None => {
+                let offset = frame.offset as usize;
+                output.extend_from_slice(&data[copied..offset]);
+                // 图形控制扩展：块大小4、无处置方式和透明色
+                output.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00, delay[0], delay[1], 0x00, 0x00]);
+                copied = offset;
+            }
+        }
+    }
+    output.extend_from_slice(&data[copied..]);
+    output
+}
+
+/// 把帧延迟对齐到grid_cs的整数倍
+///
+/// 按实际播放延迟计算，每帧的取整误差累积到下一帧，总时长误差不超过半个网格；
+/// 每帧至少保留一个网格
+fn smooth_delays(delays_cs: &[u16], grid_cs: u16) -> Vec<u16> {
+    let grid = grid_cs.max(1) as f64;
+    let mut carry = 0.0;
+    delays_cs
+        .iter()
+        .map(|&delay| {
+            let wanted = playback_delay_cs(delay) as f64 + carry;
+            let snapped = ((wanted / grid).round() * grid).max(grid).min(u16::MAX as f64);
+            carry = wanted - snapped;
+            snapped as u16
+        })
+        .collect()
+}
+
+/// 画布宽高为奇数时把逻辑画面扩大到偶数，多出的一列/一行显示背景，帧内容不变
+///
+/// 返回最终尺寸和是否补了边；shared_state被取消时终止gifsicle
+fn pad_to_even_dimensions(path: &Path, shared_state: &SharedState) -> Result<((u32, u32), bool), GifError> {
+    let (width, height) = get_canvas_size(path)?;
+    let even = (width + width % 2, height + height % 2);
+    if even == (width, height) {
+        return Ok((even, false));
+    }
+    
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let padded = TempFile::new(NamedTempFile::new()?);
+    let screen = format!("{}x{}", even.0, even.1);
+    let path_str = path.to_string_lossy().to_string();
+    let args = ["--no-warnings", "--logical-screen", &screen, &path_str, "-o", &padded.path_str()];
+    let output = run_gifsicle(&gifsicle_path, &args, shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    write_output(&padded.path, path)?;
+    println!("画布 {}x{} 已补边为 {}x{}", width, height, even.0, even.1);
+    Ok((even, true))
+}
+
+/// 对齐文件中的帧延迟，只改写延迟不重新编码，返回单帧最大的调整量（厘秒）
+fn smooth_output_delays(path: &Path, grid_cs: u16) -> Result<u16, GifError> {
+    let scan = scan_gif_blocks(path)?;
+    let original: Vec<u16> = scan.frames.iter().map(|block| playback_delay_cs(block.delay_cs)).collect();
+    let smoothed = smooth_delays(&original, grid_cs);
+    let max_adjustment = original
+        .iter()
+        .zip(&smoothed)
+        .map(|(&before, &after)| before.abs_diff(after))
+        .max()
+        .unwrap_or(0);
+    if smoothed.iter().zip(scan.frames.iter()).all(|(&delay, block)| delay == block.delay_cs) {
+        return Ok(max_adjustment);
+    }
+    
+    let data = fs::read(path)?;
+    let smoothed_file = TempFile::new(NamedTempFile::new()?);
+    fs::write(&smoothed_file.path, rewrite_frame_delays(&data, &scan.frames, &smoothed))?;
+    check_gif_output(&smoothed_file.path).map_err(GifError::Other)?;
+    write_output(&smoothed_file.path, path)?;
+    Ok(max_adjustment)
+}
+
+// 最长与最短播放延迟之比超过该值且相差超过VARIABLE_TIMING_MIN_SPREAD_CS时认为节奏不均匀
+const VARIABLE_TIMING_RATIO: f64 = 1.5;
+// 相差在两厘秒以内通常只是帧率换算成厘秒时的取整误差
+const VARIABLE_TIMING_MIN_SPREAD_CS: u16 = 2;
+// 逐帧列出延迟的帧数上限，更长的GIF只完整给出直方图
+const DELAY_LIST_LIMIT: usize = 500;
+
+// 使用某个延迟的帧数
+#[derive(Clone, Serialize)]
+pub struct DelayBucket {
+    delay_cs: u16,
+    frames: usize,
+}
+
+// 帧延迟统计，最小、平均、最大值按实际播放延迟计算
+#[derive(Clone, Serialize)]
+pub struct DelayStats {
+    frame_count: usize,
+    // 文件中记录的逐帧延迟，最多DELAY_LIST_LIMIT帧
+    delays_cs: Vec<u16>,
+    truncated: bool,
+    // 按延迟从小到大统计
+    histogram: Vec<DelayBucket>,
+    min_cs: u16,
+    avg_cs: f64,
+    max_cs: u16,
+    // 节奏不均匀时，统一的抽帧延迟会抹掉刻意的停顿
+    variable_timing: bool,
+}
+
+fn delay_stats(delays_cs: &[u16]) -> DelayStats {
+    let playback: Vec<u16> = delays_cs.iter().map(|&delay| playback_delay_cs(delay)).collect();
+    let mut histogram: Vec<DelayBucket> = Vec::new();
+    for &delay in delays_cs {
+        match histogram.iter_mut().find(|bucket| bucket.delay_cs == delay) {
+            Some(bucket) => bucket.frames += 1,
+            None => histogram.push(DelayBucket { delay_cs: delay, frames: 1 }),
+        }
+    }
+    histogram.sort_by_key(|bucket| bucket.delay_cs);
+    
+    let min_cs = playback.iter().copied().min().unwrap_or(0);
+    let max_cs = playback.iter().copied().max().unwrap_or(0);
+    DelayStats {
+        frame_count: delays_cs.len(),
+        delays_cs: delays_cs.iter().copied().take(DELAY_LIST_LIMIT).collect(),
+        truncated: delays_cs.len() > DELAY_LIST_LIMIT,
+        histogram,
+        min_cs,
+        avg_cs: playback.iter().map(|&delay| delay as f64).sum::<f64>() / playback.len().max(1) as f64,
+        max_cs,
+        variable_timing: max_cs - min_cs > VARIABLE_TIMING_MIN_SPREAD_CS
+            && max_cs as f64 > min_cs as f64 * VARIABLE_TIMING_RATIO,
+    }
+}
+
+// 统计GIF的帧延迟分布，判断是否为不均匀的节奏
+#[tauri::command]
+async fn get_delay_stats(path: String) -> Result<DelayStats, String> {
+    tokio::task::spawn_blocking(move || -> Result<DelayStats, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        let scan = scan_gif_blocks(&real_input)?;
+        let delays: Vec<u16> = scan.frames.iter().map(|block| block.delay_cs).collect();
+        Ok(delay_stats(&delays))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 单帧的延迟检查结果
+#[derive(Clone, Serialize)]
+pub struct FrameDelay {
+    index: usize,
+    original_cs: u16,
+    normalized_cs: u16,
+}
+
+// 帧延迟检查结果
+#[derive(Clone, Serialize)]
+pub struct DelayReport {
+    min_delay_cs: u16,
+    // 低于min_delay_cs的帧数，不同播放器对这些帧的处理不一致
+    below_minimum: usize,
+    // 非零延迟的放大倍数
+    scale: f64,
+    frames: Vec<FrameDelay>,
+    // 写出了修正后的文件时为输出路径
+    output_path: Option<String>,
+}
+
+// 检查过小的帧延迟；给出output_path时把修正后的GIF写到该路径，修正只改写延迟，不重新编码
+#[tauri::command]
+async fn check_frame_delays(
+    input_path: String,
+    min_delay_cs: Option<u16>,
+    output_path: Option<String>,
+) -> Result<DelayReport, String> {
+    let min_delay_cs = min_delay_cs.unwrap_or(MIN_PLAYBACK_DELAY_CS).max(1);
+    let real_input = resolve_input_path(&input_path).map_err(|e| e.to_string())?;
+    let real_output = output_path.as_ref().map(resolve_output_path);
+    if real_output.as_ref().is_some_and(|output| is_same_file(&real_input, output)) {
+        return Err("输出文件不能与输入文件相同".to_string());
+    }
+    
+    let mut report = tokio::task::spawn_blocking(move || -> Result<DelayReport, GifError> {
+        let scan = scan_gif_blocks(&real_input)?;
+        let original: Vec<u16> = scan.frames.iter().map(|block| block.delay_cs).collect();
+        let (normalized, scale) = normalize_delays(&original, min_delay_cs);
+        let below_minimum = original.iter().filter(|&&delay| delay < min_delay_cs).count();
+        println!("{} 帧中有 {} 帧延迟低于 {} 厘秒", original.len(), below_minimum, min_delay_cs);
+        
+        if let Some(real_output) = &real_output {
+            let data = fs::read(&real_input)?;
+            let fixed = TempFile::new(NamedTempFile::new()?);
+            fs::write(&fixed.path, rewrite_frame_delays(&data, &scan.frames, &normalized))?;
+            check_gif_output(&fixed.path).map_err(GifError::Other)?;
+            write_output(&fixed.path, real_output)?;
+        }
+        
+        Ok(DelayReport {
+            min_delay_cs,
+            below_minimum,
+            scale,
+            frames: original
+                .iter()
+                .zip(&normalized)
+                .enumerate()
+                .map(|(index, (&original_cs, &normalized_cs))| FrameDelay { index, original_cs, normalized_cs })
+                .collect(),
+            output_path: real_output.map(|path| path.to_string_lossy().to_string()),
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+    // 返回给前端的仍是用户给出的路径
+    if report.output_path.is_some() {
+        report.output_path = output_path;
+    }
+    Ok(report)
+}
+
+// 压缩后写入剪贴板的内容
+//
+// 大多数平台的剪贴板图片只支持PNG等静态格式，动画GIF无法直接放进剪贴板，
+// 所以只能放第一帧或输出文件路径，每次只会放其中一种
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipboardContent {
+    // 输出GIF的第一帧（静态图片）
+    #[default]
+    FirstFrame,
+    // 输出文件的路径
+    FilePath,
+}
+
+// 压缩并写入剪贴板的结果
+#[derive(Clone, Serialize)]
+pub struct ClipboardCompressResult {
+    result: CompressResult,
+    // 写入剪贴板的内容说明，写入失败时为空
+    clipboard: Option<String>,
+    clipboard_error: Option<String>,
+}
+
+/// 解码输出GIF的第一帧，作为放进剪贴板的静态图片
+#[cfg(any(desktop, test))]
+fn clipboard_first_frame(output_path: &str) -> Result<image::RgbaImage, String> {
+    let file = File::open(output_path).map_err(|e| e.to_string())?;
+    let decoder = GifDecoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let frame = decoder
+        .into_frames()
+        .next()
+        .ok_or_else(|| GifError::NoFrames.to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(frame.into_buffer())
+}
+
+/// 把输出文件写入剪贴板，返回写入内容的说明
+#[cfg(desktop)]
+fn write_to_clipboard(app: &AppHandle, output_path: &str, content: ClipboardContent) -> Result<String, String> {
+    use tauri_plugin_clipboard_manager::ClipboardExt;
+    
+    match content {
+        ClipboardContent::FirstFrame => {
+            let buffer = clipboard_first_frame(output_path)?;
+            let (width, height) = buffer.dimensions();
+            let image = tauri::image::Image::new_owned(buffer.into_raw(), width, height);
+            app.clipboard().write_image(&image).map_err(|e| e.to_string())?;
+            Ok(format!("第一帧图片 ({}x{})", width, height))
+        }
+        ClipboardContent::FilePath => {
+            app.clipboard().write_text(output_path).map_err(|e| e.to_string())?;
+            Ok(format!("文件路径: {}", output_path))
+        }
+    }
+}
+
+#[cfg(not(desktop))]
+fn write_to_clipboard(_app: &AppHandle, _output_path: &str, _content: ClipboardContent) -> Result<String, String> {
+    Err("当前平台不支持写入剪贴板".to_string())
+}
+
+// 压缩后立即把结果写入剪贴板，方便直接粘贴分享
+#[tauri::command]
+async fn compress_to_clipboard(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    input_path: String,
+    output_path: String,
+    options: CompressOptions,
+    content: Option<ClipboardContent>,
+) -> Result<ClipboardCompressResult, String> {
+    let result = run_compress(state, input_path, output_path, options, None, None, None).await?;
+    if !result.success {
+        return Ok(ClipboardCompressResult {
+            result,
+            clipboard: None,
+            clipboard_error: Some("压缩未达到目标大小，未写入剪贴板".to_string()),
+        });
+    }
+    
+    let (clipboard, clipboard_error) = match write_to_clipboard(&app, &result.output_path, content.unwrap_or_default()) {
+        Ok(description) => {
+            println!("已写入剪贴板: {}", description);
+            (Some(description), None)
+        }
+        Err(e) => {
+            println!("写入剪贴板失败: {}", e);
+            (None, Some(e))
+        }
+    };
+    
+    Ok(ClipboardCompressResult {
+        result,
+        clipboard,
+        clipboard_error,
+    })
+}
+
+// 质量曲线的探测结果：(lossy级别, 输出大小KB)
+type QualityProbes = Vec<(u32, f64)>;
+
+// 质量曲线上的一个点
+#[derive(Clone, Serialize)]
+pub struct QualityPoint {
+    lossy: u32,
+    size_kb: f64,
+    // 画质评估，暂未计算
+    ssim: Option<f64>,
+    // true: 实际运行gifsicle得到; false: 由相邻探测点线性插值
+    probed: bool,
+}
+
+// 质量曲线的lossy范围和输出间隔
+const QUALITY_CURVE_MAX_LOSSY: u32 = 240;
+const QUALITY_CURVE_STEP: u32 = 10;
+// 探测点数量范围
+const QUALITY_CURVE_MIN_POINTS: usize = 2;
+const QUALITY_CURVE_MAX_POINTS: usize = 25;
+
+/// 在lossy范围内均匀选出探测点，包含两端
+fn quality_probe_levels(points: usize) -> Vec<u32> {
+    let points = points.clamp(QUALITY_CURVE_MIN_POINTS, QUALITY_CURVE_MAX_POINTS);
+    (0..points)
+        .map(|i| (QUALITY_CURVE_MAX_LOSSY as f64 * i as f64 / (points - 1) as f64).round() as u32)
+        .collect()
+}
+
+/// 按固定间隔生成曲线，探测点之间线性插值
+fn interpolate_quality_curve(probes: &[(u32, f64)]) -> Vec<QualityPoint> {
+    let mut probes = probes.to_vec();
+    probes.sort_by_key(|(level, _)| *level);
+    
+    let mut levels: Vec<u32> = (0..=QUALITY_CURVE_MAX_LOSSY).step_by(QUALITY_CURVE_STEP as usize).collect();
+    levels.extend(probes.iter().map(|(level, _)| *level));
+    levels.sort_unstable();
+    levels.dedup();
+    
+    levels
+        .into_iter()
+        .filter_map(|level| {
+            if let Some((_, size)) = probes.iter().find(|(l, _)| *l == level) {
+                return Some(QualityPoint { lossy: level, size_kb: *size, ssim: None, probed: true });
+            }
+            let upper = probes.iter().position(|(l, _)| *l > level)?;
+            if upper == 0 {
+                return None;
+            }
+            let (l0, s0) = probes[upper - 1];
+            let (l1, s1) = probes[upper];
+            let t = (level - l0) as f64 / (l1 - l0) as f64;
+            Some(QualityPoint { lossy: level, size_kb: s0 + (s1 - s0) * t, ssim: None, probed: false })
+        })
+        .collect()
+}
+
+// 探测进度回调：(已完成的探测数, 总探测数)，每完成一个lossy级别调用一次
+type ProbeProgress<'a> = &'a (dyn Fn(usize, usize) + Sync);
+
+/// 按指定间隔抽帧后，在线程池中逐个lossy级别探测输出大小
+fn probe_quality_curve(
+    input_path: &Path,
+    skip: usize,
+    levels: &[u32],
+    threads: usize,
+    shared_state: &SharedState,
+    progress: ProbeProgress<'_>,
+) -> Result<QualityProbes, GifError> {
+    probe_lossy_levels(input_path, skip, levels, threads, shared_state, progress, |level, probe_path| {
+        let size = get_file_size_kb(probe_path).ok()?;
+        println!("质量曲线: lossy={} 大小: {:.2} KB", level, size);
+        Some((level, size))
+    })
+}
+
+/// 按指定间隔抽帧后，在线程池中逐个lossy级别压缩，用measure评估每个输出
+///
+/// 输出文件在measure返回后删除，measure返回None的级别不计入结果；
+/// 每个级别结束后（无论成败）通过progress报告进度
+fn probe_lossy_levels<T: Send>(
+    input_path: &Path,
+    skip: usize,
+    levels: &[u32],
+    threads: usize,
+    shared_state: &SharedState,
+    progress: ProbeProgress<'_>,
+    measure: impl Fn(u32, &Path) -> Option<T> + Sync,
+) -> Result<Vec<T>, GifError> {
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let frame_count = get_frame_count(input_path)?;
+    
+    // 保留原始节奏抽帧，与压缩时的策略一致
+    let strategy = Strategy {
+        skip,
+        delay: ((100.0 * skip as f64) / frame_count as f64) as u16 + 10,
+        motion_threshold: None,
+        cinemagraph: false,
+        scale: None,
+    };
+    let options = CompressOptions {
+        delay_rounding: Some(DelayRounding::Nearest),
+        ..CompressOptions::default()
+    };
+    let frames = TempFile::new(NamedTempFile::new()?);
+    extract_frames(input_path, frames.path_str(), &strategy, &options, shared_state)?;
+    let frames_path = frames.path_str();
+    // 抽帧本身也算作进展
+    progress(0, levels.len());
+    
+    let queue = std::sync::Mutex::new(levels.iter().copied());
+    let results = std::sync::Mutex::new(Vec::with_capacity(levels.len()));
+    let done = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, levels.len().max(1)) {
+            scope.spawn(|| loop {
+                if shared_state.stop_reason().is_some() {
+                    break;
+                }
+                let level = match queue.lock().unwrap().next() {
+                    Some(level) => level,
+                    None => break,
+                };
+                
+                let probe = match NamedTempFile::new() {
+                    Ok(file) => TempFile::new(file),
+                    Err(_) => continue,
+                };
+                let probe_path = probe.path_str();
+                let lossy_arg = format!("--lossy={}", level);
+                let mut args = vec!["-O3", "--no-warnings", "--no-comments", "--no-names"];
+                if level > 0 {
+                    args.push(&lossy_arg);
+                }
+                args.extend([frames_path.as_str(), "-o", probe_path.as_str()]);
+                
+                match run_gifsicle(&gifsicle_path, &args, shared_state) {
+                    Ok(output) if output.status.success() => {
+                        if let Some(result) = measure(level, &probe.path) {
+                            results.lock().unwrap().push(result);
+                        }
+                    }
+                    _ => println!("lossy={} 探测失败", level),
+                }
+                progress(done.fetch_add(1, Ordering::SeqCst) + 1, levels.len());
+            });
+        }
+    });
+    
+    if let Some(reason) = shared_state.stop_reason() {
+        return Err(GifError::Other(reason.to_string()));
+    }
+    let results = results.into_inner().unwrap();
+    if results.is_empty() {
+        return Err(GifError::NoValidResults);
+    }
+    Ok(results)
+}
+
+// 画质下限搜索中尝试的抽帧间隔和lossy级别数量
+const QUALITY_FLOOR_SKIPS: [usize; 4] = [1, 2, 3, 4];
+const QUALITY_FLOOR_POINTS: usize = 7;
+
+// 画质下限搜索中的一个候选
+#[derive(Clone, Serialize)]
+pub struct QualityFloorCandidate {
+    skip: usize,
+    lossy: u32,
+    size_kb: f64,
+    ssim: f64,
+}
+
+// 画质下限内能达到的最小大小
+#[derive(Clone, Serialize)]
+pub struct QualityFloorResult {
+    min_ssim: f64,
+    // 满足画质下限的最小结果，没有任何候选满足时为空
+    best: Option<QualityFloorCandidate>,
+    // 所有尝试过的候选，按大小从小到大
+    candidates: Vec<QualityFloorCandidate>,
+}
+
+/// 选出SSIM不低于min_ssim的最小候选
+fn smallest_meeting_floor(candidates: &[QualityFloorCandidate], min_ssim: f64) -> Option<&QualityFloorCandidate> {
+    candidates
+        .iter()
+        .filter(|candidate| candidate.ssim >= min_ssim)
+        .min_by(|a, b| a.size_kb.total_cmp(&b.size_kb))
+}
+
+/// 在几个抽帧间隔和lossy级别上压缩并计算相对原文件的SSIM，找出满足画质下限的最小大小
+fn search_quality_floor(
+    input_path: &Path,
+    min_ssim: f64,
+    threads: usize,
+    shared_state: &SharedState,
+    progress: ProbeProgress<'_>,
+) -> Result<QualityFloorResult, GifError> {
+    let frame_count = get_frame_count(input_path)?;
+    let levels = quality_probe_levels(QUALITY_FLOOR_POINTS);
+    let skips: Vec<usize> = QUALITY_FLOOR_SKIPS.into_iter().filter(|&skip| skip == 1 || frame_count / skip >= 3).collect();
+    let total = skips.len() * levels.len();
+    let mut candidates = Vec::new();
+    for (round, &skip) in skips.iter().enumerate() {
+        // 各抽帧间隔的探测数累加成总进度
+        let offset = round * levels.len();
+        let round_progress = |done: usize, _: usize| progress(offset + done, total);
+        let probed = probe_lossy_levels(input_path, skip, &levels, threads, shared_state, &round_progress, |lossy, probe_path| {
+            let size_kb = get_file_size_kb(probe_path).ok()?;
+            let ssim = gif_ssim(input_path, probe_path).ok()?;
+            println!("画质下限: 抽帧间隔={} lossy={} 大小: {:.2} KB SSIM: {:.4}", skip, lossy, size_kb, ssim);
+            Some(QualityFloorCandidate { skip, lossy, size_kb, ssim })
+        })?;
+        candidates.extend(probed);
+    }
+    candidates.sort_by(|a, b| a.size_kb.total_cmp(&b.size_kb));
+    
+    Ok(QualityFloorResult {
+        min_ssim,
+        best: smallest_meeting_floor(&candidates, min_ssim).cloned(),
+        candidates,
+    })
+}
+
+// 单项优化手段的贡献
+#[derive(Clone, Serialize)]
+pub struct LeverContribution {
+    // base: 基础优化; frames: 抽帧; lossy: 有损压缩; scale: 缩小画布
+    lever: String,
+    // 单独关闭该手段时的大小，base为只做基础优化的大小
+    size_without_kb: f64,
+    // 单独关闭该手段时增加的大小
+    marginal_kb: f64,
+    // 分摊到该手段的节省量，各项之和等于总节省量
+    attributed_kb: f64,
+}
+
+// 各项优化手段的贡献分析
+#[derive(Clone, Serialize)]
+pub struct LeverBreakdown {
+    original_kb: f64,
+    final_kb: f64,
+    total_saved_kb: f64,
+    levers: Vec<LeverContribution>,
+    notes: Vec<String>,
+}
+
+/// 贡献分析和降级顺序搜索中重新压缩使用的配置，各项为空表示不使用该手段
+#[derive(Clone, Copy)]
+struct LeverConfig<'a> {
+    strategy: Option<&'a Strategy>,
+    lossy: Option<u32>,
+    colors: Option<u16>,
+    scale: Option<(u32, u32)>,
+}
+
+/// 按指定配置重新压缩一次，返回压缩结果
+fn render_config(
+    gifsicle_path: &str,
+    input_path: &Path,
+    config: LeverConfig,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+) -> Result<TempFile, GifError> {
+    let run = |args: &[&str]| -> Result<(), GifError> {
+        let output = run_gifsicle(gifsicle_path, args, shared_state)?;
+        if !output.status.success() {
+            return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+        }
+        Ok(())
+    };
+    
+    let source = input_path.to_string_lossy().to_string();
+    let optimized = TempFile::new(NamedTempFile::new()?);
+    let scaled = TempFile::new(NamedTempFile::new()?);
+    let frames = TempFile::new(NamedTempFile::new()?);
+    
+    let mut current = source;
+    if let Some((width, height)) = config.scale {
+        let fit = format!("{}x{}", width, height);
+        run(&["-O3", "--no-warnings", "--resize-fit", &fit, &current, "-o", &scaled.path_str()])?;
+        current = scaled.path_str();
+    }
+    if let Some(strategy) = config.strategy {
+        extract_frames(&current, frames.path_str(), strategy, options, shared_state)?;
+        current = frames.path_str();
+    }
+    run(&["-O3", "--no-warnings", &current, "-o", &optimized.path_str()])?;
+    let mut result = optimized;
+    
+    if let Some(level) = config.lossy {
+        let lossy_output = TempFile::new(NamedTempFile::new()?);
+        let lossy_arg = format!("--lossy={}", level);
+        run(&["-O3", "--no-warnings", &lossy_arg, &result.path_str(), "-o", &lossy_output.path_str()])?;
+        result = lossy_output;
+    }
+    
+    if let Some(colors) = config.colors {
+        let colors_output = TempFile::new(NamedTempFile::new()?);
+        let colors_arg = colors.to_string();
+        run(&["-O3", "--no-warnings", "--colors", &colors_arg, &result.path_str(), "-o", &colors_output.path_str()])?;
+        result = colors_output;
+    }
+    Ok(result)
+}
+
+/// 分析最终结果中每项优化手段的贡献
+///
+/// 依次单独关闭每项手段重新压缩，增加的大小即该手段的边际贡献；
+/// 基础优化之外的节省量按边际贡献的比例分摊
+fn analyze_levers(
+    input_path: &Path,
+    outcome: &OptimizeOutcome,
+    options: &CompressOptions,
+    shared_state: &SharedState,
+) -> Result<LeverBreakdown, GifError> {
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let full = LeverConfig {
+        strategy: outcome.winner.as_ref().and_then(|winner| winner.strategy.as_ref()),
+        lossy: outcome.winner.as_ref().and_then(|winner| winner.lossy),
+        colors: outcome.winner.as_ref().and_then(|winner| winner.colors),
+        scale: outcome.downscaled_to,
+    };
+    let render = |config: LeverConfig| {
+        render_config(&gifsicle_path, input_path, config, options, shared_state)
+            .and_then(|file| get_file_size_kb(&file.path))
+    };
+    
+    let base_kb = render(LeverConfig { strategy: None, lossy: None, colors: None, scale: None })?;
+    let mut levers = Vec::new();
+    if full.strategy.is_some() {
+        levers.push(("frames", render(LeverConfig { strategy: None, ..full })?));
+    }
+    if full.lossy.is_some() {
+        levers.push(("lossy", render(LeverConfig { lossy: None, ..full })?));
+    }
+    if full.colors.is_some() {
+        levers.push(("colors", render(LeverConfig { colors: None, ..full })?));
+    }
+    if full.scale.is_some() {
+        levers.push(("scale", render(LeverConfig { scale: None, ..full })?));
+    }
+    
+    let final_kb = outcome.final_size;
+    let total_saved_kb = outcome.original_size - final_kb;
+    let base_saved_kb = outcome.original_size - base_kb;
+    let marginal_sum: f64 = levers.iter().map(|(_, without)| (without - final_kb).max(0.0)).sum();
+    let remaining_kb = total_saved_kb - base_saved_kb;
+    
+    let mut contributions = vec![LeverContribution {
+        lever: "base".to_string(),
+        size_without_kb: base_kb,
+        marginal_kb: base_saved_kb,
+        attributed_kb: base_saved_kb,
+    }];
+    for (lever, without) in levers {
+        let marginal_kb = without - final_kb;
+        let attributed_kb = if marginal_sum > 0.0 {
+            remaining_kb * marginal_kb.max(0.0) / marginal_sum
+        } else {
+            0.0
+        };
+        contributions.push(LeverContribution {
+            lever: lever.to_string(),
+            size_without_kb: without,
+            marginal_kb,
+            attributed_kb,
+        });
+    }
+    
+    Ok(LeverBreakdown {
+        original_kb: outcome.original_size,
+        final_kb,
+        total_saved_kb,
+        levers: contributions,
+        notes: if full.colors.is_none() {
+            vec!["未统计颜色数量：本次压缩没有减少颜色".to_string()]
+        } else {
+            Vec::new()
+        },
+    })
+}
+
+// 取消正在进行的优化手段贡献分析，压缩结果不受影响
+#[tauri::command]
+fn cancel_lever_analysis(state: State<'_, AppState>) -> bool {
+    match state.lever_analysis_job.lock().unwrap().take() {
+        Some(job) => {
+            job.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// 在登记的任务中探测质量曲线，每完成一个探测点更新一次任务进度，看门狗据此判断任务是否仍有进展
+fn probe_quality_curve_job(
+    job: &JobGuard,
+    input_path: &Path,
+    skip: usize,
+    levels: &[u32],
+    threads: usize,
+    shared_state: &SharedState,
+) -> Result<QualityProbes, GifError> {
+    let progress = |done: usize, total: usize| {
+        job.update("探测lossy级别", done as f64 / total as f64, Some(&format!("{}/{}", done, total)));
+    };
+    job.map_stalled(probe_quality_curve(input_path, skip, levels, threads, shared_state, &progress))
+}
+
+// 获取在指定抽帧间隔下，输出大小随lossy级别变化的曲线
+#[tauri::command]
+async fn get_quality_curve(
+    state: State<'_, AppState>,
+    input_path: String,
+    skip: usize,
+    points: usize,
+) -> Result<Vec<QualityPoint>, String> {
+    let real_input = resolve_input_path(&input_path).map_err(|e| e.to_string())?;
+    let skip = skip.max(1);
+    let levels = quality_probe_levels(points);
+    
+    let hash_input = real_input.clone();
+    let hash = tokio::task::spawn_blocking(move || sha256_file(&hash_input))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let key = (hash, skip);
+    
+    // 已经探测过足够多的点时直接使用缓存
+    if let Some(probes) = state.quality_curves.lock().unwrap().get(&key) {
+        if probes.len() >= levels.len() {
+            return Ok(interpolate_quality_curve(probes));
+        }
+    }
+    
+    // 新的请求会取消仍在计算的旧曲线
+    let registered = Arc::new(state.jobs.start(JobKind::QualityCurve, &input_path, None, true));
+    registered.update("探测lossy级别", 0.0, None);
+    let shared_state = Arc::new(SharedState::with_cancel_flag(registered.cancel_flag()));
+    if let Some(previous) = state.quality_curve_job.lock().unwrap().replace(Arc::clone(&shared_state)) {
+        previous.cancel();
+    }
+    
+    let threads = detect_concurrency_limits(Some(&input_path)).recommended;
+    let job_state = Arc::clone(&shared_state);
+    let job = Arc::clone(&registered);
+    let probes = tokio::task::spawn_blocking(move || probe_quality_curve_job(&job, &real_input, skip, &levels, threads, &job_state))
+        .await
+        .map_err(|e| e.to_string())?;
+    drop(registered);
+    
+    {
+        let mut job = state.quality_curve_job.lock().unwrap();
+        if job.as_ref().is_some_and(|current| Arc::ptr_eq(current, &shared_state)) {
+            *job = None;
+        }
+    }
+    
+    let probes = probes.map_err(|e| e.to_string())?;
+    let curve = interpolate_quality_curve(&probes);
+    state.quality_curves.lock().unwrap().insert(key, probes);
+    Ok(curve)
+}
+
+// 给定最低可接受的SSIM，找出在这个画质下能压缩到的最小大小及对应的参数
+#[tauri::command]
+async fn get_min_size_for_quality(
+    state: State<'_, AppState>,
+    input_path: String,
+    min_ssim: f64,
+) -> Result<QualityFloorResult, String> {
+    if !(0.0..=1.0).contains(&min_ssim) {
+        return Err(GifError::InvalidOption(format!("min_ssim {} 超出范围，必须在0到1之间", min_ssim)).to_string());
+    }
+    let real_input = resolve_input_path(&input_path).map_err(|e| e.to_string())?;
+    let registered = Arc::new(state.jobs.start(JobKind::QualityFloor, &input_path, None, false));
+    registered.update("按画质下限搜索最小大小", 0.0, None);
+    let shared_state = SharedState::with_cancel_flag(registered.cancel_flag());
+    let threads = detect_concurrency_limits(Some(&input_path)).recommended;
+    let job = Arc::clone(&registered);
+    let result = tokio::task::spawn_blocking(move || {
+        let progress = |done: usize, total: usize| {
+            job.update("按画质下限搜索最小大小", done as f64 / total as f64, Some(&format!("{}/{}", done, total)));
+        };
+        search_quality_floor(&real_input, min_ssim, threads, &shared_state, &progress)
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    registered.map_stalled(result).map_err(|e| e.to_string())
+}
+
+// 取消正在进行的压缩：给出job_id时只取消该任务，否则取消所有压缩任务，返回取消的任务数
+//
+// 正在运行的gifsicle被终止，临时文件随之删除，压缩结果的cancelled为真
+#[tauri::command]
+fn cancel_compress(state: State<'_, AppState>, job_id: Option<String>) -> usize {
+    let cancelled = state.jobs.cancel(JobKind::Compress, job_id.as_deref());
+    println!("取消了 {} 个压缩任务", cancelled);
+    cancelled
+}
+
+// cancel_compress的旧名称，保留给仍在调用它的前端
+#[tauri::command]
+fn cancel_compression(state: State<'_, AppState>, job_id: Option<String>) -> usize {
+    cancel_compress(state, job_id)
+}
+
+// 列出所有正在运行的任务
+#[tauri::command]
+fn get_active_jobs(state: State<'_, AppState>) -> Vec<ActiveJob> {
+    state.jobs.list()
+}
+
+// 在后台压缩GIF并立即返回任务id，之后通过get_job_status查询进度和结果
+//
+// 结果一直保留到clear_job清除，前端重新加载后可以通过list_jobs找回任务
+#[tauri::command]
+fn start_compress_job(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    input_path: String,
+    output_path: String,
+    options: CompressOptions,
+) -> String {
+    let id = state.jobs.detach();
+    let job_id = id.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<AppState>();
+        let target_size = options.target_size;
+        let result = match run_compress(state.clone(), input_path, output_path.clone(), options, None, None, Some(job_id.clone())).await {
+            Ok(result) => result,
+            Err(e) => build_compress_result(Err(GifError::Other(e)), &output_path, target_size),
+        };
+        println!("后台任务 {} 结束: {}", job_id, result.message);
+        state.jobs.finish_detached(&job_id, result);
+    });
+    id
+}
+
+// 查询任务的状态，正在运行的任务返回进度，start_compress_job启动的任务结束后返回结果
+#[tauri::command]
+fn get_job_status(state: State<'_, AppState>, job_id: String) -> Result<JobRecord, String> {
+    state.jobs.status(&job_id).ok_or_else(|| format!("任务不存在: {}", job_id))
+}
+
+// 列出正在运行的任务和已结束、尚未清除的后台任务
+#[tauri::command]
+fn list_jobs(state: State<'_, AppState>) -> Vec<JobRecord> {
+    state.jobs.records()
+}
+
+// 清除已结束的后台任务的结果，返回是否清除了
+#[tauri::command]
+fn clear_job(state: State<'_, AppState>, job_id: String) -> bool {
+    state.jobs.clear_detached(&job_id)
+}
+
+// 设置任务无进展的超时（秒），超过后任务被强制终止
+#[tauri::command]
+fn set_job_stall_timeout(state: State<'_, AppState>, timeout_secs: u64) {
+    state.jobs.set_stall_timeout(Duration::from_secs(timeout_secs));
+}
+
+// 取消正在计算的质量曲线
+#[tauri::command]
+fn cancel_quality_curve(state: State<'_, AppState>) -> bool {
+    match state.quality_curve_job.lock().unwrap().take() {
+        Some(job) => {
+            job.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// 文件状态快照，大小和修改时间都没变才认为文件没有变化
+#[derive(Clone, Copy, PartialEq)]
+struct FileSnapshot {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileSnapshot {
+    fn read<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+// 监视文件的轮询间隔
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 监视线程主循环
+///
+/// 压缩在本线程内同步执行，期间发生的多次写入只会在压缩结束后
+/// 按文件的最新状态触发一次重新压缩，不会堆积任务
+fn watch_input_loop(app: AppHandle, path: String, job: WatchJob, stop: Arc<AtomicBool>) {
+    let mut last = FileSnapshot::read(&path);
+    
+    'watch: while !stop.load(Ordering::Relaxed) {
+        thread::sleep(WATCH_POLL_INTERVAL);
+        
+        let mut current = match FileSnapshot::read(&path) {
+            Some(snapshot) => snapshot,
+            None => break 'watch,
+        };
+        if last == Some(current) {
+            continue;
+        }
+        
+        // 等待写入结束：两次轮询之间文件状态不再变化
+        loop {
+            thread::sleep(WATCH_POLL_INTERVAL);
+            if stop.load(Ordering::Relaxed) {
+                break 'watch;
+            }
+            match FileSnapshot::read(&path) {
+                Some(next) if next == current => break,
+                Some(next) => current = next,
+                None => break 'watch,
+            }
+        }
+        last = Some(current);
+        
+        if !job.auto_recompress {
+            println!("监视的文件已变化: {}", path);
+            let _ = app.emit("input-changed", InputChanged { path: path.clone(), removed: false });
+            continue;
+        }
+        
+        println!("监视的文件已变化，重新压缩: {}", path);
+        let registered = app.state::<AppState>().jobs.start(JobKind::WatchRecompress, &path, Some(&job.output_path), false);
+        let on_event = |event: OptimizeEvent<'_>| {
+            if let OptimizeEvent::Progress(progress) = event {
+                registered.update(&progress.status, progress.progress, progress.details.as_deref());
+            }
+        };
+        let result = optimize_gif(
+            &path,
+            &job.output_path,
+            &job.options,
+            effective_threads(&job.options, &path),
+            Some(&on_event),
+            Some(registered.cancel_flag()),
+        );
+        let result = registered.map_stalled(result);
+        drop(registered);
+        let compress_result = build_compress_result(result, &job.output_path, job.options.target_size);
+        *app.state::<AppState>().last_result.lock().unwrap() = Some(compress_result.clone());
+        let _ = app.emit("input-recompressed", InputRecompressed {
+            path: path.clone(),
+            result: compress_result,
+        });
+    }
+    
+    // 文件被删除时通知前端；主动停止的情况由unwatch_input负责
+    if !stop.load(Ordering::Relaxed) {
+        println!("监视的文件已被删除，停止监视: {}", path);
+        let _ = app.emit("input-changed", InputChanged { path: path.clone(), removed: true });
+    }
+    
+    // 只移除属于本线程的记录，避免误删同一路径上新建的监视
+    let state = app.state::<AppState>();
+    let mut watchers = state.watchers.lock().unwrap();
+    if watchers.get(&path).is_some_and(|flag| Arc::ptr_eq(flag, &stop)) {
+        watchers.remove(&path);
+    }
+}
+
+// 监视输入文件，文件变化后重新压缩或通知前端
+#[tauri::command]
+fn watch_input(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    path: String,
+    job_template: WatchJob,
+) -> Result<(), String> {
+    if !Path::new(&path).is_file() {
+        return Err(GifError::InputFileNotFound(path).to_string());
+    }
+    // 参考文件的大小在开始监视时确定
+    let mut job_template = job_template;
+    apply_target_like(&mut job_template.options).map_err(|e| e.to_string())?;
+    if is_same_file(&path, &job_template.output_path) {
+        return Err("输出路径不能与监视的输入文件相同".to_string());
+    }
+    
+    let stop = Arc::new(AtomicBool::new(false));
+    if let Some(old) = state.watchers.lock().unwrap().insert(path.clone(), Arc::clone(&stop)) {
+        old.store(true, Ordering::Relaxed);
+    }
+    
+    thread::spawn(move || watch_input_loop(app, path, job_template, stop));
+    Ok(())
+}
+
+// 停止监视输入文件，返回该文件之前是否处于监视中
+#[tauri::command]
+fn unwatch_input(state: State<'_, AppState>, path: String) -> bool {
+    match state.watchers.lock().unwrap().remove(&path) {
+        Some(stop) => {
+            stop.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+// 单帧的压缩数据大小
+#[derive(Clone, Serialize)]
+pub struct FrameSize {
+    index: usize,
+    bytes: u64,
+    // 该帧开始显示的时间和显示时长（毫秒）
+    timestamp_ms: u64,
+    delay_ms: u64,
+    // 占全部帧数据的百分比
+    percent: f64,
+}
+
+// 每帧大小分析结果
+#[derive(Clone, Serialize)]
+pub struct FrameSizeReport {
+    total_bytes: u64,
+    frames: Vec<FrameSize>,
+    // 数据量最大的几帧，按大小从大到小排列
+    top: Vec<FrameSize>,
+}
+
+// 未指定数量时列出的最大帧数
+const DEFAULT_TOP_FRAMES: usize = 5;
+
+// 延迟小于这个值（厘秒）的帧，浏览器等播放器会按DEFAULT_PLAYBACK_DELAY_CS显示
+const MIN_PLAYBACK_DELAY_CS: u16 = 2;
+const DEFAULT_PLAYBACK_DELAY_CS: u16 = 10;
+
+/// 帧实际播放时的延迟（厘秒），0和1按常见播放器的处理方式视为10
+fn playback_delay_cs(delay_cs: u16) -> u16 {
+    if delay_cs < MIN_PLAYBACK_DELAY_CS {
+        DEFAULT_PLAYBACK_DELAY_CS
+    } else {
+        delay_cs
+    }
+}
+
+/// 按实际播放延迟计算动画总时长（毫秒）
+fn animation_duration_ms<P: AsRef<Path>>(path: P) -> Result<u64, GifError> {
+    let scan = scan_gif_blocks(path)?;
+    Ok(scan.frames.iter().map(|block| playback_delay_cs(block.delay_cs) as u64 * 10).sum())
+}
+
+/// 输出相对输入的动画时长变化百分比，单帧图片没有时长时为空
+fn duration_deviation_percent<P: AsRef<Path>, Q: AsRef<Path>>(input_path: P, output_path: Q) -> Option<f64> {
+    if get_frame_count(&input_path).ok()? < 2 {
+        return None;
+    }
+    let source = animation_duration_ms(input_path).ok()?;
+    let output = animation_duration_ms(output_path).ok()?;
+    (source > 0).then(|| (output as f64 - source as f64) * 100.0 / source as f64)
+}
+
+// 启发式估算的模型参数，按gifsicle的常见压缩效果设定，可以根据实际压缩结果调整
+// 基础优化（-O3）后剩余的比例
+const HEURISTIC_BASE_FACTOR: f64 = 0.9;
+// 使用最高lossy级别后剩余的比例，较低的级别按线性插值
+const HEURISTIC_MAX_LOSSY_FACTOR: f64 = 0.45;
+// 减少颜色时与颜色位数无关、不会减小的那部分数据的比例
+const HEURISTIC_COLOR_FIXED_SHARE: f64 = 0.6;
+// 估算的误差范围：实际大小一般在估算值的1/(1+band)到(1+band)倍之间
+const HEURISTIC_ERROR_BAND: f64 = 0.5;
+
+// 不运行gifsicle的启发式大小估算
+#[derive(Clone, Serialize)]
+pub struct HeuristicEstimate {
+    // 始终为true，表示这只是按文件头信息的经验估算
+    heuristic: bool,
+    original_size: f64,
+    frame_count: usize,
+    width: u32,
+    height: u32,
+    palette_size: usize,
+    // 只做无损优化（以及指定的缩小和颜色数）时的大小
+    lossless_kb: f64,
+    // 用尽options允许的抽帧、lossy和减色后的大小
+    smallest_kb: f64,
+    // 指定了目标大小时是否可能达到
+    target_reachable: Option<bool>,
+    error_band: f64,
+}
+
+/// 按原文件大小、帧数、尺寸和调色板大小估算options下能达到的大小范围
+///
+/// 各项手段按固定比例相乘，不考虑画面内容，只用于在压缩前快速给出大致范围
+fn heuristic_estimate(
+    original_size: f64,
+    frame_count: usize,
+    (width, height): (u32, u32),
+    palette_size: usize,
+    options: &CompressOptions,
+) -> HeuristicEstimate {
+    let frame_count_f = frame_count.max(1) as f64;
+    let pixels = width.max(1) as f64 * height.max(1) as f64;
+    
+    // 缩小或按尺寸预设缩放后画面面积的比例
+    let mut area_ratio = 1.0;
+    if let Ok(Some(size)) = dimension_preset(options) {
+        let info = letterbox_geometry((width, height), size);
+        area_ratio = info.scaled_width as f64 * info.scaled_height as f64 / pixels;
+    } else if options.max_width.is_some() || options.max_height.is_some() {
+        let scale = options.max_width.map_or(1.0, |max| max as f64 / width.max(1) as f64)
+            .min(options.max_height.map_or(1.0, |max| max as f64 / height.max(1) as f64))
+            .min(1.0);
+        area_ratio = scale * scale;
+    }
+    
+    // 减少颜色按颜色位数的比例减小剩余部分
+    let palette_size = palette_size.clamp(2, 256);
+    let color_factor = |colors: u16| {
+        let colors = (colors as usize).clamp(2, 256);
+        if colors >= palette_size {
+            1.0
+        } else {
+            HEURISTIC_COLOR_FIXED_SHARE
+                + (1.0 - HEURISTIC_COLOR_FIXED_SHARE) * (colors as f64).log2() / (palette_size as f64).log2()
+        }
+    };
+    let fixed_colors = options.max_colors.map_or(1.0, color_factor);
+    let lossless_kb = original_size * HEURISTIC_BASE_FACTOR * area_ratio * fixed_colors;
+    
+    let min_frames = min_frame_floor(frame_count, options);
+    let keep_ratio = min_frames as f64 / frame_count_f;
+    let max_lossy = lossy_levels(options).last().copied().unwrap_or(0);
+    let lossy_factor = 1.0 - (1.0 - HEURISTIC_MAX_LOSSY_FACTOR) * max_lossy as f64 / LOSSY_LEVELS[LOSSY_LEVELS.len() - 1] as f64;
+    // 只有自动减色时才会低于原有的颜色数
+    let reduced_colors = if options.max_colors.is_some() {
+        fixed_colors
+    } else if auto_color_reduction(options) {
+        color_factor(color_floor(options))
+    } else {
+        1.0
+    };
+    // 不会低于每帧每像素的经验下限
+    let floor_kb = pixels * area_ratio * min_frames as f64 * MIN_BYTES_PER_PIXEL_FRAME / 1024.0;
+    let smallest_kb = (original_size * HEURISTIC_BASE_FACTOR * area_ratio * keep_ratio * lossy_factor * reduced_colors)
+        .max(floor_kb.min(lossless_kb));
+    
+    HeuristicEstimate {
+        heuristic: true,
+        original_size,
+        frame_count,
+        width,
+        height,
+        palette_size,
+        lossless_kb,
+        smallest_kb,
+        target_reachable: (options.target_size > 0.0).then_some(options.target_size >= smallest_kb),
+        error_band: HEURISTIC_ERROR_BAND,
+    }
+}
+
+// 预演中的一个抽帧策略
+#[derive(Clone, Serialize)]
+pub struct PlannedStrategy {
+    strategy: String,
+    skip: usize,
+    delay: u16,
+    // 保留的帧数，按画面变化抽帧时为空
+    kept_frames: Option<usize>,
+}
+
+// 抽帧策略的预演结果
+#[derive(Clone, Serialize)]
+pub struct StrategyDryRun {
+    frame_count: usize,
+    min_frames: usize,
+    // 按尝试顺序排列
+    strategies: Vec<PlannedStrategy>,
+    rejected: Vec<RejectedStrategy>,
+}
+
+// 预演压缩会尝试哪些抽帧策略以及排除了哪些，不运行gifsicle
+#[tauri::command]
+async fn dry_run_strategies(
+    state: State<'_, AppState>,
+    input_path: String,
+    options: CompressOptions,
+) -> Result<StrategyDryRun, String> {
+    // 检测静态背景需要解码全部帧，登记为压缩任务，cancel_compress可以中止
+    let job = state.jobs.start(JobKind::Compress, &input_path, None, true);
+    let cancel_flag = job.cancel_flag();
+    let result = tokio::task::spawn_blocking(move || -> Result<StrategyDryRun, GifError> {
+        let real_input = resolve_input_path(&input_path)?;
+        let mut options = options;
+        apply_bit_depth(&mut options)?;
+        apply_quality_level(&mut options)?;
+        let frame_count = get_frame_count(&real_input)?;
+        let plan = plan_strategies(frame_count, &options);
+        
+        let mut strategies = Vec::new();
+        match detect_cinemagraph(&real_input, &SharedState::with_cancel_flag(cancel_flag)) {
+            Ok(Some(_)) => strategies.push(Strategy { skip: 1, delay: 0, motion_threshold: None, cinemagraph: true, scale: None }),
+            Err(GifError::Cancelled) => return Err(GifError::Cancelled),
+            _ => {}
+        }
+        strategies.extend(plan.strategies);
+        Ok(StrategyDryRun {
+            frame_count,
+            min_frames: plan.min_frames,
+            strategies: strategies
+                .iter()
+                .map(|strategy| PlannedStrategy {
+                    strategy: strategy.label(),
+                    skip: strategy.skip,
+                    delay: strategy.delay,
+                    kept_frames: strategy.motion_threshold.is_none().then(|| frame_count.div_ceil(strategy.skip)),
+                })
+                .collect(),
+            rejected: plan.rejected,
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    job.map_stalled(result).map_err(|e| e.to_string())
+}
+
+// 只读取文件头和块结构，按经验模型立即估算压缩能达到的大小范围，不运行gifsicle
+#[tauri::command]
+async fn estimate_compression(input_path: String, options: CompressOptions) -> Result<HeuristicEstimate, String> {
+    tokio::task::spawn_blocking(move || -> Result<HeuristicEstimate, GifError> {
+        let real_input = resolve_input_path(&input_path)?;
+        let mut options = options;
+        apply_bit_depth(&mut options)?;
+        apply_quality_level(&mut options)?;
+        let scan = scan_gif_blocks(&real_input)?;
+        Ok(heuristic_estimate(
+            get_file_size_kb(&real_input)?,
+            scan.frames.len(),
+            get_canvas_size(&real_input)?,
+            max_palette_size(&scan).unwrap_or(256),
+            &options,
+        ))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 分析每帧压缩数据的大小，找出占用最多的帧；只扫描块结构，不解码像素
+#[tauri::command]
+async fn get_frame_sizes(path: String, top: Option<usize>) -> Result<FrameSizeReport, String> {
+    let blocks = tokio::task::spawn_blocking(move || scan_gif_blocks(&path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?
+        .frames;
+    
+    let total_bytes: u64 = blocks.iter().map(|block| block.bytes).sum();
+    let mut timestamp_ms = 0;
+    let frames: Vec<FrameSize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| {
+            let delay_ms = playback_delay_cs(block.delay_cs) as u64 * 10;
+            let frame = FrameSize {
+                index,
+                bytes: block.bytes,
+                timestamp_ms,
+                delay_ms,
+                percent: block.bytes as f64 * 100.0 / total_bytes.max(1) as f64,
+            };
+            timestamp_ms += delay_ms;
+            frame
+        })
+        .collect();
+    
+    let mut top_frames = frames.clone();
+    top_frames.sort_by_key(|frame| std::cmp::Reverse(frame.bytes));
+    top_frames.truncate(top.unwrap_or(DEFAULT_TOP_FRAMES));
+    
+    Ok(FrameSizeReport {
+        total_bytes,
+        frames,
+        top: top_frames,
+    })
+}
+
+// 获取GIF信息
+#[tauri::command]
+async fn get_gif_info(path: String) -> Result<(f64, usize), String> {
+    let file_size = match get_file_size_kb(&path) {
+        Ok(size) => size,
+        Err(e) => return Err(format!("无法获取文件大小: {}", e)),
+    };
+    
+    let frame_count = match get_frame_count(&path) {
+        Ok(count) => count,
+        Err(e) => return Err(format!("无法获取帧数: {}", e)),
+    };
+    
+    Ok((file_size, frame_count))
+}
+
+// 画面变化热力图
+#[derive(Clone, Serialize)]
+pub struct MotionHeatmap {
+    width: u32,
+    height: u32,
+    // 热力图（PNG data URL），越亮表示该处画面变化越多
+    image: String,
+    // 变化最多的像素位置
+    hottest: (u32, u32),
+    frame_count: usize,
+}
+
+/// 解码时累计相邻帧之间每个像素的RGB差值之和，透明度变化也计入
+///
+/// 只保留上一帧，不需要把所有帧都放在内存中
+fn accumulate_motion<P: AsRef<Path>>(path: P) -> Result<(u32, u32, Vec<f64>, usize), GifError> {
+    let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions();
+    let mut energy = vec![0.0; width as usize * height as usize];
+    let mut previous: Option<image::RgbaImage> = None;
+    let mut frame_count = 0;
+    for frame in decoder.into_frames() {
+        let buffer = frame?.into_buffer();
+        frame_count += 1;
+        if let Some(previous) = &previous {
+            for ((value, a), b) in energy.iter_mut().zip(previous.pixels()).zip(buffer.pixels()) {
+                *value += (0..4).map(|c| (a[c] as f64 - b[c] as f64).abs()).sum::<f64>();
+            }
+        }
+        previous = Some(buffer);
+    }
+    if frame_count == 0 {
+        return Err(GifError::NoFrames);
+    }
+    Ok((width, height, energy, frame_count))
+}
+
+/// 把0-1的强度映射为黑-红-黄-白的颜色
+fn heat_color(intensity: f64) -> image::Rgba<u8> {
+    let t = intensity.clamp(0.0, 1.0) * 3.0;
+    let channel = |value: f64| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    image::Rgba([channel(t), channel(t - 1.0), channel(t - 2.0), 255])
+}
+
+// 计算每个像素在各帧之间的变化量，生成热力图，便于决定裁剪区域
+#[tauri::command]
+async fn get_motion_heatmap(path: String) -> Result<MotionHeatmap, String> {
+    tokio::task::spawn_blocking(move || -> Result<MotionHeatmap, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        let (width, height, energy, frame_count) = accumulate_motion(&real_input)?;
+        
+        let (hottest_index, max_energy) = energy
+            .iter()
+            .copied()
+            .enumerate()
+            .fold((0, 0.0), |best, (index, value)| if value > best.1 { (index, value) } else { best });
+        // 开平方拉开低变化区域的层次，否则少数变化剧烈的像素会让其余部分全黑
+        let heatmap = image::RgbaImage::from_fn(width, height, |x, y| {
+            let value = energy[(y * width + x) as usize];
+            heat_color(if max_energy > 0.0 { (value / max_energy).sqrt() } else { 0.0 })
+        });
+        
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(heatmap).write_to(&mut png, image::ImageOutputFormat::Png)?;
+        Ok(MotionHeatmap {
+            width,
+            height,
+            image: format!("data:image/png;base64,{}", BASE64.encode(png.into_inner())),
+            hottest: ((hottest_index as u32) % width.max(1), (hottest_index as u32) / width.max(1)),
+            frame_count,
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// 把文本编码成注释扩展块，每个数据子块最多255字节
+fn encode_comment_block(comment: &str) -> Vec<u8> {
+    let mut block = vec![0x21, 0xFE];
+    for chunk in comment.as_bytes().chunks(255) {
+        block.push(chunk.len() as u8);
+        block.extend_from_slice(chunk);
+    }
+    block.push(0x00);
+    block
+}
+
+/// 删除文件中所有注释块，comment不为空时在第一帧之前写入一个新注释块
+///
+/// 只在块的层面改写，图像数据和其他字节保持不变
+fn rewrite_comments(data: &[u8], scan: &GifScan, comment: Option<&str>) -> Vec<u8> {
+    // 第一帧的图形控制扩展从延迟字段往前4个字节开始
+    let insert_at = scan.frames.first().map(|frame| match frame.delay_offset {
+        Some(delay_offset) => delay_offset as usize - 4,
+        None => frame.offset as usize,
+    });
+    let mut output = Vec::with_capacity(data.len() + comment.map_or(0, |comment| comment.len() + 8));
+    let mut copied = 0;
+    let mut inserted = false;
+    for block in &scan.comments {
+        let offset = block.offset as usize;
+        if let (Some(comment), Some(insert_at), false) = (comment, insert_at, inserted) {
+            if insert_at <= offset {
+                output.extend_from_slice(&data[copied..insert_at]);
+                output.extend_from_slice(&encode_comment_block(comment));
+                copied = insert_at;
+                inserted = true;
+            }
+        }
+        output.extend_from_slice(&data[copied..offset]);
+        copied = offset + block.bytes as usize;
+    }
+    if let (Some(comment), Some(insert_at), false) = (comment, insert_at, inserted) {
+        output.extend_from_slice(&data[copied..insert_at]);
+        output.extend_from_slice(&encode_comment_block(comment));
+        copied = insert_at;
+    }
+    output.extend_from_slice(&data[copied..]);
+    output
+}
+
+/// 替换或删除GIF中的注释并写到output_path，返回输出文件中的注释
+///
+/// 先在内存中完成改写，output_path可以就是输入文件
+fn write_gif_comment_file(input_path: &Path, output_path: &Path, comment: Option<&str>) -> Result<Vec<String>, GifError> {
+    let data = fs::read(input_path)?;
+    let scan = scan_gif_blocks(input_path)?;
+    let rewritten = TempFile::new(NamedTempFile::new()?);
+    fs::write(&rewritten.path, rewrite_comments(&data, &scan, comment))?;
+    check_gif_output(&rewritten.path).map_err(GifError::Other)?;
+    let comments = scan_gif_blocks(&rewritten.path)?.comments.into_iter().map(|block| block.text).collect();
+    write_output(&rewritten.path, output_path)?;
+    Ok(comments)
+}
+
+// 读取GIF中全部注释扩展块的内容
+#[tauri::command]
+async fn get_gif_comments(path: String) -> Result<Vec<String>, String> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<String>, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        Ok(scan_gif_blocks(&real_input)?.comments.into_iter().map(|block| block.text).collect())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 用一个新注释替换GIF中原有的注释，不重新编码图像
+#[tauri::command]
+async fn set_gif_comment(path: String, comment: String, output_path: String) -> Result<Vec<String>, String> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<String>, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        write_gif_comment_file(&real_input, &resolve_output_path(&output_path), Some(&comment))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 删除GIF中的所有注释，不重新编码图像
+#[tauri::command]
+async fn remove_gif_comments(path: String, output_path: String) -> Result<Vec<String>, String> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<String>, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        write_gif_comment_file(&real_input, &resolve_output_path(&output_path), None)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 颜色计数器最多精确记录的颜色数，超过后只能说明"至少这么多"
+const COLOR_COUNTER_CAPACITY: usize = 4096;
+// 未指定时统计颜色的取样帧数
+const DEFAULT_COLOR_SAMPLE_FRAMES: usize = 8;
+
+/// 近似的颜色计数器
+///
+/// 固定大小的开放寻址哈希表，记录满COLOR_COUNTER_CAPACITY种颜色后不再增长，
+/// 只标记为已饱和，再大的帧也不会占用更多内存
+struct ColorCounter {
+    slots: Vec<u32>,
+    len: usize,
+    saturated: bool,
+}
+
+impl ColorCounter {
+    // 空槽位，RGB只使用低24位，不会与颜色冲突
+    const EMPTY: u32 = u32::MAX;
+    
+    fn new() -> Self {
+        Self {
+            slots: vec![Self::EMPTY; (COLOR_COUNTER_CAPACITY * 2).next_power_of_two()],
+            len: 0,
+            saturated: false,
+        }
+    }
+    
+    fn insert(&mut self, rgb: u32) {
+        let mask = self.slots.len() - 1;
+        let mut slot = (rgb.wrapping_mul(0x9E37_79B1) >> 8) as usize & mask;
+        loop {
+            match self.slots[slot] {
+                existing if existing == rgb => return,
+                Self::EMPTY => break,
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+        if self.len >= COLOR_COUNTER_CAPACITY {
+            self.saturated = true;
+            return;
+        }
+        self.slots[slot] = rgb;
+        self.len += 1;
+    }
+    
+    // 记录一帧中所有不透明像素的颜色
+    fn insert_image(&mut self, image: &image::RgbaImage) {
+        for pixel in image.pixels().filter(|pixel| pixel[3] != 0) {
+            self.insert(u32::from_be_bytes([0, pixel[0], pixel[1], pixel[2]]));
+        }
+    }
+}
+
+// 使用某种大小局部颜色表的帧数
+#[derive(Clone, Serialize)]
+pub struct PaletteSizeCount {
+    size: usize,
+    frames: usize,
+}
+
+// 一个取样帧实际使用的颜色数
+#[derive(Clone, Serialize)]
+pub struct FrameColorCount {
+    index: usize,
+    colors: usize,
+    // 为真时colors只是下限
+    saturated: bool,
+}
+
+// GIF的颜色使用情况
+#[derive(Clone, Serialize)]
+pub struct ColorStats {
+    global_palette_size: Option<usize>,
+    // 按大小统计的局部颜色表，从小到大
+    local_palette_sizes: Vec<PaletteSizeCount>,
+    frames_without_local_palette: usize,
+    sampled_frames: Vec<FrameColorCount>,
+    // 所有取样帧合计使用的颜色数，saturated为真时只是下限
+    distinct_colors: usize,
+    saturated: bool,
+    // 不损失颜色的--colors取值：不少于实际颜色数的最小2的幂，颜色表本身也只能是2的幂
+    recommended_colors: u16,
+}
+
+/// 统计GIF的调色板大小和实际使用的颜色数，在动画中均匀取样sample_frames帧
+fn color_stats<P: AsRef<Path>>(path: P, sample_frames: usize) -> Result<ColorStats, GifError> {
+    let scan = scan_gif_blocks(&path)?;
+    let mut local_sizes: Vec<PaletteSizeCount> = Vec::new();
+    for size in scan.frames.iter().filter_map(|frame| frame.local_palette) {
+        match local_sizes.iter_mut().find(|count| count.size == size) {
+            Some(count) => count.frames += 1,
+            None => local_sizes.push(PaletteSizeCount { size, frames: 1 }),
+        }
+    }
+    local_sizes.sort_by_key(|count| count.size);
+    
+    let step = (scan.frames.len() / sample_frames.max(1)).max(1);
+    let decoder = GifDecoder::new(BufReader::new(File::open(&path)?))?;
+    let mut overall = ColorCounter::new();
+    let mut sampled_frames = Vec::new();
+    for (index, frame) in decoder.into_frames().enumerate().step_by(step).take(sample_frames.max(1)) {
+        let frame = frame?;
+        let mut counter = ColorCounter::new();
+        counter.insert_image(frame.buffer());
+        overall.insert_image(frame.buffer());
+        sampled_frames.push(FrameColorCount { index, colors: counter.len, saturated: counter.saturated });
+    }
+    
+    let recommended_colors = if overall.saturated || overall.len > 256 {
+        256
+    } else {
+        overall.len.next_power_of_two().clamp(2, 256) as u16
+    };
+    Ok(ColorStats {
+        global_palette_size: scan.global_palette,
+        frames_without_local_palette: scan.frames.len() - local_sizes.iter().map(|count| count.frames).sum::<usize>(),
+        local_palette_sizes: local_sizes,
+        sampled_frames,
+        distinct_colors: overall.len,
+        saturated: overall.saturated,
+        recommended_colors,
+    })
+}
+
+// 统计GIF实际使用了多少种颜色，并给出建议的--colors取值
+#[tauri::command]
+async fn get_color_stats(path: String, sample_frames: Option<usize>) -> Result<ColorStats, String> {
+    tokio::task::spawn_blocking(move || -> Result<ColorStats, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        color_stats(&real_input, sample_frames.unwrap_or(DEFAULT_COLOR_SAMPLE_FRAMES))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 推荐的输出格式
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RecommendedFormat {
+    Gif,
+    Webp,
+    Apng,
+    Mp4,
+}
+
+// 推荐输出格式时用到的源文件特征
+#[derive(Clone, Serialize)]
+pub struct SourceTraits {
+    frame_count: usize,
+    // 取样帧合计使用的颜色数，colors_saturated为真时只是下限
+    distinct_colors: usize,
+    colors_saturated: bool,
+    // 每个取样帧平均使用的颜色数
+    mean_frame_colors: f64,
+    // 画面中是否有透明像素
+    has_transparency: bool,
+    // 相邻帧之间每个像素平均的变化量，0表示完全不变，1表示每个通道都从0变到255
+    motion: f64,
+    photographic: bool,
+    high_motion: bool,
+}
+
+// 输出格式推荐
+#[derive(Clone, Serialize)]
+pub struct FormatRecommendation {
+    format: RecommendedFormat,
+    // 一句话说明推荐的原因
+    rationale: String,
+    // 影响推荐的各项特征
+    factors: Vec<String>,
+    traits: SourceTraits,
+    // 本程序能否输出推荐的格式（GIF总是可以，WebP需要gif2webp，APNG和MP4没有编码器）
+    encoder_available: bool,
+    // 不能输出推荐的格式时可以改用的格式
+    fallback: Option<RecommendedFormat>,
+}
+
+// 每个取样帧平均使用的颜色数达到这个值时视为照片类画面，抖动后的照片几乎用满调色板
+const PHOTOGRAPHIC_FRAME_COLORS: f64 = 200.0;
+// 相邻帧之间每个像素的平均变化量达到这个值时视为变化剧烈
+const HIGH_MOTION_LEVEL: f64 = 0.06;
+// 合计颜色数不超过这个值的非照片画面视为线条画
+const LINE_ART_COLORS: usize = 64;
+
+/// 统计推荐输出格式所需的源文件特征：颜色来自color_stats，画面变化来自accumulate_motion
+fn source_traits<P: AsRef<Path>>(path: P) -> Result<SourceTraits, GifError> {
+    let colors = color_stats(&path, DEFAULT_COLOR_SAMPLE_FRAMES)?;
+    let (width, height, energy, frame_count) = accumulate_motion(&path)?;
+    let mut has_transparency = false;
+    for frame in GifDecoder::new(BufReader::new(File::open(&path)?))?.into_frames() {
+        if frame?.buffer().pixels().any(|pixel| pixel[3] == 0) {
+            has_transparency = true;
+            break;
+        }
+    }
+    
+    let mean_frame_colors = colors.sampled_frames.iter().map(|frame| frame.colors as f64).sum::<f64>()
+        / colors.sampled_frames.len().max(1) as f64;
+    let samples = width as f64 * height as f64 * frame_count.saturating_sub(1) as f64 * 4.0 * 255.0;
+    let motion = if samples > 0.0 { energy.iter().sum::<f64>() / samples } else { 0.0 };
+    Ok(SourceTraits {
+        frame_count,
+        distinct_colors: colors.distinct_colors,
+        colors_saturated: colors.saturated,
+        mean_frame_colors,
+        has_transparency,
+        motion,
+        photographic: mean_frame_colors >= PHOTOGRAPHIC_FRAME_COLORS,
+        high_motion: motion >= HIGH_MOTION_LEVEL,
+    })
+}
+
+/// 按源文件特征推荐输出格式
+///
+/// 照片类且变化剧烈的画面适合视频的帧间压缩（需要透明时用WebP），照片类画面适合WebP，
+/// 各帧颜色合计超过256种的非照片画面适合不需要共用调色板的APNG，颜色少的画面用GIF即可；
+/// 推荐的格式没有编码器时按WebP、GIF的顺序给出可以改用的格式
+fn recommend_format(traits: SourceTraits, webp_available: bool) -> FormatRecommendation {
+    let mut factors = vec![format!("{} 帧", traits.frame_count)];
+    factors.push(format!(
+        "取样帧合计 {}{} 种颜色，平均每帧 {:.0} 种{}",
+        traits.distinct_colors,
+        if traits.colors_saturated { "+" } else { "" },
+        traits.mean_frame_colors,
+        if traits.photographic { "，接近照片" } else { "" },
+    ));
+    factors.push(if traits.has_transparency { "有透明像素".to_string() } else { "没有透明像素".to_string() });
+    if traits.frame_count > 1 {
+        factors.push(format!("相邻帧平均变化 {:.3}{}", traits.motion, if traits.high_motion { "，变化剧烈" } else { "" }));
+    }
+    
+    let many_colors = traits.colors_saturated || traits.distinct_colors > 256;
+    let (format, rationale) = if traits.frame_count <= 1 {
+        if traits.photographic {
+            (RecommendedFormat::Webp, "单帧照片类画面，WebP的有损压缩比GIF的256色调色板小得多，画质也更好")
+        } else {
+            (RecommendedFormat::Gif, "单帧且颜色不多，GIF的调色板足以无损保存")
+        }
+    } else if traits.photographic && traits.high_motion {
+        if traits.has_transparency {
+            (RecommendedFormat::Webp, "照片类画面且变化剧烈，适合视频编码，但MP4不支持透明，改用有损的动态WebP")
+        } else {
+            (RecommendedFormat::Mp4, "照片类画面且变化剧烈，视频编码的帧间压缩最有效，体积通常只有GIF的几分之一")
+        }
+    } else if traits.photographic {
+        (RecommendedFormat::Webp, "照片类画面，动态WebP的有损压缩在同等画质下比GIF小")
+    } else if many_colors {
+        (RecommendedFormat::Apng, "各帧颜色合计超过256种但每帧颜色不多，APNG无损保存且不需要共用调色板")
+    } else if traits.distinct_colors <= LINE_ART_COLORS {
+        (RecommendedFormat::Gif, "颜色很少的线条画，优化后的GIF压缩效果好且兼容性最好")
+    } else {
+        (RecommendedFormat::Gif, "颜色不超过256种且画面变化不剧烈，优化后的GIF即可")
+    };
+    
+    let encoder_available = match format {
+        RecommendedFormat::Gif => true,
+        RecommendedFormat::Webp => webp_available,
+        RecommendedFormat::Apng | RecommendedFormat::Mp4 => false,
+    };
+    let fallback = if encoder_available {
+        None
+    } else if format == RecommendedFormat::Mp4 && webp_available {
+        Some(RecommendedFormat::Webp)
+    } else {
+        Some(RecommendedFormat::Gif)
+    };
+    if !encoder_available {
+        factors.push(match format {
+            RecommendedFormat::Webp => "未找到gif2webp，无法输出WebP".to_string(),
+            _ => "本程序没有该格式的编码器".to_string(),
+        });
+    }
+    FormatRecommendation {
+        format,
+        rationale: rationale.to_string(),
+        factors,
+        traits,
+        encoder_available,
+        fallback,
+    }
+}
+
+// 根据帧数、颜色、透明度和画面变化推荐输出格式，并说明原因
+#[tauri::command]
+async fn recommend_output_format(path: String) -> Result<FormatRecommendation, String> {
+    tokio::task::spawn_blocking(move || -> Result<FormatRecommendation, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        let traits = source_traits(&real_input)?;
+        Ok(recommend_format(traits, find_gif2webp().is_some()))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 全局颜色表的使用情况
+#[derive(Clone, Serialize)]
+pub struct GlobalTableAnalysis {
+    global_palette_size: Option<usize>,
+    frame_count: usize,
+    frames_with_local_palette: usize,
+    // 每帧都有局部颜色表，全局颜色表不会被任何帧使用
+    redundant: bool,
+    // 删除全局颜色表可以节省的字节数
+    removable_bytes: u64,
+}
+
+fn global_table_analysis(scan: &GifScan) -> GlobalTableAnalysis {
+    let frames_with_local_palette = scan.frames.iter().filter(|frame| frame.local_palette.is_some()).count();
+    let redundant = scan.global_palette.is_some() && frames_with_local_palette == scan.frames.len();
+    GlobalTableAnalysis {
+        global_palette_size: scan.global_palette,
+        frame_count: scan.frames.len(),
+        frames_with_local_palette,
+        redundant,
+        removable_bytes: if redundant { scan.global_palette.map_or(0, |size| 3 * size as u64) } else { 0 },
+    }
+}
+
+/// 全局颜色表多余时把它删掉并写到output_path，返回节省的字节数；不多余时不写文件，返回None
+///
+/// 只改写逻辑屏幕描述符并去掉颜色表本身，背景色索引随全局颜色表失效，一并清零
+fn strip_global_color_table_file(input_path: &Path, output_path: &Path) -> Result<Option<u64>, GifError> {
+    let scan = scan_gif_blocks(input_path)?;
+    let analysis = global_table_analysis(&scan);
+    if !analysis.redundant {
+        return Ok(None);
+    }
+    
+    let data = fs::read(input_path)?;
+    let table_end = 13 + analysis.removable_bytes as usize;
+    let mut stripped = Vec::with_capacity(data.len() - analysis.removable_bytes as usize);
+    stripped.extend_from_slice(&data[..13]);
+    // 清除全局颜色表标志和大小，保留颜色分辨率
+    stripped[10] &= 0x70;
+    stripped[11] = 0;
+    stripped.extend_from_slice(&data[table_end..]);
+    
+    let stripped_file = TempFile::new(NamedTempFile::new()?);
+    fs::write(&stripped_file.path, stripped)?;
+    check_gif_output(&stripped_file.path).map_err(GifError::Other)?;
+    write_output(&stripped_file.path, output_path)?;
+    Ok(Some(analysis.removable_bytes))
+}
+
+// 一个或一组颜色表的使用情况
+#[derive(Clone, Copy, Serialize, Default, PartialEq, Debug)]
+pub struct PaletteTableStats {
+    // 颜色表的项数
+    total: usize,
+    // 至少被一个像素引用的项数
+    used: usize,
+    // 与同一颜色表中前面某项颜色相同的项数，用作透明色的项不计
+    duplicates: usize,
+    // 用到的不同颜色数，透明色算一种
+    distinct_used: usize,
+    // 浪费的项数：没有用到的项和重复的项
+    wasted: usize,
+}
+
+impl PaletteTableStats {
+    fn add(&mut self, other: &PaletteTableStats) {
+        self.total += other.total;
+        self.used += other.used;
+        self.duplicates += other.duplicates;
+        self.distinct_used += other.distinct_used;
+        self.wasted += other.wasted;
+    }
+}
+
+// 调色板利用率报告
+#[derive(Clone, Serialize)]
+pub struct PaletteEfficiency {
+    // 全局颜色表，没有时为空
+    global: Option<PaletteTableStats>,
+    // 所有局部颜色表合计，没有局部颜色表时为空
+    local: Option<PaletteTableStats>,
+    local_tables: usize,
+    // 所有颜色表合计
+    total_entries: usize,
+    used_entries: usize,
+    duplicate_entries: usize,
+    wasted_entries: usize,
+    // 不损失颜色的--colors取值，与get_color_stats一样取2的幂；不比现有最大的颜色表小时为空
+    suggested_colors: Option<u16>,
+}
+
+/// 统计一个颜色表中用到的项、重复项和浪费的项
+///
+/// palette为RGB三元组展开的颜色表，used[i]表示第i项是否被像素引用，transparent为用作透明色的项
+fn palette_table_stats(palette: &[u8], used: &[bool], transparent: &[bool]) -> PaletteTableStats {
+    let entries: Vec<&[u8]> = palette.chunks_exact(3).collect();
+    let mut seen = std::collections::HashSet::new();
+    let mut duplicates = 0;
+    for (index, color) in entries.iter().enumerate() {
+        if !transparent[index] && !seen.insert(*color) {
+            duplicates += 1;
+        }
+    }
+    let used_colors: std::collections::HashSet<&[u8]> = entries
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| used[index] && !transparent[index])
+        .map(|(_, color)| *color)
+        .collect();
+    let uses_transparency = (0..entries.len()).any(|index| used[index] && transparent[index]);
+    let distinct_used = used_colors.len() + usize::from(uses_transparency);
+    PaletteTableStats {
+        total: entries.len(),
+        used: used.iter().filter(|&&used| used).count(),
+        duplicates,
+        distinct_used,
+        wasted: entries.len() - distinct_used,
+    }
+}
+
+/// 直接解码颜色表和每帧的颜色索引，统计各颜色表中没有用到和重复的项
+fn palette_efficiency<P: AsRef<Path>>(path: P) -> Result<PaletteEfficiency, GifError> {
+    let decode_error = |e: gif::DecodingError| GifError::Other(format!("GIF解码失败: {}", e));
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(BufReader::new(File::open(&path)?)).map_err(decode_error)?;
+    let global_palette = decoder.global_palette().map(<[u8]>::to_vec);
+    let global_len = global_palette.as_ref().map_or(0, |palette| palette.len() / 3);
+    let mut global_used = vec![false; global_len];
+    let mut global_transparent = vec![false; global_len];
+    let mut local: Option<PaletteTableStats> = None;
+    let mut local_tables = 0;
+    // 单个颜色表用到的最多颜色数和最大的颜色表
+    let mut max_distinct = 0;
+    let mut max_total = 0;
+    
+    while let Some(frame) = decoder.read_next_frame().map_err(decode_error)? {
+        let (palette_len, mut used, mut transparent) = match &frame.palette {
+            Some(palette) => (palette.len() / 3, vec![false; palette.len() / 3], vec![false; palette.len() / 3]),
+            None => (global_len, std::mem::take(&mut global_used), std::mem::take(&mut global_transparent)),
+        };
+        for &index in frame.buffer.iter() {
+            if let Some(slot) = used.get_mut(index as usize) {
+                *slot = true;
+            }
+        }
+        if let Some(index) = frame.transparent.filter(|&index| (index as usize) < palette_len) {
+            transparent[index as usize] = true;
+        }
+        match &frame.palette {
+            Some(palette) => {
+                let stats = palette_table_stats(palette, &used, &transparent);
+                max_distinct = max_distinct.max(stats.distinct_used);
+                max_total = max_total.max(stats.total);
+                local_tables += 1;
+                local.get_or_insert_with(PaletteTableStats::default).add(&stats);
+            }
+            None => {
+                global_used = used;
+                global_transparent = transparent;
+            }
+        }
+    }
+    
+    let global = global_palette.map(|palette| palette_table_stats(&palette, &global_used, &global_transparent));
+    let mut overall = PaletteTableStats::default();
+    for stats in global.iter().chain(local.iter()) {
+        overall.add(stats);
+    }
+    if let Some(stats) = &global {
+        max_distinct = max_distinct.max(stats.distinct_used);
+        max_total = max_total.max(stats.total);
+    }
+    // 用到颜色最多的颜色表决定不损失颜色的--colors，比现有最大的颜色表小时才建议
+    let suggested_colors = Some(max_distinct.next_power_of_two().clamp(2, 256))
+        .filter(|&colors| colors < max_total)
+        .map(|colors| colors as u16);
+    Ok(PaletteEfficiency {
+        global,
+        local,
+        local_tables,
+        total_entries: overall.total,
+        used_entries: overall.used,
+        duplicate_entries: overall.duplicates,
+        wasted_entries: overall.wasted,
+        suggested_colors,
+    })
+}
+
+// 报告GIF颜色表中重复和没有用到的项，并给出更小的--colors建议
+#[tauri::command]
+async fn analyze_palette(path: String) -> Result<PaletteEfficiency, String> {
+    tokio::task::spawn_blocking(move || -> Result<PaletteEfficiency, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        palette_efficiency(&real_input)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// 检查GIF的全局颜色表是否因为每帧都有局部颜色表而多余
+#[tauri::command]
+async fn analyze_global_color_table(path: String) -> Result<GlobalTableAnalysis, String> {
+    tokio::task::spawn_blocking(move || -> Result<GlobalTableAnalysis, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        Ok(global_table_analysis(&scan_gif_blocks(&real_input)?))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// GIF中最大的颜色表大小，全局和局部颜色表都没有时为空
+fn max_palette_size(scan: &GifScan) -> Option<usize> {
+    scan.frames.iter().filter_map(|frame| frame.local_palette).chain(scan.global_palette).max()
+}
+
+// 降低位深的结果
+#[derive(Clone, Serialize)]
+pub struct BitDepthResult {
+    bits: u8,
+    // 要求的颜色数，即2^bits
+    colors: u16,
+    // 输出GIF中最大的颜色表大小
+    palette_size: Option<usize>,
+    original_size: f64,
+    compressed_size: f64,
+}
+
+/// 用gifsicle --colors把GIF统一降到指定位深，写到output_path
+fn reduce_bit_depth_file(input_path: &Path, output_path: &Path, bits: u8, shared_state: &SharedState) -> Result<BitDepthResult, GifError> {
+    let colors = bit_depth_colors(bits)?;
+    let gifsicle_path = find_gifsicle().ok_or(GifError::GifsicleNotFound)?;
+    let reduced = TempFile::new(NamedTempFile::new()?);
+    let colors_arg = colors.to_string();
+    let input = input_path.to_string_lossy().to_string();
+    let reduced_path = reduced.path_str();
+    let args = ["-O3", "--no-warnings", "--colors", &colors_arg, &input, "-o", &reduced_path];
+    let output = run_gifsicle(&gifsicle_path, &args, shared_state)?;
+    if !output.status.success() {
+        return Err(GifError::GifsicleExecFailed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    
+    let palette_size = max_palette_size(&scan_gif_blocks(&reduced.path)?);
+    write_output(&reduced.path, output_path)?;
+    Ok(BitDepthResult {
+        bits,
+        colors,
+        palette_size,
+        original_size: get_file_size_kb(input_path)?,
+        compressed_size: get_file_size_kb(output_path)?,
+    })
+}
+
+// 把GIF统一降到指定位深（如4位即16种颜色），用于像素风格或减小体积，不做自动的颜色搜索
+#[tauri::command]
+async fn reduce_bit_depth(state: State<'_, AppState>, path: String, bits: u8, output_path: String) -> Result<BitDepthResult, String> {
+    // 登记为压缩任务，cancel_compress可以终止其中的gifsicle
+    let job = state.jobs.start(JobKind::Compress, &path, Some(&output_path), true);
+    let cancel_flag = job.cancel_flag();
+    let result = tokio::task::spawn_blocking(move || -> Result<BitDepthResult, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        reduce_bit_depth_file(&real_input, &resolve_output_path(&output_path), bits, &SharedState::with_cancel_flag(cancel_flag))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    job.map_stalled(result).map_err(|e| e.to_string())
+}
+
+// 删除多余的全局颜色表，返回节省的字节数；全局颜色表仍被使用时返回错误
+#[tauri::command]
+async fn strip_global_color_table(path: String, output_path: String) -> Result<u64, String> {
+    tokio::task::spawn_blocking(move || -> Result<u64, GifError> {
+        let real_input = resolve_input_path(&path)?;
+        strip_global_color_table_file(&real_input, &resolve_output_path(&output_path))?
+            .ok_or_else(|| GifError::InvalidOption("全局颜色表仍被使用，不能删除".to_string()))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn low_fd_limit_caps_concurrency() {
+        // 80个句柄减去保留的64个，只够2个策略
+        let limits = recommend_concurrency(16, Some(80), None, None);
+        assert_eq!(limits.recommended, 2);
+        assert_eq!(limits.limited_by, "open_files");
+        
+        // 句柄少到不够一个策略时仍保留一个线程
+        assert_eq!(recommend_concurrency(16, Some(10), None, None).recommended, 1);
+        
+        // 限制足够宽松时由CPU数决定
+        let limits = recommend_concurrency(4, Some(4096), None, None);
+        assert_eq!((limits.recommended, limits.limited_by.as_str()), (4, "cpu"));
+    }
+    
+    #[test]
+    fn low_temp_space_caps_concurrency() {
+        // 每个策略需要4倍输入大小的临时空间
+        let limits = recommend_concurrency(8, None, Some(12 * 1024 * 1024), Some(1024 * 1024));
+        assert_eq!(limits.recommended, 3);
+        assert_eq!(limits.limited_by, "temp_space");
+    }
+    
+    #[test]
+    fn accumulate_rounding_keeps_total_duration() {
+        let delays = vec![10.0 / 3.0; 1000];
+        let exact: f64 = delays.iter().sum();
+        let rounded = round_delays(&delays, DelayRounding::Accumulate);
+        let total: u64 = rounded.iter().map(|&delay| delay as u64).sum();
+        assert!((total as f64 - exact).abs() <= 1.0, "总时长 {} 与 {} 相差超过1厘秒", total, exact);
+        
+        // 四舍五入每帧都丢掉三分之一厘秒
+        let nearest: u64 = round_delays(&delays, DelayRounding::Nearest).iter().map(|&delay| delay as u64).sum();
+        assert_eq!(nearest, 3000);
+        assert_eq!(round_delays(&[2.5], DelayRounding::Floor), vec![2]);
+        assert_eq!(round_delays(&[2.5], DelayRounding::Ceil), vec![3]);
+    }
+    
+    // 修改PATH安装假工具的测试必须串行执行
+    #[cfg(unix)]
+    static PATH_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    
+    /// 安装在PATH最前面的假命令行工具，丢弃时恢复PATH
+    #[cfg(unix)]
+    struct FakeTools {
+        _dir: tempfile::TempDir,
+        old_path: std::ffi::OsString,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+    
+    #[cfg(unix)]
+    impl FakeTools {
+        fn install(tools: &[(&str, &str)]) -> Self {
+            use std::os::unix::fs::PermissionsExt;
+            let lock = PATH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let dir = tempfile::tempdir().unwrap();
+            for (name, script) in tools {
+                let path = dir.path().join(name);
+                fs::write(&path, script).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let old_path = std::env::var_os("PATH").unwrap_or_default();
+            let mut paths = vec![dir.path().to_path_buf()];
+            paths.extend(std::env::split_paths(&old_path));
+            std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+            Self { _dir: dir, old_path, _lock: lock }
+        }
+    }
+    
+    #[cfg(unix)]
+    impl Drop for FakeTools {
+        fn drop(&mut self) {
+            std::env::set_var("PATH", &self.old_path);
+        }
+    }
+    
+    /// 假的gifsicle：等待delay秒后把最后一个输入原样写到-o指定的输出，"-"表示stdin/stdout
+    #[cfg(unix)]
+    fn copying_gifsicle(delay: &str) -> String {
+        format!(
+            "#!/bin/sh\n\
+             [ \"$1\" = \"--version\" ] && {{ echo 'fake gifsicle'; exit 0; }}\n\
+             sleep {}\n\
+             in=''; out=''; prev=''\n\
+             for a in \"$@\"; do\n\
+             if [ \"$prev\" = \"-o\" ]; then out=\"$a\"; elif [ \"$a\" = \"-\" ] || [ -f \"$a\" ]; then in=\"$a\"; fi\n\
+             prev=\"$a\"\n\
+             done\n\
+             if [ -z \"$out\" ] || [ \"$out\" = \"-\" ]; then cat \"$in\"; else cat \"$in\" > \"$out\"; fi\n",
+            delay
+        )
+    }
+    
+    /// 写出frames帧、size x size的噪点GIF，每帧延迟delay_cs厘秒
+    fn write_noise_gif(path: &Path, frames: u32, size: u16, delay_cs: u16) {
+        let palette: Vec<u8> = (0..=255u8).flat_map(|v| [v, v.wrapping_mul(7), v.wrapping_mul(13)]).collect();
+        let mut encoder = gif::Encoder::new(File::create(path).unwrap(), size, size, &palette).unwrap();
+        encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+        let pixels = size as u32 * size as u32;
+        for i in 0..frames {
+            let buffer: Vec<u8> = (0..pixels).map(|p| (p.wrapping_mul(2654435761).wrapping_add(i * 97) >> 13) as u8).collect();
+            let frame = gif::Frame { width: size, height: size, buffer: buffer.into(), delay: delay_cs, ..Default::default() };
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn time_budget_returns_best_so_far() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0.3"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 12, 32, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, time_budget_ms: Some(1000), ..Default::default() };
+        
+        let started = Instant::now();
+        let outcome = optimize_gif(&input, &output, &options, 2, None, None).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(1000 + 2500), "用时 {:?}", started.elapsed());
+        assert!(outcome.time_limited);
+        assert!(output.exists());
+        assert!(outcome.final_bytes <= outcome.original_bytes);
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn failed_output_write_leaves_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.gif");
+        let partial = dir.path().join("out.gif.processing");
+        
+        // 目录可以打开但读取时出错，模拟写到一半失败
+        let unreadable = dir.path().join("source_dir");
+        fs::create_dir(&unreadable).unwrap();
+        assert!(write_output(&unreadable, &output).is_err());
+        assert!(!output.exists() && !partial.exists());
+        
+        // 成功时重命名是最后一步，不留下.processing
+        let source = dir.path().join("in.gif");
+        write_noise_gif(&source, 1, 8, 0);
+        let sha256 = write_output(&source, &output).unwrap();
+        assert_eq!(sha256, sha256_file(&source).unwrap());
+        assert!(output.exists() && !partial.exists());
+    }
+    
+    /// 写出每帧一种纯色的GIF，每帧延迟delay_cs厘秒
+    fn write_solid_gif(path: &Path, colors: &[[u8; 3]], size: u16, delay_cs: u16) {
+        let palette: Vec<u8> = colors.iter().flatten().copied().collect();
+        let mut encoder = gif::Encoder::new(File::create(path).unwrap(), size, size, &palette).unwrap();
+        for index in 0..colors.len() {
+            let buffer = vec![index as u8; size as usize * size as usize];
+            let frame = gif::Frame { width: size, height: size, buffer: buffer.into(), delay: delay_cs, ..Default::default() };
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    
+    /// 解码GIF，返回每帧左上角像素的RGB
+    fn first_pixels(path: &Path) -> Vec<[u8; 3]> {
+        let decoder = GifDecoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        decoder
+            .into_frames()
+            .collect_frames()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                let pixel = frame.buffer().get_pixel(0, 0);
+                [pixel[0], pixel[1], pixel[2]]
+            })
+            .collect()
+    }
+    
+    #[test]
+    fn start_at_frame_rotates_leading_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]];
+        write_solid_gif(&input, &colors, 8, 10);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { start_at_frame: Some(2), ..Default::default() };
+        differencing_pass(&input, &output, &options, &SharedState::new()).unwrap();
+        
+        // 第2帧在最前，之前的帧接到末尾
+        assert_eq!(first_pixels(&output), vec![colors[2], colors[3], colors[0], colors[1]]);
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn report_splits_decode_and_encode_time() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0.1"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 20, 200, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, max_gifsicle_runs: Some(4), ..Default::default() };
+        let report = optimize_gif(&input, &output, &options, 1, None, None).unwrap().report;
+        
+        assert!(report.decode_ms > 0, "{:?}", report.decode_ms);
+        assert!(report.encode_ms >= 100, "{:?}", report.encode_ms);
+        // 单线程时两者之和占总耗时的大部分（其余为哈希、扫描等），且不会超过总耗时
+        let sum = report.decode_ms + report.encode_ms;
+        assert!(sum <= report.total_ms + 5 && sum * 4 >= report.total_ms, "{} / {}", sum, report.total_ms);
+    }
+    
+    
+    #[test]
+    fn old_gifsicle_reports_unsupported_lossy() {
+        assert_eq!(parse_gifsicle_version("LCDF Gifsicle 1.91\nCopyright"), Some((1, 91)));
+        let options = CompressOptions { auto_downscale: Some(false), min_colors: None, ..Default::default() };
+        let check = check_features("gifsicle", Some((1, 91)), &options);
+        let unsupported: Vec<&str> = check.unsupported.iter().map(|feature| feature.option.as_str()).collect();
+        assert_eq!(unsupported, vec!["--lossy"]);
+        assert_eq!(check.unsupported[0].required_version, "1.92");
+        
+        // 只检查实际用到的选项
+        let lossless = CompressOptions { max_lossy: Some(0), ..options.clone() };
+        assert!(check_features("gifsicle", Some((1, 60)), &lossless).unsupported.is_empty());
+        let resized = CompressOptions { max_width: Some(320), ..lossless };
+        let check = check_features("gifsicle", Some((1, 60)), &resized);
+        let unsupported: Vec<&str> = check.unsupported.iter().map(|feature| feature.option.as_str()).collect();
+        assert_eq!(unsupported, vec!["--resize-fit"]);
+        
+        // 无法识别版本时不报告
+        assert!(check_features("gifsicle", None, &options).unsupported.is_empty());
+        assert!(check_features("gifsicle", Some((1, 94)), &options).unsupported.is_empty());
+    }
+    
+    
+    #[test]
+    fn huge_canvas_gets_suggested_edge() {
+        let edge = auto_max_edge(3840, 2160, 10, 2048.0).unwrap();
+        assert!((MIN_AUTO_EDGE..3840).contains(&edge), "{}", edge);
+        // 按建议的最长边缩小后，估算大小不超过目标
+        let scale = edge as f64 / 3840.0;
+        let needed = 3840.0 * scale * 2160.0 * scale * 10.0 * MIN_BYTES_PER_PIXEL_FRAME;
+        assert!(needed <= 2048.0 * 1024.0);
+        assert_eq!(auto_max_edge(320, 240, 10, 2048.0), None);
+        // 自动缩小默认关闭，前端不传时同样关闭
+        assert_eq!(CompressOptions::default().auto_downscale, None);
+        let options: CompressOptions = serde_json::from_str(r#"{"target_size": 100, "threads": 1}"#).unwrap();
+        assert_eq!(options.auto_downscale, None);
+    }
+    
+    
+    fn solid_frame(value: u8) -> image::Frame {
+        image::Frame::new(image::RgbaImage::from_pixel(8, 8, image::Rgba([value, value, value, 255])))
+    }
+    
+    #[test]
+    fn motion_threshold_collapses_static_runs() {
+        // 5帧静止、4帧各不相同、再3帧与最后一个变化帧相同
+        let values = [0, 0, 0, 0, 0, 40, 80, 120, 160, 160, 160, 160];
+        let frames: Vec<image::Frame> = values.iter().map(|&value| solid_frame(value)).collect();
+        let ranges = select_frames(&frames, 1, Some(5.0), None);
+        assert_eq!(ranges, vec![0..5, 5..6, 6..7, 7..8, 8..12]);
+        // 微小抖动同样被并入上一保留帧
+        let jitter: Vec<image::Frame> = [10, 11, 12, 11, 10].iter().map(|&value| solid_frame(value)).collect();
+        assert_eq!(select_frames(&jitter, 1, Some(5.0), None), vec![0..5]);
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn candidates_cover_distinct_regimes_and_release_the_rest() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 300, 5);
+        let options = CompressOptions { target_size: 20.0, threads: 1, ..Default::default() };
+        
+        let candidates = generate_candidate_files(&input, &options, 3, &Arc::new(AtomicBool::new(false))).unwrap();
+        let regimes: Vec<CandidateRegime> = candidates.iter().map(|(info, _)| info.regime).collect();
+        assert_eq!(regimes, vec![CandidateRegime::MoreFrames, CandidateRegime::NoLossy, CandidateRegime::Resized]);
+        
+        // 写出一个候选结果后同一组的其他结果被删除
+        let store = CandidateStore::new(DEFAULT_CANDIDATE_TTL, DEFAULT_CANDIDATE_MAX_BYTES);
+        let mut ids = Vec::new();
+        let mut paths = Vec::new();
+        for (_, file) in candidates {
+            paths.push(file.path.clone());
+            ids.push(store.register(file, CandidateMeta { group: "in.gif".to_string(), label: String::new() }));
+        }
+        let (chosen, meta) = store.take(&ids[1]).unwrap();
+        assert_eq!(store.release_group(&meta.group), 2);
+        assert!(chosen.path.exists());
+        assert!(!paths[0].exists() && !paths[2].exists());
+        assert!(store.get(&ids[0]).is_none());
+    }
+    
+    
+    #[test]
+    fn loop_point_lands_on_most_similar_frame() {
+        // 画面先变亮再变暗，第9帧几乎回到第0帧，之后又开始变化
+        let values = [0, 30, 60, 90, 120, 90, 60, 30, 10, 1, 15, 35];
+        let frames: Vec<image::Frame> = values.iter().map(|&value| solid_frame(value)).collect();
+        assert_eq!(find_loop_point(&frames, 6), Some((9, 1.0)));
+        // 搜索范围之外的相似帧不会被选中，且至少保留两帧
+        assert_eq!(find_loop_point(&frames, 2).map(|(index, _)| index), Some(10));
+        assert_eq!(find_loop_point(&frames, 100).map(|(index, _)| index), Some(9));
+        assert_eq!(find_loop_point(&frames[..2], 5), None);
+    }
     
-    // 保存所有选择的帧到临时目录，并收集路径字符串
-    let mut frame_paths = Vec::new();
-    for (i, frame) in selected_frames.iter().enumerate() {
-        let frame_path = temp_dir.path().join(format!("frame_{}.gif", i));
-        let frame_file = File::create(&frame_path)?;
-        let mut frame_writer = BufWriter::new(frame_file);
+    
+    fn stored_file(bytes: usize) -> TempFile {
+        let file = TempFile::new(NamedTempFile::new().unwrap());
+        fs::write(&file.path, vec![0u8; bytes]).unwrap();
+        file
+    }
+    
+    #[test]
+    fn candidate_store_expires_and_evicts_oldest() {
+        let meta = |group: &str| CandidateMeta { group: group.to_string(), label: String::new() };
+        let store = CandidateStore::new(Duration::from_secs(60), 250);
+        let first = store.register(stored_file(100), meta("a"));
+        let first_path = store.get(&first).unwrap().0;
+        std::thread::sleep(Duration::from_millis(5));
+        let second = store.register(stored_file(100), meta("a"));
+        std::thread::sleep(Duration::from_millis(5));
+        // 超出空间上限时删除最早的文件
+        let third = store.register(stored_file(100), meta("b"));
+        assert!(store.get(&first).is_none() && !first_path.exists());
+        assert_eq!(store.usage().total_bytes, 200);
         
-        // 使用image库保存单帧GIF
-        frame.buffer().write_to(&mut frame_writer, image::ImageOutputFormat::Gif)?;
+        assert!(store.release(&second));
+        assert!(!store.release(&second));
         
-        // 保存路径字符串
-        frame_paths.push(frame_path.to_string_lossy().to_string());
+        // 调短保留时间后过期的文件被删除
+        let third_path = store.get(&third).unwrap().0;
+        std::thread::sleep(Duration::from_millis(20));
+        store.set_ttl(Duration::from_millis(10));
+        assert!(store.get(&third).is_none() && !third_path.exists());
+        assert_eq!(store.usage().items.len(), 0);
     }
     
-    // 使用gifsicle合并帧
-    let output_path_str = output_path.as_ref().to_string_lossy().to_string();
-    let delay_str = delay.to_string();
     
-    // 检查gifsicle是否存在
-    let gifsicle_path = match find_gifsicle() {
-        Some(path) => path,
-        None => return Err(GifError::GifsicleNotFound),
-    };
+    #[test]
+    fn clipboard_gets_first_frame_as_still_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.gif");
+        let colors = [[200, 10, 10], [10, 200, 10]];
+        write_solid_gif(&output, &colors, 6, 5);
+        let image = clipboard_first_frame(&output.to_string_lossy()).unwrap();
+        assert_eq!(image.dimensions(), (6, 6));
+        assert!(image.pixels().all(|pixel| pixel.0 == [200, 10, 10, 255]));
+        
+        let missing = dir.path().join("missing.gif");
+        assert!(clipboard_first_frame(&missing.to_string_lossy()).is_err());
+        // 前端不传时放第一帧
+        let content: ClipboardContent = serde_json::from_str(r#""file_path""#).unwrap();
+        assert_eq!(content, ClipboardContent::FilePath);
+        assert_eq!(ClipboardContent::default(), ClipboardContent::FirstFrame);
+    }
     
-    // 构建优化的参数列表
-    let mut gifsicle_args = Vec::with_capacity(frame_paths.len() + 8);
     
-    // 添加优化选项
-    gifsicle_args.push("--no-warnings".to_string());        // 减少不必要的输出
-    gifsicle_args.push("--no-conserve-memory".to_string()); // 使用更多内存提高速度
-    gifsicle_args.push("--no-app-extensions".to_string());  // 移除应用扩展数据
-    gifsicle_args.push("--no-comments".to_string());        // 移除注释
-    gifsicle_args.push("--no-names".to_string());           // 移除名称元数据
-    gifsicle_args.push("-o".to_string());
-    gifsicle_args.push(output_path_str);
-    gifsicle_args.push("--delay".to_string());
-    gifsicle_args.push(delay_str);
-    gifsicle_args.push("--loopcount=forever".to_string());
+    #[test]
+    fn quality_curve_interpolates_between_probes() {
+        assert_eq!(quality_probe_levels(5), vec![0, 60, 120, 180, 240]);
+        assert_eq!(quality_probe_levels(1), vec![0, 240]);
+        
+        let curve = interpolate_quality_curve(&[(240, 20.0), (0, 100.0), (120, 40.0)]);
+        assert_eq!(curve.len(), (QUALITY_CURVE_MAX_LOSSY / QUALITY_CURVE_STEP + 1) as usize);
+        let at = |level: u32| curve.iter().find(|point| point.lossy == level).unwrap();
+        assert!(at(120).probed && at(120).size_kb == 40.0);
+        assert!(!at(60).probed && (at(60).size_kb - 70.0).abs() < 1e-9);
+        assert!((at(180).size_kb - 30.0).abs() < 1e-9);
+    }
     
-    // 添加所有帧路径 (已经是String类型)
-    for path in &frame_paths {
-        gifsicle_args.push(path.clone());
+    #[cfg(unix)]
+    #[test]
+    fn quality_curve_probes_every_level_and_honours_cancel() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 32, 5);
+        
+        let levels = quality_probe_levels(4);
+        let mut probes = probe_quality_curve(&input, 2, &levels, 2, &SharedState::new(), &|_, _| {}).unwrap();
+        probes.sort_by_key(|(level, _)| *level);
+        let probed: Vec<u32> = probes.iter().map(|(level, _)| *level).collect();
+        assert_eq!(probed, levels);
+        
+        let cancelled = SharedState::new();
+        cancelled.cancel();
+        assert!(probe_quality_curve(&input, 2, &levels, 2, &cancelled, &|_, _| {}).is_err());
     }
     
-    // 执行gifsicle命令
-    let _output = Command::new(&gifsicle_path)
-        .args(&gifsicle_args)
-        .output()?;
     
-    // 检查命令是否成功
-    if !_output.status.success() {
-        let stderr = String::from_utf8_lossy(&_output.stderr).to_string();
-        return Err(GifError::GifsicleExecFailed(stderr));
+    #[cfg(unix)]
+    #[test]
+    fn crashed_gifsicle_is_retried_with_careful() {
+        // 没有--careful时以SIGSEGV崩溃
+        let crashy = "#!/bin/sh\nfor a in \"$@\"; do [ \"$a\" = \"--careful\" ] && { echo careful; exit 0; }; done\nkill -SEGV $$\n";
+        let always_crashes = "#!/bin/sh\nkill -SEGV $$\n";
+        let _tools = FakeTools::install(&[("gifsicle", crashy), ("gifsicle-broken", always_crashes)]);
+        
+        let output = run_gifsicle_uncrashed("gifsicle", &["-O3", "in.gif"], &SharedState::new()).unwrap();
+        assert!(output.status.success());
+        
+        let result = run_gifsicle_uncrashed("gifsicle-broken", &["-O3", "in.gif"], &SharedState::new());
+        assert!(matches!(result, Err(GifError::GifsicleCrashed(_))), "{:?}", result.err());
+        // 已经带--careful时不再重试
+        let result = run_gifsicle_uncrashed("gifsicle-broken", &["--careful", "in.gif"], &SharedState::new());
+        assert!(matches!(result, Err(GifError::GifsicleCrashed(_))));
     }
     
-    Ok(())
-}
-
-/// 处理单个策略
-fn process_strategy(
-    input_path: &str,
-    strategy: Strategy,
-    target_size_kb: f64,
-    thread_id: usize,
-    shared_state: &SharedState,
-) -> StrategyResult {
-    // 创建跟踪输出的记录器
-    let output_prefix = format!("线程 {}: ", thread_id);
-    let log = |msg: &str| {
-        let message = format!("{}{}", output_prefix, msg);
-        // 使用Mutex来确保输出不会被打断
-        println!("{}", message);
-    };
     
-    // 如果已经找到目标，立即返回
-    if shared_state.is_target_found() {
-        log("已有其他线程找到满足条件的结果，提前退出");
-        return StrategyResult {
-            size: f64::MAX,
-            file: None,
-            success: false,
+    #[test]
+    fn cinemagraph_detection_skips_frames_that_clear_pixels() {
+        // 只有左上角一个像素在变化
+        let frame = |value: u8, alpha: u8| {
+            let mut image = image::RgbaImage::from_pixel(16, 16, image::Rgba([50, 50, 50, 255]));
+            image.put_pixel(0, 0, image::Rgba([value, value, value, alpha]));
+            image::Frame::new(image)
         };
+        let frames = vec![frame(0, 255), frame(100, 255), frame(200, 255)];
+        assert!(static_pixel_ratio(&frames) >= CINEMAGRAPH_STATIC_RATIO);
+        assert!(!frames.windows(2).any(|pair| introduces_transparency(pair[0].buffer(), pair[1].buffer())));
+        
+        // 像素变回透明时不能只叠加变化区域
+        let clearing = vec![frame(0, 255), frame(100, 0), frame(200, 255)];
+        assert!(introduces_transparency(clearing[0].buffer(), clearing[1].buffer()));
+        assert!(!introduces_transparency(clearing[1].buffer(), clearing[2].buffer()));
     }
     
-    // 获取gifsicle路径
-    let gifsicle_path = match find_gifsicle() {
-        Some(path) => path,
-        None => {
-            log("未找到gifsicle程序");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
-            };
+    
+    #[cfg(unix)]
+    #[test]
+    fn stream_messages_arrive_in_order_and_close_once() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 32, 5);
+        let output = dir.path().join("out.gif");
+        
+        let received = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let sink = Arc::clone(&received);
+        let channel = Channel::new(move |body| {
+            if let tauri::ipc::InvokeResponseBody::Json(json) = body {
+                sink.lock().unwrap().push(serde_json::from_str(&json).unwrap());
+            }
+            Ok(())
+        });
+        let stream = CompressStream::new(channel);
+        let options = CompressOptions { target_size: 0.1, threads: 1, max_gifsicle_runs: Some(3), ..Default::default() };
+        let outcome = optimize_gif(&input, &output, &options, 1, Some(&|event| stream.handle(event)), None);
+        stream.finish(build_compress_result(outcome, &output.to_string_lossy(), options.target_size));
+        // 结束后的消息和重复的最终结果都被忽略
+        stream.send(CompressStreamMessage::IntermediateResult { preview_path: String::new(), size_kb: 0.0 });
+        stream.finish(build_compress_result(Err(GifError::Cancelled), "", 0.0));
+        
+        let messages = received.lock().unwrap();
+        let kinds: Vec<&str> = messages.iter().map(|message| message["event"].as_str().unwrap()).collect();
+        assert_eq!(kinds.first(), Some(&"progress"));
+        assert_eq!(kinds.last(), Some(&"final"));
+        assert_eq!(kinds.iter().filter(|kind| **kind == "final").count(), 1);
+        let previews: Vec<&str> = messages
+            .iter()
+            .filter(|message| message["event"] == "intermediate_result")
+            .map(|message| message["data"]["preview_path"].as_str().unwrap())
+            .collect();
+        assert!(!previews.is_empty());
+        // 流关闭后预览文件全部删除
+        assert!(previews.iter().all(|path| !Path::new(path).exists()));
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn implausibly_small_outputs_are_never_selected() {
+        // 带--lossy的调用"成功"退出，却只写出6个字节
+        let tiny = copying_gifsicle("0").replace(
+            "in=''; out=''",
+            "case \"$*\" in *--lossy*) for a in \"$@\"; do [ \"$p\" = \"-o\" ] && o=\"$a\"; p=\"$a\"; done; \
+             if [ \"$o\" = \"-\" ]; then printf GIF89a; else printf GIF89a > \"$o\"; fi; exit 0;; esac\n in=''; out=''",
+        );
+        let _tools = FakeTools::install(&[("gifsicle", &tiny)]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 8, 32, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, threads: 1, ..Default::default() };
+        
+        let outcome = optimize_gif(&input, &output, &options, 1, None, None).unwrap();
+        assert!(check_gif_output(&output).is_ok());
+        assert!(outcome.final_bytes > 6);
+        assert_eq!(check_gif_bytes(b"GIF89a"), Err("缺少GIF结束符（6 字节）".to_string()));
+    }
+    
+    
+    #[test]
+    fn palette_file_becomes_exact_colormap() {
+        let dir = tempfile::tempdir().unwrap();
+        let palette_png = dir.path().join("palette.png");
+        let mut image = image::RgbaImage::from_pixel(4, 2, image::Rgba([255, 0, 0, 255]));
+        image.put_pixel(1, 0, image::Rgba([0, 255, 0, 255]));
+        image.put_pixel(2, 0, image::Rgba([0, 0, 255, 255]));
+        image.put_pixel(3, 0, image::Rgba([9, 9, 9, 0]));
+        image.save(&palette_png).unwrap();
+        
+        let palette = prepare_palette(&palette_png.to_string_lossy()).unwrap();
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&palette.path).unwrap()).unwrap();
+        // 透明像素不算作调色板颜色，重复的颜色只出现一次；颜色表补齐到2的幂
+        assert_eq!(&decoder.global_palette().unwrap()[..9], &[255, 0, 0, 0, 255, 0, 0, 0, 255][..]);
+        assert!(decoder.read_next_frame().unwrap().is_some());
+        
+        let missing = dir.path().join("missing.png");
+        assert!(matches!(prepare_palette(&missing.to_string_lossy()), Err(GifError::InvalidPalette(_))));
+        let not_image = dir.path().join("palette.txt");
+        fs::write(&not_image, "red green blue").unwrap();
+        assert!(matches!(prepare_palette(&not_image.to_string_lossy()), Err(GifError::InvalidPalette(_))));
+    }
+    
+    
+    #[test]
+    fn duration_deviation_uses_playback_delays() {
+        let dir = tempfile::tempdir().unwrap();
+        let gif = |name: &str, frames: u32, delay_cs: u16| {
+            let path = dir.path().join(name);
+            write_noise_gif(&path, frames, 4, delay_cs);
+            path
+        };
+        let source = gif("source.gif", 6, 5);
+        assert_eq!(duration_deviation_percent(&source, gif("same.gif", 3, 10)), Some(0.0));
+        assert_eq!(duration_deviation_percent(&source, gif("faster.gif", 3, 5)), Some(-50.0));
+        
+        // 延迟为0的帧按10厘秒播放，和流程中其他地方一致
+        let zero_delay = gif("zero.gif", 6, 0);
+        assert_eq!(duration_deviation_percent(&zero_delay, gif("slow.gif", 3, 20)), Some(0.0));
+        assert_eq!(duration_deviation_percent(gif("single.gif", 1, 5), &source), None);
+    }
+    
+    
+    #[test]
+    fn trailing_duplicate_run_counts_padded_tail() {
+        let values = [0, 50, 100, 100, 150, 150, 150, 150];
+        let frames: Vec<image::Frame> = values.iter().map(|&value| solid_frame(value)).collect();
+        // 末尾4帧相同，合并成一帧后少3帧；中间重复的帧不受影响
+        assert_eq!(trailing_duplicate_run(&frames), 4);
+        assert_eq!(trailing_duplicate_run(&frames[..4]), 2);
+        assert_eq!(trailing_duplicate_run(&frames[..2]), 1);
+        assert_eq!(trailing_duplicate_run(&[]), 0);
+        
+        // 没有重复帧时原样输出，时长不变
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 4, 8, 5);
+        let output = dir.path().join("out.gif");
+        let result = strip_trailing_frames_file(&input, &output, None, &SharedState::new()).unwrap();
+        assert_eq!((result.removed_frames, result.total_frames), (0, 4));
+        assert_eq!(result.duration_after_ms, result.duration_before_ms);
+        assert_eq!(fs::read(&input).unwrap(), fs::read(&output).unwrap());
+    }
+    
+    
+    #[test]
+    fn output_extension_is_normalized() {
+        let normalize = |path: &str, conflict| normalize_output_extension(path, "gif", conflict).unwrap();
+        assert_eq!(normalize("out.gif", ExtensionConflict::Error), ("out.gif".to_string(), None));
+        let (path, warning) = normalize("out.GIF", ExtensionConflict::Error);
+        assert_eq!(path, "out.GIF");
+        assert!(warning.is_some());
+        assert_eq!(normalize("out", ExtensionConflict::Error).0, "out.gif");
+        assert_eq!(normalize("out.", ExtensionConflict::Error).0, "out.gif");
+        assert_eq!(normalize("my.clip", ExtensionConflict::Error).0, "my.clip.gif");
+        
+        // 与GIF不符的扩展名按选项报错或改写
+        assert!(normalize_output_extension("out.png", "gif", ExtensionConflict::Error).is_err());
+        assert_eq!(normalize("out.PNG", ExtensionConflict::Rewrite).0, "out.gif");
+        
+        // 输出WebP时.gif同样是不符的扩展名
+        let webp = |path: &str, conflict| normalize_output_extension(path, "webp", conflict).unwrap().0;
+        assert_eq!(webp("out.webp", ExtensionConflict::Error), "out.webp");
+        assert_eq!(webp("out", ExtensionConflict::Error), "out.webp");
+        assert!(normalize_output_extension("out.gif", "webp", ExtensionConflict::Error).is_err());
+        assert_eq!(webp("out.gif", ExtensionConflict::Rewrite), "out.webp");
+    }
+    
+    
+    #[test]
+    fn color_key_makes_background_transparent() {
+        let green = image::Rgba([0, 255, 0, 255]);
+        let mut image = image::RgbaImage::from_pixel(8, 8, green);
+        for (x, y) in [(3, 3), (4, 3), (3, 4), (4, 4)] {
+            image.put_pixel(x, y, image::Rgba([255, 0, 0, 255]));
         }
-    };
+        // 抗锯齿边缘：红绿各一半
+        image.put_pixel(2, 3, image::Rgba([128, 127, 0, 255]));
+        // 接近背景色的噪点
+        image.put_pixel(0, 0, image::Rgba([4, 250, 3, 255]));
+        
+        apply_color_key(&mut image, [0, 255, 0], 10.0, 200.0);
+        assert_eq!(image.get_pixel(7, 7)[3], 0);
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+        assert_eq!(*image.get_pixel(3, 3), image::Rgba([255, 0, 0, 255]));
+        // 边缘去掉混入的背景色，不留下绿边
+        let edge = image.get_pixel(2, 3);
+        assert_eq!(edge[3], 255);
+        assert!(edge[1] < 127 && edge[0] > 128, "{:?}", edge);
+        let transparent = image.pixels().filter(|pixel| pixel[3] == 0).count();
+        assert_eq!(transparent, 64 - 5);
+    }
     
-    let skip = strategy.skip;
-    let delay = strategy.delay;
     
-    // 预计剩余帧数
-    let expected_frames = match get_frame_count(input_path) {
-        Ok(count) => (count as f64 / skip as f64).ceil() as usize,
-        Err(_) => 0,
-    };
+    #[test]
+    fn active_jobs_track_registry_lifecycle() {
+        let registry = Arc::new(JobRegistry::new());
+        let compress = registry.start(JobKind::Compress, "a.gif", Some("b.gif"), true);
+        std::thread::sleep(Duration::from_millis(2));
+        let curve = registry.start(JobKind::QualityCurve, "c.gif", None, false);
+        compress.update("抽帧策略", 0.5, Some("2/4"));
+        
+        let jobs = registry.list();
+        let ids: Vec<&str> = jobs.iter().map(|job| job.id.as_str()).collect();
+        assert_eq!(ids, vec![compress.id.as_str(), curve.id.as_str()]);
+        assert_eq!((jobs[0].phase.as_str(), jobs[0].progress, jobs[0].details.as_deref()), ("抽帧策略", 0.5, Some("2/4")));
+        assert_eq!(jobs[0].output_path.as_deref(), Some("b.gif"));
+        
+        // 只取消可以取消的同类任务，重复取消不重复计数
+        assert_eq!(registry.cancel(JobKind::QualityCurve, None), 0);
+        assert_eq!(registry.cancel(JobKind::Compress, None), 1);
+        assert_eq!(registry.cancel(JobKind::Compress, None), 0);
+        assert!(compress.cancel_flag().load(Ordering::SeqCst));
+        
+        // 结束的任务立即从列表中消失
+        drop(compress);
+        let ids: Vec<String> = registry.list().into_iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![curve.id.clone()]);
+        drop(curve);
+        assert!(registry.list().is_empty());
+    }
     
-    log(&format!("策略: 保留约 {} 帧 (每 {} 帧取1帧), 帧延迟: {}ms", 
-                expected_frames, skip, delay));
     
-    // 使用image库提取帧
-    let temp_frames = match NamedTempFile::new() {
-        Ok(file) => TempFile::new(file),
-        Err(_) => {
-            log("  创建临时文件失败");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
+    #[tokio::test]
+    async fn tiny_delays_are_raised_to_safe_minimum() {
+        assert_eq!(normalize_delays(&[1, 1, 4], 2), (vec![2, 2, 8], 2.0));
+        assert_eq!(normalize_delays(&[0, 3], 2), (vec![2, 3], 1.0));
+        assert_eq!(normalize_delays(&[5, 10], 2), (vec![5, 10], 1.0));
+        
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 3, 4, 1);
+        let output = dir.path().join("fixed.gif");
+        let report = check_frame_delays(input.to_string_lossy().to_string(), None, Some(output.to_string_lossy().to_string()))
+            .await
+            .unwrap();
+        assert_eq!(report.below_minimum, 3);
+        let delays: Vec<u16> = scan_gif_blocks(&output).unwrap().frames.iter().map(|block| block.delay_cs).collect();
+        assert_eq!(delays, vec![MIN_PLAYBACK_DELAY_CS; 3]);
+        assert_eq!(get_frame_count(&output).unwrap(), 3);
+    }
+    
+    
+    /// 测试中代替JOB_WATCHDOG_INTERVAL的看门狗，返回的标志置位后停止
+    fn run_watchdog(registry: &Arc<JobRegistry>) -> Arc<AtomicBool> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (registry, stop_flag) = (Arc::clone(registry), Arc::clone(&stop));
+        std::thread::spawn(move || {
+            while !stop_flag.load(Ordering::SeqCst) {
+                registry.abort_stalled();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        stop
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn watchdog_kills_hanging_probe_job() {
+        let _tools = FakeTools::install(&[("gifsicle", "#!/bin/sh\n[ \"$1\" = \"--version\" ] && exit 0\nexec sleep 30\n")]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 4, 16, 5);
+        
+        let registry = Arc::new(JobRegistry::new());
+        registry.set_stall_timeout(Duration::from_millis(300));
+        let stop = run_watchdog(&registry);
+        let job = registry.start(JobKind::QualityCurve, "in.gif", None, true);
+        let shared_state = SharedState::with_cancel_flag(job.cancel_flag());
+        
+        let started = Instant::now();
+        let result = probe_quality_curve_job(&job, &input, 1, &quality_probe_levels(3), 1, &shared_state);
+        stop.store(true, Ordering::SeqCst);
+        assert!(matches!(result, Err(GifError::JobStalled(_))), "{:?}", result.err());
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn watchdog_spares_slow_but_progressing_job() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0.3"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 4, 16, 5);
+        
+        // 总耗时远超无进展超时，但每个探测点都在超时之前完成
+        let registry = Arc::new(JobRegistry::new());
+        registry.set_stall_timeout(Duration::from_millis(1500));
+        let stop = run_watchdog(&registry);
+        let job = registry.start(JobKind::QualityCurve, "in.gif", None, true);
+        let shared_state = SharedState::with_cancel_flag(job.cancel_flag());
+        
+        let started = Instant::now();
+        let levels = quality_probe_levels(8);
+        let probes = probe_quality_curve_job(&job, &input, 1, &levels, 1, &shared_state).unwrap();
+        stop.store(true, Ordering::SeqCst);
+        assert!(started.elapsed() > Duration::from_millis(2400));
+        assert_eq!(probes.len(), levels.len());
+        assert!(job.stalled().is_none());
+    }
+    
+    
+    #[test]
+    fn target_like_uses_reference_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference = dir.path().join("reference.gif");
+        fs::write(&reference, vec![0u8; 3 * 1024]).unwrap();
+        let mut options = CompressOptions { target_like: Some(reference.to_string_lossy().to_string()), ..Default::default() };
+        apply_target_like(&mut options).unwrap();
+        assert_eq!(options.target_size, 3.0);
+        
+        let missing = dir.path().join("missing.gif");
+        let mut options = CompressOptions { target_like: Some(missing.to_string_lossy().to_string()), ..Default::default() };
+        assert!(apply_target_like(&mut options).is_err());
+        
+        // 没有参考文件时必须给出正的目标大小
+        for target_size in [0.0, -1.0, f64::NAN] {
+            let mut options = CompressOptions { target_size, ..Default::default() };
+            assert!(matches!(apply_target_like(&mut options), Err(GifError::InvalidOption(_))));
+        }
+        let mut options = CompressOptions { target_size: 100.0, ..Default::default() };
+        apply_target_like(&mut options).unwrap();
+        assert_eq!(options.target_size, 100.0);
+    }
+    
+    
+    #[test]
+    fn color_reduction_is_opt_in_with_default_floor() {
+        let options: CompressOptions = serde_json::from_str(r#"{"target_size": 100, "threads": 1}"#).unwrap();
+        assert_eq!(options.auto_reduce_colors, None);
+        assert!(!auto_color_reduction(&options));
+        assert!(!auto_color_reduction(&CompressOptions::default()));
+        // 开启后没有设置min_colors时同样不低于32种颜色
+        let enabled = CompressOptions { auto_reduce_colors: Some(true), ..options.clone() };
+        assert!(auto_color_reduction(&enabled));
+        assert_eq!(color_reduction_levels(&enabled), (vec![128, 64, 32], true));
+        // 用户指定的颜色数优先
+        assert!(!auto_color_reduction(&CompressOptions { max_colors: Some(8), ..enabled.clone() }));
+        
+        // 只有下限挡住了减色时才提示
+        let shared_state = SharedState::new();
+        assert_eq!(color_floor_warning(&enabled, &shared_state), None);
+        shared_state.set_color_floor_hit();
+        assert!(color_floor_warning(&enabled, &shared_state).is_some_and(|warning| warning.contains("32")));
+        
+        // 画质等级开启自动减色，明确关闭时保持关闭
+        let mut leveled = CompressOptions { quality_level: Some(3), ..options.clone() };
+        apply_quality_level(&mut leveled).unwrap();
+        assert_eq!(leveled.auto_reduce_colors, Some(true));
+        let mut disabled = CompressOptions { quality_level: Some(3), auto_reduce_colors: Some(false), ..options };
+        apply_quality_level(&mut disabled).unwrap();
+        assert_eq!(disabled.auto_reduce_colors, Some(false));
+    }
+    
+    
+    /// 与copying_gifsicle相同，另外把每次调用的参数逐行追加到log
+    #[cfg(unix)]
+    fn logging_gifsicle(log: &Path) -> String {
+        copying_gifsicle("0").replacen(
+            "in=''",
+            &format!("[ \"$1\" != \"--version\" ] && echo \"$*\" >> '{}'\nin=''", log.display()),
+            1,
+        )
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn resize_order_controls_base_pass_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        let _tools = FakeTools::install(&[("gifsicle", &logging_gifsicle(&log))]);
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 4, 64, 5);
+        
+        let mut first_calls = Vec::new();
+        for order in [ResizeOrder::ResizeFirst, ResizeOrder::BaseFirst] {
+            let _ = fs::remove_file(&log);
+            let output = dir.path().join("out.gif");
+            let options = CompressOptions {
+                target_size: 1000.0,
+                threads: 1,
+                max_width: Some(32),
+                resize_order: order,
+                ..Default::default()
             };
+            let outcome = optimize_gif(&input, &output, &options, 1, None, None).unwrap();
+            assert!(outcome.final_bytes > 0);
+            let calls = fs::read_to_string(&log).unwrap();
+            let calls: Vec<&str> = calls.lines().collect();
+            first_calls.push((calls[0].contains("--resize-fit"), calls.get(1).is_some_and(|call| call.contains("--resize-fit"))));
         }
-    };
+        // 先缩小时缩小与基础优化在同一次调用中完成；先优化时原尺寸优化后再单独缩小
+        assert!(first_calls[0].0);
+        assert_eq!(first_calls[1], (false, true));
+    }
     
-    // 检查是否有线程已经找到结果
-    if shared_state.is_target_found() {
-        log("已有其他线程找到满足条件的结果，提前退出");
-        return StrategyResult {
-            size: f64::MAX,
-            file: None,
-            success: false,
+    
+    #[cfg(unix)]
+    #[test]
+    fn color_floor_hit_only_when_reduction_stops_at_floor() {
+        let unbounded = CompressOptions { min_colors: Some(2), ..Default::default() };
+        assert_eq!(color_reduction_levels(&unbounded), (COLOR_REDUCTION_LEVELS.to_vec(), false));
+        let floored = CompressOptions { min_colors: Some(32), ..Default::default() };
+        assert_eq!(color_reduction_levels(&floored), (vec![128, 64, 32], true));
+        
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let run = |options: &CompressOptions| {
+            let file = TempFile::new(NamedTempFile::new_in(dir.path()).unwrap());
+            write_noise_gif(&file.path, 2, 16, 5);
+            let mut best_size = get_file_size_kb(&file.path).unwrap();
+            let shared_state = SharedState::new();
+            let strategy = Strategy { skip: 2, delay: 10, motion_threshold: None, cinemagraph: false, scale: None };
+            reduce_colors("gifsicle", (&strategy, Some(40)), &mut Some(file), &mut best_size, options, &shared_state, &|_| {});
+            let attempts = shared_state.attempts.lock().unwrap().0.clone();
+            (shared_state.color_floor_hit.load(Ordering::Relaxed), attempts)
         };
+        // 用到下限仍未达到目标，最后一次尝试标记为被下限挡住
+        let (hit, attempts) = run(&CompressOptions { target_size: 0.1, ..floored.clone() });
+        assert!(hit);
+        assert_eq!(attempts.iter().map(|attempt| attempt.colors).collect::<Vec<_>>(), vec![Some(128), Some(64), Some(32)]);
+        assert!(attempts.iter().all(|attempt| attempt.lossy == Some(40) && attempt.strategy_skip == 2));
+        assert_eq!(attempts.iter().map(|attempt| attempt.floored).collect::<Vec<_>>(), vec![false, false, true]);
+        // 下限之前就达到了目标
+        let (hit, attempts) = run(&CompressOptions { target_size: 1000.0, ..floored });
+        assert!(!hit);
+        assert!(attempts.iter().all(|attempt| !attempt.floored));
+        // 下限低于所有级别
+        assert!(!run(&CompressOptions { target_size: 0.1, ..unbounded }).0);
     }
     
-    let temp_frames_path = temp_frames.path_str();
+    #[test]
+    fn degradation_order_rejects_unknown_and_duplicates() {
+        let order = |names: &[&str]| parse_degradation_order(&names.iter().map(|name| name.to_string()).collect::<Vec<_>>());
+        assert_eq!(order(&["colors", "frames", "resize"]).unwrap(), vec![DegradationKnob::Colors, DegradationKnob::Frames, DegradationKnob::Resize]);
+        assert!(matches!(order(&["colors", "blur"]), Err(GifError::InvalidOption(_))));
+        assert!(matches!(order(&["lossy", "lossy"]), Err(GifError::InvalidOption(_))));
+    }
     
-    if let Err(e) = extract_frames(input_path, &temp_frames_path, skip, delay) {
-        log(&format!("  帧提取失败: {}", e));
-        return StrategyResult {
-            size: f64::MAX,
-            file: None,
-            success: false,
+    
+    #[tokio::test]
+    async fn batch_items_complete_before_aggregate() {
+        let events = std::sync::Mutex::new(Vec::new());
+        let items: Vec<BatchItem> = (0..3).map(|i| BatchItem {
+            input_path: format!("in{}.gif", i),
+            output_path: format!("out{}.gif", i),
+        }).collect();
+        
+        let compress = |item: &BatchItem| {
+            // 处理下一个文件时，上一个文件的完成通知必须已经发出
+            let seen = events.lock().unwrap().clone();
+            let input_path = item.input_path.clone();
+            async move {
+                let index: usize = input_path[2..3].parse().unwrap();
+                if index > 0 {
+                    assert!(seen.contains(&("complete", index - 1)));
+                }
+                Err(format!("失败 {}", index))
+            }
+        };
+        let notify = |event: BatchEvent| {
+            let entry = match event {
+                BatchEvent::Started(started) => ("started", started.index),
+                BatchEvent::Complete(complete) => {
+                    assert!(!complete.result.success);
+                    ("complete", complete.index)
+                }
+            };
+            events.lock().unwrap().push(entry);
         };
+        let aggregate = run_batch(items, 100.0, compress, notify).await;
+        
+        let events = events.into_inner().unwrap();
+        assert_eq!(events, vec![
+            ("started", 0), ("complete", 0),
+            ("started", 1), ("complete", 1),
+            ("started", 2), ("complete", 2),
+        ]);
+        assert_eq!(aggregate.results.len(), 3);
+        assert_eq!(aggregate.failed, 3);
+        assert!(aggregate.results[1].message.contains("失败 1"));
     }
     
-    // 检查是否有线程已经找到结果
-    if shared_state.is_target_found() {
-        log("已有其他线程找到满足条件的结果，提前退出");
-        return StrategyResult {
-            size: f64::MAX,
-            file: None,
-            success: false,
+    
+    #[test]
+    fn webp_sibling_never_replaces_input_or_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("clip.webp");
+        fs::write(&input, b"GIF89a").unwrap();
+        let input = input.to_string_lossy().to_string();
+        
+        let output = dir.path().join("out.gif").to_string_lossy().to_string();
+        assert_eq!(webp_sibling_path(&input, &output).unwrap(), dir.path().join("out.webp"));
+        // 输出与原文件同名时WebP会覆盖原文件
+        let same_stem = dir.path().join("clip.gif").to_string_lossy().to_string();
+        assert!(webp_sibling_path(&input, &same_stem).is_err());
+        
+        fs::create_dir(dir.path().join("taken.webp")).unwrap();
+        let taken = dir.path().join("taken.gif").to_string_lossy().to_string();
+        assert!(webp_sibling_path(&input, &taken).is_err());
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn measured_gifsicle_memory_is_non_zero() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 4, 64, 5);
+        let output = dir.path().join("out.gif");
+        
+        let options = CompressOptions { target_size: 0.1, max_gifsicle_runs: Some(2), measure_memory: true, ..Default::default() };
+        let report = optimize_gif(&input, &output, &options, 1, None, None).unwrap().report;
+        assert!(report.gifsicle_peak_rss_kb.is_some_and(|kb| kb > 0), "{:?}", report.gifsicle_peak_rss_kb);
+        
+        let options = CompressOptions { measure_memory: false, ..options };
+        let report = optimize_gif(&input, &output, &options, 1, None, None).unwrap().report;
+        assert_eq!(report.gifsicle_peak_rss_kb, None);
+    }
+    
+    
+    #[test]
+    fn blending_dropped_frames_smooths_fast_pan() {
+        // 4像素宽的白条每帧向右移动4像素
+        let pan_frame = |step: u32, delay_ms: u32| {
+            let buffer = image::RgbaImage::from_fn(32, 4, |x, _| {
+                if x / 4 == step { image::Rgba([255, 255, 255, 255]) } else { image::Rgba([0, 0, 0, 255]) }
+            });
+            image::Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(delay_ms, 1))
+        };
+        let frames: Vec<_> = (0..3).map(|step| pan_frame(step, 100)).collect();
+        let blended = blend_frames(&frames);
+        let plain = &frames[0];
+        
+        // 混合后白条经过的位置都带有三分之一的亮度，直接丢帧只剩第一帧的位置
+        for x in 0..12 {
+            assert_eq!(blended.buffer().get_pixel(x, 0)[0], 85, "x={}", x);
+        }
+        assert_eq!(blended.buffer().get_pixel(12, 0)[0], 0);
+        assert_eq!(blended.delay(), plain.delay());
+        
+        // 与被覆盖的各帧之间的均方误差比直接丢帧小
+        let distance = |a: &image::Frame, b: &image::Frame| -> u64 {
+            a.buffer().pixels().zip(b.buffer().pixels()).map(|(p, q)| (p[0] as i64 - q[0] as i64).pow(2) as u64).sum()
         };
+        let blended_total: u64 = frames.iter().map(|frame| distance(&blended, frame)).sum();
+        let plain_total: u64 = frames.iter().map(|frame| distance(plain, frame)).sum();
+        assert!(blended_total < plain_total, "{} >= {}", blended_total, plain_total);
+        
+        // 按延迟加权：中间一帧显示两倍时间时占一半
+        let weighted = blend_frames(&[pan_frame(0, 100), pan_frame(1, 200), pan_frame(2, 100)]);
+        assert_eq!(weighted.buffer().get_pixel(5, 0)[0], 128);
+        assert_eq!(weighted.buffer().get_pixel(1, 0)[0], 64);
     }
     
-    // 检查提取是否成功
-    match get_file_size_kb(&temp_frames_path) {
-        Ok(size) if size < 1.0 => {
-            log("  帧提取生成的文件过小");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
-            };
-        },
-        Ok(_) => {}, // 文件大小正常，继续处理
-        Err(_) => {
-            log("  无法读取提取的帧大小");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
-            };
+    
+    #[cfg(unix)]
+    #[test]
+    fn bundle_contains_gif_webp_and_poster() {
+        let _tools = FakeTools::install(&[("gif2webp", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let gif = dir.path().join("in.gif");
+        write_noise_gif(&gif, 3, 16, 10);
+        let archive_path = dir.path().join("clip.zip");
+        let info = write_bundle(&gif, &archive_path, None, &SharedState::new()).unwrap();
+        
+        let names: Vec<_> = info.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["clip.gif", "clip.webp", "clip_poster.png"]);
+        assert!(info.warnings.is_empty(), "{:?}", info.warnings);
+        
+        let mut archive = zip::ZipArchive::new(File::open(&archive_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 3);
+        for entry in &info.entries {
+            let mut file = archive.by_name(&entry.name).unwrap();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            assert_eq!(data.len() as u64, entry.size_bytes, "{}", entry.name);
         }
-    };
+        let gif_size = fs::metadata(&gif).unwrap().len();
+        assert_eq!(info.entries[0].size_bytes, gif_size);
+        let mut poster = Vec::new();
+        archive.by_name("clip_poster.png").unwrap().read_to_end(&mut poster).unwrap();
+        assert_eq!(image::load_from_memory(&poster).unwrap().width(), 16);
+    }
     
-    // 优化提取后的帧
-    let temp_frames_opt = match NamedTempFile::new() {
-        Ok(file) => TempFile::new(file),
-        Err(_) => {
-            log("  创建优化临时文件失败");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
-            };
+    
+    #[test]
+    fn smoothed_delays_snap_to_grid_and_keep_duration() {
+        let delays = [7, 9, 8, 7, 9, 8, 7, 9];
+        let smoothed = smooth_delays(&delays, 2);
+        assert!(smoothed.iter().all(|delay| delay % 2 == 0), "{:?}", smoothed);
+        let before: i64 = delays.iter().map(|&delay| delay as i64).sum();
+        let after: i64 = smoothed.iter().map(|&delay| delay as i64).sum();
+        assert!((before - after).abs() <= 1, "{} -> {}", before, after);
+        
+        // 只改写文件中的延迟，画面不变，报告单帧最大的调整量
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.gif");
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]];
+        write_solid_gif(&path, &colors, 8, 7);
+        let adjustment = smooth_output_delays(&path, 2).unwrap();
+        assert_eq!(adjustment, 1);
+        let written: Vec<u16> = scan_gif_blocks(&path).unwrap().frames.iter().map(|block| block.delay_cs).collect();
+        assert!(written.iter().all(|delay| delay % 2 == 0), "{:?}", written);
+        assert_eq!(written.iter().sum::<u16>(), 28);
+        assert_eq!(first_pixels(&path), colors.to_vec());
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn insufficient_savings_writes_no_output() {
+        // 假gifsicle原样复制，噪点图基本无法压缩
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 4, 64, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, max_gifsicle_runs: Some(3), min_savings_percent: Some(2.0), ..Default::default() };
+        
+        match optimize_gif(&input, &output, &options, 1, None, None) {
+            Err(GifError::InsufficientSavings { saved_percent, required_percent, .. }) => {
+                assert!(saved_percent < 2.0, "{}", saved_percent);
+                assert_eq!(required_percent, 2.0);
+            }
+            other => panic!("应因节省不足跳过: {:?}", other.map(|outcome| outcome.final_size)),
         }
-    };
+        assert!(!output.exists());
+        
+        // 不设置阈值时照常写出
+        let options = CompressOptions { min_savings_percent: None, ..options };
+        optimize_gif(&input, &output, &options, 1, None, None).unwrap();
+        assert!(output.exists());
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn odd_canvas_is_padded_through_the_job() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        let _tools = FakeTools::install(&[("gifsicle", &logging_gifsicle(&log))]);
+        
+        let even = dir.path().join("even.gif");
+        write_solid_gif(&even, &[[0, 0, 0], [255, 255, 255]], 8, 10);
+        assert_eq!(pad_to_even_dimensions(&even, &SharedState::new()).unwrap(), ((8, 8), false));
+        assert!(!log.exists());
+        
+        let odd = dir.path().join("odd.gif");
+        write_solid_gif(&odd, &[[0, 0, 0], [255, 255, 255]], 9, 10);
+        assert_eq!(pad_to_even_dimensions(&odd, &SharedState::new()).unwrap(), ((10, 10), true));
+        assert!(fs::read_to_string(&log).unwrap().contains("--logical-screen 10x10"));
+        
+        // 任务已被取消时不再运行gifsicle
+        let jobs = Arc::new(JobRegistry::new());
+        let job = jobs.start(JobKind::Compress, "odd.gif", None, true);
+        assert_eq!(jobs.cancel(JobKind::Compress, None), 1);
+        let result = pad_to_even_dimensions(&odd, &SharedState::with_cancel_flag(job.cancel_flag()));
+        assert!(matches!(result, Err(GifError::Cancelled)), "{:?}", result.err());
+        assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 1);
+    }
+    
+    
+    #[tokio::test]
+    async fn heatmap_is_hottest_along_moving_object() {
+        // 4x4的白块沿顶部从左向右移动，其余区域不变
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("moving.gif");
+        let palette = [0, 0, 0, 255, 255, 255];
+        let mut encoder = gif::Encoder::new(File::create(&path).unwrap(), 32, 16, &palette).unwrap();
+        for step in 0..4u16 {
+            let buffer: Vec<u8> = (0..16u16)
+                .flat_map(|y| (0..32u16).map(move |x| (y < 4 && x / 4 == step) as u8))
+                .collect();
+            let frame = gif::Frame { width: 32, height: 16, buffer: buffer.into(), delay: 10, ..Default::default() };
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        
+        let heatmap = get_motion_heatmap(path.to_string_lossy().to_string()).await.unwrap();
+        assert_eq!((heatmap.width, heatmap.height, heatmap.frame_count), (32, 16, 4));
+        let (x, y) = heatmap.hottest;
+        assert!(x < 16 && y < 4, "最热的位置 {:?} 不在白块经过的区域", heatmap.hottest);
+        
+        let png = BASE64.decode(heatmap.image.trim_start_matches("data:image/png;base64,")).unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_rgba8();
+        // 白块经过的位置有颜色，下方和右侧不变的区域是黑色
+        assert!(image.get_pixel(6, 1)[0] > 0);
+        assert_eq!(image.get_pixel(6, 10).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(28, 1).0, [0, 0, 0, 255]);
+    }
     
-    // 检查是否有线程已经找到结果
-    if shared_state.is_target_found() {
-        log("已有其他线程找到满足条件的结果，提前退出");
-        return StrategyResult {
-            size: f64::MAX,
-            file: None,
-            success: false,
+    
+    #[cfg(unix)]
+    #[test]
+    fn search_counters_are_monotonic_and_match_report() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 12, 48, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, ..Default::default() };
+        
+        let seen = std::sync::Mutex::new(Vec::new());
+        let on_event = |event: OptimizeEvent<'_>| {
+            if let OptimizeEvent::Progress(progress) = event {
+                seen.lock().unwrap().push(progress.counters);
+            }
         };
+        let report = optimize_gif(&input, &output, &options, 2, Some(&on_event), None).unwrap().report;
+        let seen = seen.into_inner().unwrap();
+        
+        assert!(!seen.is_empty());
+        for pair in seen.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert!(
+                b.strategies_done >= a.strategies_done && b.strategies_total >= a.strategies_total
+                    && b.attempts_done >= a.attempts_done && b.attempts_total >= a.attempts_total,
+                "{:?} -> {:?}", a, b
+            );
+        }
+        for counters in &seen {
+            assert!(counters.strategies_done <= counters.strategies_total && counters.attempts_done <= counters.attempts_total, "{:?}", counters);
+        }
+        assert_eq!(*seen.last().unwrap(), report.counters);
+        assert!(report.counters.strategies_done > 0);
+        assert_eq!(report.counters.strategies_done, report.strategy_timings.len());
     }
     
-    let temp_frames_opt_path = temp_frames_opt.path_str();
     
-    let args = vec!["-O3", &temp_frames_path, "-o", &temp_frames_opt_path];
+    #[test]
+    fn score_weights_flip_the_winner() {
+        // 小但画质差，与大但画质好
+        let candidates = [(40.0, Some(0.50)), (90.0, Some(0.98)), (120.0, Some(1.0))];
+        assert_eq!(pick_by_score(&candidates, 100.0, (0.7, 0.3)), Some((0, 0.50)));
+        assert_eq!(pick_by_score(&candidates, 100.0, (0.3, 0.7)), Some((1, 0.98)));
+        
+        // 无法计算SSIM的结果不参与评分，而不是按0分处理
+        let candidates = [(40.0, Some(0.80)), (10.0, None)];
+        assert_eq!(pick_by_score(&candidates, 100.0, (1.0, 0.0)), Some((0, 0.80)));
+        assert_eq!(pick_by_score(&[(10.0, None)], 100.0, (1.0, 0.0)), None);
+    }
     
-    let _output = match Command::new(&gifsicle_path)
-        .args(&args)
-        .output() {
-        Ok(output) => output,
-        Err(_) => {
-            log("  执行gifsicle帧优化失败");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
-            };
-        }
-    };
+    #[test]
+    fn unreadable_candidate_has_no_ssim() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference = dir.path().join("ref.gif");
+        write_solid_gif(&reference, &[[0, 0, 0], [255, 255, 255]], 8, 10);
+        assert!((gif_ssim(&reference, &reference).unwrap() - 1.0).abs() < 1e-9);
+        
+        let broken = dir.path().join("broken.gif");
+        fs::write(&broken, b"GIF89a\x08\x00").unwrap();
+        assert!(gif_ssim(&reference, &broken).is_err());
+    }
     
-    if !_output.status.success() {
-        log("  帧优化失败");
-        return StrategyResult {
-            size: f64::MAX,
-            file: None,
-            success: false,
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn results_record_and_aggregate_encoder_version() {
+        let identity = {
+            let _tools = FakeTools::install(&[("gifsicle", "#!/bin/sh\necho 'LCDF Gifsicle 1.94'\n")]);
+            probe_gifsicle_identity()
         };
+        assert_eq!(identity.backend, "gifsicle");
+        assert_eq!(identity.version.as_deref(), Some("1.94"));
+        assert_eq!(identity.path.as_deref(), Some("gifsicle"));
+        assert_eq!(identity.label(), "gifsicle 1.94");
+        
+        // 批量汇总按编码器及版本统计
+        let items: Vec<BatchItem> = (0..3).map(|i| BatchItem {
+            input_path: format!("in{}.gif", i),
+            output_path: format!("out{}.gif", i),
+        }).collect();
+        let compress = |item: &BatchItem| {
+            let mut result = build_compress_result(Err(GifError::Other("测试".to_string())), &item.output_path, 100.0);
+            let mut encoder = identity.clone();
+            if item.input_path == "in2.gif" {
+                encoder.version = Some("1.95".to_string());
+            }
+            result.encoder = Some(encoder);
+            async move { Ok(result) }
+        };
+        let aggregate = run_batch(items, 100.0, compress, |_| {}).await;
+        assert_eq!(aggregate.by_encoder.get("gifsicle 1.94"), Some(&2));
+        assert_eq!(aggregate.by_encoder.get("gifsicle 1.95"), Some(&1));
     }
     
-    // 清理第一个临时文件，不再需要它
-    let _ = temp_frames.cleanup();
     
-    let frames_size = match get_file_size_kb(&temp_frames_opt_path) {
-        Ok(size) => size,
-        Err(_) => {
-            log("  无法读取优化后帧大小");
-            return StrategyResult {
-                size: f64::MAX,
-                file: None,
-                success: false,
-            };
-        }
-    };
+    #[tokio::test]
+    async fn comment_round_trip_leaves_image_data_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 3, 16, 10);
+        let original = fs::read(&input).unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        
+        // 超过255字节的注释分成多个数据子块
+        let license = "CC BY 4.0 ".repeat(30);
+        let comments = set_gif_comment(path("in.gif"), license.clone(), path("stamped.gif")).await.unwrap();
+        assert_eq!(comments, vec![license.clone()]);
+        assert_eq!(get_gif_comments(path("stamped.gif")).await.unwrap(), vec![license.clone()]);
+        let stamped = fs::read(dir.path().join("stamped.gif")).unwrap();
+        assert_eq!(stamped.len(), original.len() + encode_comment_block(&license).len());
+        
+        // 替换后只剩新注释
+        let comments = set_gif_comment(path("stamped.gif"), "署名".to_string(), path("replaced.gif")).await.unwrap();
+        assert_eq!(comments, vec!["署名".to_string()]);
+        
+        // 删除后与原文件逐字节相同，图像数据没有被重新编码
+        assert!(remove_gif_comments(path("replaced.gif"), path("stripped.gif")).await.unwrap().is_empty());
+        assert_eq!(fs::read(dir.path().join("stripped.gif")).unwrap(), original);
+        let decode = |name: &str| {
+            let decoder = GifDecoder::new(BufReader::new(File::open(dir.path().join(name)).unwrap())).unwrap();
+            decoder.into_frames().collect_frames().unwrap().into_iter().map(|frame| frame.into_buffer().into_raw()).collect::<Vec<_>>()
+        };
+        assert_eq!(decode("stamped.gif"), decode("in.gif"));
+    }
     
-    log(&format!("  抽帧后大小: {:.2} KB", frames_size));
     
-    if frames_size <= target_size_kb {
-        log("  已达到目标大小!");
-        // 设置标志通知其他线程已找到满足条件的结果
-        shared_state.set_found_target();
-        return StrategyResult {
-            size: frames_size,
-            file: Some(temp_frames_opt),
-            success: true,
+    /// 在本地端口上依次回应responses中的每个请求，hang为true时发完后保持连接不关闭
+    fn serve_http(responses: Vec<(Vec<u8>, bool)>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (response, hang) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0u8; 1];
+                while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                    request.push(byte[0]);
+                }
+                let _ = stream.write_all(&response);
+                if hang {
+                    thread::sleep(Duration::from_secs(5));
+                }
+            }
+        });
+        format!("http://{}/clip.gif", address)
+    }
+    
+    fn http_response(content_type: &str, body: &[u8], declared_length: usize) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            content_type, declared_length
+        ).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+    
+    #[tokio::test]
+    async fn download_from_local_server_checks_type_and_cancels() {
+        let dir = tempfile::tempdir().unwrap();
+        let gif = dir.path().join("in.gif");
+        write_noise_gif(&gif, 2, 16, 10);
+        let body = fs::read(&gif).unwrap();
+        let jobs = Arc::new(JobRegistry::new());
+        
+        let url = serve_http(vec![
+            (http_response("image/gif", &body, body.len()), false),
+            (http_response("text/html", b"<html></html>", 13), false),
+            // 声明的长度比实际发送的多，服务器发完前一半后不再发送
+            (http_response("image/gif", &body[..body.len() / 2], body.len()), true),
+        ]);
+        let job = jobs.start(JobKind::Compress, &url, None, true);
+        let download = download_gif(&url, MAX_DOWNLOAD_BYTES, &job).await.unwrap();
+        assert_eq!(fs::read(&download.path).unwrap(), body);
+        
+        let error = download_gif(&url, MAX_DOWNLOAD_BYTES, &job).await.err().unwrap();
+        assert!(error.to_string().contains("text/html"), "{}", error);
+        
+        // 下载停住时取消任务立即中止下载
+        let canceller = {
+            let jobs = Arc::clone(&jobs);
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                jobs.cancel(JobKind::Compress, None)
+            })
         };
+        let started = Instant::now();
+        let result = download_gif(&url, MAX_DOWNLOAD_BYTES, &job).await;
+        assert!(matches!(result, Err(GifError::Cancelled)), "{:?}", result.err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(canceller.await.unwrap(), 1);
     }
     
-    // 跟踪当前策略下的最佳结果
-    let mut best_size = frames_size;
-    let mut best_file = Some(temp_frames_opt);
     
-    // 批量尝试不同的lossy值
-    // 创建临时文件和对应的lossy级别
-    let lossy_levels = [30, 60, 90, 120, 150, 180, 210, 240];
+    #[test]
+    fn color_stats_count_used_colors_and_saturate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.gif");
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0], [0, 255, 255]];
+        write_solid_gif(&path, &colors, 8, 10);
+        
+        let stats = color_stats(&path, 8).unwrap();
+        assert_eq!(stats.global_palette_size, Some(8));
+        assert!(stats.local_palette_sizes.is_empty());
+        assert_eq!(stats.frames_without_local_palette, 5);
+        assert_eq!(stats.sampled_frames.len(), 5);
+        assert!(stats.sampled_frames.iter().all(|frame| frame.colors == 1 && !frame.saturated));
+        assert_eq!((stats.distinct_colors, stats.saturated, stats.recommended_colors), (5, false, 8));
+        
+        // 取样帧数少于总帧数时均匀取样
+        let sampled: Vec<usize> = color_stats(&path, 2).unwrap().sampled_frames.iter().map(|frame| frame.index).collect();
+        assert_eq!(sampled, vec![0, 2]);
+        
+        // 超过容量后只记录下限
+        let mut counter = ColorCounter::new();
+        for rgb in 0..(COLOR_COUNTER_CAPACITY as u32 + 100) {
+            counter.insert(rgb * 7);
+        }
+        counter.insert(0);
+        assert_eq!((counter.len, counter.saturated), (COLOR_COUNTER_CAPACITY, true));
+    }
     
-    // 每次处理两个lossy级别，平衡进程创建开销和并行效率
-    let chunk_size = 2;
     
-    for chunk in lossy_levels.chunks(chunk_size) {
-        // 先检查是否有线程已经找到结果
-        if shared_state.is_target_found() {
-            log("已有其他线程找到满足条件的结果，提前退出");
-            return StrategyResult {
-                size: best_size,
-                file: best_file,
-                success: true,
-            };
+    #[test]
+    fn redundant_global_table_is_stripped() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        let global: Vec<u8> = (0..=255u8).flat_map(|v| [v, v, v]).collect();
+        let mut encoder = gif::Encoder::new(File::create(&input).unwrap(), 8, 8, &global).unwrap();
+        for color in [[255u8, 0, 0], [0, 0, 255]] {
+            let palette = [color, [0, 0, 0]].concat();
+            let frame = gif::Frame { width: 8, height: 8, buffer: vec![0; 64].into(), palette: Some(palette), delay: 10, ..Default::default() };
+            encoder.write_frame(&frame).unwrap();
         }
+        drop(encoder);
         
-        let mut temp_files = Vec::with_capacity(chunk.len());
-        let mut results = Vec::with_capacity(chunk.len());
+        let analysis = global_table_analysis(&scan_gif_blocks(&input).unwrap());
+        assert!(analysis.redundant);
+        assert_eq!(analysis.removable_bytes, 768);
         
-        // 创建这一批次的临时文件
-        for &level in chunk {
-            match NamedTempFile::new() {
-                Ok(file) => {
-                    temp_files.push((level, TempFile::new(file)));
-                },
-                Err(_) => {
-                    log(&format!("  创建lossy={}临时文件失败", level));
-                }
-            }
-        }
+        let output = dir.path().join("out.gif");
+        assert_eq!(strip_global_color_table_file(&input, &output).unwrap(), Some(768));
+        assert_eq!(fs::metadata(&output).unwrap().len() + 768, fs::metadata(&input).unwrap().len());
+        assert_eq!(scan_gif_blocks(&output).unwrap().global_palette, None);
+        assert_eq!(first_pixels(&output), first_pixels(&input));
         
-        let current_best_path = match &best_file {
-            Some(file) => file.path_str(),
-            None => break,
-        };
+        // 全局颜色表仍被使用时不写文件
+        let used = dir.path().join("used.gif");
+        write_solid_gif(&used, &[[0, 0, 0], [255, 255, 255]], 8, 10);
+        let untouched = dir.path().join("untouched.gif");
+        assert_eq!(strip_global_color_table_file(&used, &untouched).unwrap(), None);
+        assert!(!untouched.exists());
+    }
+    
+    
+    #[test]
+    fn delay_stats_detect_variable_timing() {
+        // 帧率换算成厘秒的取整误差不算节奏不均匀
+        let steady = delay_stats(&[3, 4, 3, 4, 3, 4]);
+        assert!(!steady.variable_timing);
+        assert_eq!((steady.min_cs, steady.max_cs), (3, 4));
+        assert!((steady.avg_cs - 3.5).abs() < 1e-9);
         
-        // 处理这一批次的lossy级别
-        for (level, temp_file) in &temp_files {
-            let temp_path = temp_file.path_str();
-            
-            // 创建lossy参数
-            let lossy_arg = format!("--lossy={}", level);
-            
-            // 优化的gifsicle命令参数
-            let args = vec![
-                "-O3", 
-                "--no-warnings",
-                "--no-conserve-memory", 
-                "--no-comments", 
-                "--no-names",
-                &lossy_arg,
-                &current_best_path, 
-                "-o", 
-                &temp_path
-            ];
-            
-            let _output = match Command::new(&gifsicle_path)
-                .args(&args)
-                .output() {
-                Ok(output) if output.status.success() => {
-                    match get_file_size_kb(&temp_path) {
-                        Ok(size) => {
-                            log(&format!("  抽帧 + lossy={} 后大小: {:.2} KB", level, size));
-                            results.push((*level, size));
-                        },
-                        Err(_) => {
-                            log(&format!("  无法读取lossy={}压缩后大小", level));
-                        }
-                    }
-                },
-                _ => {
-                    log(&format!("  lossy={}压缩失败", level));
+        // 有刻意的停顿
+        let paused = delay_stats(&[5, 5, 5, 100]);
+        assert!(paused.variable_timing);
+        let histogram: Vec<(u16, usize)> = paused.histogram.iter().map(|bucket| (bucket.delay_cs, bucket.frames)).collect();
+        assert_eq!(histogram, vec![(5, 3), (100, 1)]);
+        
+        // 长GIF只列出前DELAY_LIST_LIMIT帧，直方图仍完整
+        let long = delay_stats(&vec![4; DELAY_LIST_LIMIT + 10]);
+        assert!(long.truncated);
+        assert_eq!(long.delays_cs.len(), DELAY_LIST_LIMIT);
+        assert_eq!(long.frame_count, DELAY_LIST_LIMIT + 10);
+        assert_eq!(long.histogram[0].frames, DELAY_LIST_LIMIT + 10);
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn verbose_gifsicle_lines_reach_progress_details() {
+        // 带--verbose时向stderr输出一行
+        let script = copying_gifsicle("0").replacen(
+            "in=''",
+            "for a in \"$@\"; do [ \"$a\" = \"--verbose\" ] && echo 'gifsicle: optimizing frame' >&2; done\nin=''",
+            1,
+        );
+        let _tools = FakeTools::install(&[("gifsicle", &script)]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 32, 5);
+        let output = dir.path().join("out.gif");
+        
+        let details_for = |verbose_gifsicle: bool| {
+            let details = std::sync::Mutex::new(Vec::new());
+            let on_event = |event: OptimizeEvent<'_>| {
+                if let OptimizeEvent::Progress(progress) = event {
+                    details.lock().unwrap().extend(progress.details);
                 }
             };
-        }
+            let options = CompressOptions { target_size: 0.1, max_gifsicle_runs: Some(4), verbose_gifsicle, ..Default::default() };
+            optimize_gif(&input, &output, &options, 1, Some(&on_event), None).unwrap();
+            details.into_inner().unwrap()
+        };
+        assert!(details_for(true).iter().any(|details| details.contains("gifsicle: optimizing frame")));
+        assert!(!details_for(false).iter().any(|details| details.contains("optimizing frame")));
         
-        // 处理这一批次的结果
-        for (_result_idx, (level, size)) in results.iter().enumerate() {
-            if *size <= target_size_kb {
-                log(&format!("  lossy={} 已达到目标大小!", level));
-                
-                // 找到对应的临时文件
-                if let Some((_, temp_file)) = temp_files.iter().find(|(l, _)| *l == *level) {
-                    // 如果当前结果比之前的好，替换并清理旧文件
-                    if best_size > *size {
-                        if let Some(old_file) = best_file.take() {
-                            let _ = old_file.cleanup(); // 清理旧文件
-                        }
-                        best_size = *size;
-                        best_file = Some(temp_file.clone());
-                    }
-                }
-                
-                // 设置标志通知其他线程已找到满足条件的结果
-                shared_state.set_found_target();
-                break;
-            } else if *size < best_size {
-                // 找到对应的临时文件
-                if let Some((_, temp_file)) = temp_files.iter().find(|(l, _)| *l == *level) {
-                    // 替换旧文件并清理
-                    if let Some(old_file) = best_file.take() {
-                        let _ = old_file.cleanup(); // 清理旧文件
-                    }
-                    best_size = *size;
-                    best_file = Some(temp_file.clone());
-                }
-            }
+        // 过长的行被截断，积压过多时只保留最近的行
+        let shared_state = SharedState::new();
+        shared_state.push_verbose_line(&"x".repeat(VERBOSE_LINE_MAX_CHARS + 50));
+        for i in 0..VERBOSE_MAX_PENDING_LINES {
+            shared_state.push_verbose_line(&format!("line {}", i));
         }
+        let pending = shared_state.take_verbose_details().unwrap();
+        assert_eq!(pending.lines().count(), VERBOSE_MAX_PENDING_LINES);
+        assert!(!pending.contains("xxx"));
+        assert!(shared_state.take_verbose_details().is_none());
         
-        // 如果已找到目标，不再处理更多批次
-        if shared_state.is_target_found() {
-            break;
-        }
+        let shared_state = SharedState::new();
+        shared_state.push_verbose_line(&"x".repeat(VERBOSE_LINE_MAX_CHARS + 50));
+        assert_eq!(shared_state.take_verbose_details().unwrap().chars().count(), VERBOSE_LINE_MAX_CHARS + 1);
+    }
+    
+    
+    #[test]
+    fn inline_output_is_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small.gif");
+        write_noise_gif(&small, 2, 16, 10);
+        assert_eq!(read_inline_output(&small, MAX_INLINE_OUTPUT_BYTES).unwrap(), fs::read(&small).unwrap());
         
-        // 清理这批次中未被选中的临时文件
-        for (_level, temp_file) in &temp_files {
-            if let Some(best) = &best_file {
-                if best.path != temp_file.path {
-                    let _ = temp_file.cleanup();
-                }
-            } else {
-                let _ = temp_file.cleanup();
-            }
-        }
+        // 稀疏文件，不实际占用空间
+        let large = dir.path().join("large.gif");
+        File::create(&large).unwrap().set_len(MAX_INLINE_OUTPUT_BYTES + 1).unwrap();
+        let error = read_inline_output(&large, MAX_INLINE_OUTPUT_BYTES).unwrap_err();
+        assert!(error.contains("compress_gif"), "{}", error);
     }
     
-    StrategyResult {
-        size: best_size,
-        file: best_file,
-        success: true,
+    
+    #[test]
+    fn input_bytes_are_validated_and_tracked() {
+        assert!(write_input_bytes(b"\x89PNG\r\n\x1a\n").is_err());
+        assert!(write_input_bytes(b"").is_err());
+        
+        let dir = tempfile::tempdir().unwrap();
+        let gif = dir.path().join("in.gif");
+        write_noise_gif(&gif, 2, 16, 10);
+        let data = fs::read(&gif).unwrap();
+        let input = write_input_bytes(&data).unwrap();
+        assert_eq!(fs::read(&input.path).unwrap(), data);
+        // 临时输入登记在IN_FLIGHT中，丢弃后删除并取消登记
+        assert!(IN_FLIGHT.temp_paths.lock().unwrap().contains(&input.path));
+        let path = input.path.clone();
+        drop(input);
+        assert!(!path.exists());
+        assert!(!IN_FLIGHT.temp_paths.lock().unwrap().contains(&path));
+        
+        // 请求头中的参数经过encodeURIComponent编码
+        let args = r#"{"output_path":"/tmp/输出.gif","options":{"target_size":100}}"#;
+        let encoded: String = args.bytes().map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        }).collect();
+        assert_eq!(percent_decode(&encoded).unwrap(), args);
+        assert!(percent_decode("%E4%B8").is_err());
+        assert!(percent_decode("%G1").is_err());
     }
-}
-
-/// 优化GIF到目标大小 (并行版本)
-fn optimize_gif<P: AsRef<Path>, Q: AsRef<Path>>(
-    input_path: P,
-    output_path: Q,
-    target_size_kb: f64,
-    min_frame_percent: u32,
-    threads: usize,
-) -> Result<(f64, f64), GifError> {
-    // 获取初始文件大小
-    let original_size = get_file_size_kb(&input_path)?;
-    println!("原始大小: {:.2} KB", original_size);
     
-    // 如果已经小于目标大小，直接复制
-    if original_size <= target_size_kb {
-        println!("文件已经小于目标大小，无需压缩");
-        fs::copy(&input_path, &output_path)?;
-        return Ok((original_size, original_size));
+    
+    #[test]
+    fn quality_floor_picks_smallest_candidate_above_floor() {
+        let candidate = |skip, lossy, size_kb, ssim| QualityFloorCandidate { skip, lossy, size_kb, ssim };
+        let candidates = [
+            candidate(3, 200, 20.0, 0.70),
+            candidate(2, 80, 35.0, 0.91),
+            candidate(1, 40, 50.0, 0.97),
+            candidate(2, 120, 30.0, 0.89),
+        ];
+        let best = smallest_meeting_floor(&candidates, 0.9).unwrap();
+        assert_eq!((best.skip, best.lossy, best.size_kb), (2, 80, 35.0));
+        assert!(best.ssim >= 0.9);
+        // 没有更小的候选同样满足下限
+        assert!(candidates.iter().filter(|c| c.ssim >= 0.9).all(|c| c.size_kb >= best.size_kb));
+        assert_eq!(smallest_meeting_floor(&candidates, 0.99).map(|c| c.size_kb), None);
     }
     
-    // 获取初始帧数
-    let original_frame_count = get_frame_count(&input_path)?;
-    println!("原始帧数: {}", original_frame_count);
+    #[cfg(unix)]
+    #[test]
+    fn quality_floor_search_respects_floor() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 24, 5);
+        
+        let result = search_quality_floor(&input, 0.95, 2, &SharedState::new(), &|_, _| {}).unwrap();
+        assert!(!result.candidates.is_empty());
+        assert!(result.candidates.windows(2).all(|pair| pair[0].size_kb <= pair[1].size_kb));
+        let best = result.best.unwrap();
+        assert!(best.ssim >= 0.95, "{}", best.ssim);
+        assert!(result.candidates.iter().filter(|c| c.ssim >= 0.95).all(|c| c.size_kb >= best.size_kb));
+        
+        // SSIM不会超过1
+        let unreachable = search_quality_floor(&input, 1.01, 2, &SharedState::new(), &|_, _| {}).unwrap();
+        assert!(unreachable.best.is_none());
+    }
     
-    // 检查gifsicle是否存在
-    let gifsicle_path = match find_gifsicle() {
-        Some(path) => path,
-        None => return Err(GifError::GifsicleNotFound),
-    };
     
-    // 基础优化 - 使用gifsicle的最高优化级别和更多高级选项
-    let temp_file = NamedTempFile::new()?;
-    let temp_file_opt = TempFile::new(temp_file);
-    let temp_file_opt_path = temp_file_opt.path_str();
+    #[cfg(unix)]
+    #[test]
+    fn unified_queue_respects_thread_limit() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0.1"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 12, 48, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, unified_queue: true, max_gifsicle_runs: Some(16), ..Default::default() };
+        let report = optimize_gif(&input, &output, &options, 2, None, None).unwrap().report;
+        
+        assert!(report.gifsicle_runs > 4, "{}", report.gifsicle_runs);
+        assert_eq!(report.peak_concurrent_gifsicle, 2);
+    }
     
-    // 使用String而不是&str，避免生命周期问题
-    let input_path_str = input_path.as_ref().to_string_lossy().to_string();
+    #[test]
+    fn panicking_search_job_still_releases_the_queue() {
+        let queue: std::sync::Mutex<SearchQueue> = std::sync::Mutex::new((VecDeque::new(), 1));
+        let ready = Condvar::new();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _running = RunningSearchJob { queue: &queue, ready: &ready };
+            panic!("任务失败");
+        }));
+        assert!(result.is_err());
+        // 其他工作线程看到队列为空且没有正在处理的任务，可以退出
+        assert_eq!(queue.lock().unwrap().1, 0);
+    }
+    
+    
+    #[tokio::test]
+    async fn batch_channel_progress_is_monotonic_and_carries_results() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = Arc::clone(&received);
+        let channel = ProgressChannel::with_sink(move |progress| {
+            sink.lock().unwrap().push(progress);
+            Ok(())
+        });
+        let items: Vec<BatchItem> = (0..3).map(|i| BatchItem {
+            input_path: format!("in{}.gif", i),
+            output_path: format!("out{}.gif", i),
+        }).collect();
+        
+        // 每个文件自己的进度从0到1
+        let compress = |item: &BatchItem| {
+            for fraction in [0.0, 0.4, 1.0] {
+                channel.handle(&OptimizeEvent::Progress(CompressProgress {
+                    status: "压缩中".to_string(),
+                    progress: fraction,
+                    details: None,
+                    phase: Some(ProgressPhase::Strategies),
+                    eta_seconds: None,
+                    counters: SearchCounters::default(),
+                    new_attempts: Vec::new(),
+                    item_result: None,
+                }));
+            }
+            let result = build_compress_result(Err(GifError::Other(format!("{} 失败", item.input_path))), &item.output_path, 100.0);
+            async move { Ok(result) }
+        };
+        run_batch(items, 100.0, compress, |event| notify_batch_channel(&channel, event)).await;
+        drop(channel);
+        
+        let received = received.lock().unwrap();
+        let values: Vec<f64> = received.iter().map(|progress| progress.progress).collect();
+        assert!(values.windows(2).all(|pair| pair[1] >= pair[0] - 1e-9), "{:?}", values);
+        assert!((values.last().unwrap() - 1.0).abs() < 1e-9);
+        let results: Vec<&CompressResult> = received.iter().filter_map(|progress| progress.item_result.as_deref()).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[1].message.contains("in1.gif 失败"));
+    }
     
-    // 构建优化的参数列表
-    let args = vec![
-        "-O3",                            // 最高级别优化
-        "--no-warnings",                  // 不显示警告
-        "--no-conserve-memory",           // 使用更多内存以提高速度
-        "--no-comments",                  // 删除注释以减小文件大小
-        "--no-names",                     // 删除图像和对象名称
-        "--careful",                      // 更慎重的优化，避免损坏文件
-        &input_path_str,                  // 输入文件
-        "-o",                             // 输出选项
-        &temp_file_opt_path               // 输出文件
-    ];
     
-    let _output = Command::new(&gifsicle_path)
-        .args(&args)
-        .output()?;
+    #[cfg(unix)]
+    #[test]
+    fn bit_depth_maps_to_gifsicle_colors() {
+        assert_eq!(bit_depth_colors(4).unwrap(), 16);
+        assert_eq!(bit_depth_colors(1).unwrap(), 2);
+        assert_eq!(bit_depth_colors(8).unwrap(), 256);
+        assert!(bit_depth_colors(0).is_err());
+        assert!(bit_depth_colors(9).is_err());
+        
+        let mut options = CompressOptions { bit_depth: Some(4), ..Default::default() };
+        apply_bit_depth(&mut options).unwrap();
+        assert_eq!(options.max_colors, Some(16));
+        let mut conflicting = CompressOptions { bit_depth: Some(4), max_colors: Some(32), ..Default::default() };
+        assert!(apply_bit_depth(&mut conflicting).is_err());
+        
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        let _tools = FakeTools::install(&[("gifsicle", &logging_gifsicle(&log))]);
+        let input = dir.path().join("in.gif");
+        write_solid_gif(&input, &[[0, 0, 0], [255, 255, 255]], 8, 10);
+        let result = reduce_bit_depth_file(&input, &dir.path().join("out.gif"), 4, &SharedState::new()).unwrap();
+        assert!(fs::read_to_string(&log).unwrap().contains("--colors 16"));
+        // 报告的是输出中实际的颜色表大小
+        assert_eq!((result.colors, result.palette_size), (16, Some(2)));
+    }
     
-    if !_output.status.success() {
-        let stderr = String::from_utf8_lossy(&_output.stderr).to_string();
-        return Err(GifError::GifsicleExecFailed(stderr));
+    #[test]
+    #[ignore = "需要安装gifsicle"]
+    fn four_bit_output_has_at_most_16_colors() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 3, 32, 10);
+        let output = dir.path().join("out.gif");
+        let result = reduce_bit_depth_file(&input, &output, 4, &SharedState::new()).unwrap();
+        assert!(result.palette_size.is_some_and(|size| size <= 16), "{:?}", result.palette_size);
+        let mut counter = ColorCounter::new();
+        let decoder = GifDecoder::new(BufReader::new(File::open(&output).unwrap())).unwrap();
+        for frame in decoder.into_frames() {
+            counter.insert_image(frame.unwrap().buffer());
+        }
+        assert!(counter.len <= 16, "{}", counter.len);
     }
     
-    let opt_size = get_file_size_kb(&temp_file_opt_path)?;
-    println!("基础优化后大小: {:.2} KB", opt_size);
     
-    // 如果已经达到目标大小，直接复制
-    if opt_size <= target_size_kb {
-        fs::copy(&temp_file_opt_path, &output_path)?;
-        return Ok((original_size, opt_size));
+    #[test]
+    fn quality_levels_map_to_monotonic_caps() {
+        let all: Vec<QualityCaps> = (1..=10).map(|level| quality_caps(level).unwrap()).collect();
+        for (caps, &(max_lossy, min_frame_percent, min_colors, allow_resize)) in all.iter().zip(QUALITY_LEVEL_TABLE.iter()) {
+            assert_eq!((caps.max_lossy, caps.min_frame_percent, caps.min_colors, caps.allow_resize), (max_lossy, min_frame_percent, min_colors, allow_resize));
+        }
+        // 等级越高损失越少
+        for pair in all.windows(2) {
+            assert!(pair[1].max_lossy <= pair[0].max_lossy, "{:?}", pair);
+            assert!(pair[1].min_frame_percent > pair[0].min_frame_percent, "{:?}", pair);
+            assert!(pair[1].min_colors >= pair[0].min_colors, "{:?}", pair);
+            assert!(pair[0].allow_resize || !pair[1].allow_resize, "{:?}", pair);
+        }
+        assert_eq!((all[9].max_lossy, all[9].min_frame_percent, all[9].min_colors), (0, 100, 256));
+        assert!(quality_caps(0).is_err());
+        assert!(quality_caps(11).is_err());
+        
+        for level in 1..=10 {
+            let caps = quality_caps(level).unwrap();
+            // 未指定的参数由等级填入
+            let mut options = CompressOptions { quality_level: Some(level), ..Default::default() };
+            assert_eq!(apply_quality_level(&mut options).unwrap(), Some(caps));
+            assert_eq!(options.max_lossy, Some(caps.max_lossy));
+            assert_eq!(options.min_frame_percent, Some(caps.min_frame_percent));
+            assert_eq!(options.min_colors, Some(caps.min_colors));
+            assert_eq!(options.auto_downscale, Some(caps.allow_resize));
+            
+            // 明确指定与等级一致的值不算冲突，包括0%和关闭自动缩小
+            let mut same = CompressOptions {
+                quality_level: Some(level),
+                min_frame_percent: Some(caps.min_frame_percent),
+                auto_downscale: Some(caps.allow_resize),
+                ..Default::default()
+            };
+            assert!(apply_quality_level(&mut same).is_ok());
+            
+            let mut frames = CompressOptions { quality_level: Some(level), min_frame_percent: Some(0), ..Default::default() };
+            let error = apply_quality_level(&mut frames).unwrap_err().to_string();
+            assert!(error.contains("min_frame_percent"), "{}", error);
+            let mut resize = CompressOptions { quality_level: Some(level), auto_downscale: Some(!caps.allow_resize), ..Default::default() };
+            let error = apply_quality_level(&mut resize).unwrap_err().to_string();
+            assert!(error.contains("auto_downscale"), "{}", error);
+        }
     }
     
-    // 计算最小保留帧数
-    let min_frames = std::cmp::max(3, (original_frame_count as f64 * min_frame_percent as f64 / 100.0) as usize);
     
-    // 构建抽帧策略
-    let mut strategies = Vec::new();
+    fn within_heuristic_band(estimate_kb: f64, actual_kb: f64) -> bool {
+        let band = 1.0 + HEURISTIC_ERROR_BAND;
+        actual_kb >= estimate_kb / band && actual_kb <= estimate_kb * band
+    }
     
-    // 从2抽1开始，最多抽到保留最小帧数
-    let max_skip = std::cmp::max(2, std::cmp::min(10, 
-        ((original_frame_count as f64) / (min_frames as f64)).ceil() as usize));
+    // 样例输入：噪点动画、少色纯色动画和帧数少的大画布
+    fn heuristic_samples(dir: &Path) -> Vec<PathBuf> {
+        let noise = dir.join("noise.gif");
+        write_noise_gif(&noise, 12, 64, 10);
+        let solid = dir.join("solid.gif");
+        write_solid_gif(&solid, &[[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255]], 48, 8);
+        let wide = dir.join("wide.gif");
+        write_noise_gif(&wide, 4, 160, 5);
+        vec![noise, solid, wide]
+    }
     
-    for skip in 2..=max_skip {
-        strategies.push(Strategy {
-            skip,
-            delay: ((100.0 * skip as f64) / original_frame_count as f64) as u16 + 10,
-        });
+    fn estimate_for(path: &Path, options: &CompressOptions) -> HeuristicEstimate {
+        let scan = scan_gif_blocks(path).unwrap();
+        heuristic_estimate(
+            get_file_size_kb(path).unwrap(),
+            scan.frames.len(),
+            get_canvas_size(path).unwrap(),
+            max_palette_size(&scan).unwrap_or(256),
+            options,
+        )
     }
     
-    // 如果帧数很多，尝试更激进的抽帧策略
-    if original_frame_count > 30 {
-        let aggressive_skips = [max_skip + 5, max_skip + 10];
-        for &skip in &aggressive_skips {
-            if original_frame_count / skip >= min_frames {
-                strategies.push(Strategy {
-                    skip,
-                    delay: ((100.0 * skip as f64) / original_frame_count as f64) as u16 + 10,
-                });
-            }
+    #[test]
+    fn heuristic_estimate_is_consistent_on_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        for sample in heuristic_samples(dir.path()) {
+            let defaults = estimate_for(&sample, &CompressOptions::default());
+            assert!(defaults.heuristic);
+            assert_eq!(defaults.error_band, HEURISTIC_ERROR_BAND);
+            assert!(defaults.smallest_kb > 0.0 && defaults.smallest_kb <= defaults.lossless_kb, "{:?}", sample);
+            assert!(defaults.lossless_kb <= defaults.original_size, "{:?}", sample);
+            
+            // 保留的帧数与搜索使用同一个下限
+            let options = CompressOptions { min_frame_percent: Some(100), max_lossy: Some(0), min_colors: Some(256), ..Default::default() };
+            let keep_all = estimate_for(&sample, &options);
+            assert_eq!(min_frame_floor(keep_all.frame_count, &options), keep_all.frame_count);
+            assert!((keep_all.smallest_kb - keep_all.lossless_kb).abs() < 1e-9, "{:?}", sample);
+            let half = CompressOptions { min_frame_percent: Some(50), ..options.clone() };
+            let halved = estimate_for(&sample, &half);
+            let expected = keep_all.lossless_kb * min_frame_floor(halved.frame_count, &half) as f64 / halved.frame_count as f64;
+            assert!(halved.smallest_kb >= expected - 1e-9 && halved.smallest_kb <= keep_all.smallest_kb, "{:?}", sample);
+            
+            let reachable = CompressOptions { target_size: defaults.lossless_kb, ..Default::default() };
+            assert_eq!(estimate_for(&sample, &reachable).target_reachable, Some(true));
+            let tiny = CompressOptions { target_size: defaults.smallest_kb / 2.0, ..Default::default() };
+            assert_eq!(estimate_for(&sample, &tiny).target_reachable, Some(false));
         }
+        assert!(within_heuristic_band(100.0, 140.0) && within_heuristic_band(100.0, 70.0));
+        assert!(!within_heuristic_band(100.0, 160.0) && !within_heuristic_band(100.0, 60.0));
     }
     
-    // 限制线程数，不超过策略数量
-    let thread_count = std::cmp::min(threads, strategies.len());
-    println!("开始使用 {} 个线程并行处理 {} 个压缩策略...", thread_count, strategies.len());
+    // 用真实的gifsicle校准：无损优化和最高lossy级别的结果都应落在误差范围内
+    #[test]
+    #[ignore = "需要安装gifsicle"]
+    fn heuristic_estimate_is_within_error_band() {
+        let dir = tempfile::tempdir().unwrap();
+        let max_lossy = LOSSY_LEVELS[LOSSY_LEVELS.len() - 1];
+        for sample in heuristic_samples(dir.path()) {
+            let options = CompressOptions { min_frame_percent: Some(100), max_lossy: Some(0), min_colors: Some(256), ..Default::default() };
+            let lossless = estimate_for(&sample, &options);
+            let output = dir.path().join("lossless.gif");
+            let args = vec!["-O3".to_string(), sample.display().to_string(), "-o".to_string(), output.display().to_string()];
+            run_gifsicle("gifsicle", &args, &SharedState::new()).unwrap();
+            let actual = get_file_size_kb(&output).unwrap();
+            assert!(within_heuristic_band(lossless.lossless_kb, actual), "{:?}: {} / {}", sample, lossless.lossless_kb, actual);
+            
+            let lossy = estimate_for(&sample, &CompressOptions { max_lossy: Some(max_lossy), ..options });
+            let output = dir.path().join("lossy.gif");
+            let args = vec![
+                "-O3".to_string(),
+                format!("--lossy={}", max_lossy),
+                sample.display().to_string(),
+                "-o".to_string(),
+                output.display().to_string(),
+            ];
+            run_gifsicle("gifsicle", &args, &SharedState::new()).unwrap();
+            let actual = get_file_size_kb(&output).unwrap();
+            assert!(within_heuristic_band(lossy.smallest_kb, actual), "{:?}: {} / {}", sample, lossy.smallest_kb, actual);
+        }
+    }
     
-    // 创建通道以接收处理结果
-    let (tx, rx): (Sender<StrategyResult>, Receiver<StrategyResult>) = mpsc::channel();
     
-    // 创建线程池
-    let input_path_arc = Arc::new(input_path_str);
-    let mut handles = Vec::new();
+    #[test]
+    fn budget_truncation_is_recorded_when_work_is_skipped() {
+        let state = SharedState::new();
+        state.set_budget(SearchBudget { max_gifsicle_runs: Some(0), time_budget_ms: None, safe_mode: false });
+        // 预算已用完但还没有跳过任何尝试
+        assert!(state.is_budget_exhausted());
+        assert!(!state.report(Instant::now()).budget.unwrap().truncated);
+        assert_eq!(state.stop_reason(), Some("gifsicle调用次数预算已用完"));
+        assert!(state.report(Instant::now()).budget.unwrap().truncated);
+        
+        let state = SharedState::new();
+        state.set_budget(SearchBudget { max_gifsicle_runs: None, time_budget_ms: Some(60_000), safe_mode: false });
+        assert!(!state.skip_for_budget());
+        assert!(!state.report(Instant::now()).budget.unwrap().truncated);
+    }
     
-    // 创建共享状态
-    let shared_state = Arc::new(SharedState::new());
+    #[cfg(unix)]
+    #[test]
+    fn search_finishing_within_budget_is_not_truncated() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 16, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, max_gifsicle_runs: Some(10_000), ..Default::default() };
+        let outcome = optimize_gif(&input, &output, &options, 2, None, None).unwrap();
+        assert!(!outcome.time_limited);
+        let usage = outcome.report.budget.unwrap();
+        assert!(!usage.truncated);
+        assert!(usage.gifsicle_runs > 0 && usage.gifsicle_runs < 10_000);
+    }
     
-    // 设置初始最佳大小为基础优化后的大小
-    shared_state.update_best_size(opt_size);
     
-    for (i, chunk) in strategies.into_iter().enumerate() {
-        let tx_clone = tx.clone();
-        let input_path_clone = Arc::clone(&input_path_arc);
-        let shared_state_clone = Arc::clone(&shared_state);
+    #[cfg(unix)]
+    #[test]
+    fn two_segment_lossy_schedule_runs_each_level() {
+        let segment = |start_frame, end_frame, lossy| LossySegment { start_frame, end_frame, lossy };
+        let schedule = vec![segment(3, 5, 120), segment(0, 2, 40)];
+        assert_eq!(validate_lossy_schedule(&schedule, 6).unwrap(), vec![segment(0, 2, 40), segment(3, 5, 120)]);
+        assert!(validate_lossy_schedule(&[segment(0, 2, 40), segment(3, 5, MAX_LOSSY_LEVEL)], 6).is_ok());
+        let error = validate_lossy_schedule(&[segment(0, 2, 40), segment(3, 5, MAX_LOSSY_LEVEL + 1)], 6).unwrap_err().to_string();
+        assert!(error.contains("301"), "{}", error);
+        assert!(validate_lossy_schedule(&[segment(0, 2, 40), segment(4, 5, 120)], 6).is_err());
+        assert!(validate_lossy_schedule(&[segment(0, 3, 40), segment(3, 5, 120)], 6).is_err());
+        assert!(validate_lossy_schedule(&[segment(0, 2, 40)], 6).is_err());
         
-        // 创建线程处理这个策略
-        let handle = thread::spawn(move || {
-            let result = process_strategy(
-                &input_path_clone,
-                chunk,
-                target_size_kb,
-                i + 1,
-                &shared_state_clone
-            );
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        let _tools = FakeTools::install(&[("gifsicle", &logging_gifsicle(&log))]);
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 16, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, threads: 1, lossy_schedule: Some(schedule), ..Default::default() };
+        let outcome = optimize_gif(&input, &output, &options, 1, None, None).unwrap();
+        let results = outcome.winner.unwrap().lossy_schedule.unwrap();
+        let segments: Vec<LossySegment> = results.iter().map(|result| result.segment).collect();
+        assert_eq!(segments, vec![segment(0, 2, 40), segment(3, 5, 120)]);
+        let calls = fs::read_to_string(&log).unwrap();
+        assert!(calls.lines().any(|line| line.contains("--lossy=40") && line.contains("#0-2")), "{}", calls);
+        assert!(calls.lines().any(|line| line.contains("--lossy=120") && line.contains("#3-5")), "{}", calls);
+        assert!(output.exists());
+    }
+    
+    
+    #[test]
+    fn palette_report_counts_duplicate_and_unused_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dup.gif");
+        // 全局颜色表中第2项与第0项、第4项与第1项重复，第4到7项没有用到
+        let global = [[255, 0, 0], [0, 255, 0], [255, 0, 0], [0, 0, 255], [0, 255, 0], [0, 0, 0], [255, 255, 255], [128, 128, 128]];
+        let palette: Vec<u8> = global.iter().flatten().copied().collect();
+        let mut encoder = gif::Encoder::new(File::create(&path).unwrap(), 4, 4, &palette).unwrap();
+        let buffer: Vec<u8> = [0, 1, 2, 3].iter().cycle().take(16).copied().collect();
+        encoder.write_frame(&gif::Frame { width: 4, height: 4, buffer: buffer.into(), ..Default::default() }).unwrap();
+        // 局部颜色表两两重复，只用到第0项和第2项
+        let local: Vec<u8> = [[0, 0, 0], [0, 0, 0], [255, 255, 255], [255, 255, 255]].iter().flatten().copied().collect();
+        let buffer: Vec<u8> = [0, 2].iter().cycle().take(16).copied().collect();
+        let frame = gif::Frame { width: 4, height: 4, buffer: buffer.into(), palette: Some(local), ..Default::default() };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        
+        let report = palette_efficiency(&path).unwrap();
+        let global = report.global.unwrap();
+        assert_eq!((global.total, global.used, global.duplicates, global.distinct_used, global.wasted), (8, 4, 2, 3, 5));
+        let local = report.local.unwrap();
+        assert_eq!((local.total, local.used, local.duplicates, local.distinct_used, local.wasted), (4, 2, 2, 2, 2));
+        assert_eq!(report.local_tables, 1);
+        assert_eq!((report.total_entries, report.used_entries, report.duplicate_entries, report.wasted_entries), (12, 6, 4, 7));
+        assert_eq!(report.suggested_colors, Some(4));
+        
+        // 透明色不算重复，但算作用到的一种颜色
+        let stats = palette_table_stats(&[9, 9, 9, 9, 9, 9], &[true, true], &[false, true]);
+        assert_eq!((stats.duplicates, stats.distinct_used, stats.wasted), (0, 2, 0));
+    }
+    
+    
+    #[test]
+    fn strategy_plan_invariants_hold_for_random_inputs() {
+        // 固定种子的线性同余生成器，保证失败时可以复现
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        for _ in 0..2000 {
+            let frame_count = 1 + next(400) as usize;
+            let options = CompressOptions {
+                min_frame_percent: (next(4) > 0).then(|| next(101) as u32),
+                motion_threshold: (next(3) == 0).then(|| next(40) as f64),
+                ..Default::default()
+            };
+            let context = format!("frame_count={} min_frame_percent={:?} motion={:?}", frame_count, options.min_frame_percent, options.motion_threshold);
             
-            // 如果这是一个好的结果，更新共享状态中的最佳大小
-            if result.success && result.size < shared_state_clone.get_best_size() {
-                let is_better = shared_state_clone.update_best_size(result.size);
-                
-                // 如果我们的结果被接受为更好的结果，并且达到了目标大小，设置found_target标志
-                if is_better && result.size <= target_size_kb {
-                    shared_state_clone.set_found_target();
-                }
-            }
+            // 下限随百分比单调不减，且在1到总帧数之间
+            let floor = min_frame_floor(frame_count, &options);
+            assert!(floor >= 1 && floor <= frame_count, "{}", context);
+            assert!(floor >= 3.min(frame_count), "{}", context);
+            let higher = CompressOptions { min_frame_percent: Some(options.min_frame_percent.unwrap_or(0) + 1), ..options.clone() };
+            assert!(min_frame_floor(frame_count, &higher) >= floor, "{}", context);
+            assert!(min_frame_floor(frame_count + 1, &options) >= floor, "{}", context);
             
-            // 发送结果到主线程
-            let _ = tx_clone.send(result);
-        });
-        
-        handles.push(handle);
+            let plan = plan_strategies(frame_count, &options);
+            assert_eq!(plan.min_frames, floor, "{}", context);
+            // 没有低于下限的策略，保留的帧互不相同
+            let mut kept = std::collections::HashSet::new();
+            for strategy in plan.strategies.iter().filter(|strategy| strategy.motion_threshold.is_none()) {
+                assert!(frame_count.div_ceil(strategy.skip) >= floor, "{} {}", context, strategy.label());
+                assert!(kept.insert(strategy.skip.min(frame_count)), "{} {}", context, strategy.label());
+            }
+            assert!(plan.rejected.iter().all(|rejected| !plan.strategies.iter().any(|strategy| strategy.label() == rejected.strategy)), "{}", context);
+            // 至少考虑过一个策略；2抽1满足下限时一定有可用的策略
+            assert!(!plan.strategies.is_empty() || !plan.rejected.is_empty(), "{}", context);
+            if frame_count.div_ceil(2) >= floor || options.motion_threshold.is_some() {
+                assert!(!plan.strategies.is_empty(), "{}", context);
+            }
+            assert!(plan.max_skip >= 1 && plan.max_skip <= MAX_REGULAR_SKIP, "{}", context);
+        }
     }
     
-    // 丢弃发送者以允许接收者知道何时所有发送者都已完成
-    drop(tx);
     
-    // 等待并收集所有策略的结果
-    let mut best_size = opt_size;
-    let mut best_file: Option<TempFile> = Some(temp_file_opt);
-    let mut found_solution = false;
+    #[cfg(unix)]
+    #[test]
+    fn in_memory_run_passes_no_files_to_gifsicle() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        // 只经由stdin/stdout工作的gifsicle，参数中出现任何已有文件都会失败
+        let piping_gifsicle = format!(
+            "#!/bin/sh\n\
+             [ \"$1\" = \"--version\" ] && {{ echo 'fake gifsicle'; exit 0; }}\n\
+             for a in \"$@\"; do [ -e \"$a\" ] && {{ echo \"file argument: $a\" >&2; exit 1; }}; done\n\
+             echo \"$*\" >> '{}'\n\
+             cat\n",
+            log.display()
+        );
+        let _tools = FakeTools::install(&[("gifsicle", &piping_gifsicle)]);
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 6, 16, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, threads: 1, in_memory: true, ..Default::default() };
+        
+        let outcome = optimize_gif(&input, &output, &options, 1, None, None).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&input).unwrap());
+        assert_eq!(outcome.final_bytes, fs::metadata(&input).unwrap().len());
+        let calls = fs::read_to_string(&log).unwrap();
+        assert!(calls.lines().count() > 1, "{}", calls);
+        assert!(calls.lines().all(|line| line.ends_with("-o -")), "{}", calls);
+        // 输出目录中只有输入和最终结果，没有中间文件
+        let mut names: Vec<String> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        assert_eq!(names, vec!["calls.log", "in.gif", "out.gif"]);
+    }
     
-    // 从通道接收结果
-    for result in rx.iter() {
-        if !result.success {
-            continue;
+    
+    #[test]
+    fn archetype_inputs_get_sensible_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        // 颜色很少的线条画
+        let line_art = dir.path().join("line_art.gif");
+        write_solid_gif(&line_art, &[[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]], 32, 10);
+        let traits = source_traits(&line_art).unwrap();
+        assert!(!traits.photographic && !traits.has_transparency, "{}", traits.mean_frame_colors);
+        let recommendation = recommend_format(traits, false);
+        assert_eq!(recommendation.format, RecommendedFormat::Gif);
+        assert!(recommendation.encoder_available && recommendation.fallback.is_none());
+        
+        // 每帧都用满调色板且每帧独立的噪点，相当于照片类的剧烈运动
+        let photo = dir.path().join("photo.gif");
+        let palette: Vec<u8> = (0..=255u8).flat_map(|v| [v, v.wrapping_mul(7), v.wrapping_mul(13)]).collect();
+        let mut encoder = gif::Encoder::new(File::create(&photo).unwrap(), 64, 64, &palette).unwrap();
+        for i in 0..6u32 {
+            let buffer: Vec<u8> = (0..64 * 64u32)
+                .map(|p| ((p ^ i.wrapping_mul(0x9e37_79b9)).wrapping_mul(2654435761) >> 13) as u8)
+                .collect();
+            encoder.write_frame(&gif::Frame { width: 64, height: 64, buffer: buffer.into(), delay: 5, ..Default::default() }).unwrap();
         }
+        drop(encoder);
+        let traits = source_traits(&photo).unwrap();
+        assert!(traits.photographic && traits.high_motion, "{} {}", traits.mean_frame_colors, traits.motion);
+        let recommendation = recommend_format(traits.clone(), true);
+        assert_eq!(recommendation.format, RecommendedFormat::Mp4);
+        assert!(!recommendation.encoder_available);
+        assert_eq!(recommendation.fallback, Some(RecommendedFormat::Webp));
+        assert_eq!(recommend_format(traits.clone(), false).fallback, Some(RecommendedFormat::Gif));
+        // 需要透明时不推荐MP4
+        let transparent = SourceTraits { has_transparency: true, ..traits.clone() };
+        assert_eq!(recommend_format(transparent, true).format, RecommendedFormat::Webp);
         
-        if result.size <= target_size_kb {
-            // 清理之前的最佳文件（如果有的话）
-            if let Some(old_file) = best_file.take() {
-                let _ = old_file.cleanup();
-            }
-            
-            best_size = result.size;
-            best_file = result.file;
-            found_solution = true;
-            println!("找到达到目标大小的策略! 大小: {:.2} KB", best_size);
-            // 设置标志，以便其他线程可以提前退出
-            shared_state.set_found_target();
-            break; // 提前退出循环，不再处理其他结果
-        } else if result.size < best_size {
-            // 清理之前的最佳文件（如果有的话）
-            if let Some(old_file) = best_file.take() {
-                let _ = old_file.cleanup();
-            }
-            
-            best_size = result.size;
-            best_file = result.file;
-        } else if result.file.is_some() {
-            // 该结果不比当前最佳结果好，清理它
-            if let Some(file) = result.file {
-                let _ = file.cleanup();
-            }
+        // 单帧照片
+        let still = dir.path().join("still.gif");
+        write_noise_gif(&still, 1, 64, 0);
+        let traits = source_traits(&still).unwrap();
+        assert_eq!(traits.frame_count, 1);
+        let recommendation = recommend_format(traits, false);
+        assert_eq!(recommendation.format, RecommendedFormat::Webp);
+        assert_eq!(recommendation.fallback, Some(RecommendedFormat::Gif));
+        
+        // 各帧颜色合计超过256种但每帧颜色不多
+        let many_palettes = SourceTraits {
+            frame_count: 12,
+            distinct_colors: 600,
+            colors_saturated: false,
+            mean_frame_colors: 50.0,
+            has_transparency: false,
+            motion: 0.01,
+            photographic: false,
+            high_motion: false,
+        };
+        assert_eq!(recommend_format(many_palettes, true).format, RecommendedFormat::Apng);
+    }
+    
+    #[test]
+    fn spread_subset_keeps_ends_and_order() {
+        let items: Vec<usize> = (0..10).collect();
+        assert_eq!(spread_subset(items.clone(), 3), vec![0, 4, 9]);
+        assert_eq!(spread_subset(items.clone(), 2), vec![0, 9]);
+        assert_eq!(spread_subset(items.clone(), 1), vec![0]);
+        assert_eq!(spread_subset(items.clone(), 0), vec![0]);
+        assert_eq!(spread_subset(items.clone(), 10), items);
+        assert_eq!(spread_subset(items.clone(), 50), items);
+        assert!(spread_subset(Vec::<usize>::new(), 3).is_empty());
+        for count in 2..10 {
+            let subset = spread_subset(items.clone(), count);
+            assert_eq!(subset.len(), count);
+            assert!(subset.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!((subset[0], subset[count - 1]), (0, 9));
         }
     }
     
-    // 我们不再等待所有线程完成
-    // 如果已经找到满足条件的结果，其他线程会自动退出
-    // 如果我们想要优雅地等待，可以设置一个超时
-    if found_solution {
-        println!("已找到满足条件的结果，不再等待其他线程");
-    } else {
-        println!("尚未找到满足目标大小的结果，等待所有线程完成...");
-        // 等待所有线程完成
-        for handle in handles {
-            let _ = handle.join();
+    #[test]
+    fn matte_accepts_long_and_short_hex() {
+        assert_eq!(parse_matte(None).unwrap(), DEFAULT_MATTE);
+        assert_eq!(parse_matte(Some("#ff8000")).unwrap(), [255, 128, 0]);
+        assert_eq!(parse_matte(Some(" 00FF7f ")).unwrap(), [0, 255, 127]);
+        assert_eq!(parse_matte(Some("#f80")).unwrap(), [255, 136, 0]);
+        for invalid in ["", "#", "#ff80", "#gg0000", "#ff00000", "red"] {
+            assert!(parse_matte(Some(invalid)).is_err(), "{}", invalid);
         }
     }
     
-    // 使用找到的最佳文件
-    if let Some(best) = best_file {
-        println!("\n复制最佳结果到输出文件...");
-        fs::copy(&best.path, &output_path)?;
-        
-        // 复制完成后清理临时文件
-        let _ = best.cleanup();
+    
+    #[cfg(unix)]
+    #[test]
+    fn one_off_commands_stop_when_their_job_is_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        let _tools = FakeTools::install(&[("gifsicle", &logging_gifsicle(&log))]);
+        let input = dir.path().join("in.gif");
+        write_solid_gif(&input, &[[0, 0, 0], [255, 255, 255], [255, 255, 255], [255, 255, 255]], 8, 10);
+        let output = dir.path().join("out.gif");
         
-        let final_size = get_file_size_kb(&output_path)?;
-        println!("完成! 最终大小: {:.2} KB", final_size);
+        let jobs = Arc::new(JobRegistry::new());
+        let job = jobs.start(JobKind::Compress, "in.gif", None, true);
+        assert_eq!(jobs.cancel(JobKind::Compress, None), 1);
+        let shared_state = SharedState::with_cancel_flag(job.cancel_flag());
+        assert!(matches!(strip_trailing_frames_file(&input, &output, None, &shared_state), Err(GifError::Cancelled)));
+        assert!(matches!(make_perfect_loop_file(&input, &output, None, &shared_state), Err(GifError::Cancelled)));
+        assert!(matches!(reduce_bit_depth_file(&input, &output, 4, &shared_state), Err(GifError::Cancelled)));
+        assert!(matches!(detect_cinemagraph(&input, &shared_state), Err(GifError::Cancelled)));
+        assert!(matches!(generate_candidate_files(&input, &CompressOptions::default(), 3, &job.cancel_flag()), Err(GifError::NoValidResults)));
+        // 取消后不再启动gifsicle，也没有写出结果
+        assert!(!log.exists() || fs::read_to_string(&log).unwrap().is_empty());
+        assert!(!output.exists());
+    }
+    
+    
+    #[cfg(unix)]
+    #[test]
+    fn cancelled_search_stops_waiting_and_keeps_best_result() {
+        // lossy尝试很慢，取消时正在运行
+        let slow_lossy = copying_gifsicle("0").replacen("in=''", "case \"$*\" in *--lossy*) sleep 5;; esac\nin=''", 1);
+        let _tools = FakeTools::install(&[("gifsicle", &slow_lossy)]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 12, 32, 5);
+        let output = dir.path().join("out.gif");
+        let options = CompressOptions { target_size: 0.1, ..Default::default() };
         
-        return Ok((original_size, final_size));
-    } else {
-        return Err(GifError::NoValidResults);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = Arc::clone(&cancel);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(800));
+                cancel.store(true, Ordering::SeqCst);
+            })
+        };
+        let started = Instant::now();
+        let outcome = optimize_gif(&input, &output, &options, 2, None, Some(cancel)).unwrap();
+        canceller.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(4), "用时 {:?}", started.elapsed());
+        assert!(outcome.cancelled);
+        // 取消时保存目前最好的结果
+        assert!(output.exists());
+        assert!(outcome.final_bytes <= outcome.original_bytes);
     }
-}
-
-// 应用状态管理
-struct AppState {
-    // 保存处理结果
-    last_result: std::sync::Mutex<Option<CompressResult>>,
-}
-
-// 查找gifsicle可执行文件的辅助函数
-fn find_gifsicle() -> Option<String> {
-    // 常见的gifsicle安装路径
-    let possible_paths = vec![
-        "gifsicle",                    // PATH中的版本
-        "/opt/homebrew/bin/gifsicle",  // M1/M2 Mac的Homebrew路径
-        "/usr/local/bin/gifsicle",     // Intel Mac的Homebrew路径
-        "/usr/bin/gifsicle",           // Linux常见路径
-        "C:\\Program Files\\gifsicle\\gifsicle.exe" // Windows可能路径
-    ];
-
-    println!("DEBUG: 正在查找gifsicle可执行文件...");
     
-    for path in possible_paths {
-        println!("DEBUG: 尝试路径: {}", path);
-        match Command::new(path).arg("--version").status() {
-            Ok(status) => {
-                println!("DEBUG: 路径 {} 可用，状态: {}", path, status);
-                return Some(path.to_string());
-            },
-            Err(err) => {
-                println!("DEBUG: 路径 {} 不可用: {}", path, err);
-            }
+    
+    #[cfg(unix)]
+    #[test]
+    fn hanging_base_pass_fails_with_gifsicle_timeout() {
+        // 基础优化（带--careful）一直不结束；exec使终止后不留下占用管道的子进程
+        let hanging = copying_gifsicle("0").replacen("in=''", "case \"$*\" in *--careful*) exec sleep 30;; esac\nin=''", 1);
+        let _tools = FakeTools::install(&[("gifsicle", &hanging)]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 12, 32, 5);
+        let output = dir.path().join("out.gif");
+        for in_memory in [false, true] {
+            let options = CompressOptions { target_size: 0.1, gifsicle_timeout_secs: Some(1), in_memory, ..Default::default() };
+            let started = Instant::now();
+            let result = optimize_gif(&input, &output, &options, 2, None, None);
+            // 基础优化的限制是单次调用限制的BASE_PASS_TIMEOUT_FACTOR倍
+            assert!(
+                matches!(result, Err(GifError::GifsicleTimeout(secs)) if secs == BASE_PASS_TIMEOUT_FACTOR as u64),
+                "in_memory={}", in_memory
+            );
+            assert!(started.elapsed() < Duration::from_secs(BASE_PASS_TIMEOUT_FACTOR as u64 + 5));
+            assert!(!output.exists());
         }
+        
+        let state = SharedState::new();
+        state.set_gifsicle_timeout(Some(1));
+        assert_eq!(state.gifsicle_timeout(), Some(Duration::from_secs(1)));
+        let base_pass = BasePassGifsicle::start();
+        assert_eq!(state.gifsicle_timeout(), Some(Duration::from_secs(BASE_PASS_TIMEOUT_FACTOR as u64)));
+        drop(base_pass);
+        assert_eq!(state.gifsicle_timeout(), Some(Duration::from_secs(1)));
+        // 设置为0时基础优化同样不限制
+        let unlimited = SharedState::new();
+        unlimited.set_gifsicle_timeout(Some(0));
+        let _base_pass = BasePassGifsicle::start();
+        assert_eq!(unlimited.gifsicle_timeout(), None);
     }
     
-    println!("DEBUG: 未找到gifsicle可执行文件");
-    None
-}
-
-// 检查gifsicle是否已安装
-#[tauri::command]
-fn check_gifsicle_installed() -> bool {
-    println!("DEBUG: 直接使用Command::new检查gifsicle是否已安装");
-    // 先尝试简单的PATH检查
-    let result = Command::new("gifsicle").arg("--version").output();
-    let is_installed = result.is_ok();
-    println!("DEBUG: 简单PATH检查 gifsicle已安装: {}", is_installed);
     
-    if is_installed {
-        true
-    } else {
-        // 如果PATH检查失败，尝试具体路径
-        println!("DEBUG: PATH检查失败，尝试特定路径");
-        find_gifsicle().is_some()
+    #[test]
+    fn failed_temp_cleanup_is_still_untracked() {
+        let temp = TempFile::new(NamedTempFile::new().unwrap());
+        assert!(IN_FLIGHT.temp_paths.lock().unwrap().contains(&temp.path));
+        // 同名的目录不能用remove_file删除
+        let _ = fs::remove_file(&temp.path);
+        fs::create_dir(&temp.path).unwrap();
+        assert!(temp.cleanup().is_err());
+        assert!(!IN_FLIGHT.temp_paths.lock().unwrap().contains(&temp.path));
+        fs::remove_dir(&temp.path).unwrap();
     }
-}
-
-// 压缩GIF文件
-#[tauri::command]
-async fn compress_gif(
-    state: State<'_, AppState>,
-    input_path: String, 
-    output_path: String,
-    options: CompressOptions,
-) -> Result<CompressResult, String> {
-    // 在这里先克隆一次，这样闭包中使用的是克隆版本
-    let output_path_for_result = output_path.clone();
     
-    let result = tokio::task::spawn_blocking(move || {
-        optimize_gif(
-            input_path.clone(),
-            output_path.clone(),
-            options.target_size,
-            options.min_frame_percent,
-            if options.threads == 0 { num_cpus::get() } else { options.threads }
-        )
-    }).await.unwrap();
     
-    let compress_result = match result {
-        Ok((original_size, final_size)) => {
-            let success = final_size <= options.target_size;
-            let msg = if success {
-                format!("成功压缩GIF到目标大小以下，压缩率: {:.1}%", (1.0 - (final_size / original_size)) * 100.0)
-            } else {
-                format!("无法达到目标大小，但已尽可能压缩，压缩率: {:.1}%", (1.0 - (final_size / original_size)) * 100.0)
-            };
-            
-            CompressResult {
-                success,
-                original_size,
-                compressed_size: final_size,
-                output_path: output_path_for_result.clone(),
-                message: msg,
-            }
-        },
-        Err(e) => {
-            CompressResult {
-                success: false,
-                original_size: 0.0,
-                compressed_size: 0.0,
-                output_path: String::new(),
-                message: format!("压缩失败: {}", e),
+    #[test]
+    fn webp_encoding_stops_when_cancelled() {
+        // 只在真正转换时变慢，查找gif2webp时的-version立即返回；exec使终止后不留下占用管道的子进程
+        let slow = copying_gifsicle("0").replacen("in=''", "case \"$*\" in *-o*) exec sleep 5;; esac\nin=''", 1);
+        let _tools = FakeTools::install(&[("gif2webp", &slow)]);
+        let dir = tempfile::tempdir().unwrap();
+        let gif = dir.path().join("in.gif");
+        write_noise_gif(&gif, 3, 16, 10);
+        
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancel_flag);
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            flag.store(true, Ordering::SeqCst);
+        });
+        let started = Instant::now();
+        let result = webp_for_target(&gif, 1.0, None, &SharedState::with_cancel_flag(cancel_flag));
+        canceller.join().unwrap();
+        
+        assert!(matches!(result, Err(GifError::Cancelled)));
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+    
+    
+    /// 在write_noise_gif写出的GIF的全局颜色表之后插入ICCRGBG1应用扩展
+    fn insert_icc_extension(path: &Path, profile: &[u8]) {
+        let mut data = fs::read(path).unwrap();
+        let mut extension = vec![0x21, 0xFF, ICC_APP_IDENTIFIER.len() as u8];
+        extension.extend_from_slice(ICC_APP_IDENTIFIER);
+        for block in profile.chunks(255) {
+            extension.push(block.len() as u8);
+            extension.extend_from_slice(block);
+        }
+        extension.push(0);
+        // 文件头、逻辑屏幕描述符和256色的全局颜色表
+        let end_of_table = 13 + 3 * 256;
+        data.splice(end_of_table..end_of_table, extension);
+        fs::write(path, data).unwrap();
+    }
+    
+    /// 读出WebP中ICCP块的内容，同时要求VP8X中设置了ICC标志
+    fn webp_icc_profile(webp: &[u8]) -> Option<Vec<u8>> {
+        let mut pos = 12;
+        let mut icc_flag = false;
+        while pos + 8 <= webp.len() {
+            let size = u32::from_le_bytes(webp[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let data = &webp[pos + 8..pos + 8 + size];
+            match &webp[pos..pos + 4] {
+                b"VP8X" => icc_flag = data[0] & 0x20 != 0,
+                b"ICCP" if icc_flag => return Some(data.to_vec()),
+                _ => {}
             }
+            pos += 8 + size + size % 2;
         }
-    };
+        None
+    }
     
-    // 更新状态
-    *state.last_result.lock().unwrap() = Some(compress_result.clone());
+    #[cfg(unix)]
+    #[test]
+    fn icc_profile_survives_webp_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let gif = dir.path().join("in.gif");
+        write_noise_gif(&gif, 3, 16, 10);
+        // 长度为奇数并跨越多个子块
+        let profile: Vec<u8> = (0..601u32).map(|i| (i * 31) as u8).collect();
+        insert_icc_extension(&gif, &profile);
+        let source_icc = scan_gif_blocks(&gif).unwrap().icc_profile;
+        assert_eq!(source_icc.as_deref(), Some(profile.as_slice()));
+        
+        // gif2webp的替身给出只有VP8L块的简单格式WebP
+        let frame = dir.path().join("frame.webp");
+        let pixels = vec![200u8; 16 * 16 * 4];
+        image::codecs::webp::WebPEncoder::new_lossless(File::create(&frame).unwrap())
+            .encode(&pixels, 16, 16, image::ColorType::Rgba8)
+            .unwrap();
+        let script = format!(
+            "#!/bin/sh\nprev=''\nfor a in \"$@\"; do [ \"$prev\" = \"-o\" ] && cp '{}' \"$a\"; prev=\"$a\"; done\nexit 0\n",
+            frame.display()
+        );
+        let _tools = FakeTools::install(&[("gif2webp", &script)]);
+        
+        let (webp, _, _) = webp_for_target(&gif, 100.0, source_icc.as_deref(), &SharedState::new()).unwrap();
+        let data = fs::read(&webp.path).unwrap();
+        assert_eq!(webp_icc_profile(&data).as_deref(), Some(profile.as_slice()));
+        // 写入ICCP块后仍是可以解码的WebP
+        let decoded = image::load_from_memory_with_format(&data, image::ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 16));
+    }
     
-    Ok(compress_result)
-}
-
-// 获取GIF信息
-#[tauri::command]
-async fn get_gif_info(path: String) -> Result<(f64, usize), String> {
-    let file_size = match get_file_size_kb(&path) {
-        Ok(size) => size,
-        Err(e) => return Err(format!("无法获取文件大小: {}", e)),
-    };
+    #[test]
+    #[ignore = "需要安装gif2webp"]
+    fn icc_profile_survives_animated_webp() {
+        let dir = tempfile::tempdir().unwrap();
+        let gif = dir.path().join("in.gif");
+        write_noise_gif(&gif, 4, 32, 10);
+        let profile: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        insert_icc_extension(&gif, &profile);
+        let webp = encode_webp(&gif, None, Some(&profile), &SharedState::new()).unwrap();
+        assert_eq!(webp_icc_profile(&fs::read(&webp.path).unwrap()).as_deref(), Some(profile.as_slice()));
+    }
     
-    let frame_count = match get_frame_count(&path) {
-        Ok(count) => count,
-        Err(e) => return Err(format!("无法获取帧数: {}", e)),
-    };
     
-    Ok((file_size, frame_count))
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failing_batch_item_does_not_stop_the_rest() {
+        let _tools = FakeTools::install(&[("gifsicle", &copying_gifsicle("0"))]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        write_noise_gif(Path::new(&path("a.gif")), 3, 16, 10);
+        write_noise_gif(Path::new(&path("c.gif")), 3, 16, 10);
+        // 第二项的输入不存在
+        let items = batch_items(vec![
+            (path("a.gif"), path("a_out.gif")),
+            (path("missing.gif"), path("b_out.gif")),
+            (path("c.gif"), path("c_out.gif")),
+        ]);
+        let options = CompressOptions { target_size: 100.0, threads: 1, ..Default::default() };
+        let compress = |item: &BatchItem| {
+            let result = optimize_gif(Path::new(&item.input_path), Path::new(&item.output_path), &options, 1, None, None);
+            let result = build_compress_result(result, &item.output_path, options.target_size);
+            async move { Ok(result) }
+        };
+        let batch = run_batch(items, options.target_size, compress, |_| {}).await;
+        
+        let success: Vec<bool> = batch.results.iter().map(|result| result.success).collect();
+        assert_eq!(success, vec![true, false, true]);
+        assert!(!batch.results[1].message.is_empty());
+        assert!(Path::new(&path("c_out.gif")).exists());
+        assert_eq!((batch.succeeded, batch.failed), (2, 1));
+    }
+    
+    
+    /// 假的gif2webp：把每次的质量（无损为100）追加到log，输出的字节数为输入的质量%
+    #[cfg(unix)]
+    fn sizing_gif2webp(log: &Path) -> String {
+        format!(
+            "#!/bin/sh\n\
+             [ \"$1\" = \"-version\" ] && exit 0\n\
+             q=100; in=''; out=''; prev=''\n\
+             for a in \"$@\"; do\n\
+             if [ \"$prev\" = \"-q\" ]; then q=\"$a\"; elif [ \"$prev\" = \"-o\" ]; then out=\"$a\"; elif [ -f \"$a\" ]; then in=\"$a\"; fi\n\
+             prev=\"$a\"\n\
+             done\n\
+             echo \"$q\" >> '{}'\n\
+             head -c $(( $(wc -c < \"$in\") * q / 100 )) /dev/zero > \"$out\"\n",
+            log.display()
+        )
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn webp_output_encodes_base_pass_against_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let gifsicle_log = dir.path().join("gifsicle.log");
+        let webp_log = dir.path().join("gif2webp.log");
+        let _tools = FakeTools::install(&[("gifsicle", &logging_gifsicle(&gifsicle_log)), ("gif2webp", &sizing_gif2webp(&webp_log))]);
+        let input = dir.path().join("in.gif");
+        write_noise_gif(&input, 12, 32, 5);
+        let input_bytes = fs::metadata(&input).unwrap().len();
+        let base = dir.path().join("base.gif");
+        let read_log = |path: &Path| fs::read_to_string(path).unwrap_or_default().lines().map(str::to_string).collect::<Vec<_>>();
+        
+        // 原文件已小于目标时同样做基础优化，不直接复制原文件
+        let options = CompressOptions { target_size: 1000.0, threads: 1, output_format: OutputFormat::Webp, ..Default::default() };
+        optimize_gif(&input, &base, &base_pass_options(&options), 1, None, None).unwrap();
+        let calls = read_log(&gifsicle_log);
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].contains("-O3") && calls[0].contains("--careful"));
+        
+        // 超过目标时也只做基础优化，有损质量按WebP的大小与target_size比较，60%是第一个不超过65%的
+        let _ = fs::remove_file(&gifsicle_log);
+        let target_size = input_bytes as f64 * 0.65 / 1024.0;
+        let options = CompressOptions { target_size, ..options };
+        optimize_gif(&input, &base, &base_pass_options(&options), 1, None, None).unwrap();
+        assert_eq!(read_log(&gifsicle_log).len(), 1);
+        let conversion = convert_to_webp(&base, &input, &options, 1, None, Arc::new(AtomicBool::new(false))).unwrap();
+        assert_eq!(read_log(&webp_log), vec!["100", "90", "75", "60"]);
+        assert_eq!(conversion.quality, Some(60));
+        assert!(conversion.bytes <= kb_to_bytes(target_size));
+        assert!(!conversion.frames_dropped);
+        
+        // 最低质量仍超过目标时按目标大小搜索抽帧和lossy
+        let _ = fs::remove_file(&gifsicle_log);
+        let options = CompressOptions { target_size: 0.01, ..options };
+        let conversion = convert_to_webp(&base, &input, &options, 1, None, Arc::new(AtomicBool::new(false))).unwrap();
+        assert!(read_log(&gifsicle_log).iter().any(|call| call.contains("--lossy")));
+        assert_eq!(conversion.quality, Some(15));
+    }
 }